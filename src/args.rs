@@ -1,4 +1,6 @@
-use clap::Parser;
+use crate::doctor::LONG_VERSION;
+use crate::logging::LogFormat;
+use clap::{Parser, Subcommand};
 
 static DEFAULT_CONFIG_PATH: &str = "/etc/osc/bsud.json";
 
@@ -7,8 +9,31 @@ pub fn parse() -> Args {
 }
 
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about=None)]
+#[command(author, version, long_version = LONG_VERSION, about, long_about=None)]
 pub struct Args {
     #[arg(long = "config", short = 'c', default_value_t = String::from(DEFAULT_CONFIG_PATH))]
     pub config_path: String,
+    /// Print what bsud finds and would do for each drive, then exit without changing anything
+    #[arg(long = "discover-only")]
+    pub discover_only: bool,
+    /// Log what bsud would do during one reconcile pass of each drive, then exit without changing anything
+    #[arg(long = "dry-run")]
+    pub dry_run: bool,
+    /// Run one reconcile pass of each drive in parallel, report each result, then exit (non-zero if any failed)
+    #[arg(long = "once")]
+    pub once: bool,
+    /// Validate the configuration file and print each drive's effective settings, then exit
+    #[arg(long = "check-config")]
+    pub check_config: bool,
+    /// Log format, "json" emits one JSON object per line with the drive as a field
+    #[arg(long = "log-format", value_enum, default_value_t = LogFormat::Text)]
+    pub log_format: LogFormat,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug, Clone, PartialEq)]
+pub enum Command {
+    /// Check binaries, permissions, configuration, VM metadata and Outscale API access, then exit
+    Doctor,
 }
@@ -1,32 +1,65 @@
-use crate::config::{DiskType, CLOUD_CONFIG, SUBREGION, VM_ID};
-use crate::utils::gib_to_bytes;
+use crate::config::{self, DiskType, CLOUD_CONFIG, SUBREGION, VM_ID};
+use crate::error::BsudError;
+use crate::metrics::{self, BsuOperation};
+use crate::snapshot::Snapshot;
+use crate::utils::{dry_run_log, epoch_s_to_iso8601, gib_to_bytes, iso8601_to_epoch_s};
 use easy_error::format_err;
-use log::{debug, error};
+use log::{debug, error, warn};
+use outscale_api::apis::configuration::Configuration;
 use outscale_api::apis::tag_api::create_tags;
 use outscale_api::apis::volume_api::{
-    create_volume, delete_volume, link_volume, read_volumes, unlink_volume,
+    create_volume, delete_volume, link_volume, read_volumes, unlink_volume, update_volume,
 };
 use outscale_api::models::{
     CreateTagsRequest, CreateVolumeRequest, DeleteVolumeRequest, FiltersVolume, LinkVolumeRequest,
-    ReadVolumesRequest, ResourceTag, UnlinkVolumeRequest, Volume,
+    ReadVolumesRequest, ResourceTag, UnlinkVolumeRequest, UpdateVolumeRequest, Volume,
 };
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
+use std::fmt;
 use std::path::PathBuf;
 
-use datetime::{Duration, Instant};
 use lazy_static::lazy_static;
-use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, RwLock};
 use std::thread::sleep;
 use std::time;
 
-const API_LIMITER_S: u64 = 3;
-const BSU_TAG_KEY: &str = "osc.bsud.drive-name";
+// Sustained rate is the same as the former one call every 3 seconds, bursts are allowed
+pub const DEFAULT_API_REFILL_PER_S: f64 = 1.0 / 3.0;
+// One call per hour, the limiter sleeps for whole refill periods
+pub const MIN_API_REFILL_PER_S: f64 = 1.0 / 3600.0;
+pub const DEFAULT_API_BURST: usize = 5;
+pub const DEFAULT_API_MAX_ATTEMPTS: usize = 5;
+const API_RETRY_BASE_DELAY_MS: u64 = 500;
+const API_RETRY_MAX_DELAY_MS: u64 = 30_000;
+pub const DEFAULT_TAG_PREFIX: &str = "osc.bsud";
+// Drive name tag key with the default prefix
+pub const BSU_TAG_KEY: &str = "osc.bsud.drive-name";
+const VERSION: &str = env!("CARGO_PKG_VERSION");
 const MAX_IOPS_PER_VOLUMES: usize = 13000;
 const DEFAULT_IO1_IOPS_PER_GB: usize = 100;
+const DEFAULT_DEVICE_TEMPLATE: &str = "/dev/xvd{a}";
+const DEVICE_TEMPLATE_MAX_INDEX: usize = 64;
+const DRY_RUN_BSU_ID: &str = "vol-dry-run";
+pub const DEFAULT_WAIT_TIMEOUT_S: u64 = 600;
+const DEFAULT_WAIT_POLL_INTERVAL_MS: u64 = 2000;
+// A stop is noticed within this delay while waiting between two polls
+const EARLY_EXIT_CHECK_INTERVAL: time::Duration = time::Duration::from_millis(200);
+// BSU still in "creating" state after this delay are considered as failed creations
+const STALE_CREATING_S: i64 = 600;
+
+static API_MAX_ATTEMPTS: AtomicUsize = AtomicUsize::new(DEFAULT_API_MAX_ATTEMPTS);
 
 lazy_static! {
-    pub static ref API_LIMITER: Mutex<Instant> =
-        Mutex::new(Instant::now() - Duration::of(API_LIMITER_S as i64));
+    static ref RESERVED_DEVICES: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+    // Set from `device-template`, the scheme is detected otherwise
+    static ref DEVICE_TEMPLATE: RwLock<Option<String>> = RwLock::new(None);
+    pub static ref API_LIMITER: Mutex<TokenBucket> = Mutex::new(TokenBucket::new(
+        DEFAULT_API_REFILL_PER_S,
+        DEFAULT_API_BURST,
+        time::Instant::now()
+    ));
 }
 
 #[derive(Debug, Default, Clone)]
@@ -37,10 +70,90 @@ pub struct Bsu {
     pub size_bytes: usize,
     pub size_gib: usize,
     pub device_path: Option<String>,
+    pub state: String,
+    pub creation_epoch_s: Option<i64>,
+    pub disk_type: String,
+    pub iops: Option<usize>,
+    // Creation order of the BSU in its drive
+    pub seq: Option<usize>,
+    pub created_at_epoch_s: Option<i64>,
+    pub bsud_version: Option<String>,
+    pub managed: bool,
+    pub owner: Option<String>,
+    pub subregion: Option<String>,
+}
+
+// All tag keys of BSU and snapshots share a prefix, daemons with different prefixes manage
+// disjoint sets of volumes in the same account.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TagKeys {
+    pub prefix: String,
+}
+
+impl TagKeys {
+    pub fn new(prefix: &str) -> Self {
+        TagKeys {
+            prefix: prefix.to_string(),
+        }
+    }
+
+    fn key(&self, name: &str) -> String {
+        format!("{}.{}", self.prefix, name)
+    }
+
+    pub fn drive_name(&self) -> String {
+        self.key("drive-name")
+    }
+
+    // Informative tags, BSU created by older versions don't have them
+    pub fn created_at(&self) -> String {
+        self.key("created-at")
+    }
+
+    pub fn version(&self) -> String {
+        self.key("version")
+    }
+
+    pub fn seq(&self) -> String {
+        self.key("seq")
+    }
+
+    pub fn snapshot_batch(&self) -> String {
+        self.key("snapshot-batch")
+    }
+
+    pub fn snapshot_batch_size(&self) -> String {
+        self.key("snapshot-batch-size")
+    }
+
+    // Only set by bsud, volumes without it are never detached nor deleted
+    pub fn managed(&self) -> String {
+        self.key("managed")
+    }
+
+    // VM which created (or adopted) the BSU
+    pub fn owner(&self) -> String {
+        self.key("owner")
+    }
+}
+
+impl Default for TagKeys {
+    fn default() -> Self {
+        TagKeys::new(DEFAULT_TAG_PREFIX)
+    }
 }
 
 impl Bsu {
-    pub fn new(volume: &Volume) -> Result<Self, Box<dyn Error>> {
+    pub fn new(volume: &Volume, tag_keys: &TagKeys) -> Result<Self, Box<dyn Error>> {
+        Bsu::from_volume(volume, tag_keys, true)
+    }
+
+    // Volumes which lost their drive tag get an empty drive name unless it is required
+    fn from_volume(
+        volume: &Volume,
+        tag_keys: &TagKeys,
+        needs_drive_tag: bool,
+    ) -> Result<Self, Box<dyn Error>> {
         let Some(bsu_id) = volume.volume_id.clone() else {
             return Err(Box::new(format_err!(
                 "BSU {:?} does not have an id",
@@ -54,13 +167,20 @@ impl Bsu {
             )));
         };
         let vm_id = Bsu::get_drive_linked_vm_id(volume);
-        let Some(drive_name) = Bsu::get_drive_name(volume) else {
-            Err(format_err!(
+        let drive_name = match Bsu::get_tag(volume, &tag_keys.drive_name()) {
+            Some(drive_name) => drive_name,
+            None if !needs_drive_tag => String::new(),
+            None => Err(format_err!(
                 "Cannot extract drive name from BSU id {}",
                 bsu_id
-            ))?
+            ))?,
         };
         let device_path = Bsu::get_drive_device_path(volume);
+        let state = volume.state.clone().unwrap_or_default();
+        let creation_epoch_s = volume
+            .creation_date
+            .as_ref()
+            .and_then(|date| iso8601_to_epoch_s(date));
 
         Ok(Bsu {
             vm_id,
@@ -69,9 +189,130 @@ impl Bsu {
             size_bytes: gib_to_bytes(bsu_size_gib as usize),
             size_gib: bsu_size_gib as usize,
             device_path,
+            state,
+            creation_epoch_s,
+            disk_type: volume.volume_type.clone().unwrap_or_default(),
+            iops: volume.iops.map(|iops| iops as usize),
+            seq: Bsu::get_tag(volume, &tag_keys.seq()).and_then(|seq| seq.parse().ok()),
+            created_at_epoch_s: Bsu::get_tag(volume, &tag_keys.created_at())
+                .and_then(|date| iso8601_to_epoch_s(&date)),
+            bsud_version: Bsu::get_tag(volume, &tag_keys.version()),
+            managed: Bsu::get_tag(volume, &tag_keys.managed()).as_deref() == Some("true"),
+            owner: Bsu::get_tag(volume, &tag_keys.owner()),
+            subregion: volume.subregion_name.clone(),
         })
     }
 
+    pub fn creation_tags(
+        tag_keys: &TagKeys,
+        drive_name: &str,
+        owner: &str,
+        seq: usize,
+        now_epoch_s: i64,
+    ) -> Vec<ResourceTag> {
+        let mut tags = vec![ResourceTag::new(
+            tag_keys.drive_name(),
+            drive_name.to_string(),
+        )];
+        tags.extend(Bsu::ownership_tags(tag_keys, owner));
+        tags.extend(vec![
+            ResourceTag::new(tag_keys.created_at(), epoch_s_to_iso8601(now_epoch_s)),
+            ResourceTag::new(tag_keys.version(), VERSION.to_string()),
+            ResourceTag::new(tag_keys.seq(), seq.to_string()),
+        ]);
+        tags
+    }
+
+    fn ownership_tags(tag_keys: &TagKeys, owner: &str) -> Vec<ResourceTag> {
+        vec![
+            ResourceTag::new(tag_keys.managed(), "true".to_string()),
+            ResourceTag::new(tag_keys.owner(), owner.to_string()),
+        ]
+    }
+
+    // A drive name matching a volume created by someone else must not make bsud destroy it
+    pub fn check_managed(&self, action: &str) -> Result<(), Box<dyn Error>> {
+        if self.managed {
+            return Ok(());
+        }
+        warn!(
+            "\"{}\" drive: REFUSING to {} BSU {}: it has the drive tag but was not created by bsud (managed tag missing), check this volume or set adopt-unmanaged-bsu",
+            self.drive_name, action, self.id
+        );
+        Err(Box::new(format_err!(
+            "\"{}\" drive: refusing to {} unmanaged BSU {}",
+            self.drive_name,
+            action,
+            self.id
+        )))
+    }
+
+    // Explicitly takes ownership of a BSU tagged with the drive name by someone else
+    pub fn adopt(
+        &self,
+        backend: &dyn VolumeBackend,
+        tag_keys: &TagKeys,
+        owner: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        debug!(
+            "\"{}\" drive: adopting unmanaged BSU {}",
+            self.drive_name, self.id
+        );
+        if backend.dry_run() {
+            dry_run_log(&format!("adopt BSU {}", self.id));
+            return Ok(());
+        }
+        let tags = Bsu::ownership_tags(tag_keys, owner);
+        backend.create_tags(&CreateTagsRequest::new(vec![self.id.clone()], tags))
+    }
+
+    // Puts back the drive tag of a volume which is still part of the drive, ownership tags
+    // are left untouched so an unmanaged volume stays protected
+    pub fn restore_drive_tag(
+        backend: &dyn VolumeBackend,
+        tag_keys: &TagKeys,
+        volume_id: &str,
+        drive_name: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        debug!(
+            "\"{}\" drive: restoring drive tag of BSU {}",
+            drive_name, volume_id
+        );
+        if backend.dry_run() {
+            dry_run_log(&format!("tag BSU {} with drive {}", volume_id, drive_name));
+            return Ok(());
+        }
+        let tags = vec![ResourceTag::new(
+            tag_keys.drive_name(),
+            drive_name.to_string(),
+        )];
+        backend.create_tags(&CreateTagsRequest::new(vec![volume_id.to_string()], tags))
+    }
+
+    // BSU can only be attached to VMs of their subregion, the API error would not tell it
+    pub fn check_subregion(&self, vm_subregion: &str) -> Result<(), Box<dyn Error>> {
+        let Some(subregion) = &self.subregion else {
+            return Ok(());
+        };
+        if vm_subregion.is_empty() || subregion == vm_subregion {
+            return Ok(());
+        }
+        Err(Box::new(BsudError::Config(format!(
+            "\"{}\" drive: BSU {} is in subregion {} while this VM is in {}, BSU can only be attached within the VM's subregion (check the drive's subregion)",
+            self.drive_name, self.id, subregion, vm_subregion
+        ))))
+    }
+
+    pub fn is_stale_creating(&self, now_epoch_s: i64) -> bool {
+        if self.state != "creating" {
+            return false;
+        }
+        let Some(creation_epoch_s) = self.creation_epoch_s else {
+            return false;
+        };
+        now_epoch_s - creation_epoch_s > STALE_CREATING_S
+    }
+
     fn get_drive_linked_vm_id(volume: &Volume) -> Option<String> {
         let Some(linked_volumes) = &volume.linked_volumes else {
             return None;
@@ -91,12 +332,12 @@ impl Bsu {
         None
     }
 
-    fn get_drive_name(volume: &Volume) -> Option<String> {
+    fn get_tag(volume: &Volume, key: &str) -> Option<String> {
         let Some(tags) = &volume.tags else {
             return None;
         };
         for tag in tags {
-            if tag.key == *BSU_TAG_KEY.to_string() {
+            if tag.key == key {
                 return Some(tag.value.clone());
             }
         }
@@ -110,25 +351,39 @@ impl Bsu {
         linked_volumes.iter().next()?.device_name.clone()
     }
 
-    pub fn fetch_drive(drive_name: &String) -> Result<Vec<Bsu>, Box<dyn Error>> {
+    // Device names the API reports for the volumes linked to `vm_id`, whatever their drive
+    pub fn vm_device_names(
+        backend: &dyn VolumeBackend,
+        vm_id: &String,
+    ) -> Result<Vec<String>, Box<dyn Error>> {
+        let filter = FiltersVolume {
+            link_volume_vm_ids: Some(vec![vm_id.clone()]),
+            ..Default::default()
+        };
+        Ok(backend
+            .read_volumes(&filter)?
+            .iter()
+            .flat_map(|volume| volume.linked_volumes.iter().flatten())
+            .filter(|link| link.vm_id.as_ref() == Some(vm_id))
+            .filter_map(|link| link.device_name.clone())
+            .collect())
+    }
+
+    pub fn fetch_drive(
+        backend: &dyn VolumeBackend,
+        tag_keys: &TagKeys,
+        drive_name: &String,
+    ) -> Result<Vec<Bsu>, Box<dyn Error>> {
         debug!("\"{}\" drive: fetching all bsu", drive_name);
-        api_limiter()?;
-        let mut request = ReadVolumesRequest::new();
         let mut filter = FiltersVolume::default();
-        let tag = format!("{}={}", BSU_TAG_KEY, drive_name);
+        let tag = format!("{}={}", tag_keys.drive_name(), drive_name);
         filter.tags = Some(vec![tag]);
         filter.volume_states = Some(vec![
             "creating".to_string(),
             "available".to_string(),
             "in-use".to_string(),
         ]);
-        request.filters = Some(Box::new(filter));
-        let response = read_volumes(&*CLOUD_CONFIG.read()?, Some(request));
-        if response.is_err() {
-            error!("read volume response: {:?}", response);
-        }
-        let response = response?;
-        let volumes = response.volumes.unwrap_or_default();
+        let volumes = backend.read_volumes(&filter)?;
         // Check state filtering
         let volumes: Vec<Volume> = volumes
             .into_iter()
@@ -139,28 +394,147 @@ impl Bsu {
                 matches!(state.as_str(), "creating" | "available" | "in-use")
             })
             .collect();
-        let bsu_list = volumes.iter().map(Bsu::new).collect();
+        let bsu_list = volumes
+            .iter()
+            .map(|volume| Bsu::new(volume, tag_keys))
+            .collect();
         bsu_list
     }
 
-    pub fn detach(&self) -> Result<(), Box<dyn Error>> {
+    // Volumes attached to a VM without any drive tag, as (volume id, device path)
+    pub fn fetch_vm_untagged(
+        backend: &dyn VolumeBackend,
+        tag_keys: &TagKeys,
+        vm_id: &str,
+    ) -> Result<Vec<(String, String)>, Box<dyn Error>> {
+        debug!("fetching untagged bsu of vm {}", vm_id);
+        let filter = FiltersVolume {
+            link_volume_vm_ids: Some(vec![vm_id.to_string()]),
+            volume_states: Some(vec!["in-use".to_string()]),
+            ..Default::default()
+        };
+        let volumes = backend.read_volumes(&filter)?;
+        Ok(volumes
+            .iter()
+            .filter(|volume| Bsu::get_tag(volume, &tag_keys.drive_name()).is_none())
+            .filter_map(|volume| {
+                Some((
+                    volume.volume_id.clone()?,
+                    Bsu::get_drive_device_path(volume)?,
+                ))
+            })
+            .collect())
+    }
+
+    // BSU created (or adopted) by the bsud of a VM, whatever their drive
+    pub fn fetch_owned(
+        backend: &dyn VolumeBackend,
+        tag_keys: &TagKeys,
+        owner: &str,
+    ) -> Result<Vec<Bsu>, Box<dyn Error>> {
+        debug!("fetching all bsu owned by {}", owner);
+        let filter = FiltersVolume {
+            tags: Some(vec![
+                format!("{}=true", tag_keys.managed()),
+                format!("{}={}", tag_keys.owner(), owner),
+            ]),
+            volume_states: Some(vec![
+                "creating".to_string(),
+                "available".to_string(),
+                "in-use".to_string(),
+            ]),
+            ..Default::default()
+        };
+        let volumes = backend.read_volumes(&filter)?;
+        volumes
+            .iter()
+            .map(|volume| Bsu::from_volume(volume, tag_keys, false))
+            .collect()
+    }
+
+    pub fn fetch_all_drives(
+        backend: &dyn VolumeBackend,
+        tag_keys: &TagKeys,
+    ) -> Result<Vec<Bsu>, Box<dyn Error>> {
+        debug!("fetching all bsu of all drives");
+        Bsu::fetch_drives_filtered(backend, tag_keys, FiltersVolume::default())
+    }
+
+    // Drives of other VMs sharing the account are left out
+    pub fn fetch_vm_drives(
+        backend: &dyn VolumeBackend,
+        tag_keys: &TagKeys,
+        vm_id: &String,
+    ) -> Result<Vec<Bsu>, Box<dyn Error>> {
+        debug!("fetching all bsu of drives linked to vm {}", vm_id);
+        let filter = FiltersVolume {
+            link_volume_vm_ids: Some(vec![vm_id.clone()]),
+            ..Default::default()
+        };
+        Bsu::fetch_drives_filtered(backend, tag_keys, filter)
+    }
+
+    fn fetch_drives_filtered(
+        backend: &dyn VolumeBackend,
+        tag_keys: &TagKeys,
+        filter: FiltersVolume,
+    ) -> Result<Vec<Bsu>, Box<dyn Error>> {
+        let filter = FiltersVolume {
+            tag_keys: Some(vec![tag_keys.drive_name()]),
+            volume_states: Some(vec![
+                "creating".to_string(),
+                "available".to_string(),
+                "in-use".to_string(),
+            ]),
+            ..filter
+        };
+        let volumes = backend.read_volumes(&filter)?;
+        volumes
+            .iter()
+            .map(|volume| Bsu::new(volume, tag_keys))
+            .collect()
+    }
+
+    pub fn detach(
+        &self,
+        backend: &dyn VolumeBackend,
+        wait: &WaitOptions,
+        early_exit: EarlyExit,
+    ) -> Result<(), Box<dyn Error>> {
         debug!("detaching BSU {} on vm {:?}", self.id, self.vm_id);
-        api_limiter()?;
-        let request = UnlinkVolumeRequest::new(self.id.clone());
-        let response = unlink_volume(&*CLOUD_CONFIG.read()?, Some(request));
-        if response.is_err() {
-            error!("unlink volume response: {:?}", response);
-            response?;
+        if backend.dry_run() {
+            dry_run_log(&format!("detach BSU {}", self.id));
+            return Ok(());
         }
-        Bsu::wait_state(&self.id, "available")?;
+        backend.unlink_volume(&UnlinkVolumeRequest::new(self.id.clone()))?;
+        metrics::count_operation(&self.drive_name, BsuOperation::Detach);
+        Bsu::wait_state(backend, &self.id, "available", wait, early_exit)?;
         Ok(())
     }
 
-    pub fn multiple_attach(vm_id: &String, bsus: &Vec<Bsu>) -> Result<(), Box<dyn Error>> {
+    pub fn multiple_attach(
+        backend: &dyn VolumeBackend,
+        vm_id: &String,
+        bsus: &Vec<Bsu>,
+        wait: &WaitOptions,
+        early_exit: EarlyExit,
+    ) -> Result<(), Box<dyn Error>> {
+        // All links are requested first, then all BSU are waited at once
+        let on_host = |device: &str| PathBuf::from(device).exists();
+        let scheme = DeviceScheme::configured_or_detect(on_host);
+        let linked = match scheme {
+            DeviceScheme::Nvme => Bsu::vm_device_names(backend, vm_id)?,
+            _ => Vec::new(),
+        };
+        let exists = |device: &str| on_host(device) || linked.iter().any(|name| name == device);
+        let mut reservation = DeviceReservation::default();
         for bsu in bsus {
             debug!("attaching BSU {} on vm {:?}", bsu.id, vm_id);
-            api_limiter()?;
-            let Some(device_name) = Bsu::find_next_available_device() else {
+            if backend.dry_run() {
+                dry_run_log(&format!("attach BSU {} on VM {}", bsu.id, vm_id));
+                continue;
+            }
+            let Some(device_name) = reservation.reserve_next(&scheme, exists) else {
                 return Err(Box::new(format_err!(
                     "cannot find available device to attach {} BSU on {} VM",
                     bsu.id,
@@ -168,19 +542,25 @@ impl Bsu {
                 )));
             };
             let request = LinkVolumeRequest::new(device_name, vm_id.clone(), bsu.id.clone());
-            let response = link_volume(&*CLOUD_CONFIG.read()?, Some(request));
-            if response.is_err() {
-                error!("link volume response: {:?}", response);
-                response?;
-            }
+            backend.link_volume(&request)?;
+            metrics::count_operation(&bsu.drive_name, BsuOperation::Attach);
         }
-        Bsu::wait_states(bsus, "in-use")?;
+        if backend.dry_run() {
+            return Ok(());
+        }
+        Bsu::wait_states(backend, bsus, "in-use", wait, early_exit)?;
         Ok(())
     }
 
-    pub fn multiple_detach(bsus: &Vec<Bsu>) -> Result<(), Box<dyn Error>> {
+    pub fn multiple_detach(
+        backend: &dyn VolumeBackend,
+        bsus: &Vec<Bsu>,
+        wait: &WaitOptions,
+        early_exit: EarlyExit,
+    ) -> Result<(), Box<dyn Error>> {
         let vm_id: String = VM_ID.try_read()?.clone();
         let mut unlinked_volumes = Vec::new();
+        let mut refused = None;
         for bsu in bsus {
             debug!("detaching BSU {} on vm {}", bsu.id, vm_id);
             let Some(ref bsu_vm_id) = bsu.vm_id else {
@@ -197,150 +577,279 @@ impl Bsu {
                 );
                 continue;
             }
-            api_limiter()?;
-            let request = UnlinkVolumeRequest::new(bsu.id.clone());
-            let response = unlink_volume(&*CLOUD_CONFIG.read()?, Some(request));
-            if response.is_err() {
-                error!("unlink volume response: {:?}", response);
-                response?;
+            if let Err(err) = bsu.check_managed("detach") {
+                refused = Some(err);
+                continue;
+            }
+            if backend.dry_run() {
+                dry_run_log(&format!("detach BSU {} from VM {}", bsu.id, vm_id));
+                continue;
             }
+            backend.unlink_volume(&UnlinkVolumeRequest::new(bsu.id.clone()))?;
+            metrics::count_operation(&bsu.drive_name, BsuOperation::Detach);
             unlinked_volumes.push(bsu.clone());
         }
-        Bsu::wait_states(&unlinked_volumes, "available")?;
-        Ok(())
+        Bsu::wait_states(backend, &unlinked_volumes, "available", wait, early_exit)?;
+        match refused {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
     }
 
-    pub fn delete(&self) -> Result<(), Box<dyn Error>> {
+    pub fn delete(&self, backend: &dyn VolumeBackend) -> Result<(), Box<dyn Error>> {
         debug!("deleting BSU {}", self.id);
-        api_limiter()?;
-        let request = DeleteVolumeRequest::new(self.id.clone());
-        let response = delete_volume(&*CLOUD_CONFIG.read()?, Some(request));
-        if response.is_err() {
-            error!("delete volume response: {:?}", response);
-            response?;
+        if backend.dry_run() {
+            dry_run_log(&format!("delete BSU {}", self.id));
+            return Ok(());
         }
+        backend.delete_volume(&self.id)?;
+        metrics::count_operation(&self.drive_name, BsuOperation::Delete);
         Ok(())
     }
 
-    pub fn wait_state(bsu_id: &String, desired_state: &str) -> Result<(), Box<dyn Error>> {
-        loop {
-            let volume_state = Bsu::get_state(bsu_id)?;
-            debug!(
-                "volume {} state: {}, desired state: {}",
-                bsu_id, volume_state, desired_state
-            );
-            if volume_state == desired_state {
-                return Ok(());
-            }
-        }
+    pub fn wait_state(
+        backend: &dyn VolumeBackend,
+        bsu_id: &String,
+        desired_state: &str,
+        wait: &WaitOptions,
+        early_exit: EarlyExit,
+    ) -> Result<(), Box<dyn Error>> {
+        wait_for_state(
+            || Bsu::get_state(backend, bsu_id),
+            desired_state,
+            wait,
+            early_exit,
+        )
     }
 
-    pub fn wait_states(bsus: &[Bsu], desired_state: &str) -> Result<(), Box<dyn Error>> {
+    pub fn wait_states(
+        backend: &dyn VolumeBackend,
+        bsus: &[Bsu],
+        desired_state: &str,
+        wait: &WaitOptions,
+        early_exit: EarlyExit,
+    ) -> Result<(), Box<dyn Error>> {
+        // An empty id filter would match all volumes of the account
+        if bsus.is_empty() {
+            return Ok(());
+        }
         let bsu_ids: Vec<String> = bsus.iter().map(|bsu| bsu.id.clone()).collect();
         debug!("fetching multiple BSU states {:?}", &bsu_ids);
-        let mut request = ReadVolumesRequest::new();
         let filter = FiltersVolume {
             volume_ids: Some(bsu_ids),
             ..Default::default()
         };
-        request.filters = Some(Box::new(filter));
-        loop {
-            api_limiter()?;
-            let response = read_volumes(&*CLOUD_CONFIG.read()?, Some(request.clone()));
-            if response.is_err() {
-                error!("read volume response: {:?}", response);
-                continue;
-            }
-            let volumes = response?.volumes.unwrap_or_default();
-            if !volumes
+        let get_states = || -> Result<String, Box<dyn Error>> {
+            let Ok(volumes) = backend.read_volumes(&filter) else {
+                return Ok("unknown".to_string());
+            };
+            let other_state = volumes
                 .iter()
                 .filter_map(|volume| volume.state.clone())
-                .any(|state| state != desired_state)
-            {
-                return Ok(());
-            }
-        }
+                .find(|state| state != desired_state);
+            Ok(other_state.unwrap_or_else(|| desired_state.to_string()))
+        };
+        wait_for_state(get_states, desired_state, wait, early_exit)
     }
 
-    pub fn get_state(bsu_id: &String) -> Result<String, Box<dyn Error>> {
+    pub fn get_state(
+        backend: &dyn VolumeBackend,
+        bsu_id: &String,
+    ) -> Result<String, Box<dyn Error>> {
         debug!("fetching BSU {} state", bsu_id);
-        api_limiter()?;
-        let mut request = ReadVolumesRequest::new();
+        let volume = Bsu::read_volume(backend, bsu_id)?;
+        let Some(state) = volume.state else {
+            return Err(Box::new(format_err!("cannot find state in BSU {}", bsu_id)));
+        };
+        Ok(state)
+    }
+
+    fn read_volume(backend: &dyn VolumeBackend, bsu_id: &String) -> Result<Volume, Box<dyn Error>> {
         let filter = FiltersVolume {
             volume_ids: Some(vec![bsu_id.clone()]),
             ..Default::default()
         };
-        request.filters = Some(Box::new(filter));
-        let response = read_volumes(&*CLOUD_CONFIG.read()?, Some(request));
-        if response.is_err() {
-            error!("read volume response: {:?}", response);
-        }
-        let response = response?;
-        let volumes = response.volumes.unwrap_or_default();
+        let volumes = backend.read_volumes(&filter)?;
         let Some(volume) = volumes.into_iter().next() else {
             return Err(Box::new(format_err!("cannot find BSU {}", bsu_id)));
         };
-        let Some(state) = volume.state else {
-            return Err(Box::new(format_err!("cannot find state in BSU {}", bsu_id)));
-        };
-        Ok(state)
+        Ok(volume)
     }
 
-    fn find_next_available_device() -> Option<String> {
-        for c1 in b'b'..=b'z' {
-            let device = format!("/dev/xvd{}", c1 as char);
-            let path = PathBuf::from(device.clone());
-            if !path.exists() {
-                return Some(device);
-            }
+    // Only io1 BSU have provisioned iops, gp2 and standard performance scales with their size
+    pub fn needs_modification(
+        &self,
+        disk_type: &DiskType,
+        disk_iops_per_gib: Option<usize>,
+    ) -> bool {
+        if self.disk_type != disk_type.to_string() {
+            return true;
         }
-        for c1 in b'b'..=b'z' {
-            for c2 in b'a'..=b'z' {
-                let device = format!("/dev/xvd{}{}", c1 as char, c2 as char);
-                let path = PathBuf::from(device.clone());
-                if !path.exists() {
-                    return Some(device);
-                }
-            }
+        match disk_type {
+            DiskType::Io1 => self.iops != Some(io1_iops(self.size_gib, disk_iops_per_gib)),
+            _ => false,
         }
-        None
     }
 
-    pub fn create_gib(
-        drive_name: &String,
+    pub fn modify_request(
+        bsu_id: &str,
         disk_type: &DiskType,
+        size_gib: usize,
         disk_iops_per_gib: Option<usize>,
-        disk_size_gib: usize,
+    ) -> UpdateVolumeRequest {
+        let mut request = UpdateVolumeRequest::new(bsu_id.to_string());
+        request.volume_type = Some(disk_type.to_string());
+        request.iops = match disk_type {
+            DiskType::Io1 => Some(io1_iops(size_gib, disk_iops_per_gib) as i32),
+            _ => None,
+        };
+        request
+    }
+
+    // Modification is asynchronous, the BSU keeps its former type until it is done
+    pub fn modify(
+        &self,
+        backend: &dyn VolumeBackend,
+        disk_type: &DiskType,
+        disk_iops_per_gib: Option<usize>,
+        wait: &WaitOptions,
+        early_exit: EarlyExit,
     ) -> Result<(), Box<dyn Error>> {
+        let request = Bsu::modify_request(&self.id, disk_type, self.size_gib, disk_iops_per_gib);
         debug!(
-            "\"{}\" drive: creating BSU of type {}, size {} GiB",
-            drive_name,
+            "modifying BSU {} from {} to {} ({:?} iops)",
+            self.id,
+            self.disk_type,
             disk_type.to_string(),
+            request.iops
+        );
+        if backend.dry_run() {
+            dry_run_log(&format!("modify BSU {}: {:?}", self.id, request));
+            return Ok(());
+        }
+        backend.update_volume(&request)?;
+        metrics::count_operation(&self.drive_name, BsuOperation::Modify);
+        let get_modification = || -> Result<String, Box<dyn Error>> {
+            let volume = Bsu::read_volume(backend, &self.id)?;
+            let iops_done = request.iops.is_none() || volume.iops == request.iops;
+            match (volume.volume_type, iops_done) {
+                (Some(volume_type), true) => Ok(volume_type),
+                (Some(volume_type), false) => Ok(format!("{} (updating iops)", volume_type)),
+                (None, _) => Ok("unknown".to_string()),
+            }
+        };
+        wait_for_state(get_modification, &disk_type.to_string(), wait, early_exit)
+    }
+
+    pub fn create_gib(
+        backend: &dyn VolumeBackend,
+        tag_keys: &TagKeys,
+        spec: &BsuSpec,
+        disk_size_gib: usize,
+        wait: &WaitOptions,
+        early_exit: EarlyExit,
+    ) -> Result<String, Box<dyn Error>> {
+        debug!(
+            "\"{}\" drive: creating BSU of type {}, size {} GiB",
+            spec.drive_name,
+            spec.disk_type.to_string(),
             disk_size_gib
         );
-        api_limiter()?;
-        let mut creation_request = CreateVolumeRequest::new(SUBREGION.read()?.clone());
-        creation_request.volume_type = Some(disk_type.to_string());
-        creation_request.iops = match disk_type {
-            DiskType::Io1 => match disk_iops_per_gib {
-                Some(disk_iops_per_gib) => {
-                    Some((disk_size_gib * disk_iops_per_gib).max(MAX_IOPS_PER_VOLUMES) as i32)
-                }
-                None => {
-                    Some((DEFAULT_IO1_IOPS_PER_GB * disk_size_gib).max(MAX_IOPS_PER_VOLUMES) as i32)
-                }
-            },
+        let mut creation_request =
+            CreateVolumeRequest::new(Bsu::creation_subregion(spec.subregion)?);
+        creation_request.volume_type = Some(spec.disk_type.to_string());
+        creation_request.iops = match spec.disk_type {
+            DiskType::Io1 => Some(io1_iops(disk_size_gib, spec.disk_iops_per_gib) as i32),
             _ => None,
         };
+        debug!(
+            "\"{}\" drive: requesting {:?} iops",
+            spec.drive_name, creation_request.iops
+        );
         creation_request.size = Some(disk_size_gib as i32);
-        let create_result = match create_volume(&*CLOUD_CONFIG.read()?, Some(creation_request)) {
-            Ok(create) => create,
-            Err(err) => {
-                debug!("\"{}\" drive: during bsu creation: {:?}", drive_name, err);
-                return Err(Box::new(err));
-            }
+        Bsu::create(
+            backend,
+            tag_keys,
+            spec.drive_name,
+            creation_request,
+            spec.seq,
+            wait,
+            early_exit,
+        )
+    }
+
+    // Restored BSU get the snapshot content, its size and the drive's disk type
+    pub fn create_from_snapshot(
+        backend: &dyn VolumeBackend,
+        tag_keys: &TagKeys,
+        spec: &BsuSpec,
+        snapshot: &Snapshot,
+        default_size_gib: usize,
+        wait: &WaitOptions,
+        early_exit: EarlyExit,
+    ) -> Result<String, Box<dyn Error>> {
+        debug!(
+            "\"{}\" drive: creating BSU of type {} from snapshot {}",
+            spec.drive_name,
+            spec.disk_type.to_string(),
+            snapshot.id
+        );
+        let creation_request = Bsu::snapshot_creation_request(spec, snapshot, default_size_gib)?;
+        Bsu::create(
+            backend,
+            tag_keys,
+            spec.drive_name,
+            creation_request,
+            spec.seq,
+            wait,
+            early_exit,
+        )
+    }
+
+    // io1 volumes cannot be created without iops: a snapshot of unknown size gets the iops
+    // of `default_size_gib`
+    pub fn snapshot_creation_request(
+        spec: &BsuSpec,
+        snapshot: &Snapshot,
+        default_size_gib: usize,
+    ) -> Result<CreateVolumeRequest, Box<dyn Error>> {
+        let mut creation_request =
+            CreateVolumeRequest::new(Bsu::creation_subregion(spec.subregion)?);
+        creation_request.volume_type = Some(spec.disk_type.to_string());
+        creation_request.snapshot_id = Some(snapshot.id.clone());
+        let size_gib = snapshot.volume_size_gib.unwrap_or(default_size_gib);
+        creation_request.iops = match spec.disk_type {
+            DiskType::Io1 => Some(io1_iops(size_gib, spec.disk_iops_per_gib) as i32),
+            _ => None,
         };
-        let Some(bsu) = create_result.volume else {
+        Ok(creation_request)
+    }
+
+    // The drive's subregion overrides the one of the VM
+    fn creation_subregion(subregion: Option<&str>) -> Result<String, Box<dyn Error>> {
+        match subregion {
+            Some(subregion) => Ok(subregion.to_string()),
+            None => Ok(SUBREGION.read()?.clone()),
+        }
+    }
+
+    fn create(
+        backend: &dyn VolumeBackend,
+        tag_keys: &TagKeys,
+        drive_name: &String,
+        creation_request: CreateVolumeRequest,
+        seq: usize,
+        wait: &WaitOptions,
+        early_exit: EarlyExit,
+    ) -> Result<String, Box<dyn Error>> {
+        if backend.dry_run() {
+            dry_run_log(&format!(
+                "create BSU for \"{}\" drive: {:?}",
+                drive_name, creation_request
+            ));
+            return Ok(DRY_RUN_BSU_ID.to_string());
+        }
+        let Some(bsu) = backend.create_volume(&creation_request)? else {
             return Err(Box::new(format_err!(
                 "volume creation did not provide a volume object"
             )));
@@ -351,32 +860,878 @@ impl Bsu {
             )));
         };
         debug!("\"{}\" drive: created BSU id {}", drive_name, bsu_id);
-        debug!("\"{}\" drive: adding tag to BSU {}", drive_name, bsu_id);
-        api_limiter()?;
-        let tag = ResourceTag::new(BSU_TAG_KEY.to_string(), drive_name.clone());
-        let tag_request = CreateTagsRequest::new(vec![bsu_id.clone()], vec![tag]);
-        if let Err(err) = create_tags(&*CLOUD_CONFIG.read()?, Some(tag_request)) {
-            debug!(
-                "\"{}\" drive: during bsu tag creation: {:?}",
-                drive_name, err
-            );
-            return Err(Box::new(err));
-        }
-        Bsu::wait_state(&bsu_id, "available")?;
+        metrics::count_operation(drive_name, BsuOperation::Create);
+        debug!("\"{}\" drive: adding tags to BSU {}", drive_name, bsu_id);
+        let owner = VM_ID.read()?.clone();
+        let now_epoch_s = time::SystemTime::now()
+            .duration_since(time::UNIX_EPOCH)?
+            .as_secs() as i64;
+        let tags = Bsu::creation_tags(tag_keys, drive_name, &owner, seq, now_epoch_s);
+        if let Err(err) = backend.create_tags(&CreateTagsRequest::new(vec![bsu_id.clone()], tags)) {
+            Bsu::delete_untagged(backend, drive_name, &bsu_id);
+            return Err(err);
+        }
+        Bsu::wait_state(backend, &bsu_id, "available", wait, early_exit)?;
+        Ok(bsu_id)
+    }
+
+    // An untagged BSU is never fetched again: it would be billed without bsud ever managing
+    // nor deleting it
+    fn delete_untagged(backend: &dyn VolumeBackend, drive_name: &str, bsu_id: &str) {
+        warn!(
+            "\"{}\" drive: cannot tag new BSU {}, deleting it",
+            drive_name, bsu_id
+        );
+        match backend.delete_volume(bsu_id) {
+            Ok(()) => metrics::count_operation(drive_name, BsuOperation::Delete),
+            Err(err) => error!(
+                "\"{}\" drive: ORPHAN BSU {} is not tagged and could not be deleted ({}), bsud will never manage it: delete it manually",
+                drive_name, bsu_id, err
+            ),
+        }
+    }
+}
+
+// Settings of a new BSU which come from its drive, `seq` orders the BSU of the drive
+pub struct BsuSpec<'a> {
+    pub drive_name: &'a String,
+    pub disk_type: &'a DiskType,
+    pub disk_iops_per_gib: Option<usize>,
+    pub subregion: Option<&'a str>,
+    pub seq: usize,
+}
+
+// Volume calls of the Outscale API, tests replace them to run drives without any cloud
+// account.
+pub trait VolumeBackend: fmt::Debug + Send + Sync {
+    fn create_volume(
+        &self,
+        request: &CreateVolumeRequest,
+    ) -> Result<Option<Volume>, Box<dyn Error>>;
+    fn read_volumes(&self, filters: &FiltersVolume) -> Result<Vec<Volume>, Box<dyn Error>>;
+    fn link_volume(&self, request: &LinkVolumeRequest) -> Result<(), Box<dyn Error>>;
+    fn unlink_volume(&self, request: &UnlinkVolumeRequest) -> Result<(), Box<dyn Error>>;
+    fn delete_volume(&self, volume_id: &str) -> Result<(), Box<dyn Error>>;
+    fn update_volume(&self, request: &UpdateVolumeRequest) -> Result<(), Box<dyn Error>>;
+    fn create_tags(&self, request: &CreateTagsRequest) -> Result<(), Box<dyn Error>>;
+
+    // Calls changing volumes are only logged in dry-run mode
+    fn dry_run(&self) -> bool {
+        false
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OutscaleVolumes {
+    pub dry_run: bool,
+}
+
+impl VolumeBackend for OutscaleVolumes {
+    fn dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    fn create_volume(
+        &self,
+        request: &CreateVolumeRequest,
+    ) -> Result<Option<Volume>, Box<dyn Error>> {
+        // A failed creation may still have created a BSU, only retry when the API did not
+        // handle the request
+        let response = api_call_non_idempotent("create volume", |config| {
+            create_volume(config, Some(request.clone()))
+        })?;
+        Ok(response.volume.map(|volume| *volume))
+    }
+
+    fn read_volumes(&self, filters: &FiltersVolume) -> Result<Vec<Volume>, Box<dyn Error>> {
+        let mut request = ReadVolumesRequest::new();
+        request.filters = Some(Box::new(filters.clone()));
+        let response = api_call("read volume", |config| {
+            read_volumes(config, Some(request.clone()))
+        })?;
+        Ok(response.volumes.unwrap_or_default())
+    }
+
+    fn link_volume(&self, request: &LinkVolumeRequest) -> Result<(), Box<dyn Error>> {
+        api_call("link volume", |config| {
+            link_volume(config, Some(request.clone()))
+        })?;
         Ok(())
     }
+
+    fn unlink_volume(&self, request: &UnlinkVolumeRequest) -> Result<(), Box<dyn Error>> {
+        api_call("unlink volume", |config| {
+            unlink_volume(config, Some(request.clone()))
+        })?;
+        Ok(())
+    }
+
+    fn delete_volume(&self, volume_id: &str) -> Result<(), Box<dyn Error>> {
+        let request = DeleteVolumeRequest::new(volume_id.to_string());
+        api_call("delete volume", |config| {
+            delete_volume(config, Some(request.clone()))
+        })?;
+        Ok(())
+    }
+
+    fn update_volume(&self, request: &UpdateVolumeRequest) -> Result<(), Box<dyn Error>> {
+        api_call("update volume", |config| {
+            update_volume(config, Some(request.clone()))
+        })?;
+        Ok(())
+    }
+
+    fn create_tags(&self, request: &CreateTagsRequest) -> Result<(), Box<dyn Error>> {
+        api_call("create tags", |config| {
+            create_tags(config, Some(request.clone()))
+        })?;
+        Ok(())
+    }
+}
+
+pub type EarlyExit<'a> = &'a mut dyn FnMut() -> Result<(), Box<dyn Error>>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct WaitOptions {
+    pub timeout: time::Duration,
+    pub poll_interval: time::Duration,
+}
+
+impl Default for WaitOptions {
+    fn default() -> Self {
+        WaitOptions {
+            timeout: time::Duration::from_secs(DEFAULT_WAIT_TIMEOUT_S),
+            poll_interval: time::Duration::from_millis(DEFAULT_WAIT_POLL_INTERVAL_MS),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct WaitTimeoutError {
+    pub desired_state: String,
+    pub last_state: String,
+    pub waited: time::Duration,
+}
+
+impl fmt::Display for WaitTimeoutError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "timeout after {}s waiting for state {}, last state: {}",
+            self.waited.as_secs(),
+            self.desired_state,
+            self.last_state
+        )
+    }
+}
+
+impl Error for WaitTimeoutError {}
+
+fn wait_for_state<F>(
+    mut get_state: F,
+    desired_state: &str,
+    wait: &WaitOptions,
+    early_exit: EarlyExit,
+) -> Result<(), Box<dyn Error>>
+where
+    F: FnMut() -> Result<String, Box<dyn Error>>,
+{
+    let start = time::Instant::now();
+    loop {
+        let state = get_state()?;
+        debug!("state: {}, desired state: {}", state, desired_state);
+        if state == desired_state {
+            return Ok(());
+        }
+        let waited = start.elapsed();
+        if waited >= wait.timeout {
+            return Err(Box::new(BsudError::Timeout(WaitTimeoutError {
+                desired_state: desired_state.to_string(),
+                last_state: state,
+                waited,
+            })));
+        }
+        // The cloud operation goes on server side, the next reconcile sees its result
+        let next_poll = time::Instant::now() + wait.poll_interval.min(wait.timeout - waited);
+        loop {
+            early_exit()?;
+            let now = time::Instant::now();
+            if now >= next_poll {
+                break;
+            }
+            sleep((next_poll - now).min(EARLY_EXIT_CHECK_INTERVAL));
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeviceScheme {
+    Xvd,
+    // The API only accepts xvd names, the kernel names nvme nodes by itself: names are taken
+    // on the API side and BSU are found on the host by id
+    Nvme,
+    Template(String),
+}
+
+impl DeviceScheme {
+    // VM exposing nvme devices always have at least the root device as nvme0
+    pub fn detect<F: Fn(&str) -> bool>(exists: F) -> Self {
+        if exists("/dev/nvme0") || exists("/dev/nvme0n1") {
+            DeviceScheme::Nvme
+        } else {
+            DeviceScheme::Xvd
+        }
+    }
+
+    pub fn configured_or_detect<F: Fn(&str) -> bool>(exists: F) -> Self {
+        let template = DEVICE_TEMPLATE
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone();
+        match template {
+            Some(template) => DeviceScheme::Template(template),
+            None => DeviceScheme::detect(exists),
+        }
+    }
+}
+
+// Device names generated from a template holding exactly one placeholder: "{a}" iterates
+// letters like xvd devices do (b to z, then ba to zz), "{n}" iterates numbers from 1.
+pub fn device_candidates(template: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    let placeholders = template.matches("{a}").count() + template.matches("{n}").count();
+    let stripped = template.replacen("{a}", "", 1).replacen("{n}", "", 1);
+    if placeholders != 1 || stripped.contains(['{', '}']) {
+        return Err(Box::new(format_err!(
+            "device template \"{}\" must contain exactly one \"{{a}}\" or \"{{n}}\" placeholder",
+            template
+        )));
+    }
+    if template.contains("{n}") {
+        return Ok((1..=DEVICE_TEMPLATE_MAX_INDEX)
+            .map(|index| template.replace("{n}", &index.to_string()))
+            .collect());
+    }
+    let mut candidates = Vec::new();
+    for c1 in b'b'..=b'z' {
+        candidates.push(template.replace("{a}", &(c1 as char).to_string()));
+    }
+    for c1 in b'b'..=b'z' {
+        for c2 in b'a'..=b'z' {
+            candidates.push(template.replace("{a}", &format!("{}{}", c1 as char, c2 as char)));
+        }
+    }
+    Ok(candidates)
+}
+
+pub fn configure_device_template(template: &str) -> Result<(), Box<dyn Error>> {
+    device_candidates(template)?;
+    *DEVICE_TEMPLATE
+        .write()
+        .unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(template.to_string());
+    Ok(())
+}
+
+pub fn find_next_available_device_in<F: Fn(&str) -> bool>(
+    scheme: &DeviceScheme,
+    exists: F,
+) -> Option<String> {
+    let candidates: Vec<String> = match scheme {
+        DeviceScheme::Xvd | DeviceScheme::Nvme => {
+            device_candidates(DEFAULT_DEVICE_TEMPLATE).unwrap_or_default()
+        }
+        DeviceScheme::Template(template) => device_candidates(template).unwrap_or_default(),
+    };
+    candidates.into_iter().find(|device| !exists(device))
+}
+
+// Devices only appear once BSU are attached: names chosen for a batch are reserved until the
+// reservation is dropped so concurrent attachments (of any drive) never pick the same one.
+#[derive(Debug, Default)]
+pub struct DeviceReservation {
+    devices: Vec<String>,
+}
+
+impl DeviceReservation {
+    pub fn reserve_next<F: Fn(&str) -> bool>(
+        &mut self,
+        scheme: &DeviceScheme,
+        exists: F,
+    ) -> Option<String> {
+        let mut reserved = RESERVED_DEVICES
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let device = find_next_available_device_in(scheme, |device| {
+            exists(device) || reserved.contains(device)
+        })?;
+        reserved.insert(device.clone());
+        self.devices.push(device.clone());
+        Some(device)
+    }
+
+    pub fn devices(&self) -> &[String] {
+        &self.devices
+    }
+}
+
+impl Drop for DeviceReservation {
+    fn drop(&mut self) {
+        let mut reserved = RESERVED_DEVICES
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        for device in self.devices.iter() {
+            reserved.remove(device);
+        }
+    }
+}
+
+fn io1_iops(disk_size_gib: usize, disk_iops_per_gib: Option<usize>) -> usize {
+    let iops_per_gib = disk_iops_per_gib.unwrap_or(DEFAULT_IO1_IOPS_PER_GB);
+    (disk_size_gib * iops_per_gib).min(MAX_IOPS_PER_VOLUMES)
+}
+
+// Daemon-wide cap on BSU so all drives together stay below the account volume quota. Drives
+// set their count on each BSU fetch, which also corrects any drift of reservations.
+#[derive(Debug, Default)]
+pub struct VolumeBudget {
+    pub max_bsu: Option<usize>,
+    counts: HashMap<String, usize>,
+}
+
+impl VolumeBudget {
+    pub fn new(max_bsu: Option<usize>) -> Self {
+        VolumeBudget {
+            max_bsu,
+            counts: HashMap::new(),
+        }
+    }
+
+    pub fn total(&self) -> usize {
+        self.counts.values().sum()
+    }
+
+    pub fn set_count(&mut self, drive_name: &str, count: usize) {
+        self.counts.insert(drive_name.to_string(), count);
+    }
+
+    pub fn fits(&self, count: usize) -> bool {
+        match self.max_bsu {
+            None => true,
+            Some(max_bsu) => self.total() + count <= max_bsu,
+        }
+    }
+
+    // Counts `count` BSU about to be created, nothing is reserved if they do not all fit
+    pub fn try_reserve(&mut self, drive_name: &str, count: usize) -> bool {
+        if !self.fits(count) {
+            return false;
+        }
+        *self.counts.entry(drive_name.to_string()).or_default() += count;
+        true
+    }
+
+    pub fn release(&mut self, drive_name: &str, count: usize) {
+        if let Some(drive_count) = self.counts.get_mut(drive_name) {
+            *drive_count = drive_count.saturating_sub(count);
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    refill_per_s: f64,
+    burst: f64,
+    tokens: f64,
+    last_refill: time::Instant,
+}
+
+impl TokenBucket {
+    pub fn new(refill_per_s: f64, burst: usize, now: time::Instant) -> Self {
+        let burst = burst.max(1) as f64;
+        TokenBucket {
+            refill_per_s,
+            burst,
+            tokens: burst,
+            last_refill: now,
+        }
+    }
+
+    // Take one token if available, otherwise return how long to wait for the next one.
+    pub fn try_take(&mut self, now: time::Instant) -> Option<time::Duration> {
+        let elapsed_s = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed_s * self.refill_per_s).min(self.burst);
+        self.last_refill = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return None;
+        }
+        let missing_s = (1.0 - self.tokens) / self.refill_per_s;
+        Some(time::Duration::from_secs_f64(missing_s))
+    }
+}
+
+pub fn configure_api_limiter(refill_per_s: f64, burst: usize) -> Result<(), Box<dyn Error>> {
+    if refill_per_s.is_nan() || refill_per_s < MIN_API_REFILL_PER_S {
+        return Err(Box::new(format_err!(
+            "api refill rate must be at least {}, got {}",
+            MIN_API_REFILL_PER_S,
+            refill_per_s
+        )));
+    }
+    *API_LIMITER.lock()? = TokenBucket::new(refill_per_s, burst, time::Instant::now());
+    Ok(())
 }
 
 pub fn api_limiter() -> Result<(), Box<dyn Error>> {
-    let mut limiter = API_LIMITER.lock()?;
-    let waited_time_s = Instant::now().seconds() - limiter.seconds();
-    let time_left = (API_LIMITER_S as i64 - waited_time_s).max(0) as u64;
+    loop {
+        let wait = API_LIMITER.lock()?.try_take(time::Instant::now());
+        let Some(wait) = wait else {
+            return Ok(());
+        };
+        debug!("api limiter sleeps for {:?}", wait);
+        sleep(wait);
+    }
+}
 
-    if time_left > 0 {
-        debug!("api limiter sleeps for {} seconds", time_left);
-        sleep(time::Duration::from_secs(time_left));
+#[derive(Debug, Clone, PartialEq)]
+pub enum ApiFailure {
+    Status(u16),
+    Connection,
+    Timeout,
+    Other,
+}
+
+fn api_failure<T>(err: &outscale_api::apis::Error<T>) -> ApiFailure {
+    match err {
+        outscale_api::apis::Error::ResponseError(response) => {
+            ApiFailure::Status(response.status.as_u16())
+        }
+        outscale_api::apis::Error::Reqwest(err) => {
+            if err.is_timeout() {
+                ApiFailure::Timeout
+            } else if let Some(status) = err.status() {
+                ApiFailure::Status(status.as_u16())
+            } else if err.get_ref().is_some_and(|inner| is_connect_failure(inner)) {
+                ApiFailure::Connection
+            } else {
+                ApiFailure::Other
+            }
+        }
+        _ => ApiFailure::Other,
     }
+}
+
+// Nothing was sent when connecting failed, other transport failures may happen once the API
+// has the request. reqwest 0.9 has no `is_connect`, hyper describes these errors as below.
+fn is_connect_failure(err: &dyn Error) -> bool {
+    err.to_string().starts_with("error trying to connect")
+}
 
-    *limiter = Instant::now();
+// Throttling and connection failures mean the request was not handled. Server errors and
+// timeouts may have been handled, they are only retried for idempotent requests.
+fn is_retryable(failure: &ApiFailure, idempotent: bool) -> bool {
+    match failure {
+        ApiFailure::Status(429) | ApiFailure::Connection => true,
+        ApiFailure::Status(status) => idempotent && (500..600).contains(status),
+        ApiFailure::Timeout => idempotent,
+        ApiFailure::Other => false,
+    }
+}
+
+// Rejected credentials may just have been rotated
+fn is_auth_failure(failure: &ApiFailure) -> bool {
+    matches!(failure, ApiFailure::Status(401) | ApiFailure::Status(403))
+}
+
+// Exponential backoff with `jitter` (between 0 and 1) spreading the second half of the delay
+pub fn backoff_delay(attempt: usize, jitter: f64) -> time::Duration {
+    let exponent = attempt.saturating_sub(1).min(16) as u32;
+    let delay_ms = API_RETRY_BASE_DELAY_MS
+        .saturating_mul(2_u64.pow(exponent))
+        .min(API_RETRY_MAX_DELAY_MS);
+    let jitter = jitter.clamp(0.0, 1.0);
+    time::Duration::from_millis(delay_ms / 2 + (delay_ms as f64 / 2.0 * jitter) as u64)
+}
+
+fn jitter() -> f64 {
+    match time::SystemTime::now().duration_since(time::UNIX_EPOCH) {
+        Ok(now) => now.subsec_nanos() as f64 / 1_000_000_000.0,
+        Err(_) => 0.5,
+    }
+}
+
+pub fn configure_api_retry(max_attempts: usize) -> Result<(), Box<dyn Error>> {
+    if max_attempts == 0 {
+        return Err(Box::new(format_err!("api max attempts must be at least 1")));
+    }
+    API_MAX_ATTEMPTS.store(max_attempts, Ordering::SeqCst);
     Ok(())
 }
+
+pub fn api_call<T, E, F>(operation: &str, call: F) -> Result<T, Box<dyn Error>>
+where
+    E: fmt::Debug + 'static,
+    F: FnMut(&Configuration) -> Result<T, outscale_api::apis::Error<E>>,
+{
+    api_call_with_retry(operation, true, call)
+}
+
+pub fn api_call_non_idempotent<T, E, F>(operation: &str, call: F) -> Result<T, Box<dyn Error>>
+where
+    E: fmt::Debug + 'static,
+    F: FnMut(&Configuration) -> Result<T, outscale_api::apis::Error<E>>,
+{
+    api_call_with_retry(operation, false, call)
+}
+
+// All Outscale API calls go through the rate limiter and are retried on transient failures
+fn api_call_with_retry<T, E, F>(
+    operation: &str,
+    idempotent: bool,
+    mut call: F,
+) -> Result<T, Box<dyn Error>>
+where
+    E: fmt::Debug + 'static,
+    F: FnMut(&Configuration) -> Result<T, outscale_api::apis::Error<E>>,
+{
+    let max_attempts = API_MAX_ATTEMPTS.load(Ordering::SeqCst);
+    let mut attempt = 1;
+    let mut credentials_reloaded = false;
+    loop {
+        api_limiter()?;
+        // Not holding the lock during the call lets credentials be swapped at any time
+        let cloud_config = CLOUD_CONFIG.read()?.clone();
+        let err = match call(&cloud_config) {
+            Ok(response) => return Ok(response),
+            Err(err) => err,
+        };
+        let failure = api_failure(&err);
+        // Rejected requests were not handled, retrying once with new credentials is safe
+        if is_auth_failure(&failure) && !credentials_reloaded {
+            credentials_reloaded = true;
+            if let Err(err) = config::reload_credentials() {
+                error!("cannot reload credentials: {}", err);
+            }
+            if !config::same_credentials(&cloud_config, &*CLOUD_CONFIG.read()?) {
+                warn!(
+                    "{} failed ({:?}), retrying with reloaded credentials",
+                    operation, failure
+                );
+                continue;
+            }
+        }
+        if attempt >= max_attempts || !is_retryable(&failure, idempotent) {
+            error!("{} response: {:?}", operation, err);
+            return Err(Box::new(BsudError::Api {
+                operation: operation.to_string(),
+                failure,
+                message: err.to_string(),
+            }));
+        }
+        let delay = backoff_delay(attempt, jitter());
+        warn!(
+            "{} failed ({:?}), attempt {}/{}, retrying in {:?}",
+            operation, failure, attempt, max_attempts, delay
+        );
+        sleep(delay);
+        attempt += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use secrecy::SecretString;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn stale_creating_bsu() {
+        let bsu = Bsu {
+            state: "creating".to_string(),
+            creation_epoch_s: Some(1000),
+            ..Default::default()
+        };
+        assert!(!bsu.is_stale_creating(1100));
+        assert!(bsu.is_stale_creating(1000 + 3600));
+        let bsu = Bsu {
+            state: "available".to_string(),
+            ..bsu
+        };
+        assert!(!bsu.is_stale_creating(1000 + 3600));
+    }
+
+    #[test]
+    fn io1_iops_capped_per_volume() {
+        assert_eq!(io1_iops(10, Some(100)), 1000);
+        assert_eq!(io1_iops(10, None), 1000);
+        assert_eq!(io1_iops(10, Some(20)), 200);
+        assert_eq!(io1_iops(1000, Some(100)), 13000);
+        assert_eq!(io1_iops(1000, None), 13000);
+    }
+
+    #[test]
+    fn token_bucket_allows_burst_then_throttles() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(1.0, 3, start);
+        assert_eq!(bucket.try_take(start), None);
+        assert_eq!(bucket.try_take(start), None);
+        assert_eq!(bucket.try_take(start), None);
+        let wait = bucket.try_take(start).expect("bucket should be empty");
+        assert!(wait > Duration::from_millis(900) && wait <= Duration::from_secs(1));
+    }
+
+    #[test]
+    fn token_bucket_refills_over_time() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(2.0, 2, start);
+        assert_eq!(bucket.try_take(start), None);
+        assert_eq!(bucket.try_take(start), None);
+        assert!(bucket.try_take(start).is_some());
+        let later = start + Duration::from_millis(500);
+        assert_eq!(bucket.try_take(later), None);
+        assert!(bucket.try_take(later).is_some());
+        // Bucket never holds more than burst
+        let much_later = later + Duration::from_secs(60);
+        assert_eq!(bucket.try_take(much_later), None);
+        assert_eq!(bucket.try_take(much_later), None);
+        assert!(bucket.try_take(much_later).is_some());
+    }
+
+    #[test]
+    fn next_available_xvd_device() {
+        let fake_dev = ["/dev/xvda", "/dev/xvdb"];
+        let exists = |device: &str| fake_dev.contains(&device);
+        let scheme = DeviceScheme::detect(exists);
+        assert_eq!(scheme, DeviceScheme::Xvd);
+        assert_eq!(
+            find_next_available_device_in(&scheme, exists),
+            Some("/dev/xvdc".to_string())
+        );
+    }
+
+    #[test]
+    fn device_template_candidates() {
+        let candidates = device_candidates("/dev/sd{a}").expect("letter template");
+        assert_eq!(candidates[..3], ["/dev/sdb", "/dev/sdc", "/dev/sdd"]);
+        assert_eq!(candidates[25], "/dev/sdba");
+        assert_eq!(candidates.last().map(String::as_str), Some("/dev/sdzz"));
+        assert_eq!(
+            device_candidates("/dev/disk/by-bsud/data{n}").expect("number template")[..2],
+            ["/dev/disk/by-bsud/data1", "/dev/disk/by-bsud/data2"]
+        );
+        // Default template is the former xvd naming
+        assert_eq!(
+            device_candidates(DEFAULT_DEVICE_TEMPLATE).expect("default template"),
+            device_candidates("/dev/xvd{a}").expect("xvd template")
+        );
+        for template in ["/dev/sd", "/dev/sd{a}{a}", "/dev/sd{a}{n}", "/dev/sd{x}"] {
+            assert!(device_candidates(template).is_err(), "{}", template);
+        }
+
+        let scheme = DeviceScheme::Template("/dev/sd{a}".to_string());
+        let fake_dev = ["/dev/sda", "/dev/sdb"];
+        assert_eq!(
+            find_next_available_device_in(&scheme, |device: &str| fake_dev.contains(&device)),
+            Some("/dev/sdc".to_string())
+        );
+
+        let config = Config {
+            device_template: Some("/dev/sd".to_string()),
+            ..Default::default()
+        };
+        let error = config.validate().expect_err("invalid template").to_string();
+        assert!(error.starts_with("invalid device-template"), "{}", error);
+    }
+
+    #[test]
+    fn attach_batch_reserves_distinct_devices() {
+        let exists = |device: &str| device == "/dev/xvda";
+        let mut reservation = DeviceReservation::default();
+        let first = reservation.reserve_next(&DeviceScheme::Xvd, exists);
+        let second = reservation.reserve_next(&DeviceScheme::Xvd, exists);
+        assert_eq!(first.as_deref(), Some("/dev/xvdb"));
+        assert_eq!(second.as_deref(), Some("/dev/xvdc"));
+
+        // Another batch running at the same time gets other devices
+        let mut other = DeviceReservation::default();
+        assert_eq!(
+            other.reserve_next(&DeviceScheme::Xvd, exists).as_deref(),
+            Some("/dev/xvdd")
+        );
+        assert_eq!(reservation.devices(), vec!["/dev/xvdb", "/dev/xvdc"]);
+
+        // Devices are released once the batch is over
+        drop(reservation);
+        drop(other);
+        let mut reservation = DeviceReservation::default();
+        assert_eq!(
+            reservation
+                .reserve_next(&DeviceScheme::Xvd, exists)
+                .as_deref(),
+            Some("/dev/xvdb")
+        );
+    }
+
+    #[test]
+    fn api_retry_classifier() {
+        let idempotent = [
+            (ApiFailure::Status(429), true),
+            (ApiFailure::Status(500), true),
+            (ApiFailure::Status(503), true),
+            (ApiFailure::Status(400), false),
+            (ApiFailure::Status(403), false),
+            (ApiFailure::Status(404), false),
+            (ApiFailure::Connection, true),
+            (ApiFailure::Timeout, true),
+            (ApiFailure::Other, false),
+        ];
+        for (failure, retryable) in idempotent {
+            assert_eq!(is_retryable(&failure, true), retryable, "{:?}", failure);
+        }
+        // The API may have handled the request, creations must not be duplicated
+        assert!(is_retryable(&ApiFailure::Status(429), false));
+        assert!(is_retryable(&ApiFailure::Connection, false));
+        assert!(!is_retryable(&ApiFailure::Status(503), false));
+        assert!(!is_retryable(&ApiFailure::Timeout, false));
+
+        let serde_err = serde_json::from_str::<usize>("not json").expect_err("invalid json");
+        let err: outscale_api::apis::Error<()> = outscale_api::apis::Error::Serde(serde_err);
+        assert_eq!(api_failure(&err), ApiFailure::Other);
+        let io_err = std::io::Error::other("io");
+        let err: outscale_api::apis::Error<()> = outscale_api::apis::Error::Io(io_err);
+        assert_eq!(api_failure(&err), ApiFailure::Other);
+
+        // Only a refused connection is known not to have reached the API
+        let mut config = outscale_api::apis::configuration::Configuration::new();
+        config.base_path = "http://127.0.0.1:1".to_string();
+        let err = outscale_api::apis::volume_api::read_volumes(&config, None).expect_err("refused");
+        assert_eq!(api_failure(&err), ApiFailure::Connection);
+    }
+
+    #[test]
+    fn api_retry_backoff() {
+        assert_eq!(backoff_delay(1, 0.0), Duration::from_millis(250));
+        assert_eq!(backoff_delay(1, 1.0), Duration::from_millis(500));
+        assert_eq!(backoff_delay(2, 1.0), Duration::from_millis(1000));
+        assert_eq!(backoff_delay(3, 0.5), Duration::from_millis(1500));
+        // Capped, even with a huge number of attempts
+        assert_eq!(backoff_delay(100, 1.0), Duration::from_secs(30));
+        assert_eq!(backoff_delay(100, 0.0), Duration::from_secs(15));
+    }
+
+    #[test]
+    fn credentials_rebuild() {
+        let current = config::cloud_config(
+            "AK1".to_string(),
+            SecretString::new("SK1".to_string()),
+            "eu-west-2",
+        );
+        let reloaded = config::cloud_config(
+            "AK1".to_string(),
+            SecretString::new("SK1".to_string()),
+            "eu-west-2",
+        );
+        assert!(config::same_credentials(&current, &reloaded));
+        let new_secret = config::cloud_config(
+            "AK1".to_string(),
+            SecretString::new("SK2".to_string()),
+            "eu-west-2",
+        );
+        assert!(!config::same_credentials(&current, &new_secret));
+        let rotated = config::cloud_config(
+            "AK2".to_string(),
+            SecretString::new("SK2".to_string()),
+            "eu-west-2",
+        );
+        assert!(!config::same_credentials(&current, &rotated));
+        let key = rotated.aws_v4_key.as_ref().expect("aws v4 key");
+        assert_eq!(key.access_key, "AK2");
+        assert_eq!(key.region, "eu-west-2");
+        assert_eq!(
+            rotated.base_path,
+            "https://api.eu-west-2.outscale.com/api/v1"
+        );
+
+        assert!(is_auth_failure(&ApiFailure::Status(401)));
+        assert!(is_auth_failure(&ApiFailure::Status(403)));
+        assert!(!is_auth_failure(&ApiFailure::Status(429)));
+        assert!(!is_auth_failure(&ApiFailure::Connection));
+    }
+
+    #[test]
+    fn restored_io1_bsu_gets_iops() {
+        let name = "data".to_string();
+        let spec = BsuSpec {
+            drive_name: &name,
+            disk_type: &DiskType::Io1,
+            disk_iops_per_gib: Some(50),
+            subregion: Some("eu-west-2a"),
+            seq: 0,
+        };
+        let snapshot = Snapshot {
+            id: "snap-a".to_string(),
+            volume_size_gib: Some(20),
+            ..Default::default()
+        };
+        let request = Bsu::snapshot_creation_request(&spec, &snapshot, 10).expect("request");
+        assert_eq!(request.snapshot_id.as_deref(), Some("snap-a"));
+        assert_eq!(request.iops, Some(1000));
+        assert_eq!(request.size, None);
+        // Snapshot size unknown: iops of the drive's initial size
+        let snapshot = Snapshot {
+            volume_size_gib: None,
+            ..snapshot
+        };
+        let request = Bsu::snapshot_creation_request(&spec, &snapshot, 10).expect("request");
+        assert_eq!(request.iops, Some(500));
+    }
+
+    fn short_wait() -> WaitOptions {
+        WaitOptions {
+            timeout: Duration::from_millis(50),
+            poll_interval: Duration::from_millis(5),
+        }
+    }
+
+    #[test]
+    fn wait_state_timeout() {
+        let mut polls = 0;
+        let err = wait_for_state(
+            || {
+                polls += 1;
+                Ok("creating".to_string())
+            },
+            "available",
+            &short_wait(),
+            &mut || Ok(()),
+        )
+        .unwrap_err();
+        let Some(BsudError::Timeout(timeout)) = err.downcast_ref::<BsudError>() else {
+            panic!("not a timeout: {}", err);
+        };
+        assert_eq!(timeout.last_state, "creating");
+        assert!(timeout.waited >= Duration::from_millis(50));
+        assert!(polls > 1);
+    }
+
+    #[test]
+    fn wait_state_early_exit() {
+        let err = wait_for_state(
+            || Ok("creating".to_string()),
+            "available",
+            &short_wait(),
+            &mut || Err("stop".into()),
+        )
+        .unwrap_err();
+        assert!(err.downcast_ref::<BsudError>().is_none());
+        assert!(wait_for_state(
+            || Ok("available".to_string()),
+            "available",
+            &short_wait(),
+            &mut || Ok(())
+        )
+        .is_ok());
+    }
+}
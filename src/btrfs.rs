@@ -0,0 +1,178 @@
+use crate::utils::bytes_to_gib;
+use crate::utils::Executor;
+use crate::utils::SystemExecutor;
+use log::debug;
+use std::error::Error;
+
+// Drives using the btrfs-raw backend put each BSU directly in a multi-device btrfs
+// filesystem: btrfs spreads data over devices and migrates it itself on device delete.
+
+// Label of the drive's filesystem, gives a stable fstab source whatever the devices are
+pub fn label(drive_name: &str) -> String {
+    format!("bsud-{}", drive_name)
+}
+
+pub fn fstab_source(drive_name: &str) -> String {
+    format!("LABEL={}", label(drive_name))
+}
+
+// BSU device paths may be links (e.g. /dev/xvdb), btrfs reports kernel device names
+pub fn resolve_device(device_path: &str) -> String {
+    match std::fs::canonicalize(device_path) {
+        Ok(path) => path.to_string_lossy().to_string(),
+        Err(_) => device_path.to_string(),
+    }
+}
+
+pub fn label_device_path(drive_name: &str) -> String {
+    format!("/dev/disk/by-label/{}", label(drive_name))
+}
+
+// A filesystem mounted on the drive's path is the drive's one if it is btrfs and comes from
+// one of its devices or carries its label
+pub fn is_drive_mount(
+    drive_name: &str,
+    fs_type: &str,
+    source: &str,
+    device_paths: &[String],
+) -> bool {
+    if fs_type != "btrfs" {
+        return false;
+    }
+    let source = resolve_device(source);
+    device_paths.contains(&source) || resolve_device(&label_device_path(drive_name)) == source
+}
+
+pub fn format_args<'a>(label: &'a str, device_path: &'a str) -> Vec<&'a str> {
+    vec!["-L", label, device_path]
+}
+
+pub fn format(label: &str, device_path: &str) -> Result<(), Box<dyn Error>> {
+    format_with(&SystemExecutor::default(), label, device_path)
+}
+
+pub fn format_with(
+    executor: &dyn Executor,
+    label: &str,
+    device_path: &str,
+) -> Result<(), Box<dyn Error>> {
+    executor.exec_mut("mkfs.btrfs", &format_args(label, device_path))?;
+    Ok(())
+}
+
+// The kernel must know all devices of a multi-device filesystem before mounting it
+pub fn device_scan() -> Result<(), Box<dyn Error>> {
+    device_scan_with(&SystemExecutor::default())
+}
+
+pub fn device_scan_with(executor: &dyn Executor) -> Result<(), Box<dyn Error>> {
+    executor.exec_checked("btrfs", &["device", "scan"])?;
+    Ok(())
+}
+
+pub fn device_add_args<'a>(device_paths: &'a [String], mount_target: &'a str) -> Vec<&'a str> {
+    let mut args = vec!["device", "add"];
+    args.extend(device_paths.iter().map(String::as_str));
+    args.push(mount_target);
+    args
+}
+
+// Without `-f`, btrfs refuses devices which already hold a filesystem
+pub fn device_add(device_paths: &[String], mount_target: &str) -> Result<(), Box<dyn Error>> {
+    device_add_with(&SystemExecutor::default(), device_paths, mount_target)
+}
+
+pub fn device_add_with(
+    executor: &dyn Executor,
+    device_paths: &[String],
+    mount_target: &str,
+) -> Result<(), Box<dyn Error>> {
+    executor.exec_mut("btrfs", &device_add_args(device_paths, mount_target))?;
+    Ok(())
+}
+
+pub fn device_delete_args<'a>(device_path: &'a str, mount_target: &'a str) -> Vec<&'a str> {
+    vec!["device", "delete", device_path, mount_target]
+}
+
+// Blocks until all data of the device has been moved to the other devices
+pub fn device_delete(device_path: &str, mount_target: &str) -> Result<(), Box<dyn Error>> {
+    device_delete_with(&SystemExecutor::default(), device_path, mount_target)
+}
+
+pub fn device_delete_with(
+    executor: &dyn Executor,
+    device_path: &str,
+    mount_target: &str,
+) -> Result<(), Box<dyn Error>> {
+    debug!("btrfs device delete {} from {}", device_path, mount_target);
+    executor.exec_mut("btrfs", &device_delete_args(device_path, mount_target))?;
+    Ok(())
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Device {
+    pub devid: u64,
+    pub size_bytes: usize,
+    pub used_bytes: usize,
+    pub path: String,
+    pub missing: bool,
+}
+
+pub fn devices(mount_target: &str) -> Result<Vec<Device>, Box<dyn Error>> {
+    devices_with(&SystemExecutor::default(), mount_target)
+}
+
+pub fn devices_with(
+    executor: &dyn Executor,
+    mount_target: &str,
+) -> Result<Vec<Device>, Box<dyn Error>> {
+    let output = executor.exec_checked("btrfs", &["filesystem", "show", "--raw", mount_target])?;
+    let devices = parse_filesystem_show(&output.stdout);
+    for device in devices.iter() {
+        debug!(
+            "btrfs device {} ({}): {}B ({}GiB)",
+            device.devid,
+            device.path,
+            device.size_bytes,
+            bytes_to_gib(device.size_bytes)
+        );
+    }
+    Ok(devices)
+}
+
+// Parse `btrfs filesystem show --raw` lines like
+// "devid    1 size 10737418240 used 2172649472 path /dev/xvdb"
+pub fn parse_filesystem_show(output: &str) -> Vec<Device> {
+    let mut devices = Vec::new();
+    for line in output.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let ["devid", devid, "size", size, "used", used, "path", path, rest @ ..] =
+            fields.as_slice()
+        else {
+            continue;
+        };
+        let (Ok(devid), Ok(size_bytes), Ok(used_bytes)) =
+            (devid.parse(), size.parse(), used.parse())
+        else {
+            continue;
+        };
+        devices.push(Device {
+            devid,
+            size_bytes,
+            used_bytes,
+            path: path.to_string(),
+            missing: rest.contains(&"MISSING") || path.starts_with('<'),
+        });
+    }
+    devices
+}
+
+// Devices of the drive's BSU which are not part of the filesystem yet
+pub fn missing_devices(bsu_device_paths: &[String], fs_devices: &[Device]) -> Vec<String> {
+    bsu_device_paths
+        .iter()
+        .filter(|path| !fs_devices.iter().any(|device| device.path == **path))
+        .cloned()
+        .collect()
+}
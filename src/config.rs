@@ -1,15 +1,31 @@
+use crate::bsu::{backoff_delay, device_candidates, TagKeys, MIN_API_REFILL_PER_S};
+use crate::credentials;
+use crate::drive::{
+    DEFAULT_INITIAL_DISK_GIB, DEFAULT_MAX_DISKS, DEFAULT_MAX_USED_PERC, DEFAULT_MIN_USED_PERC,
+    MAX_BSU_SIZE_GIB,
+};
+use crate::error::BsudError;
+use crate::snapshot::parse_schedule;
+use crate::sweeper::{Sweeper, DEFAULT_ORPHAN_GRACE_S};
+use datetime::Instant;
 use easy_error::format_err;
 use lazy_static::lazy_static;
-use log::debug;
+use log::{debug, warn};
 use outscale_api::apis::configuration::AWSv4Key;
-use secrecy::Secret;
 use secrecy::SecretString;
-use serde::Deserialize;
+use secrecy::{ExposeSecret, Secret};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::env;
 use std::error::Error;
-use std::fs::read_to_string;
+use std::fs::{canonicalize, metadata, read_to_string};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
 use std::str::FromStr;
+use std::sync::atomic::{AtomicI64, Ordering};
 use std::sync::RwLock;
+use std::thread::sleep;
+use std::time;
 
 type CloudConfig = outscale_api::apis::configuration::Configuration;
 
@@ -17,103 +33,551 @@ const VERSION: &str = env!("CARGO_PKG_VERSION");
 const METADATA_SUBREGION_URL: &str =
     "http://169.254.169.254/latest/meta-data/placement/availability-zone";
 const METADATA_VMID_URL: &str = "http://169.254.169.254/latest/meta-data/instance-id";
+pub const DEFAULT_METADATA_TIMEOUT_S: u64 = 2;
+const METADATA_MAX_ATTEMPTS: usize = 5;
+// Minimum delay between two credentials reloads caused by rejected API calls
+const CREDENTIALS_RELOAD_INTERVAL_S: i64 = 10;
+
+static LAST_CREDENTIALS_RELOAD_EPOCH_S: AtomicI64 = AtomicI64::new(i64::MIN);
 
 lazy_static! {
     pub static ref CLOUD_CONFIG: RwLock<CloudConfig> = RwLock::new(CloudConfig::new());
     pub static ref REGION: RwLock<String> = RwLock::new(String::new());
     pub static ref SUBREGION: RwLock<String> = RwLock::new(String::new());
     pub static ref VM_ID: RwLock<String> = RwLock::new(String::new());
+    // Where the last loaded credentials come from, they are read again from it after a rotation
+    static ref CREDENTIALS_ORIGIN: RwLock<Option<CredentialsOrigin>> = RwLock::new(None);
 }
-#[derive(Deserialize, Debug)]
+
+#[derive(Debug, Clone)]
+enum CredentialsOrigin {
+    InstanceProfile(time::Duration),
+    // `authentication` of the configuration file
+    ConfigFile(String),
+    CredentialsFile(String),
+    Env,
+}
+
+#[derive(Deserialize, Debug, Default)]
 pub struct Config {
     pub drives: Vec<ConfigFileDrive>,
+    pub state_dir: Option<String>,
+    pub api_refill_per_s: Option<f64>,
+    pub api_burst: Option<usize>,
+    pub api_max_attempts: Option<usize>,
+    pub metrics_address: Option<String>,
+    pub control_socket: Option<String>,
+    pub watchdog_staleness_s: Option<u64>,
+    pub metadata_timeout_s: Option<u64>,
+    pub shutdown_timeout_s: Option<u64>,
+    pub max_concurrent_drives: Option<usize>,
+    pub max_account_bsu: Option<usize>,
+    pub tag_prefix: Option<String>,
+    pub device_template: Option<String>,
+    pub orphan_sweep_schedule: Option<String>,
+    pub orphan_grace_s: Option<u64>,
+    pub auto_reap: Option<bool>,
+    pub credentials_file: Option<String>,
+    pub credentials_source: CredentialsSource,
+    // Resolved by `load`, from the configuration file or the environment
+    pub authentication: Option<ConfigFileAuth>,
 }
 
-pub fn discover_vm_config() -> Result<(), Box<dyn Error>> {
+// Metadata does not change during the VM life, it is only fetched once
+pub fn discover_vm_config(timeout: time::Duration) -> Result<(), Box<dyn Error>> {
+    if is_vm_config_discovered()? {
+        debug!("vm metadata already discovered");
+        return Ok(());
+    }
+    let client = metadata_client(timeout)?;
     debug!("getting subregion from metadata");
-    let subregion = reqwest::blocking::get(METADATA_SUBREGION_URL)?.text()?;
-    let mut region = subregion.clone();
+    let subregion = metadata_get(&client, METADATA_SUBREGION_URL)?;
+    debug!("get vm id");
+    let vm_id = metadata_get(&client, METADATA_VMID_URL)?;
+    set_vm_config(&subregion, &vm_id)
+}
+
+pub fn metadata_client(
+    timeout: time::Duration,
+) -> Result<reqwest::blocking::Client, Box<dyn Error>> {
+    Ok(reqwest::blocking::Client::builder()
+        .connect_timeout(timeout)
+        .timeout(timeout)
+        .build()?)
+}
+
+// Metadata service may not answer yet when the VM boots
+pub fn metadata_get(
+    client: &reqwest::blocking::Client,
+    url: &str,
+) -> Result<String, Box<dyn Error>> {
+    let mut attempt = 1;
+    loop {
+        let result = client
+            .get(url)
+            .send()
+            .and_then(|response| response.error_for_status())
+            .and_then(|response| response.text());
+        let err = match result {
+            Ok(text) => return Ok(text),
+            Err(err) => err,
+        };
+        if attempt >= METADATA_MAX_ATTEMPTS {
+            return Err(Box::new(format_err!(
+                "cannot get {} from metadata service after {} attempts: {}",
+                url,
+                attempt,
+                err
+            )));
+        }
+        let delay = backoff_delay(attempt, 0.5);
+        warn!(
+            "cannot get {} from metadata service (attempt {}/{}), retrying in {:?}: {}",
+            url, attempt, METADATA_MAX_ATTEMPTS, delay, err
+        );
+        sleep(delay);
+        attempt += 1;
+    }
+}
+
+fn is_vm_config_discovered() -> Result<bool, Box<dyn Error>> {
+    Ok(!SUBREGION.read()?.is_empty() && !VM_ID.read()?.is_empty())
+}
+
+// Region is the subregion without its trailing letter (e.g. "eu-west-2a")
+fn set_vm_config(subregion: &str, vm_id: &str) -> Result<(), Box<dyn Error>> {
+    let mut region = subregion.to_string();
     region.pop();
     {
-        *SUBREGION.write()? = subregion;
+        *SUBREGION.write()? = subregion.to_string();
         *REGION.write()? = region;
     }
-    debug!("get vm id");
-    let vm_id = reqwest::blocking::get(METADATA_VMID_URL)?.text()?;
     {
-        *VM_ID.write()? = vm_id;
+        *VM_ID.write()? = vm_id.to_string();
     }
     Ok(())
 }
 
+// A region (lowercase words and a number, e.g. "cloudgouv-eu-west-1") followed by a letter
+pub fn is_plausible_subregion(subregion: &str) -> bool {
+    let mut region = subregion.to_string();
+    let Some(zone) = region.pop() else {
+        return false;
+    };
+    let Some((name, number)) = region.rsplit_once('-') else {
+        return false;
+    };
+    zone.is_ascii_lowercase()
+        && !number.is_empty()
+        && number.chars().all(|c| c.is_ascii_digit())
+        && name
+            .split('-')
+            .all(|word| !word.is_empty() && word.chars().all(|c| c.is_ascii_lowercase()))
+}
+
 pub fn region() -> Result<String, Box<dyn Error>> {
     Ok(String::from(&(*REGION.read()?)))
 }
 
-pub fn load(path: String) -> Result<Config, Box<dyn Error>> {
+// Reads and validates the configuration file, without credentials nor any cloud access
+pub fn check(path: String) -> Result<Config, Box<dyn Error>> {
+    let (config, _authentication) = read(path)?;
+    Ok(config)
+}
+
+fn read(path: String) -> Result<(Config, Option<ConfigFileAuth>), Box<dyn Error>> {
     debug!("trying to read \"{}\"", path);
     let data = read_to_string(path)?;
     let config_file: ConfigFile = serde_json::from_str(&data)?;
+    let config = Config {
+        drives: config_file.drives,
+        state_dir: config_file.state_dir,
+        api_refill_per_s: config_file.api_refill_per_s,
+        api_burst: config_file.api_burst,
+        api_max_attempts: config_file.api_max_attempts,
+        metrics_address: config_file.metrics_address,
+        control_socket: config_file.control_socket,
+        watchdog_staleness_s: config_file.watchdog_staleness_s,
+        metadata_timeout_s: config_file.metadata_timeout_s,
+        shutdown_timeout_s: config_file.shutdown_timeout_s,
+        max_concurrent_drives: config_file.max_concurrent_drives,
+        max_account_bsu: config_file.max_account_bsu,
+        tag_prefix: config_file.tag_prefix,
+        device_template: config_file.device_template,
+        orphan_sweep_schedule: config_file.orphan_sweep_schedule,
+        orphan_grace_s: config_file.orphan_grace_s,
+        auto_reap: config_file.auto_reap,
+        credentials_file: config_file.credentials_file,
+        credentials_source: config_file.credentials_source.unwrap_or_default(),
+        authentication: None,
+    };
+    config.validate()?;
+    if config_file.authentication.is_some() && config.credentials_file.is_some() {
+        return Err(Box::new(format_err!(
+            "authentication and credentials-file cannot be both set"
+        )));
+    }
+    Ok((config, config_file.authentication))
+}
 
-    let config_file_auth = match config_file.authentication {
-        Some(c) => c,
-        None => {
+// Reads the configuration and credentials, VM metadata must then be discovered before
+// calling `configure_cloud`.
+pub fn load(path: String) -> Result<Config, Box<dyn Error>> {
+    let (mut config, authentication) = read(path.clone())?;
+    if config.credentials_source == CredentialsSource::InstanceProfile {
+        debug!("credentials will be fetched from the instance profile");
+        *CREDENTIALS_ORIGIN.write()? = Some(CredentialsOrigin::InstanceProfile(
+            config.metadata_timeout(),
+        ));
+        return Ok(config);
+    }
+    let config_file_auth = match (authentication, &config.credentials_file) {
+        (Some(c), _) => {
+            *CREDENTIALS_ORIGIN.write()? = Some(CredentialsOrigin::ConfigFile(path));
+            c
+        }
+        (None, Some(credentials_file)) => {
+            let authentication = read_credentials_file(credentials_file)?;
+            *CREDENTIALS_ORIGIN.write()? =
+                Some(CredentialsOrigin::CredentialsFile(credentials_file.clone()));
+            authentication
+        }
+        (None, None) => {
             debug!("cannot get credentials through configuration file, trying to get credentials through env");
-            let Ok(access_key) = env::var("OSC_ACCESS_KEY") else {
-                return Err(Box::new(format_err!(
-                    "Cannot get OSC_ACCESS_KEY env variable"
-                )));
-            };
-            let Ok(secret_key) = env::var("OSC_SECRET_KEY") else {
-                return Err(Box::new(format_err!(
-                    "Cannot get OSC_SECRET_KEY env variable"
-                )));
-            };
-            ConfigFileAuth {
-                access_key,
-                secret_key: SecretString::new(secret_key),
-            }
+            let authentication = read_env_credentials()?;
+            *CREDENTIALS_ORIGIN.write()? = Some(CredentialsOrigin::Env);
+            authentication
         }
     };
-    discover_vm_config()?;
+    config.authentication = Some(config_file_auth);
+    Ok(config)
+}
 
+fn read_env_credentials() -> Result<ConfigFileAuth, Box<dyn Error>> {
+    let Ok(access_key) = env::var("OSC_ACCESS_KEY") else {
+        return Err(Box::new(format_err!(
+            "Cannot get OSC_ACCESS_KEY env variable"
+        )));
+    };
+    let Ok(secret_key) = env::var("OSC_SECRET_KEY") else {
+        return Err(Box::new(format_err!(
+            "Cannot get OSC_SECRET_KEY env variable"
+        )));
+    };
+    Ok(ConfigFileAuth {
+        access_key,
+        secret_key: SecretString::new(secret_key),
+    })
+}
+
+// Only `authentication` is read, the rest of the configuration may be in the middle of an edit
+fn read_config_file_credentials(path: &str) -> Result<ConfigFileAuth, Box<dyn Error>> {
+    let data = read_to_string(path)?;
+    let config_file: ConfigFileAuthOnly = serde_json::from_str(&data)?;
+    let Some(authentication) = config_file.authentication else {
+        return Err(Box::new(format_err!(
+            "\"{}\" does not contain authentication anymore",
+            path
+        )));
+    };
+    Ok(authentication)
+}
+
+// Same content as `authentication`, in a file only readable by the user running bsud
+fn read_credentials_file(path: &str) -> Result<ConfigFileAuth, Box<dyn Error>> {
+    debug!("reading credentials from \"{}\"", path);
+    let mode = metadata(path)
+        .map_err(|err| format_err!("cannot read credentials file \"{}\": {}", path, err))?
+        .permissions()
+        .mode();
+    if is_mode_too_open(mode) {
+        warn!(
+            "credentials file \"{}\" is accessible by group or others (mode {:o}), restrict it with \"chmod 600\"",
+            path,
+            mode & 0o777
+        );
+    }
+    let data = read_to_string(path)
+        .map_err(|err| format_err!("cannot read credentials file \"{}\": {}", path, err))?;
+    let authentication = serde_json::from_str(&data)
+        .map_err(|err| format_err!("invalid credentials file \"{}\": {}", path, err))?;
+    Ok(authentication)
+}
+
+fn is_mode_too_open(mode: u32) -> bool {
+    mode & 0o077 != 0
+}
+
+pub fn configure_cloud(config: &Config) -> Result<(), Box<dyn Error>> {
+    if config.credentials_source == CredentialsSource::InstanceProfile {
+        return credentials::use_instance_profile(config.metadata_timeout());
+    }
+    let Some(authentication) = &config.authentication else {
+        return Err(Box::new(format_err!("configuration has no credentials")));
+    };
+    credentials::use_static();
+    set_cloud_credentials(
+        authentication.access_key.clone(),
+        authentication.secret_key.clone(),
+    )
+}
+
+pub fn set_cloud_credentials(
+    access_key: String,
+    secret_key: SecretString,
+) -> Result<(), Box<dyn Error>> {
+    let region = region()?;
+    if region.is_empty() {
+        return Err(Box::new(format_err!(
+            "region is unknown, VM metadata must be discovered first"
+        )));
+    }
     debug!("forge cloud configuration");
+    let cloud_config = cloud_config(access_key, secret_key, &region);
+    // API calls work on their own copy, the swap never waits for a request in flight
+    *CLOUD_CONFIG.write()? = cloud_config;
+    Ok(())
+}
+
+pub fn cloud_config(access_key: String, secret_key: SecretString, region: &str) -> CloudConfig {
     let mut cloud_config = CloudConfig::new();
-    let region = region()?;
     cloud_config.aws_v4_key = Some(AWSv4Key {
-        access_key: config_file_auth.access_key,
-        secret_key: config_file_auth.secret_key,
-        region: region.clone(),
+        access_key,
+        secret_key,
+        region: region.to_string(),
         service: "oapi".to_string(),
     });
     cloud_config.user_agent = Some(format!("bsud/{}", VERSION));
     cloud_config.base_path = format!("https://api.{}.outscale.com/api/v1", region);
+    cloud_config
+}
+
+pub fn same_credentials(a: &CloudConfig, b: &CloudConfig) -> bool {
+    match (&a.aws_v4_key, &b.aws_v4_key) {
+        (Some(a), Some(b)) => {
+            a.access_key == b.access_key
+                && a.secret_key.expose_secret() == b.secret_key.expose_secret()
+        }
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+// Called when the API rejects our credentials: they may have been rotated in the configuration,
+// the credentials file or the environment since they were loaded. Concurrent callers share a
+// single reload and reloads are spaced so wrong credentials do not hammer their source.
+pub fn reload_credentials() -> Result<(), Box<dyn Error>> {
+    let now = Instant::now().seconds();
+    let last = LAST_CREDENTIALS_RELOAD_EPOCH_S.load(Ordering::SeqCst);
+    if now.saturating_sub(last) < CREDENTIALS_RELOAD_INTERVAL_S
+        || LAST_CREDENTIALS_RELOAD_EPOCH_S
+            .compare_exchange(last, now, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
     {
-        *CLOUD_CONFIG.write()? = cloud_config;
+        debug!("credentials were reloaded recently, not reloading them again");
+        return Ok(());
     }
+    let Some(origin) = CREDENTIALS_ORIGIN.read()?.clone() else {
+        return Err(Box::new(format_err!("no configuration loaded yet")));
+    };
+    warn!(
+        "credentials rejected by the API, reloading them from {:?}",
+        origin
+    );
+    let authentication = match origin {
+        CredentialsOrigin::InstanceProfile(timeout) => {
+            return credentials::use_instance_profile(timeout)
+        }
+        CredentialsOrigin::ConfigFile(path) => read_config_file_credentials(&path)?,
+        CredentialsOrigin::CredentialsFile(path) => read_credentials_file(&path)?,
+        CredentialsOrigin::Env => read_env_credentials()?,
+    };
+    set_cloud_credentials(authentication.access_key, authentication.secret_key)
+}
 
-    Ok(Config {
-        drives: config_file.drives,
-    })
+impl Config {
+    pub fn metadata_timeout(&self) -> time::Duration {
+        time::Duration::from_secs(
+            self.metadata_timeout_s
+                .unwrap_or(DEFAULT_METADATA_TIMEOUT_S),
+        )
+    }
+
+    // Catches settings which would only break scaling at runtime
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        if let Some(tag_prefix) = &self.tag_prefix {
+            // "=" separates tag keys from values in API filters
+            if tag_prefix.is_empty() || tag_prefix.contains('=') {
+                return Err(Box::new(format_err!(
+                    "invalid tag-prefix \"{}\": must be non-empty and without \"=\"",
+                    tag_prefix
+                )));
+            }
+        }
+        if let Some(device_template) = &self.device_template {
+            if let Err(err) = device_candidates(device_template) {
+                return Err(Box::new(format_err!("invalid device-template: {}", err)));
+            }
+        }
+        if let Some(schedule) = &self.orphan_sweep_schedule {
+            if parse_schedule(schedule).is_none() {
+                return Err(Box::new(format_err!(
+                    "invalid orphan-sweep-schedule: \"{}\" is not an interval like \"6h\"",
+                    schedule
+                )));
+            }
+        } else if self.auto_reap == Some(true) {
+            return Err(Box::new(format_err!(
+                "invalid auto-reap: orphan-sweep-schedule must be set"
+            )));
+        }
+        if let Some(refill_per_s) = self.api_refill_per_s {
+            if refill_per_s.is_nan() || refill_per_s < MIN_API_REFILL_PER_S {
+                return Err(Box::new(format_err!(
+                    "invalid api-refill-per-s: {} is below one call per hour",
+                    refill_per_s
+                )));
+            }
+        }
+        if self.max_concurrent_drives == Some(0) {
+            return Err(Box::new(format_err!(
+                "invalid max-concurrent-drives: must be at least 1"
+            )));
+        }
+        if self.max_account_bsu == Some(0) {
+            return Err(Box::new(format_err!(
+                "invalid max-account-bsu: must be at least 1"
+            )));
+        }
+        for drive in self.drives.iter() {
+            drive.validate()?;
+        }
+        let conflicts = drive_conflicts(&self.drives);
+        if !conflicts.is_empty() {
+            return Err(Box::new(format_err!(
+                "conflicting drives: {}",
+                conflicts.join(", ")
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn tag_keys(&self) -> TagKeys {
+        match &self.tag_prefix {
+            Some(tag_prefix) => TagKeys::new(tag_prefix),
+            None => TagKeys::default(),
+        }
+    }
+
+    pub fn orphan_sweeper(&self) -> Option<Sweeper> {
+        let interval_s = parse_schedule(self.orphan_sweep_schedule.as_ref()?)?;
+        Some(Sweeper::new(
+            interval_s,
+            self.orphan_grace_s.unwrap_or(DEFAULT_ORPHAN_GRACE_S),
+            self.auto_reap.unwrap_or(false),
+            self.tag_keys(),
+        ))
+    }
+}
+
+// Drives sharing a name share the same VG and BSU tag (VG names are case-insensitive), drives
+// sharing a mount path fight over it.
+pub fn drive_conflicts(drives: &[ConfigFileDrive]) -> Vec<String> {
+    let mut names: BTreeMap<String, Vec<&str>> = BTreeMap::new();
+    let mut mount_paths: BTreeMap<String, Vec<&str>> = BTreeMap::new();
+    for drive in drives {
+        names
+            .entry(drive.name.to_lowercase())
+            .or_default()
+            .push(&drive.name);
+        for mount_path in drive.mount_paths() {
+            mount_paths
+                .entry(canonical_mount_path(mount_path))
+                .or_default()
+                .push(&drive.name);
+        }
+    }
+    let quoted = |names: &Vec<&str>| {
+        names
+            .iter()
+            .map(|name| format!("\"{}\"", name))
+            .collect::<Vec<String>>()
+            .join(" and ")
+    };
+    let mut conflicts = Vec::new();
+    for (name, drives) in names.iter().filter(|(_, drives)| drives.len() > 1) {
+        conflicts.push(format!("{} share the name \"{}\"", quoted(drives), name));
+    }
+    for (mount_path, drives) in mount_paths.iter().filter(|(_, drives)| drives.len() > 1) {
+        conflicts.push(format!(
+            "{} share mount path {}",
+            quoted(drives),
+            mount_path
+        ));
+    }
+    conflicts
+}
+
+// Mount paths may not exist yet, they are then only normalized (trailing slash, "." and "..")
+pub fn canonical_mount_path(mount_path: &str) -> String {
+    if let Ok(canonical) = canonicalize(mount_path) {
+        return canonical.to_string_lossy().to_string();
+    }
+    let mut normalized = PathBuf::new();
+    for component in Path::new(mount_path).components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized.to_string_lossy().to_string()
 }
 
 #[derive(Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
 struct ConfigFile {
     authentication: Option<ConfigFileAuth>,
     drives: Vec<ConfigFileDrive>,
+    state_dir: Option<String>,
+    api_refill_per_s: Option<f64>,
+    api_burst: Option<usize>,
+    api_max_attempts: Option<usize>,
+    metrics_address: Option<String>,
+    control_socket: Option<String>,
+    watchdog_staleness_s: Option<u64>,
+    metadata_timeout_s: Option<u64>,
+    shutdown_timeout_s: Option<u64>,
+    max_concurrent_drives: Option<usize>,
+    max_account_bsu: Option<usize>,
+    tag_prefix: Option<String>,
+    device_template: Option<String>,
+    orphan_sweep_schedule: Option<String>,
+    orphan_grace_s: Option<u64>,
+    auto_reap: Option<bool>,
+    credentials_file: Option<String>,
+    credentials_source: Option<CredentialsSource>,
 }
 
 #[derive(Deserialize, Debug)]
+struct ConfigFileAuthOnly {
+    authentication: Option<ConfigFileAuth>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
 #[serde(rename_all = "kebab-case")]
 pub struct ConfigFileAuth {
-    access_key: String,
+    pub access_key: String,
     secret_key: Secret<String>,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
 #[serde(rename_all = "kebab-case")]
 pub struct ConfigFileDrive {
     pub name: String,
+    #[serde(default)]
     pub target: DriveTarget,
     pub mount_path: String,
     pub disk_type: Option<DiskType>,
@@ -124,14 +588,391 @@ pub struct ConfigFileDrive {
     pub max_used_space_perc: Option<usize>,
     pub min_used_space_perc: Option<usize>,
     pub disk_scale_factor_perc: Option<usize>,
+    pub max_attach_attempts: Option<usize>,
+    pub on_attach_failure: Option<AttachFailurePolicy>,
+    pub remove_mount_path_on_delete: Option<bool>,
+    pub on_missing_pv: Option<MissingPvPolicy>,
+    pub append_only: Option<bool>,
+    pub max_fs_size_gib: Option<usize>,
+    pub wait_timeout_s: Option<u64>,
+    pub trace_reconcile: Option<bool>,
+    pub filesystem: Option<Filesystem>,
+    pub mount_options: Option<String>,
+    pub manage_fstab: Option<bool>,
+    pub volumes: Option<Vec<ConfigFileVolume>>,
+    pub snapshot_schedule: Option<String>,
+    pub snapshot_retention: Option<usize>,
+    pub snapshot_freeze: Option<bool>,
+    pub restore_from_snapshot: Option<String>,
+    pub clean_shutdown: Option<bool>,
+    pub resize_cooldown_s: Option<u64>,
+    pub resize_dead_band_perc: Option<usize>,
+    pub growth_strategy: Option<GrowthStrategy>,
+    pub min_creation_interval_s: Option<u64>,
+    pub backend: Option<Backend>,
+    pub migrate_disk_type: Option<bool>,
+    pub adopt_unmanaged_bsu: Option<bool>,
+    pub initial_reconcile_jitter_s: Option<u64>,
+    pub encryption: Option<ConfigFileEncryption>,
+    pub vg_alloc_policy: Option<VgAllocPolicy>,
+    pub vg_extent_size_mib: Option<usize>,
+    pub min_free_bytes: Option<usize>,
+    pub max_free_bytes: Option<usize>,
+    pub time_to_max_warning_s: Option<u64>,
+    pub pvmove_ionice: Option<IoNiceClass>,
+    pub removal_margin_bytes: Option<usize>,
+    pub scrub_schedule: Option<String>,
+    pub usage_basis: Option<UsageBasis>,
+    pub rebalance_schedule: Option<String>,
+    pub min_bsu_count: Option<usize>,
+    pub floor_size_gib: Option<usize>,
+    pub subregion: Option<String>,
+    pub emergency_used_space_perc: Option<usize>,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+fn is_drive_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '+' | '_' | '.' | '-')
+}
+
+impl ConfigFileDrive {
+    // Unset values are checked with their defaults, as resolved by the drive
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        let invalid = |field: &str, reason: String| -> Box<dyn Error> {
+            Box::new(BsudError::Config(format!(
+                "\"{}\" drive: invalid {}: {}",
+                self.name, field, reason
+            )))
+        };
+        if self.name.is_empty() {
+            return Err(invalid("name", "cannot be empty".to_string()));
+        }
+        // The name ends up in tags, commands arguments and the drive's state file name
+        if !self.name.chars().all(is_drive_name_char) {
+            return Err(invalid(
+                "name",
+                "only letters, digits, '+', '_', '.' and '-' are allowed".to_string(),
+            ));
+        }
+        if self.name.starts_with('-') {
+            return Err(invalid("name", "cannot start with '-'".to_string()));
+        }
+        let max_used = self.max_used_space_perc.unwrap_or(DEFAULT_MAX_USED_PERC);
+        let min_used = self.min_used_space_perc.unwrap_or(DEFAULT_MIN_USED_PERC);
+        if max_used > 100 {
+            return Err(invalid(
+                "max-used-space-perc",
+                format!("{}% is above 100%", max_used),
+            ));
+        }
+        if min_used >= max_used {
+            return Err(invalid(
+                "min-used-space-perc",
+                format!(
+                    "{}% must be lower than max-used-space-perc ({}%)",
+                    min_used, max_used
+                ),
+            ));
+        }
+        if let (Some(min_free), Some(max_free)) = (self.min_free_bytes, self.max_free_bytes) {
+            if min_free >= max_free {
+                return Err(invalid(
+                    "min-free-bytes",
+                    format!(
+                        "{}B must be lower than max-free-bytes ({}B)",
+                        min_free, max_free
+                    ),
+                ));
+            }
+        }
+        if let Some(emergency) = self.emergency_used_space_perc {
+            if emergency <= max_used || emergency > 100 {
+                return Err(invalid(
+                    "emergency-used-space-perc",
+                    format!(
+                        "{}% must be above max-used-space-perc ({}%) and at most 100%",
+                        emergency, max_used
+                    ),
+                ));
+            }
+        }
+        if let Some(dead_band) = self.resize_dead_band_perc {
+            if dead_band > 100 {
+                return Err(invalid(
+                    "resize-dead-band-perc",
+                    format!("{}% is above 100%", dead_band),
+                ));
+            }
+        }
+        if self.max_bsu_count == Some(0) {
+            return Err(invalid("max-bsu-count", "must be at least 1".to_string()));
+        }
+        let initial_size_gib = self.initial_size_gib.unwrap_or(DEFAULT_INITIAL_DISK_GIB);
+        if initial_size_gib == 0 || initial_size_gib > MAX_BSU_SIZE_GIB {
+            return Err(invalid(
+                "initial-size-gib",
+                format!(
+                    "{} GiB must be between 1 and {} GiB",
+                    initial_size_gib, MAX_BSU_SIZE_GIB
+                ),
+            ));
+        }
+        if let Some(max_total_size_gib) = self.max_total_size_gib {
+            if max_total_size_gib < initial_size_gib {
+                return Err(invalid(
+                    "max-total-size-gib",
+                    format!(
+                        "{} GiB is lower than initial-size-gib ({} GiB)",
+                        max_total_size_gib, initial_size_gib
+                    ),
+                ));
+            }
+        }
+        if let Some(min_bsu_count) = self.min_bsu_count {
+            let max_bsu_count = self.max_bsu_count.unwrap_or(DEFAULT_MAX_DISKS);
+            if min_bsu_count > max_bsu_count {
+                return Err(invalid(
+                    "min-bsu-count",
+                    format!(
+                        "{} is above max-bsu-count ({})",
+                        min_bsu_count, max_bsu_count
+                    ),
+                ));
+            }
+        }
+        if let (Some(floor_size_gib), Some(max_total_size_gib)) =
+            (self.floor_size_gib, self.max_total_size_gib)
+        {
+            if floor_size_gib > max_total_size_gib {
+                return Err(invalid(
+                    "floor-size-gib",
+                    format!(
+                        "{} GiB is above max-total-size-gib ({} GiB)",
+                        floor_size_gib, max_total_size_gib
+                    ),
+                ));
+            }
+        }
+        if let Some(subregion) = &self.subregion {
+            if !is_plausible_subregion(subregion) {
+                return Err(invalid(
+                    "subregion",
+                    format!("\"{}\" is not a subregion like \"eu-west-2a\"", subregion),
+                ));
+            }
+        }
+        if let Some(schedule) = &self.snapshot_schedule {
+            if parse_schedule(schedule).is_none() {
+                return Err(invalid(
+                    "snapshot-schedule",
+                    format!("\"{}\" is not an interval like \"6h\"", schedule),
+                ));
+            }
+        }
+        // Pruning everything would delete each batch right after taking it
+        if self.snapshot_retention == Some(0) {
+            return Err(invalid(
+                "snapshot-retention",
+                "must be at least 1".to_string(),
+            ));
+        }
+        if let Some(schedule) = &self.scrub_schedule {
+            if parse_schedule(schedule).is_none() {
+                return Err(invalid(
+                    "scrub-schedule",
+                    format!("\"{}\" is not an interval like \"7d\"", schedule),
+                ));
+            }
+            if self
+                .filesystem
+                .as_ref()
+                .is_some_and(|fs| *fs != Filesystem::Btrfs)
+            {
+                return Err(invalid(
+                    "scrub-schedule",
+                    "scrub requires the btrfs filesystem".to_string(),
+                ));
+            }
+        }
+        if let Some(schedule) = &self.rebalance_schedule {
+            if parse_schedule(schedule).is_none() {
+                return Err(invalid(
+                    "rebalance-schedule",
+                    format!("\"{}\" is not an interval like \"7d\"", schedule),
+                ));
+            }
+            if self.append_only == Some(true)
+                || self
+                    .filesystem
+                    .as_ref()
+                    .is_some_and(|fs| !fs.can_shrink_online())
+            {
+                return Err(invalid(
+                    "rebalance-schedule",
+                    "rebalancing removes BSU, the drive must be able to shrink".to_string(),
+                ));
+            }
+        }
+        if self.usage_basis == Some(UsageBasis::Physical)
+            && self
+                .filesystem
+                .as_ref()
+                .is_some_and(|fs| *fs != Filesystem::Btrfs)
+        {
+            return Err(invalid(
+                "usage-basis",
+                "physical usage requires the btrfs filesystem".to_string(),
+            ));
+        }
+        if self.backend == Some(Backend::BtrfsRaw) {
+            if self
+                .filesystem
+                .as_ref()
+                .is_some_and(|fs| *fs != Filesystem::Btrfs)
+            {
+                return Err(invalid(
+                    "backend",
+                    "btrfs-raw requires the btrfs filesystem".to_string(),
+                ));
+            }
+            if self
+                .volumes
+                .as_ref()
+                .is_some_and(|volumes| !volumes.is_empty())
+            {
+                return Err(invalid(
+                    "backend",
+                    "btrfs-raw does not support additional volumes".to_string(),
+                ));
+            }
+            if self.max_fs_size_gib.is_some() {
+                return Err(invalid(
+                    "backend",
+                    "btrfs-raw does not support max-fs-size-gib".to_string(),
+                ));
+            }
+            if self.is_encrypted() {
+                return Err(invalid(
+                    "backend",
+                    "btrfs-raw does not support encryption".to_string(),
+                ));
+            }
+            if self.vg_alloc_policy.is_some() || self.vg_extent_size_mib.is_some() {
+                return Err(invalid(
+                    "backend",
+                    "btrfs-raw does not support vg-alloc-policy nor vg-extent-size-mib".to_string(),
+                ));
+            }
+        }
+        if let Some(extent_size_mib) = self.vg_extent_size_mib {
+            if !extent_size_mib.is_power_of_two() {
+                return Err(invalid(
+                    "vg-extent-size-mib",
+                    format!("{} MiB is not a power of two", extent_size_mib),
+                ));
+            }
+        }
+        if let Some(encryption) = &self.encryption {
+            if encryption.enabled && encryption.key_file.is_empty() {
+                return Err(invalid(
+                    "encryption",
+                    "key-file cannot be empty".to_string(),
+                ));
+            }
+        }
+        // Main volume gets what additional volumes leave
+        let volumes_perc: usize = self
+            .volumes
+            .iter()
+            .flatten()
+            .map(|volume| volume.size_perc)
+            .sum();
+        if self
+            .volumes
+            .iter()
+            .flatten()
+            .any(|volume| volume.size_perc == 0)
+            || volumes_perc >= 100
+        {
+            return Err(invalid(
+                "volumes",
+                "each size-perc must be positive and their sum lower than 100%".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.encryption
+            .as_ref()
+            .is_some_and(|encryption| encryption.enabled)
+    }
+
+    pub fn mount_paths(&self) -> Vec<&str> {
+        let mut mount_paths = vec![self.mount_path.as_str()];
+        for volume in self.volumes.iter().flatten() {
+            mount_paths.push(volume.mount_path.as_str());
+        }
+        mount_paths
+    }
+}
+
+// Additional filesystem of a drive, backed by its own LV in the drive's VG
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct ConfigFileVolume {
+    pub name: String,
+    pub mount_path: String,
+    pub size_perc: usize,
+    pub mount_options: Option<String>,
+}
+
+// LUKS layer below LVM, see crypt module for key management caveats
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct ConfigFileEncryption {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default)]
+    pub key_file: String,
+}
+
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum CredentialsSource {
+    #[default]
+    Static, // access key and secret key from configuration file or environment
+    InstanceProfile, // temporary credentials from the VM metadata service
+}
+
+impl FromStr for CredentialsSource {
+    type Err = ();
+    fn from_str(input: &str) -> Result<CredentialsSource, Self::Err> {
+        match input.to_lowercase().as_str() {
+            "static" => Ok(Self::Static),
+            "instance-profile" => Ok(Self::InstanceProfile),
+            _ => Err(()),
+        }
+    }
+}
+
+impl ToString for CredentialsSource {
+    fn to_string(&self) -> String {
+        match self {
+            Self::Static => "static".to_string(),
+            Self::InstanceProfile => "instance-profile".to_string(),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
 #[serde(rename_all = "kebab-case")]
 pub enum DriveTarget {
-    Online,  // normal  drive flow, drive is available
-    Offline, // unmount + detach from VM
-    Delete,  // unmount + detach from VM + delete data
+    #[default]
+    Online, // normal  drive flow, drive is available
+    Offline,  // unmount + detach from VM
+    Delete,   // unmount + detach from VM + delete data
+    Frozen,   // like online, without adding nor removing BSU
+    ReadOnly, // mounted read-only, nothing is created, formated nor resized
 }
 
 impl FromStr for DriveTarget {
@@ -141,6 +982,8 @@ impl FromStr for DriveTarget {
             "online" => Ok(DriveTarget::Online),
             "offline" => Ok(DriveTarget::Offline),
             "delete" => Ok(DriveTarget::Delete),
+            "frozen" => Ok(DriveTarget::Frozen),
+            "read-only" => Ok(DriveTarget::ReadOnly),
             _ => Err(()),
         }
     }
@@ -152,6 +995,8 @@ impl ToString for DriveTarget {
             DriveTarget::Online => String::from("online"),
             DriveTarget::Offline => String::from("offline"),
             DriveTarget::Delete => String::from("delete"),
+            DriveTarget::Frozen => String::from("frozen"),
+            DriveTarget::ReadOnly => String::from("read-only"),
         }
     }
 }
@@ -185,3 +1030,376 @@ impl ToString for DiskType {
         }
     }
 }
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum MissingPvPolicy {
+    Reattach, // try to attach again BSU belonging to the drive
+    Readonly, // partially activate VG and mount fs read-only
+    Fail,     // stop the drive
+}
+
+impl FromStr for MissingPvPolicy {
+    type Err = ();
+    fn from_str(input: &str) -> Result<MissingPvPolicy, Self::Err> {
+        match input.to_lowercase().as_str() {
+            "reattach" => Ok(Self::Reattach),
+            "readonly" => Ok(Self::Readonly),
+            "fail" => Ok(Self::Fail),
+            _ => Err(()),
+        }
+    }
+}
+
+impl ToString for MissingPvPolicy {
+    fn to_string(&self) -> String {
+        match self {
+            Self::Reattach => "reattach".to_string(),
+            Self::Readonly => "readonly".to_string(),
+            Self::Fail => "fail".to_string(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum AttachFailurePolicy {
+    Delete, // delete BSU created by bsud which cannot be attached
+    Flag,   // keep BSU but stop creating new ones
+}
+
+impl FromStr for AttachFailurePolicy {
+    type Err = ();
+    fn from_str(input: &str) -> Result<AttachFailurePolicy, Self::Err> {
+        match input.to_lowercase().as_str() {
+            "delete" => Ok(Self::Delete),
+            "flag" => Ok(Self::Flag),
+            _ => Err(()),
+        }
+    }
+}
+
+impl ToString for AttachFailurePolicy {
+    fn to_string(&self) -> String {
+        match self {
+            Self::Delete => "delete".to_string(),
+            Self::Flag => "flag".to_string(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Backend {
+    #[default]
+    Lvm, // BSU are PV of the drive's VG, filesystems live in LV
+    BtrfsRaw, // BSU are devices of a multi-device btrfs filesystem
+}
+
+impl FromStr for Backend {
+    type Err = ();
+    fn from_str(input: &str) -> Result<Backend, Self::Err> {
+        match input.to_lowercase().as_str() {
+            "lvm" => Ok(Self::Lvm),
+            "btrfs-raw" => Ok(Self::BtrfsRaw),
+            _ => Err(()),
+        }
+    }
+}
+
+impl ToString for Backend {
+    fn to_string(&self) -> String {
+        match self {
+            Self::Lvm => "lvm".to_string(),
+            Self::BtrfsRaw => "btrfs-raw".to_string(),
+        }
+    }
+}
+
+// IO scheduling class pvmove runs with while BSU are removed
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum IoNiceClass {
+    #[default]
+    None, // pvmove runs like any other process
+    BestEffort, // lowest priority of the best-effort class
+    Idle,       // only gets disk time when no other process needs it
+}
+
+impl IoNiceClass {
+    pub fn command_prefix(&self) -> Vec<&'static str> {
+        match self {
+            Self::None => Vec::new(),
+            Self::BestEffort => vec!["ionice", "-c", "2", "-n", "7"],
+            Self::Idle => vec!["ionice", "-c", "3"],
+        }
+    }
+}
+
+impl FromStr for IoNiceClass {
+    type Err = ();
+    fn from_str(input: &str) -> Result<IoNiceClass, Self::Err> {
+        match input.to_lowercase().as_str() {
+            "none" => Ok(Self::None),
+            "best-effort" => Ok(Self::BestEffort),
+            "idle" => Ok(Self::Idle),
+            _ => Err(()),
+        }
+    }
+}
+
+impl ToString for IoNiceClass {
+    fn to_string(&self) -> String {
+        match self {
+            Self::None => "none".to_string(),
+            Self::BestEffort => "best-effort".to_string(),
+            Self::Idle => "idle".to_string(),
+        }
+    }
+}
+
+// Usage the scaler decides on: statvfs figures or bytes btrfs actually stores on the devices,
+// which are lower with compression
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum UsageBasis {
+    #[default]
+    Logical,
+    Physical,
+}
+
+impl FromStr for UsageBasis {
+    type Err = ();
+    fn from_str(input: &str) -> Result<UsageBasis, Self::Err> {
+        match input.to_lowercase().as_str() {
+            "logical" => Ok(Self::Logical),
+            "physical" => Ok(Self::Physical),
+            _ => Err(()),
+        }
+    }
+}
+
+impl ToString for UsageBasis {
+    fn to_string(&self) -> String {
+        match self {
+            Self::Logical => "logical".to_string(),
+            Self::Physical => "physical".to_string(),
+        }
+    }
+}
+
+// LVM allocation policy of the drive's VG, set on VG creation
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum VgAllocPolicy {
+    #[default]
+    Normal,
+    Contiguous,
+    Cling,
+    Anywhere,
+}
+
+impl FromStr for VgAllocPolicy {
+    type Err = ();
+    fn from_str(input: &str) -> Result<VgAllocPolicy, Self::Err> {
+        match input.to_lowercase().as_str() {
+            "normal" => Ok(Self::Normal),
+            "contiguous" => Ok(Self::Contiguous),
+            "cling" => Ok(Self::Cling),
+            "anywhere" => Ok(Self::Anywhere),
+            _ => Err(()),
+        }
+    }
+}
+
+impl ToString for VgAllocPolicy {
+    fn to_string(&self) -> String {
+        match self {
+            Self::Normal => "normal".to_string(),
+            Self::Contiguous => "contiguous".to_string(),
+            Self::Cling => "cling".to_string(),
+            Self::Anywhere => "anywhere".to_string(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum GrowthStrategy {
+    #[default]
+    Adaptive, // scale the largest or smallest BSU depending on the BSU count
+    Uniform,   // same size as the largest BSU
+    Geometric, // double the largest BSU
+}
+
+impl FromStr for GrowthStrategy {
+    type Err = ();
+    fn from_str(input: &str) -> Result<GrowthStrategy, Self::Err> {
+        match input.to_lowercase().as_str() {
+            "adaptive" => Ok(Self::Adaptive),
+            "uniform" => Ok(Self::Uniform),
+            "geometric" => Ok(Self::Geometric),
+            _ => Err(()),
+        }
+    }
+}
+
+impl ToString for GrowthStrategy {
+    fn to_string(&self) -> String {
+        match self {
+            Self::Adaptive => "adaptive".to_string(),
+            Self::Uniform => "uniform".to_string(),
+            Self::Geometric => "geometric".to_string(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Filesystem {
+    #[default]
+    Btrfs,
+    Ext4,
+    Xfs,
+}
+
+impl Filesystem {
+    // ext4 can only be shrunk offline and xfs cannot be shrunk at all
+    pub fn can_shrink_online(&self) -> bool {
+        matches!(self, Self::Btrfs)
+    }
+}
+
+impl FromStr for Filesystem {
+    type Err = ();
+    fn from_str(input: &str) -> Result<Filesystem, Self::Err> {
+        match input.to_lowercase().as_str() {
+            "btrfs" => Ok(Self::Btrfs),
+            "ext4" => Ok(Self::Ext4),
+            "xfs" => Ok(Self::Xfs),
+            _ => Err(()),
+        }
+    }
+}
+
+impl ToString for Filesystem {
+    fn to_string(&self) -> String {
+        match self {
+            Self::Btrfs => "btrfs".to_string(),
+            Self::Ext4 => "ext4".to_string(),
+            Self::Xfs => "xfs".to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+    use std::fs::{create_dir_all, remove_dir_all, set_permissions, write, Permissions};
+    use std::time::Duration;
+
+    #[test]
+    fn credentials_file() {
+        assert!(!is_mode_too_open(0o100600));
+        assert!(!is_mode_too_open(0o400));
+        assert!(is_mode_too_open(0o644));
+        assert!(is_mode_too_open(0o660));
+
+        let dir = temp_dir().join("bsud-units-credentials-file");
+        create_dir_all(&dir).expect("create folder");
+        let credentials = dir.join("credentials.json");
+        write(
+            &credentials,
+            r#"{"access-key": "AK-FILE", "secret-key": "SK-FILE"}"#,
+        )
+        .expect("write credentials");
+        set_permissions(&credentials, Permissions::from_mode(0o600)).expect("chmod credentials");
+        let path = dir.join("bsud.json");
+        let config_with = |extra: &str| {
+            format!(
+                r#"{{{}"credentials-file": "{}", "drives": [{{"name": "data", "mount-path": "/mnt/data"}}]}}"#,
+                extra,
+                credentials.to_string_lossy()
+            )
+        };
+        write(&path, config_with("")).expect("write config");
+        let config = load(path.to_string_lossy().to_string()).expect("load config");
+        assert_eq!(
+            config
+                .authentication
+                .as_ref()
+                .map(|authentication| authentication.access_key.as_str()),
+            Some("AK-FILE")
+        );
+        // A too open file is only reported
+        set_permissions(&credentials, Permissions::from_mode(0o644)).expect("chmod credentials");
+        assert!(read_credentials_file(&credentials.to_string_lossy()).is_ok());
+
+        write(
+            &path,
+            config_with(r#""authentication": {"access-key": "AK", "secret-key": "SK"}, "#),
+        )
+        .expect("write config");
+        let err = load(path.to_string_lossy().to_string()).expect_err("both credentials");
+        assert!(err.to_string().contains("credentials-file"), "{}", err);
+
+        write(&credentials, r#"{"access-key": "AK-FILE"}"#).expect("write credentials");
+        let err =
+            read_credentials_file(&credentials.to_string_lossy()).expect_err("missing secret key");
+        assert!(
+            err.to_string().starts_with("invalid credentials file"),
+            "{}",
+            err
+        );
+        remove_dir_all(&dir).expect("cleanup");
+    }
+
+    #[test]
+    fn load_config_without_network() {
+        let dir = temp_dir().join("bsud-units-load-config");
+        create_dir_all(&dir).expect("create folder");
+        let path = dir.join("bsud.json");
+        write(
+            &path,
+            r#"{
+                "authentication": {"access-key": "AK", "secret-key": "SK"},
+                "drives": [{"name": "data", "mount-path": "/mnt/data"}]
+            }"#,
+        )
+        .expect("write config");
+        let config = load(path.to_string_lossy().to_string()).expect("load config");
+        assert_eq!(config.drives.len(), 1);
+        assert!(config.authentication.is_some());
+
+        set_vm_config("eu-west-2a", "i-12345678").expect("set vm config");
+        assert_eq!(region().expect("region"), "eu-west-2");
+        // Already discovered metadata is not fetched again
+        assert!(is_vm_config_discovered().expect("discovered"));
+        discover_vm_config(Duration::from_millis(1)).expect("cached vm config");
+        configure_cloud(&config).expect("configure cloud");
+        let cloud_config = CLOUD_CONFIG.read().expect("cloud config");
+        assert_eq!(
+            cloud_config.base_path,
+            "https://api.eu-west-2.outscale.com/api/v1"
+        );
+        remove_dir_all(&dir).expect("cleanup");
+    }
+
+    #[test]
+    fn api_refill_rate_lower_bound() {
+        for refill_per_s in [0.0, -1.0, 1e-300, f64::NAN] {
+            let config = Config {
+                api_refill_per_s: Some(refill_per_s),
+                ..Default::default()
+            };
+            let error = config.validate().expect_err("refill rate").to_string();
+            assert!(error.contains("invalid api-refill-per-s"), "{}", error);
+        }
+        let config = Config {
+            api_refill_per_s: Some(1.0 / 3600.0),
+            ..Default::default()
+        };
+        assert!(config.validate().is_ok());
+    }
+}
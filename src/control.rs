@@ -0,0 +1,234 @@
+use crate::config::DriveTarget;
+use crate::drive::{DriveCmd, DriveRouter, DriveStatus};
+use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs::{
+    remove_dir, remove_dir_all, remove_file, rename, set_permissions, DirBuilder, Permissions,
+};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::thread;
+use std::time;
+
+pub const DEFAULT_CONTROL_SOCKET: &str = "/run/bsud.sock";
+const STATUS_TIMEOUT_S: u64 = 5;
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ControlAction {
+    Status,
+    Online,
+    Offline,
+    Delete,
+    Frozen,
+    ReadOnly,
+    GrowTo,
+    Rebalance,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct ControlRequest {
+    pub drive: String,
+    pub action: ControlAction,
+    // Total size to reach with the "grow-to" action
+    #[serde(default)]
+    pub size_gib: Option<usize>,
+}
+
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct ControlResponse {
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<DriveStatus>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    // The drive was busy, the request is applied once it is free
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub queued: Option<bool>,
+}
+
+impl ControlResponse {
+    fn error<E: ToString>(err: E) -> Self {
+        ControlResponse {
+            ok: false,
+            error: Some(err.to_string()),
+            ..Default::default()
+        }
+    }
+}
+
+// Socket allows to delete drives, it is bound in a folder only root can enter and restricted
+// to root before being moved in place so no other user can connect in between
+fn bind_private(path: &Path) -> Result<UnixListener, Box<dyn Error>> {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let private_dir = parent.join(format!(".bsud-control-{}", std::process::id()));
+    if private_dir.exists() {
+        remove_dir_all(&private_dir)?;
+    }
+    DirBuilder::new().mode(0o700).create(&private_dir)?;
+    let private_path = private_dir.join("control.sock");
+    let bound = UnixListener::bind(&private_path).and_then(|listener| {
+        set_permissions(&private_path, Permissions::from_mode(0o600))?;
+        rename(&private_path, path)?;
+        Ok(listener)
+    });
+    if bound.is_err() {
+        let _ = remove_file(&private_path);
+    }
+    remove_dir(&private_dir)?;
+    Ok(bound?)
+}
+
+pub fn serve(path: &str, router: DriveRouter) -> Result<(), Box<dyn Error>> {
+    // Socket file may remain from a previous run
+    if Path::new(path).exists() {
+        remove_file(path)?;
+    }
+    let listener = bind_private(Path::new(path))?;
+    info!("listening for control commands on {}", path);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let router = router.clone();
+                    thread::spawn(move || {
+                        if let Err(err) = handle_client(stream, &router) {
+                            debug!("control client: {}", err);
+                        }
+                    });
+                }
+                Err(err) => error!("control connection: {}", err),
+            }
+        }
+    });
+    Ok(())
+}
+
+fn handle_client(stream: UnixStream, router: &DriveRouter) -> Result<(), Box<dyn Error>> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = handle_line(&line, router);
+        writeln!(writer, "{}", serde_json::to_string(&response)?)?;
+    }
+    Ok(())
+}
+
+pub fn handle_line(line: &str, router: &DriveRouter) -> ControlResponse {
+    let request: ControlRequest = match serde_json::from_str(line) {
+        Ok(request) => request,
+        Err(err) => return ControlResponse::error(format!("invalid request: {}", err)),
+    };
+    info!(
+        "\"{}\" drive: control request {:?}",
+        request.drive, request.action
+    );
+    let target = match request.action {
+        ControlAction::Status => {
+            let timeout = time::Duration::from_secs(STATUS_TIMEOUT_S);
+            return match router.status(&request.drive, timeout) {
+                Ok(status) => ControlResponse {
+                    ok: true,
+                    status: Some(status),
+                    ..Default::default()
+                },
+                Err(err) => ControlResponse::error(err),
+            };
+        }
+        ControlAction::Online => DriveTarget::Online,
+        ControlAction::Offline => DriveTarget::Offline,
+        ControlAction::Delete => DriveTarget::Delete,
+        ControlAction::Frozen => DriveTarget::Frozen,
+        ControlAction::ReadOnly => DriveTarget::ReadOnly,
+        ControlAction::Rebalance => {
+            return match router.send(&request.drive, DriveCmd::Rebalance) {
+                Ok(()) => ControlResponse {
+                    ok: true,
+                    ..Default::default()
+                },
+                Err(err) => ControlResponse::error(err),
+            };
+        }
+        ControlAction::GrowTo => {
+            let Some(size_gib) = request.size_gib else {
+                return ControlResponse::error("grow-to needs size_gib");
+            };
+            let timeout = time::Duration::from_secs(STATUS_TIMEOUT_S);
+            return match router.grow_to(&request.drive, size_gib, timeout) {
+                Ok(queued) => ControlResponse {
+                    ok: true,
+                    queued: Some(queued),
+                    ..Default::default()
+                },
+                Err(err) => ControlResponse::error(err),
+            };
+        }
+    };
+    match router.send(&request.drive, DriveCmd::SetTarget(target)) {
+        Ok(()) => ControlResponse {
+            ok: true,
+            ..Default::default()
+        },
+        Err(err) => ControlResponse::error(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+    use std::fs::{create_dir_all, write};
+    use std::sync::mpsc::channel;
+
+    #[test]
+    fn control_socket_commands() {
+        let router = DriveRouter::default();
+        let (sender, receiver) = channel::<DriveCmd>();
+        router.insert("data".to_string(), sender);
+
+        let response = handle_line(r#"{"drive":"data","action":"offline"}"#, &router);
+        assert!(response.ok);
+        match receiver.try_recv() {
+            Ok(DriveCmd::SetTarget(DriveTarget::Offline)) => {}
+            other => panic!("unexpected command {:?}", other),
+        };
+
+        let response = handle_line(r#"{"drive":"unknown","action":"delete"}"#, &router);
+        assert!(!response.ok);
+        let error = response.error.expect("unknown drive error");
+        assert!(
+            error.starts_with("\"unknown\" drive: unknown drive"),
+            "{}",
+            error
+        );
+
+        let response = handle_line("not json", &router);
+        assert!(!response.ok);
+    }
+
+    #[test]
+    fn control_socket_restricted_to_root() {
+        let dir = temp_dir().join("bsud-units-control");
+        let _ = remove_dir_all(&dir);
+        create_dir_all(&dir).expect("socket dir");
+        let path = dir.join("bsud.sock");
+        write(&path, "left by a previous run").expect("stale socket");
+        serve(&path.to_string_lossy(), DriveRouter::default()).expect("serve");
+        let metadata = std::fs::metadata(&path).expect("socket");
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+        // Only the socket is left next to it, the private folder it was bound in is gone
+        let entries: Vec<_> = std::fs::read_dir(&dir).expect("socket dir").collect();
+        assert_eq!(entries.len(), 1);
+        std::os::unix::net::UnixStream::connect(&path).expect("connect");
+        remove_dir_all(&dir).expect("cleanup");
+    }
+}
@@ -0,0 +1,185 @@
+use crate::config::{metadata_client, metadata_get, set_cloud_credentials};
+use crate::utils::iso8601_to_epoch_s;
+use datetime::Instant;
+use easy_error::format_err;
+use log::{debug, error, info};
+use secrecy::{ExposeSecret, SecretString};
+use serde::Deserialize;
+use std::error::Error;
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use std::thread;
+use std::time;
+
+const METADATA_CREDENTIALS_URL: &str =
+    "http://169.254.169.254/latest/meta-data/iam/security-credentials/";
+// Credentials are refreshed this long before they expire
+const REFRESH_MARGIN_S: i64 = 300;
+// Used when the metadata service does not provide any expiration
+const DEFAULT_REFRESH_INTERVAL_S: i64 = 900;
+const REFRESH_RETRY_S: i64 = 30;
+const UNKNOWN_EXPIRATION: i64 = i64::MIN;
+
+static REFRESH_ENABLED: AtomicBool = AtomicBool::new(false);
+static REFRESH_STARTED: AtomicBool = AtomicBool::new(false);
+static EXPIRATION_EPOCH_S: AtomicI64 = AtomicI64::new(UNKNOWN_EXPIRATION);
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct MetadataCredentials {
+    access_key_id: String,
+    secret_access_key: SecretString,
+    // Always empty on Outscale: the key pair alone authenticates API calls
+    token: Option<SecretString>,
+    expiration: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct InstanceCredentials {
+    pub access_key: String,
+    pub secret_key: SecretString,
+    pub expiration_epoch_s: Option<i64>,
+}
+
+fn parse_instance_credentials(json: &str) -> Result<InstanceCredentials, Box<dyn Error>> {
+    let credentials: MetadataCredentials = serde_json::from_str(json)?;
+    if credentials.access_key_id.is_empty() {
+        return Err(Box::new(format_err!(
+            "instance profile credentials do not contain any access key"
+        )));
+    }
+    // API calls are signed without session token, a key pair needing one would be rejected
+    if credentials
+        .token
+        .as_ref()
+        .is_some_and(|token| !token.expose_secret().is_empty())
+    {
+        return Err(Box::new(format_err!(
+            "instance profile credentials need a session token, which is not supported"
+        )));
+    }
+    Ok(InstanceCredentials {
+        access_key: credentials.access_key_id,
+        secret_key: credentials.secret_access_key,
+        expiration_epoch_s: credentials
+            .expiration
+            .as_deref()
+            .and_then(iso8601_to_epoch_s),
+    })
+}
+
+// The metadata service lists the role attached to the VM, then provides its credentials
+pub fn fetch_instance_credentials(
+    timeout: time::Duration,
+) -> Result<InstanceCredentials, Box<dyn Error>> {
+    let client = metadata_client(timeout)?;
+    debug!("getting instance profile from metadata");
+    let roles = metadata_get(&client, METADATA_CREDENTIALS_URL)?;
+    let Some(role) = roles.lines().map(str::trim).find(|line| !line.is_empty()) else {
+        return Err(Box::new(format_err!(
+            "no instance profile is attached to this VM"
+        )));
+    };
+    debug!("getting credentials of instance profile {}", role);
+    let url = format!("{}{}", METADATA_CREDENTIALS_URL, role);
+    parse_instance_credentials(&metadata_get(&client, &url)?)
+}
+
+fn refresh_delay(expiration_epoch_s: Option<i64>, now_epoch_s: i64) -> time::Duration {
+    let delay_s = match expiration_epoch_s {
+        None => DEFAULT_REFRESH_INTERVAL_S,
+        Some(expiration) => expiration - now_epoch_s - REFRESH_MARGIN_S,
+    };
+    time::Duration::from_secs(delay_s.max(REFRESH_RETRY_S) as u64)
+}
+
+// Applies instance profile credentials, then keeps them fresh from a background thread so
+// reconciles never wait for the metadata service.
+pub fn use_instance_profile(timeout: time::Duration) -> Result<(), Box<dyn Error>> {
+    apply(fetch_instance_credentials(timeout)?)?;
+    REFRESH_ENABLED.store(true, Ordering::SeqCst);
+    if !REFRESH_STARTED.swap(true, Ordering::SeqCst) {
+        spawn_refresh(timeout);
+    }
+    Ok(())
+}
+
+// A reload may switch back to static credentials, the refresh thread then stays idle
+pub fn use_static() {
+    REFRESH_ENABLED.store(false, Ordering::SeqCst);
+}
+
+fn apply(credentials: InstanceCredentials) -> Result<(), Box<dyn Error>> {
+    let expiration = credentials.expiration_epoch_s;
+    set_cloud_credentials(credentials.access_key, credentials.secret_key)?;
+    EXPIRATION_EPOCH_S.store(expiration.unwrap_or(UNKNOWN_EXPIRATION), Ordering::SeqCst);
+    info!(
+        "instance profile credentials set, expiring at {:?}",
+        expiration
+    );
+    Ok(())
+}
+
+fn spawn_refresh(timeout: time::Duration) {
+    thread::spawn(move || {
+        let mut delay = time::Duration::ZERO;
+        loop {
+            if delay.is_zero() {
+                let expiration = match EXPIRATION_EPOCH_S.load(Ordering::SeqCst) {
+                    UNKNOWN_EXPIRATION => None,
+                    expiration => Some(expiration),
+                };
+                delay = refresh_delay(expiration, Instant::now().seconds());
+            }
+            debug!("refreshing instance profile credentials in {:?}", delay);
+            thread::sleep(delay);
+            delay = time::Duration::ZERO;
+            if !REFRESH_ENABLED.load(Ordering::SeqCst) {
+                continue;
+            }
+            if let Err(err) = fetch_instance_credentials(timeout).and_then(apply) {
+                error!("cannot refresh instance profile credentials: {}", err);
+                delay = time::Duration::from_secs(REFRESH_RETRY_S as u64);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn instance_profile_credentials() {
+        let credentials = parse_instance_credentials(
+            r#"{
+                "Code": "Success",
+                "AccessKeyId": "AK",
+                "SecretAccessKey": "SK",
+                "Token": "",
+                "Expiration": "2024-03-01T10:20:30Z"
+            }"#,
+        )
+        .expect("parse credentials");
+        assert_eq!(credentials.access_key, "AK");
+        assert_eq!(credentials.expiration_epoch_s, Some(1709288430));
+        assert!(
+            parse_instance_credentials(r#"{"AccessKeyId": "", "SecretAccessKey": "SK"}"#).is_err()
+        );
+        assert!(parse_instance_credentials("not json").is_err());
+        // Outscale provides an empty token, a real one could not be sent along the key pair
+        assert!(
+            parse_instance_credentials(r#"{"AccessKeyId": "AK", "SecretAccessKey": "SK"}"#).is_ok()
+        );
+        let err = parse_instance_credentials(
+            r#"{"AccessKeyId": "AK", "SecretAccessKey": "SK", "Token": "session"}"#,
+        )
+        .expect_err("session token");
+        assert!(err.to_string().contains("session token"));
+
+        // Refreshed 5 minutes before expiration, never in a tight loop
+        assert_eq!(refresh_delay(Some(4000), 1000), Duration::from_secs(2700));
+        assert_eq!(refresh_delay(Some(1000), 1000), Duration::from_secs(30));
+        assert_eq!(refresh_delay(None, 1000), Duration::from_secs(900));
+    }
+}
@@ -0,0 +1,100 @@
+use crate::utils::Executor;
+use crate::utils::SystemExecutor;
+use log::debug;
+use std::error::Error;
+use std::path::Path;
+
+// Encrypted drives put a LUKS layer on each BSU and LVM runs on the mapped devices:
+// /dev/xvdb -> LUKS -> /dev/mapper/bsud-<BSU id> -> PV of the drive's VG.
+//
+// Key management is left to the user:
+// - the key file is read by cryptsetup on each open, it must be provisioned before bsud starts
+//   and only be readable by root: anyone reading it can decrypt the BSU and their snapshots.
+// - bsud never backs up nor rotates the key, losing the key file means losing the drive's data.
+//   Key slots are managed with cryptsetup (`luksAddKey`, `luksKillSlot`), bsud only uses the
+//   slot opened by the key file.
+// - snapshots keep the LUKS header: restoring them on another VM requires the same key file.
+// - the key file path can be changed on a running drive but encryption cannot be enabled nor
+//   disabled once the drive exists.
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Encryption {
+    pub key_file: String,
+}
+
+// Mapping names come from BSU ids as device paths may change between attachments
+pub fn mapping_name(bsu_id: &str) -> String {
+    format!("bsud-{}", bsu_id)
+}
+
+pub fn mapped_path(bsu_id: &str) -> String {
+    format!("/dev/mapper/{}", mapping_name(bsu_id))
+}
+
+pub fn is_open(bsu_id: &str) -> bool {
+    Path::new(&mapped_path(bsu_id)).exists()
+}
+
+pub fn is_luks(device_path: &str) -> Result<bool, Box<dyn Error>> {
+    is_luks_with(&SystemExecutor::default(), device_path)
+}
+
+pub fn is_luks_with(executor: &dyn Executor, device_path: &str) -> Result<bool, Box<dyn Error>> {
+    let ret = executor.exec_bool("cryptsetup", &["isLuks", device_path])?;
+    debug!("is {} a LUKS device ? -> {}", device_path, ret);
+    Ok(ret)
+}
+
+pub fn format_args<'a>(device_path: &'a str, key_file: &'a str) -> Vec<&'a str> {
+    vec![
+        "luksFormat",
+        "--batch-mode",
+        "--type",
+        "luks2",
+        "--key-file",
+        key_file,
+        device_path,
+    ]
+}
+
+pub fn format(device_path: &str, key_file: &str) -> Result<(), Box<dyn Error>> {
+    format_with(&SystemExecutor::default(), device_path, key_file)
+}
+
+pub fn format_with(
+    executor: &dyn Executor,
+    device_path: &str,
+    key_file: &str,
+) -> Result<(), Box<dyn Error>> {
+    executor.exec_mut("cryptsetup", &format_args(device_path, key_file))?;
+    Ok(())
+}
+
+pub fn open_args<'a>(device_path: &'a str, name: &'a str, key_file: &'a str) -> Vec<&'a str> {
+    vec!["luksOpen", "--key-file", key_file, device_path, name]
+}
+
+pub fn open(device_path: &str, bsu_id: &str, key_file: &str) -> Result<(), Box<dyn Error>> {
+    open_with(&SystemExecutor::default(), device_path, bsu_id, key_file)
+}
+
+pub fn open_with(
+    executor: &dyn Executor,
+    device_path: &str,
+    bsu_id: &str,
+    key_file: &str,
+) -> Result<(), Box<dyn Error>> {
+    let name = mapping_name(bsu_id);
+    executor.exec_mut("cryptsetup", &open_args(device_path, &name, key_file))?;
+    Ok(())
+}
+
+// The BSU can only be detached once its mapping is closed
+pub fn close(bsu_id: &str) -> Result<(), Box<dyn Error>> {
+    close_with(&SystemExecutor::default(), bsu_id)
+}
+
+pub fn close_with(executor: &dyn Executor, bsu_id: &str) -> Result<(), Box<dyn Error>> {
+    executor.exec_mut("cryptsetup", &["luksClose", &mapping_name(bsu_id)])?;
+    Ok(())
+}
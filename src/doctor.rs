@@ -0,0 +1,206 @@
+use crate::bsu::{Bsu, OutscaleVolumes};
+use crate::config::{
+    self, Backend, ConfigFileDrive, Filesystem, IoNiceClass, REGION, SUBREGION, VM_ID,
+};
+use crate::fs;
+use crate::utils::{find_binary_in, Executor, SystemExecutor};
+use std::error::Error;
+use std::fmt;
+use std::time;
+
+pub const LONG_VERSION: &str = concat!(
+    env!("CARGO_PKG_VERSION"),
+    "\ncommit: ",
+    env!("BSUD_GIT_COMMIT"),
+    "\ntarget: ",
+    env!("BSUD_BUILD_TARGET"),
+    "\nprofile: ",
+    env!("BSUD_BUILD_PROFILE")
+);
+
+// Binaries bsud runs on drives
+pub const REQUIRED_BINARIES: [&str; 6] =
+    ["lvm", "btrfs", "mount", "vgchange", "lvchange", "vgscan"];
+
+// External binaries needed to reconcile `drives`, depending on their backend and filesystem
+pub fn required_binaries(drives: &[ConfigFileDrive]) -> Vec<&'static str> {
+    let mut binaries = vec!["mount", "umount"];
+    for drive in drives {
+        let filesystem = drive.filesystem.clone().unwrap_or_default();
+        let mut needed = match drive.backend.clone().unwrap_or_default() {
+            Backend::Lvm => vec!["lvm", "vgchange", "lvchange", "vgscan"],
+            Backend::BtrfsRaw => vec!["btrfs"],
+        };
+        needed.push(fs::format_command(&filesystem, "").0);
+        needed.push(fs::grow_command(&filesystem, "", "").0);
+        // Device stats are read on btrfs filesystems
+        if filesystem == Filesystem::Btrfs {
+            needed.push("btrfs");
+        }
+        if drive.snapshot_freeze.unwrap_or(false) {
+            needed.push("fsfreeze");
+        }
+        if drive.is_encrypted() {
+            needed.push("cryptsetup");
+        }
+        if drive
+            .pvmove_ionice
+            .as_ref()
+            .is_some_and(|ionice| *ionice != IoNiceClass::None)
+        {
+            needed.push("ionice");
+        }
+        for binary in needed {
+            if !binaries.contains(&binary) {
+                binaries.push(binary);
+            }
+        }
+    }
+    binaries
+}
+
+pub fn missing_binaries<'a>(binaries: &[&'a str], path: &str) -> Vec<&'a str> {
+    binaries
+        .iter()
+        .filter(|binary| find_binary_in(binary, path).is_none())
+        .copied()
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Check {
+    pub name: String,
+    pub passed: bool,
+    // bsud cannot run when a critical check fails
+    pub critical: bool,
+    pub detail: String,
+}
+
+impl Check {
+    fn new(name: &str, critical: bool, result: Result<String, Box<dyn Error>>) -> Self {
+        let (passed, detail) = match result {
+            Ok(detail) => (true, detail),
+            Err(err) => (false, err.to_string()),
+        };
+        Check {
+            name: name.to_string(),
+            passed,
+            critical,
+            detail,
+        }
+    }
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let status = match (self.passed, self.critical) {
+            (true, _) => "PASS",
+            (false, true) => "FAIL",
+            (false, false) => "WARN",
+        };
+        write!(f, "[{}] {}", status, self.name)?;
+        if !self.detail.is_empty() {
+            write!(f, ": {}", self.detail)?;
+        }
+        Ok(())
+    }
+}
+
+pub fn exit_code(checks: &[Check]) -> i32 {
+    match checks.iter().any(|check| check.critical && !check.passed) {
+        true => 1,
+        false => 0,
+    }
+}
+
+// A binary is fine when it is in the PATH and answers `--version`
+pub fn binary_check(executor: &dyn Executor, binary: &str) -> Check {
+    let result = executor.exec_checked(binary, &["--version"]).map(|output| {
+        output
+            .stdout
+            .lines()
+            .map(str::trim)
+            .find(|line| !line.is_empty())
+            .unwrap_or_default()
+            .to_string()
+    });
+    Check::new(binary, true, result)
+}
+
+// Same as the daemon pre-flight check: LVM must be usable with bsud's permissions
+pub fn lvm_report_check(executor: &dyn Executor) -> Check {
+    let result = executor
+        .exec_checked("lvm", &["fullreport"])
+        .map(|_| "lvm fullreport can be read".to_string());
+    Check::new("lvm permissions", true, result)
+}
+
+fn metadata_check(timeout: time::Duration) -> Check {
+    let result = config::discover_vm_config(timeout).and_then(|_| {
+        Ok(format!(
+            "region {}, subregion {}, vm-id {}",
+            REGION.read()?,
+            SUBREGION.read()?,
+            VM_ID.read()?
+        ))
+    });
+    Check::new("metadata service", true, result)
+}
+
+fn api_check(config: &config::Config) -> Check {
+    let result = config::configure_cloud(config)
+        .and_then(|_| Bsu::fetch_all_drives(&OutscaleVolumes::default(), &config.tag_keys()))
+        .map(|all_bsu| format!("{} BSU managed by bsud", all_bsu.len()));
+    Check::new("outscale api", true, result)
+}
+
+// Checks everything bsud needs on this VM, each check runs even if a previous one failed
+pub fn run(config_path: &str) -> Vec<Check> {
+    let executor = SystemExecutor::default();
+    let config = config::load(config_path.to_string());
+    let mut binaries = REQUIRED_BINARIES.to_vec();
+    if let Ok(config) = &config {
+        for binary in required_binaries(&config.drives) {
+            if !binaries.contains(&binary) {
+                binaries.push(binary);
+            }
+        }
+    }
+    let mut checks: Vec<Check> = binaries
+        .iter()
+        .map(|binary| binary_check(&executor, binary))
+        .collect();
+    checks.push(lvm_report_check(&executor));
+
+    let metadata_timeout = match &config {
+        Ok(config) => config.metadata_timeout(),
+        Err(_) => time::Duration::from_secs(config::DEFAULT_METADATA_TIMEOUT_S),
+    };
+    let config = match config {
+        Ok(config) => {
+            let detail = format!("{} drive(s) in {}", config.drives.len(), config_path);
+            checks.push(Check::new("configuration", true, Ok(detail)));
+            Some(config)
+        }
+        Err(err) => {
+            checks.push(Check::new("configuration", true, Err(err)));
+            None
+        }
+    };
+
+    let metadata = metadata_check(metadata_timeout);
+    let metadata_passed = metadata.passed;
+    checks.push(metadata);
+
+    let api = match config {
+        Some(config) if metadata_passed => api_check(&config),
+        _ => Check {
+            name: "outscale api".to_string(),
+            passed: false,
+            critical: true,
+            detail: "skipped, needs a valid configuration and VM metadata".to_string(),
+        },
+    };
+    checks.push(api);
+    checks
+}
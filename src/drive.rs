@@ -1,88 +1,517 @@
-use crate::bsu::Bsu;
-use crate::config::{self, Config, ConfigFileDrive, DriveTarget, VM_ID};
+use crate::bsu::{
+    self, Bsu, BsuSpec, OutscaleVolumes, TagKeys, VolumeBackend, VolumeBudget, WaitOptions,
+};
+use crate::btrfs;
+use crate::config::{
+    self, AttachFailurePolicy, Backend, Config, ConfigFileDrive, ConfigFileVolume, DriveTarget,
+    Filesystem, GrowthStrategy, IoNiceClass, MissingPvPolicy, UsageBasis, VgAllocPolicy, SUBREGION,
+    VM_ID,
+};
+use crate::crypt::{self, Encryption};
+use crate::error::{self, BsudError, RetryPolicy};
 use crate::fs;
 use crate::lvm;
-use crate::utils::{bytes_to_gib, bytes_to_gib_rounded, gib_to_bytes};
+use crate::metrics::{self, DriveMetrics};
+use crate::notify;
+use crate::scheduler::Scheduler;
+use crate::snapshot::{self, Snapshot, DEFAULT_SNAPSHOT_RETENTION};
+use crate::state::{self, PersistedState, ReconcileError, ScrubReport, DEFAULT_STATE_DIR};
+use crate::utils::{
+    batch_with_fallback, bytes_to_gib, bytes_to_gib_rounded, format_eta_s, gib_to_bytes,
+    is_gib_close, is_run_once, Executor, SystemExecutor,
+};
 use datetime::{Duration, Instant};
 use easy_error::format_err;
 use log::info;
-use log::{debug, error};
+use log::{debug, error, warn};
+use serde::Serialize;
 use std::cmp::Ordering;
 use std::cmp::{max, min};
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::error::Error;
+use std::fmt;
 use std::path::Path;
-use std::sync::mpsc::{channel, Receiver, Sender};
-use std::thread::sleep;
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::thread::{self, sleep};
 use std::time;
 use threadpool::ThreadPool;
 
 const RECONCILE_COOLDOWN_S: u64 = 30;
-const DEFAULT_INITIAL_DISK_GIB: usize = 10;
-const DEFAULT_MAX_DISKS: usize = 10;
-const DEFAULT_MAX_USED_PERC: usize = 85;
-const DEFAULT_MIN_USED_PERC: usize = 40;
+const TRANSIENT_RETRY_S: u64 = 5;
+// Usage is checked at this interval between reconciles when a near-full threshold is set
+const NEAR_FULL_CHECK_INTERVAL_S: u64 = 5;
+const MAX_RETRY_DELAY_S: u64 = 600;
+// Drive is considered stale if no reconcile succeeded during this period
+const STALE_RECONCILE_S: i64 = RECONCILE_COOLDOWN_S as i64 * 20;
+pub const DEFAULT_INITIAL_DISK_GIB: usize = 10;
+pub const DEFAULT_MAX_DISKS: usize = 10;
+pub const DEFAULT_MAX_USED_PERC: usize = 85;
+pub const DEFAULT_MIN_USED_PERC: usize = 40;
 const DEFAULT_SCALE_FACTOR_PERC: usize = 20;
 const DEFAULT_DISK_TYPE: config::DiskType = config::DiskType::Gp2;
 // https://docs.outscale.com/api#createvolume
-const MAX_BSU_SIZE_GIB: usize = 14901;
+pub const MAX_BSU_SIZE_GIB: usize = 14901;
+const MAX_USAGE_SAMPLES: usize = 10;
+const DEFAULT_MAX_ATTACH_ATTEMPTS: usize = 5;
+const DEFAULT_ON_ATTACH_FAILURE: AttachFailurePolicy = AttachFailurePolicy::Flag;
+const DEFAULT_ON_MISSING_PV: MissingPvPolicy = MissingPvPolicy::Reattach;
+const DEFAULT_SHUTDOWN_TIMEOUT_S: u64 = 60;
+// Hysteresis and jitter are opt-in, 0 keeps the plain thresholds and an immediate start
+const DEFAULT_RESIZE_COOLDOWN_S: u64 = 0;
+const DEFAULT_RESIZE_DEAD_BAND_PERC: usize = 0;
+const DEFAULT_INITIAL_RECONCILE_JITTER_S: u64 = 0;
+const DEFAULT_TIME_TO_MAX_WARNING_S: u64 = 86400;
+// Room for btrfs metadata and chunk allocation on top of the removed BSU size
+const DEFAULT_REMOVAL_MARGIN_BYTES: usize = 1024 * 1024 * 1024;
+// A pvmove which did not progress for this long is reported instead of being waited for
+const PV_MOVE_STALL_WARNING_S: i64 = 3600;
+// A failed snapshot batch is retried after this delay (or the schedule interval if shorter)
+const SNAPSHOT_RETRY_S: u64 = 3600;
+const CLEAN_SHUTDOWN_UMOUNT_ATTEMPTS: usize = 3;
+const CLEAN_SHUTDOWN_RETRY_INTERVAL: time::Duration = time::Duration::from_secs(1);
+const DISCOVERED_DRIVES_MOUNT_ROOT: &str = "/mnt/bsud";
 
 type DriveName = String;
+// Outcome of the single reconcile pass of each drive in `--once` mode
+pub type PassResults = Arc<Mutex<BTreeMap<DriveName, Result<(), String>>>>;
+
+// Shared access to drives command channels, also used outside of the main thread
+#[derive(Debug, Default, Clone)]
+pub struct DriveRouter {
+    senders: Arc<RwLock<HashMap<DriveName, Sender<DriveCmd>>>>,
+}
+
+impl DriveRouter {
+    fn read(&self) -> RwLockReadGuard<'_, HashMap<DriveName, Sender<DriveCmd>>> {
+        self.senders
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, HashMap<DriveName, Sender<DriveCmd>>> {
+        self.senders
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub fn insert(&self, name: DriveName, sender: Sender<DriveCmd>) {
+        self.write().insert(name, sender);
+    }
+
+    pub fn remove(&self, name: &str) -> Option<Sender<DriveCmd>> {
+        self.write().remove(name)
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.read().contains_key(name)
+    }
+
+    pub fn names(&self) -> HashSet<DriveName> {
+        self.read().keys().cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.read().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.read().is_empty()
+    }
+
+    pub fn send(&self, name: &str, cmd: DriveCmd) -> Result<(), Box<dyn Error>> {
+        let Some(sender) = self.read().get(name).cloned() else {
+            return Err(Box::new(format_err!("\"{}\" drive: unknown drive", name)));
+        };
+        if sender.send(cmd).is_err() {
+            return Err(Box::new(format_err!(
+                "\"{}\" drive: drive is stopped",
+                name
+            )));
+        }
+        Ok(())
+    }
+
+    // Drives answer between two reconcile steps, a long step (e.g. pvmove) may delay the answer
+    pub fn status(
+        &self,
+        name: &str,
+        timeout: time::Duration,
+    ) -> Result<DriveStatus, Box<dyn Error>> {
+        let (sender, receiver) = channel::<DriveStatus>();
+        self.send(name, DriveCmd::Status(sender))?;
+        match receiver.recv_timeout(timeout) {
+            Ok(status) => Ok(status),
+            Err(_) => Err(Box::new(format_err!(
+                "\"{}\" drive: no status received, drive is busy",
+                name
+            ))),
+        }
+    }
+
+    // Returns true when the busy drive did not answer in time: the request stays queued and
+    // the drive applies it, or logs why it cannot, once its current step is over
+    pub fn grow_to(
+        &self,
+        name: &str,
+        size_gib: usize,
+        timeout: time::Duration,
+    ) -> Result<bool, Box<dyn Error>> {
+        let (sender, receiver) = channel::<Result<(), String>>();
+        self.send(name, DriveCmd::GrowTo(size_gib, sender))?;
+        match receiver.recv_timeout(timeout) {
+            Ok(result) => {
+                result?;
+                Ok(false)
+            }
+            Err(RecvTimeoutError::Timeout) => Ok(true),
+            Err(RecvTimeoutError::Disconnected) => Err(Box::new(format_err!(
+                "\"{}\" drive: drive stopped before answering",
+                name
+            ))),
+        }
+    }
+
+    pub fn stop_all(&self) {
+        for (name, sender) in self.read().iter() {
+            info!("asking drive {} to stop", name);
+            if sender.send(DriveCmd::Stop).is_err() {
+                debug!("drive {} already stopped", name);
+            }
+        }
+    }
+}
 
 #[derive(Debug, Default)]
 pub struct Drives {
-    drives_cmd: HashMap<DriveName, Sender<DriveCmd>>,
+    drives_cmd: DriveRouter,
+    drives_config: HashMap<DriveName, ConfigFileDrive>,
     drives_threads: ThreadPool,
+    // Drives whose thread did not return yet
+    running: Arc<Mutex<HashSet<DriveName>>>,
+    state_dir: String,
+    tag_keys: TagKeys,
+    pub shutdown_timeout: time::Duration,
+    // Caps reconcile threads, drives are then run by a scheduler
+    pub max_concurrent_drives: Option<usize>,
+    scheduler: Option<Sender<Drive>>,
+    pass_results: PassResults,
+    // Shared by all drives, caps the number of BSU of the daemon
+    volume_budget: Arc<Mutex<VolumeBudget>>,
+    dry_run: bool,
 }
 
 impl Drives {
-    pub fn run(config: Config) -> Result<Drives, Box<dyn Error>> {
-        let mut drives_cmd = HashMap::<DriveName, Sender<DriveCmd>>::new();
-        let mut drive_list = Vec::<Drive>::new();
+    pub fn new(thread_count: usize) -> Drives {
+        Drives {
+            drives_cmd: DriveRouter::default(),
+            drives_config: HashMap::new(),
+            drives_threads: ThreadPool::new(max(thread_count, 1)),
+            running: Arc::new(Mutex::new(HashSet::new())),
+            state_dir: DEFAULT_STATE_DIR.to_string(),
+            tag_keys: TagKeys::default(),
+            shutdown_timeout: time::Duration::from_secs(DEFAULT_SHUTDOWN_TIMEOUT_S),
+            max_concurrent_drives: None,
+            scheduler: None,
+            pass_results: PassResults::default(),
+            volume_budget: Arc::new(Mutex::new(VolumeBudget::default())),
+            dry_run: false,
+        }
+    }
 
-        for drive_config in config.drives {
-            let name = drive_config.name.clone();
+    // Startup can be slow (API calls during discovery), check `interrupted` between each step
+    // so a signal received during startup stops the drives already started.
+    pub fn run(
+        config: Config,
+        interrupted: &AtomicBool,
+        dry_run: bool,
+    ) -> Result<Drives, Box<dyn Error>> {
+        // Conflicting drives would corrupt each other, nothing is started
+        config.validate()?;
+        let mut drives = match config.max_concurrent_drives {
+            // Only the scheduler holds a thread of the pool
+            Some(max_concurrent_drives) => {
+                let mut drives = Drives::new(1);
+                drives.max_concurrent_drives = Some(max_concurrent_drives);
+                drives
+            }
+            None => Drives::new(config.drives.len()),
+        };
 
-            let (sender, receiver) = channel::<DriveCmd>();
-            let drive = Drive::new(drive_config, receiver);
-            drives_cmd.insert(name.clone(), sender);
-            drive_list.push(drive);
+        if config.api_refill_per_s.is_some() || config.api_burst.is_some() {
+            bsu::configure_api_limiter(
+                config
+                    .api_refill_per_s
+                    .unwrap_or(bsu::DEFAULT_API_REFILL_PER_S),
+                config.api_burst.unwrap_or(bsu::DEFAULT_API_BURST),
+            )?;
+        }
+        if let Some(max_attempts) = config.api_max_attempts {
+            bsu::configure_api_retry(max_attempts)?;
+        }
+        if let Some(device_template) = &config.device_template {
+            bsu::configure_device_template(device_template)?;
+        }
+        if let Some(state_dir) = &config.state_dir {
+            drives.state_dir = state_dir.clone();
+        }
+        drives.dry_run = dry_run;
+        drives.volume_budget = Arc::new(Mutex::new(VolumeBudget::new(config.max_account_bsu)));
+        drives.tag_keys = config.tag_keys();
+        if let Some(shutdown_timeout_s) = config.shutdown_timeout_s {
+            drives.shutdown_timeout = time::Duration::from_secs(shutdown_timeout_s);
+        }
+        let count = config.drives.len();
+        for (index, drive_config) in config.drives.into_iter().enumerate() {
+            drives.interrupt_check(interrupted)?;
+            drives.start_configured_drive(drive_config, index, count);
         }
 
-        for (sender, drive) in Drives::discover_local_drives()? {
-            let name: String = drive.name.clone();
-            if drives_cmd.get(&name).is_some() {
+        drives.interrupt_check(interrupted)?;
+        let known_drives: HashSet<DriveName> = drives.drives_cmd.names();
+        let discovery = match Drives::discover_local_drives(&known_drives, &drives.tag_keys) {
+            Ok(discovery) => discovery,
+            Err(err) => {
+                drives.stop()?;
+                return Err(err);
+            }
+        };
+        for (sender, mut drive) in discovery {
+            drives.interrupt_check(interrupted)?;
+            if drives.drives_cmd.contains(&drive.name) {
                 continue;
             }
-            drives_cmd.insert(name.clone(), sender);
-            drive_list.push(drive);
+            info!(
+                "\"{}\" drive: discovered drive not present in configuration",
+                drive.name
+            );
+            drive.state_dir = drives.state_dir.clone();
+            drive.tag_keys = drives.tag_keys.clone();
+            let drive = drive.with_dry_run(drives.dry_run);
+            drives.start_drive(sender, drive);
         }
+        Ok(drives)
+    }
 
-        let drives_threads = ThreadPool::new(drive_list.len());
-        for mut drive in drive_list {
-            drives_threads.execute(move || drive.run());
+    // `index` among the `count` drives started together spreads their first reconciles
+    fn start_configured_drive(
+        &mut self,
+        drive_config: ConfigFileDrive,
+        index: usize,
+        count: usize,
+    ) {
+        let (sender, receiver) = channel::<DriveCmd>();
+        let mut drive = Drive::new(drive_config.clone(), receiver).with_dry_run(self.dry_run);
+        drive.delay_first_reconcile(initial_reconcile_delay_s(
+            index,
+            count,
+            drive.initial_reconcile_jitter_s,
+        ));
+        drive.state_dir = self.state_dir.clone();
+        drive.tag_keys = self.tag_keys.clone();
+        self.drives_config
+            .insert(drive_config.name.clone(), drive_config);
+        self.start_drive(sender, drive);
+    }
+
+    // Apply a freshly loaded configuration to running drives
+    pub fn reload(&mut self, config: Config) -> Result<(), Box<dyn Error>> {
+        let running: HashSet<DriveName> = self.drives_cmd.names();
+        let diff = diff_configs(&self.drives_config, &config.drives, &running);
+        self.lock_volume_budget().max_bsu = config.max_account_bsu;
+        for name in diff.removed {
+            info!("\"{}\" drive: removed from configuration, stopping", name);
+            // its BSU still exist and keep counting in the budget
+            self.drives_config.remove(&name);
+            if let Some(sender) = self.drives_cmd.remove(&name) {
+                if sender.send(DriveCmd::Stop).is_err() {
+                    debug!("drive {} already stopped", name);
+                }
+            }
         }
+        for drive_config in diff.changed {
+            info!(
+                "\"{}\" drive: configuration changed, reconfiguring",
+                drive_config.name
+            );
+            self.drives_cmd.send(
+                &drive_config.name,
+                DriveCmd::Reconfigure(Box::new(drive_config.clone())),
+            )?;
+            self.drives_config
+                .insert(drive_config.name.clone(), drive_config);
+        }
+        let count = diff.added.len();
+        for (index, drive_config) in diff.added.into_iter().enumerate() {
+            info!(
+                "\"{}\" drive: added to configuration, starting",
+                drive_config.name
+            );
+            self.start_configured_drive(drive_config, index, count);
+        }
+        Ok(())
+    }
 
-        Ok(Drives {
-            drives_cmd,
-            drives_threads,
-        })
+    pub fn start_drive(&mut self, sender: Sender<DriveCmd>, mut drive: Drive) {
+        self.drives_cmd.insert(drive.name.clone(), sender);
+        drive.pass_results = self.pass_results.clone();
+        drive.volume_budget = self.volume_budget.clone();
+        if let Some(max_concurrent_drives) = self.max_concurrent_drives {
+            self.schedule_drive(drive, max_concurrent_drives);
+            return;
+        }
+        let thread_count = max(self.drives_threads.max_count(), self.drives_cmd.len());
+        self.drives_threads.set_num_threads(thread_count);
+        let name = drive.name.clone();
+        self.execute(name, move || drive.run());
     }
 
-    pub fn stop(&mut self) -> Result<(), Box<dyn Error>> {
-        for (name, sender) in self.drives_cmd.iter() {
-            info!("asking drive {} to stop", name);
-            sender.send(DriveCmd::Stop)?;
+    pub fn execute<F: FnOnce() + Send + 'static>(&mut self, name: DriveName, job: F) {
+        self.lock_running().insert(name.clone());
+        let running = self.running.clone();
+        self.drives_threads.execute(move || {
+            job();
+            running
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .remove(&name);
+        });
+    }
+
+    fn schedule_drive(&mut self, drive: Drive, max_concurrent_drives: usize) {
+        self.lock_running().insert(drive.name.clone());
+        // The scheduler stops once its sender is dropped and all its drives stopped
+        let drive = match self.scheduler.as_ref() {
+            Some(scheduler) => match scheduler.send(drive) {
+                Ok(()) => return,
+                Err(err) => err.0,
+            },
+            None => drive,
+        };
+        let (scheduler_sender, scheduler_receiver) = channel::<Drive>();
+        scheduler_sender.send(drive).ok();
+        self.scheduler = Some(scheduler_sender);
+        let running = self.running.clone();
+        self.drives_threads.execute(move || {
+            Scheduler::new(scheduler_receiver, max_concurrent_drives, running).run();
+        });
+    }
+
+    fn lock_running(&self) -> MutexGuard<'_, HashSet<DriveName>> {
+        self.running
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn lock_volume_budget(&self) -> MutexGuard<'_, VolumeBudget> {
+        self.volume_budget
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub fn interrupt_check(&mut self, interrupted: &AtomicBool) -> Result<(), Box<dyn Error>> {
+        if !interrupted.load(AtomicOrdering::SeqCst) {
+            return Ok(());
         }
-        info!("waiting for drives to stop");
+        warn!("startup interrupted, stopping already started drives");
+        self.stop()?;
+        Err(Box::new(format_err!("startup interrupted")))
+    }
+
+    pub fn set_target(&self, name: &str, target: DriveTarget) -> Result<(), Box<dyn Error>> {
+        self.drives_cmd.send(name, DriveCmd::SetTarget(target))
+    }
+
+    pub fn router(&self) -> DriveRouter {
+        self.drives_cmd.clone()
+    }
+
+    // Only returns once all drives stopped by themselves (e.g. in dry run)
+    pub fn wait(&mut self) {
+        self.scheduler = None;
         self.drives_threads.join();
-        info!("all drives stopped");
-        Ok(())
     }
 
-    pub fn discover_local_drives() -> Result<DriveDiscovery, Box<dyn Error>> {
-        // TODO
-        Ok(vec![])
+    pub fn is_idle(&self) -> bool {
+        self.lock_running().is_empty()
+    }
+
+    // Drives which stopped before the end of their pass are reported as failed
+    pub fn pass_results(&self) -> BTreeMap<DriveName, Result<(), String>> {
+        let mut results = self
+            .pass_results
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone();
+        for name in self.drives_cmd.names() {
+            results
+                .entry(name)
+                .or_insert_with(|| Err("reconcile pass did not complete".to_string()));
+        }
+        results
+    }
+
+    // A drive stuck in a long step must not prevent bsud from exiting: drives still running
+    // after `shutdown_timeout` are reported and left behind.
+    pub fn stop(&mut self) -> Result<(), Box<dyn Error>> {
+        self.drives_cmd.stop_all();
+        self.scheduler = None;
+        info!("waiting for drives to stop");
+        let (joined_sender, joined_receiver) = channel::<()>();
+        let drives_threads = self.drives_threads.clone();
+        thread::spawn(move || {
+            drives_threads.join();
+            joined_sender.send(()).ok();
+        });
+        if joined_receiver.recv_timeout(self.shutdown_timeout).is_ok() {
+            info!("all drives stopped");
+            return Ok(());
+        }
+        let mut running: Vec<DriveName> = self.lock_running().iter().cloned().collect();
+        running.sort();
+        Err(Box::new(format_err!(
+            "drives {:?} did not stop within {}s",
+            running,
+            self.shutdown_timeout.as_secs()
+        )))
+    }
+
+    pub fn discover_local_drives(
+        known_drives: &HashSet<DriveName>,
+        tag_keys: &TagKeys,
+    ) -> Result<DriveDiscovery, Box<dyn Error>> {
+        let vm_id: String = VM_ID.try_read()?.clone();
+        let all_bsu = Bsu::fetch_vm_drives(&OutscaleVolumes::default(), tag_keys, &vm_id)?;
+        Ok(Drives::drives_from_bsu(all_bsu, known_drives))
+    }
+
+    pub fn drives_from_bsu(all_bsu: Vec<Bsu>, known_drives: &HashSet<DriveName>) -> DriveDiscovery {
+        let mut drives_bsu = HashMap::<DriveName, Vec<Bsu>>::new();
+        for bsu in all_bsu {
+            if known_drives.contains(&bsu.drive_name) {
+                continue;
+            }
+            drives_bsu
+                .entry(bsu.drive_name.clone())
+                .or_default()
+                .push(bsu);
+        }
+        let mut discovery = DriveDiscovery::new();
+        for (name, bsu) in drives_bsu {
+            // Discovered drives are kept offline until explicitly promoted
+            let config = ConfigFileDrive {
+                name: name.clone(),
+                target: DriveTarget::Offline,
+                mount_path: format!("{}/{}", DISCOVERED_DRIVES_MOUNT_ROOT, name),
+                ..Default::default()
+            };
+            let (sender, receiver) = channel::<DriveCmd>();
+            let mut drive = Drive::new(config, receiver);
+            drive.all_bsu = bsu;
+            discovery.push((sender, drive));
+        }
+        discovery
     }
 }
 
@@ -92,39 +521,226 @@ type DevicePath = String;
 #[derive(Debug)]
 pub enum DriveCmd {
     Stop,
+    SetTarget(DriveTarget),
+    Reconfigure(Box<ConfigFileDrive>),
+    Status(Sender<DriveStatus>),
+    // Provision BSU up to this total size now, the answer tells if the size is reachable
+    GrowTo(usize, Sender<Result<(), String>>),
+    Rebalance,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DriveStatus {
+    pub name: String,
+    pub target: DriveTarget,
+    pub bsu_count: usize,
+    pub total_size_bytes: usize,
+    pub fs_used_bytes: usize,
+    pub fs_size_bytes: usize,
+    // None when the drive is not growing
+    pub seconds_to_full: Option<u64>,
+    // None when the drive has no max total size or is not growing
+    pub seconds_to_max: Option<u64>,
+    pub last_reconcile_epoch_s: Option<i64>,
+    pub last_success_epoch_s: Option<i64>,
+    pub last_error: Option<ReconcileError>,
+    pub last_scrub: Option<ScrubReport>,
+    // BSU tagged for the drive which are not part of its vg
+    pub orphan_bsu: Vec<String>,
+    // btrfs error counters of each device, empty for other filesystems
+    pub device_stats: Vec<fs::DeviceStat>,
+    // Low on space at max-bsu-count while no BSU can be removed
+    pub growth_blocked: bool,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct ConfigDiff {
+    pub added: Vec<ConfigFileDrive>,
+    pub removed: Vec<DriveName>,
+    pub changed: Vec<ConfigFileDrive>,
+}
+
+// `running` also contains discovered drives which are not part of the configuration:
+// they are reconfigured (not started again) when they appear in the configuration.
+pub fn diff_configs(
+    current: &HashMap<DriveName, ConfigFileDrive>,
+    new: &[ConfigFileDrive],
+    running: &HashSet<DriveName>,
+) -> ConfigDiff {
+    let mut diff = ConfigDiff::default();
+    let new_names: HashSet<&DriveName> = new.iter().map(|drive| &drive.name).collect();
+    for name in current.keys() {
+        if !new_names.contains(name) {
+            diff.removed.push(name.clone());
+        }
+    }
+    diff.removed.sort();
+    for drive in new {
+        match current.get(&drive.name) {
+            Some(current_drive) if current_drive == drive => {}
+            Some(_) => diff.changed.push(drive.clone()),
+            None if running.contains(&drive.name) => diff.changed.push(drive.clone()),
+            None => diff.added.push(drive.clone()),
+        };
+    }
+    diff
 }
 
 #[derive(Debug)]
 pub struct Drive {
-    last_reconcile: Instant,
+    pub last_reconcile: Instant,
+    // Upper bound of the delay of the first reconcile, the delay is set by `Drives`
+    pub initial_reconcile_jitter_s: u64,
+    pub initial_reconcile_delay_s: u64,
     all_bsu: Vec<Bsu>,
     drive_cmd: Receiver<DriveCmd>,
     exit: bool,
     pv_to_be_initialized: Vec<DevicePath>,
     pv_to_add_to_vg: Vec<DevicePath>,
+    btrfs_devices_to_add: Vec<DevicePath>,
+    pv_move_progress: Option<lvm::PvMoveProgress>,
+    retry_policy: Option<RetryPolicy>,
+    consecutive_failures: u32,
+    usage_samples: VecDeque<UsageSample>,
+    created_bsu: HashSet<String>,
+    attach_failures: HashMap<String, usize>,
+    pub seconds_to_full: f64,
+    pub seconds_to_max: f64,
+    pub time_to_max_warning_s: u64,
+    pub pvmove_ionice: IoNiceClass,
+    pub creation_blocked: bool,
+    // Set by the last scale decision: the drive is low on space at `max-bsu-count` and cannot
+    // remove a BSU to make room for a larger one
+    pub growth_blocked: bool,
+    pub usage_source: UsageSource,
+    pub state_dir: String,
+    pub tag_keys: TagKeys,
+    pub persisted: PersistedState,
+    pub ready: bool,
+    vg_adopted: bool,
+    pub orphan_bsu: Vec<String>,
+    pub device_stats: Vec<fs::DeviceStat>,
     pub name: String,
     pub target: DriveTarget,
     pub mount_path: String,
     pub disk_type: config::DiskType,
     pub disk_iops_per_gib: Option<usize>,
+    // Subregion of created BSU, the VM's one when unset
+    pub subregion: Option<String>,
     pub max_total_size_gib: Option<usize>,
+    // Shrinking never goes below these, whatever the usage
+    pub min_bsu_count: usize,
+    pub floor_size_gib: Option<usize>,
+    // Total size requested by a grow-to command, provisioned at the next reconcile
+    pub grow_to_gib: Option<usize>,
     pub initial_size_gib: usize,
     pub max_bsu_count: usize,
     pub max_used_space_perc: f32,
     pub min_used_space_perc: f32,
     pub disk_scale_factor_perc: f32,
+    pub max_attach_attempts: usize,
+    pub on_attach_failure: AttachFailurePolicy,
+    pub remove_mount_path_on_delete: bool,
+    pub on_missing_pv: MissingPvPolicy,
+    pub append_only: bool,
+    pub max_fs_size_gib: Option<usize>,
+    pub wait_options: WaitOptions,
+    pub trace_reconcile: bool,
+    pub trace: ReconcileTrace,
+    pub fs_used_bytes: usize,
+    pub fs_size_bytes: usize,
+    pending_config: Option<ConfigFileDrive>,
+    pub filesystem: Filesystem,
+    pub mount_options: Option<String>,
+    pub manage_fstab: bool,
+    pub last_reconcile_epoch_s: Option<i64>,
+    pub dry_run: bool,
+    // Stop after the first reconcile pass, see `Drives::pass_results`
+    pub run_once: bool,
+    pub pass_results: PassResults,
+    pub volumes: Vec<Volume>,
+    pub snapshot_interval_s: Option<u64>,
+    pub snapshot_retention: usize,
+    pub snapshot_freeze: bool,
+    // Last failed snapshot batch, not persisted: a restart may retry right away
+    last_snapshot_failure_epoch_s: Option<i64>,
+    pub scrub_interval_s: Option<u64>,
+    pub rebalance_interval_s: Option<u64>,
+    // Set by the rebalance command until no BSU can be removed anymore
+    pub rebalance_requested: bool,
+    pub disk_by_id_dir: String,
+    pub restore_from_snapshot: Option<String>,
+    restored_bsu: HashSet<String>,
+    pub clean_shutdown: bool,
+    pub resize_cooldown_s: u64,
+    pub resize_dead_band_perc: f32,
+    pub last_resize: Option<ResizeEvent>,
+    pub growth_strategy: GrowthStrategy,
+    pub min_creation_interval_s: u64,
+    pub last_creation_epoch_s: Option<i64>,
+    // Above it, the drive grows right away instead of waiting for its next reconcile
+    pub emergency_used_space_perc: Option<f32>,
+    // The last reconcile ended near full (e.g. max size reached): no fast path until usage
+    // goes below the threshold
+    pub near_full_seen: bool,
+    pub last_near_full_check_epoch_s: i64,
+    pub backend: Backend,
+    pub migrate_disk_type: bool,
+    pub adopt_unmanaged_bsu: bool,
+    pub encryption: Option<Encryption>,
+    luks_to_open: Vec<Bsu>,
+    pub vg_alloc_policy: VgAllocPolicy,
+    pub vg_extent_size_mib: Option<usize>,
+    pub min_free_bytes: Option<usize>,
+    pub max_free_bytes: Option<usize>,
+    pub removal_margin_bytes: usize,
+    pub usage_basis: UsageBasis,
+    // Runs all LVM, btrfs and filesystem commands, replaced by a mock in tests
+    pub executor: Arc<dyn Executor>,
+    // Volume calls to the Outscale API, replaced by in-memory volumes in tests
+    pub volume_backend: Arc<dyn VolumeBackend>,
+    // Shared with the other drives of the daemon, see `max-account-bsu`
+    pub volume_budget: Arc<Mutex<VolumeBudget>>,
 }
 
 impl Drive {
     pub fn new(config: ConfigFileDrive, drive_cmd: Receiver<DriveCmd>) -> Self {
         Drive {
+            // The first reconcile happens right away, see `delay_first_reconcile`
             last_reconcile: Instant::now() - Duration::of(RECONCILE_COOLDOWN_S as i64),
+            initial_reconcile_jitter_s: config
+                .initial_reconcile_jitter_s
+                .unwrap_or(DEFAULT_INITIAL_RECONCILE_JITTER_S),
+            initial_reconcile_delay_s: 0,
             all_bsu: Vec::default(),
             drive_cmd,
             exit: false,
             pv_to_be_initialized: Vec::new(),
             pv_to_add_to_vg: Vec::new(),
-            name: config.name,
+            btrfs_devices_to_add: Vec::new(),
+            pv_move_progress: None,
+            retry_policy: None,
+            consecutive_failures: 0,
+            usage_samples: VecDeque::with_capacity(MAX_USAGE_SAMPLES),
+            created_bsu: HashSet::new(),
+            attach_failures: HashMap::new(),
+            seconds_to_full: f64::INFINITY,
+            seconds_to_max: f64::INFINITY,
+            time_to_max_warning_s: config
+                .time_to_max_warning_s
+                .unwrap_or(DEFAULT_TIME_TO_MAX_WARNING_S),
+            pvmove_ionice: config.pvmove_ionice.unwrap_or_default(),
+            creation_blocked: false,
+            growth_blocked: false,
+            usage_source: UsageSource::Fs,
+            state_dir: DEFAULT_STATE_DIR.to_string(),
+            tag_keys: TagKeys::default(),
+            persisted: PersistedState::default(),
+            ready: false,
+            vg_adopted: false,
+            orphan_bsu: Vec::new(),
+            device_stats: Vec::new(),
+            name: config.name.clone(),
             target: config.target,
             mount_path: config.mount_path,
             disk_type: config.disk_type.unwrap_or(DEFAULT_DISK_TYPE),
@@ -139,230 +755,1738 @@ impl Drive {
                 .unwrap_or(DEFAULT_SCALE_FACTOR_PERC) as f32
                 / 100.0,
             disk_iops_per_gib: config.disk_iops_per_gib,
+            subregion: config.subregion,
             max_total_size_gib: config.max_total_size_gib,
+            min_bsu_count: config.min_bsu_count.unwrap_or(1),
+            floor_size_gib: config.floor_size_gib,
+            grow_to_gib: None,
+            max_attach_attempts: config
+                .max_attach_attempts
+                .unwrap_or(DEFAULT_MAX_ATTACH_ATTEMPTS),
+            on_attach_failure: config
+                .on_attach_failure
+                .unwrap_or(DEFAULT_ON_ATTACH_FAILURE),
+            remove_mount_path_on_delete: config.remove_mount_path_on_delete.unwrap_or(false),
+            on_missing_pv: config.on_missing_pv.unwrap_or(DEFAULT_ON_MISSING_PV),
+            append_only: config.append_only.unwrap_or(false),
+            max_fs_size_gib: config.max_fs_size_gib,
+            wait_options: WaitOptions {
+                timeout: time::Duration::from_secs(
+                    config.wait_timeout_s.unwrap_or(bsu::DEFAULT_WAIT_TIMEOUT_S),
+                ),
+                ..Default::default()
+            },
+            trace_reconcile: config.trace_reconcile.unwrap_or(false),
+            trace: ReconcileTrace::default(),
+            fs_used_bytes: 0,
+            fs_size_bytes: 0,
+            pending_config: None,
+            filesystem: config.filesystem.unwrap_or_default(),
+            mount_options: config
+                .mount_options
+                .map(|options| options.trim().to_string())
+                .filter(|options| !options.is_empty()),
+            manage_fstab: config.manage_fstab.unwrap_or(false),
+            last_reconcile_epoch_s: None,
+            dry_run: false,
+            run_once: is_run_once(),
+            pass_results: PassResults::default(),
+            volumes: config
+                .volumes
+                .unwrap_or_default()
+                .into_iter()
+                .map(Volume::from)
+                .collect(),
+            snapshot_interval_s: config.snapshot_schedule.as_deref().and_then(|schedule| {
+                let interval_s = snapshot::parse_schedule(schedule);
+                if interval_s.is_none() {
+                    error!(
+                        "\"{}\" drive: invalid snapshot schedule \"{}\", snapshots disabled",
+                        config.name, schedule
+                    );
+                }
+                interval_s
+            }),
+            snapshot_retention: config
+                .snapshot_retention
+                .unwrap_or(DEFAULT_SNAPSHOT_RETENTION),
+            snapshot_freeze: config.snapshot_freeze.unwrap_or(false),
+            last_snapshot_failure_epoch_s: None,
+            scrub_interval_s: config.scrub_schedule.as_deref().and_then(|schedule| {
+                let interval_s = snapshot::parse_schedule(schedule);
+                if interval_s.is_none() {
+                    error!(
+                        "\"{}\" drive: invalid scrub schedule \"{}\", scrub disabled",
+                        config.name, schedule
+                    );
+                }
+                interval_s
+            }),
+            rebalance_interval_s: config.rebalance_schedule.as_deref().and_then(|schedule| {
+                let interval_s = snapshot::parse_schedule(schedule);
+                if interval_s.is_none() {
+                    error!(
+                        "\"{}\" drive: invalid rebalance schedule \"{}\", rebalance disabled",
+                        config.name, schedule
+                    );
+                }
+                interval_s
+            }),
+            rebalance_requested: false,
+            disk_by_id_dir: fs::DISK_BY_ID_DIR.to_string(),
+            restore_from_snapshot: config.restore_from_snapshot,
+            restored_bsu: HashSet::new(),
+            clean_shutdown: config.clean_shutdown.unwrap_or(false),
+            resize_cooldown_s: config
+                .resize_cooldown_s
+                .unwrap_or(DEFAULT_RESIZE_COOLDOWN_S),
+            resize_dead_band_perc: config
+                .resize_dead_band_perc
+                .unwrap_or(DEFAULT_RESIZE_DEAD_BAND_PERC) as f32
+                / 100.0,
+            last_resize: None,
+            growth_strategy: config.growth_strategy.unwrap_or_default(),
+            min_creation_interval_s: config.min_creation_interval_s.unwrap_or(0),
+            last_creation_epoch_s: None,
+            emergency_used_space_perc: config
+                .emergency_used_space_perc
+                .map(|perc| perc as f32 / 100.0),
+            near_full_seen: false,
+            last_near_full_check_epoch_s: 0,
+            backend: config.backend.unwrap_or_default(),
+            migrate_disk_type: config.migrate_disk_type.unwrap_or(false),
+            adopt_unmanaged_bsu: config.adopt_unmanaged_bsu.unwrap_or(false),
+            encryption: config
+                .encryption
+                .filter(|encryption| encryption.enabled)
+                .map(|encryption| Encryption {
+                    key_file: encryption.key_file,
+                }),
+            luks_to_open: Vec::new(),
+            vg_alloc_policy: config.vg_alloc_policy.unwrap_or_default(),
+            vg_extent_size_mib: config.vg_extent_size_mib,
+            min_free_bytes: config.min_free_bytes,
+            max_free_bytes: config.max_free_bytes,
+            removal_margin_bytes: config
+                .removal_margin_bytes
+                .unwrap_or(DEFAULT_REMOVAL_MARGIN_BYTES),
+            usage_basis: config.usage_basis.unwrap_or_default(),
+            executor: Arc::new(SystemExecutor::default()),
+            volume_backend: Arc::new(OutscaleVolumes::default()),
+            volume_budget: Arc::new(Mutex::new(VolumeBudget::default())),
         }
     }
 
+    pub fn with_executor(mut self, executor: Arc<dyn Executor>) -> Self {
+        self.executor = executor;
+        self
+    }
+
+    pub fn with_volume_backend(mut self, volume_backend: Arc<dyn VolumeBackend>) -> Self {
+        self.volume_backend = volume_backend;
+        self
+    }
+
+    // Commands and API calls changing anything are only logged
+    pub fn with_dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self.with_executor(Arc::new(SystemExecutor { dry_run }))
+            .with_volume_backend(Arc::new(OutscaleVolumes { dry_run }))
+    }
+
+    pub fn delay_first_reconcile(&mut self, delay_s: u64) {
+        self.initial_reconcile_delay_s = delay_s;
+        self.last_reconcile =
+            Instant::now() - Duration::of(RECONCILE_COOLDOWN_S.saturating_sub(delay_s) as i64);
+    }
+
     pub fn run(&mut self) {
+        self.start();
         loop {
-            if Instant::now().seconds() - self.last_reconcile.seconds()
-                <= RECONCILE_COOLDOWN_S as i64
-            {
-                sleep(time::Duration::from_millis(10));
-                if self.early_exit().is_err() {
-                    break;
-                }
-                continue;
+            if self.early_exit().is_err() {
+                break;
             }
-            if let Err(err) = self.reconcile() {
-                error!("\"{}\" drive: {}", self.name, err);
-            } else {
-                info!("\"{}\" drive: reconcile loop over with success", self.name);
+            if !self.run_once && self.wait_cooldown().is_err() {
+                break;
             }
-            self.last_reconcile = Instant::now();
-            if self.exit {
+            if !self.run_pass() {
                 break;
             }
         }
-        info!("\"{}\" drive: stopped", self.name);
+        self.finish();
     }
 
-    pub fn early_exit(&mut self) -> Result<(), Box<dyn Error>> {
-        if let Ok(cmd) = self.drive_cmd.try_recv() {
-            info!("\"{}\" drive received {:?} command", self.name, cmd);
-            match cmd {
-                DriveCmd::Stop => {
-                    self.exit = true;
-                    return Err(Box::new(format_err!(
-                        "\"{}\" drive: early exit due to drive stop",
-                        self.name
-                    )));
-                }
+    pub fn start(&mut self) {
+        self.load_persisted_state();
+        notify::register_drive(&self.name);
+    }
+
+    // One reconcile, returns false once the drive must stop
+    pub fn run_pass(&mut self) -> bool {
+        let result = self.reconcile();
+        self.last_reconcile_epoch_s = Some(Instant::now().seconds());
+        if self.dry_run {
+            match result {
+                Ok(()) => info!("\"{}\" drive: dry run: nothing to do", self.name),
+                Err(err) => info!("\"{}\" drive: {}", self.name, err),
             };
+            return false;
         }
-        Ok(())
+        self.record_reconcile(&result, Instant::now().seconds());
+        self.last_reconcile = Instant::now();
+        if self.run_once {
+            self.record_pass(&result);
+            return false;
+        }
+        !self.exit
     }
 
-    pub fn reconcile(&mut self) -> Result<(), Box<dyn Error>> {
-        info!(
-            "\"{}\" drive: entering {:?} drive target",
-            self.name, self.target
-        );
-        info!(
-            "\"{}\" drive: start reconcile {}",
-            self.name,
-            self.target.to_string()
-        );
-        match self.target {
-            DriveTarget::Online => self.reconcile_online(),
-            DriveTarget::Offline => self.reconcile_offline(),
-            DriveTarget::Delete => self.reconcile_delete(),
-        }
+    pub fn record_pass(&self, result: &Result<(), Box<dyn Error>>) {
+        let result = result.as_ref().map(|_| ()).map_err(|err| err.to_string());
+        self.pass_results
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .insert(self.name.clone(), result);
     }
 
-    pub fn reconcile_offline(&mut self) -> Result<(), Box<dyn Error>> {
-        self.early_exit()?;
-        while self.is_fs_mounted()? {
-            self.early_exit()?;
-            self.fs_umount()?;
+    pub fn is_due(&mut self) -> bool {
+        let elapsed_s = Instant::now().seconds() - self.last_reconcile.seconds();
+        self.check_near_full()
+            || self.grow_to_gib.is_some()
+            || self.rebalance_requested
+            || cooldown_remaining(elapsed_s, self.reconcile_delay_s()).is_none()
+    }
+
+    // Between reconciles, true when usage just crossed the emergency threshold
+    pub fn check_near_full(&mut self) -> bool {
+        let Some(threshold) = self.emergency_used_space_perc else {
+            return false;
+        };
+        let now_epoch_s = Instant::now().seconds();
+        if !self.can_scale()
+            || now_epoch_s - self.last_near_full_check_epoch_s < NEAR_FULL_CHECK_INTERVAL_S as i64
+        {
+            return false;
+        }
+        self.last_near_full_check_epoch_s = now_epoch_s;
+        let state = match self.gather_state() {
+            Ok(state) => state,
+            Err(err) => {
+                debug!("\"{}\" drive: cannot check usage: {}", self.name, err);
+                return false;
+            }
+        };
+        let near_full = is_near_full(&state, threshold);
+        if !near_full {
+            self.near_full_seen = false;
+            return false;
+        }
+        if self.near_full_seen {
+            return false;
         }
+        warn!(
+            "\"{}\" drive: {}% used, above {}%, reconciling now",
+            self.name,
+            (state.used_perc() * 100.0).round(),
+            (threshold * 100.0).round()
+        );
+        true
+    }
 
-        self.disable_lv().ok();
-        self.disable_vg().ok();
+    pub fn reconcile_delay_s(&self) -> u64 {
+        retry_delay_s(self.retry_policy, self.consecutive_failures)
+    }
 
-        self.early_exit()?;
-        self.fetch_all_drive_bsu()?;
-        if self.bsu_count() == 0 {
-            return Ok(());
+    pub fn finish(&mut self) {
+        let mounted_target = matches!(
+            self.target,
+            DriveTarget::Online | DriveTarget::Frozen | DriveTarget::ReadOnly
+        );
+        if self.exit && self.clean_shutdown && mounted_target && !self.dry_run {
+            self.shutdown_cleanly();
         }
+        notify::forget_drive(&self.name);
+        info!("\"{}\" drive: stopped", self.name);
+    }
 
-        while self.are_bsu_attached()? {
-            self.early_exit()?;
-            self.bsu_detach_all_from_this_vm()?;
-            self.early_exit()?;
-            self.fetch_all_drive_bsu()?;
-            self.early_exit()?;
+    // Effective settings, defaults included
+    pub fn settings(&self) -> String {
+        let perc = |ratio: f32| (ratio * 100.0).round() as usize;
+        let or_none = |value: Option<String>| value.unwrap_or_else(|| "none".to_string());
+        let mut lines = vec![
+            format!(
+                "\"{}\" drive (target: {})",
+                self.name,
+                self.target.to_string()
+            ),
+            format!(
+                "  mount path: {}, backend: {}, filesystem: {}, mount options: {}, manage fstab: {}",
+                self.mount_path,
+                self.backend.to_string(),
+                self.filesystem.to_string(),
+                or_none(self.mount_options.clone()),
+                self.manage_fstab
+            ),
+            format!(
+                "  vg alloc policy: {}, vg extent size: {}, pvmove ionice: {}",
+                self.vg_alloc_policy.to_string(),
+                or_none(
+                    self.vg_extent_size_mib
+                        .map(|size_mib| format!("{}MiB", size_mib))
+                ),
+                self.pvmove_ionice.to_string()
+            ),
+            format!(
+                "  encryption key file: {}",
+                or_none(
+                    self.encryption
+                        .as_ref()
+                        .map(|encryption| encryption.key_file.clone())
+                )
+            ),
+            format!(
+                "  disk type: {}, iops per GiB: {}, subregion: {}, migrate disk type: {}, adopt unmanaged BSU: {}",
+                self.disk_type.to_string(),
+                or_none(self.disk_iops_per_gib.map(|iops| iops.to_string())),
+                or_none(self.subregion.clone()),
+                self.migrate_disk_type,
+                self.adopt_unmanaged_bsu
+            ),
+            format!(
+                "  initial size: {}GiB, max BSU count: {}, max total size: {}, max fs size: {}, time to max warning: {}s",
+                self.initial_size_gib,
+                self.max_bsu_count,
+                or_none(self.max_total_size_gib.map(|gib| format!("{}GiB", gib))),
+                or_none(self.max_fs_size_gib.map(|gib| format!("{}GiB", gib))),
+                self.time_to_max_warning_s
+            ),
+            format!(
+                "  shrink floor: {} BSU, {}",
+                self.min_bsu_count,
+                or_none(self.floor_size_gib.map(|gib| format!("{}GiB", gib)))
+            ),
+            format!(
+                "  used space: {}% to {}%, scale factor: {}%, growth strategy: {}, append only: {}",
+                perc(self.min_used_space_perc),
+                perc(self.max_used_space_perc),
+                perc(self.disk_scale_factor_perc),
+                self.growth_strategy.to_string(),
+                self.append_only
+            ),
+            format!(
+                "  min free: {}, max free: {}, removal margin: {}B, usage basis: {}",
+                or_none(self.min_free_bytes.map(|bytes| format!("{}B", bytes))),
+                or_none(self.max_free_bytes.map(|bytes| format!("{}B", bytes))),
+                self.removal_margin_bytes,
+                self.usage_basis.to_string()
+            ),
+            format!(
+                "  resize cooldown: {}s, resize dead band: {}%, min creation interval: {}s, emergency used space: {}, initial reconcile jitter: {}s",
+                self.resize_cooldown_s,
+                perc(self.resize_dead_band_perc),
+                self.min_creation_interval_s,
+                or_none(self.emergency_used_space_perc.map(|ratio| format!("{}%", perc(ratio)))),
+                self.initial_reconcile_jitter_s
+            ),
+        ];
+        for volume in self.volumes.iter() {
+            lines.push(format!(
+                "  volume {}: mount path: {}, size: {}",
+                volume.lv_name,
+                volume.mount_path,
+                or_none(volume.size_perc.map(|perc| format!("{}%", perc)))
+            ));
         }
-        self.vg_scan().ok();
-        Ok(())
+        lines.push(format!(
+            "  snapshot interval: {}, retention: {}, freeze: {}, restore from: {}",
+            or_none(self.snapshot_interval_s.map(|s| format!("{}s", s))),
+            self.snapshot_retention,
+            self.snapshot_freeze,
+            or_none(self.restore_from_snapshot.clone())
+        ));
+        lines.push(format!(
+            "  scrub interval: {}",
+            or_none(self.scrub_interval_s.map(|s| format!("{}s", s)))
+        ));
+        lines.push(format!(
+            "  rebalance interval: {}",
+            or_none(self.rebalance_interval_s.map(|s| format!("{}s", s)))
+        ));
+        lines.push(format!("  clean shutdown: {}", self.clean_shutdown));
+        lines.join("\n")
     }
 
-    pub fn reconcile_delete(&mut self) -> Result<(), Box<dyn Error>> {
-        self.reconcile_offline()?;
-        self.delete_all_bsu()?;
-        Ok(())
+    pub fn load_persisted_state(&mut self) {
+        match state::load(&self.state_dir, &self.name) {
+            Ok(persisted) => self.persisted = persisted,
+            Err(err) => error!("\"{}\" drive: cannot load state: {}", self.name, err),
+        };
     }
 
-    pub fn reconcile_online(&mut self) -> Result<(), Box<dyn Error>> {
-        'start_again: loop {
-            debug!("\"{}\" drive: reconcile online loop again", self.name);
-            self.early_exit()?;
-            self.crash_resume()?;
-
-            self.early_exit()?;
-            self.fetch_all_drive_bsu()?;
+    pub fn save_persisted_state(&self) {
+        if self.dry_run {
+            return;
+        }
+        if let Err(err) = state::save(&self.state_dir, &self.name, &self.persisted) {
+            error!("\"{}\" drive: cannot save state: {}", self.name, err);
+        }
+    }
 
-            self.early_exit()?;
-            while !self.are_bsu_attached()? {
-                self.bsu_attach_missing()?;
-                self.fetch_all_drive_bsu()?;
-                self.early_exit()?;
+    // Last error is kept until a reconcile succeeds, so the status explains an unhealthy drive
+    pub fn record_reconcile(&mut self, result: &Result<(), Box<dyn Error>>, now_epoch_s: i64) {
+        match result {
+            Err(err) => {
+                error!("\"{}\" drive: {}", self.name, err);
+                self.retry_policy = Some(error::retry_policy(err.as_ref()));
+                self.consecutive_failures = self.consecutive_failures.saturating_add(1);
+                self.persisted.last_error = Some(ReconcileError {
+                    message: err.to_string(),
+                    epoch_s: now_epoch_s,
+                });
             }
-
-            self.early_exit()?;
-            if self.bsu_count() == 0 {
-                self.create_initial_bsu()?;
-                continue 'start_again;
+            Ok(()) => {
+                info!("\"{}\" drive: reconcile loop over with success", self.name);
+                self.retry_policy = None;
+                self.consecutive_failures = 0;
+                self.persisted.last_error = None;
+                self.persisted.last_success_epoch_s = Some(now_epoch_s);
+                notify::record_reconcile_success(&self.name, now_epoch_s);
             }
+        };
+        self.save_persisted_state();
+    }
 
-            self.early_exit()?;
-            while !self.are_pv_initialized()? {
-                self.pv_initialize_missing()?;
-                self.early_exit()?;
+    pub fn health(&self, now_epoch_s: i64) -> DriveHealth {
+        if self.retry_policy == Some(RetryPolicy::Unhealthy) {
+            return DriveHealth::Unhealthy;
+        }
+        match self.persisted.last_success_epoch_s {
+            None => DriveHealth::NeverReconciled,
+            Some(last_success) if now_epoch_s - last_success > STALE_RECONCILE_S => {
+                DriveHealth::Stale
             }
+            Some(_) => DriveHealth::Healthy,
+        }
+    }
 
-            self.early_exit()?;
-            self.vg_scan().ok();
+    // Planned actions are not applied in dry run: following checks would just plan the
+    // same action again, so the pass stops at the first one.
+    pub fn dry_run_stop(&self) -> Result<(), Box<dyn Error>> {
+        if !self.dry_run {
+            return Ok(());
+        }
+        Err(Box::new(format_err!(
+            "dry run: stopping at first planned action, next ones depend on it"
+        )))
+    }
 
-            self.early_exit()?;
-            while !self.is_vg_created()? {
-                self.vg_create()?;
-                self.early_exit()?;
+    // Sleeps until the cooldown is over, commands received meanwhile are handled right away
+    pub fn wait_cooldown(&mut self) -> Result<(), Box<dyn Error>> {
+        loop {
+            if self.check_near_full() {
+                return Ok(());
             }
+            let elapsed_s = Instant::now().seconds() - self.last_reconcile.seconds();
+            let Some(remaining) = cooldown_remaining(elapsed_s, self.reconcile_delay_s()) else {
+                return Ok(());
+            };
+            let remaining = match self.emergency_used_space_perc {
+                Some(_) => remaining.min(time::Duration::from_secs(NEAR_FULL_CHECK_INTERVAL_S)),
+                None => remaining,
+            };
+            match self.drive_cmd.recv_timeout(remaining) {
+                Ok(cmd) => {
+                    self.handle_cmd(cmd)?;
+                    // Requested growth or rebalance does not wait for the cooldown
+                    if self.grow_to_gib.is_some() || self.rebalance_requested {
+                        return Ok(());
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => continue,
+                // No command can come anymore, recv_timeout would not wait
+                Err(RecvTimeoutError::Disconnected) => {
+                    sleep(remaining);
+                    continue;
+                }
+            };
+        }
+    }
 
-            self.early_exit()?;
-            self.enable_vg().ok();
+    pub fn early_exit(&mut self) -> Result<(), Box<dyn Error>> {
+        if let Ok(cmd) = self.drive_cmd.try_recv() {
+            self.handle_cmd(cmd)?;
+        }
+        Ok(())
+    }
 
+    fn handle_cmd(&mut self, cmd: DriveCmd) -> Result<(), Box<dyn Error>> {
+        info!("\"{}\" drive received {:?} command", self.name, cmd);
+        match cmd {
+            DriveCmd::Stop => {
+                self.exit = true;
+                return Err(Box::new(format_err!(
+                    "\"{}\" drive: early exit due to drive stop",
+                    self.name
+                )));
+            }
+            DriveCmd::SetTarget(target) => {
+                info!(
+                    "\"{}\" drive: target changed from {} to {}",
+                    self.name,
+                    self.target.to_string(),
+                    target.to_string()
+                );
+                self.target = target;
+            }
+            DriveCmd::Reconfigure(config) => self.pending_config = Some(*config),
+            DriveCmd::Status(sender) => {
+                if sender.send(self.status()).is_err() {
+                    debug!("\"{}\" drive: status requester is gone", self.name);
+                }
+            }
+            DriveCmd::Rebalance => self.rebalance_requested = true,
+            DriveCmd::GrowTo(size_gib, sender) => {
+                let result = self.request_grow_to(size_gib);
+                if let Err(err) = &result {
+                    error!(
+                        "\"{}\" drive: cannot grow to {}GiB: {}",
+                        self.name, size_gib, err
+                    );
+                }
+                if sender.send(result).is_err() {
+                    debug!("\"{}\" drive: grow-to requester is gone", self.name);
+                }
+            }
+        };
+        Ok(())
+    }
+
+    pub fn request_grow_to(&mut self, size_gib: usize) -> Result<(), String> {
+        let sizes_gib: Vec<usize> = self.all_bsu.iter().map(|bsu| bsu.size_gib).collect();
+        grow_to_plan(
+            &sizes_gib,
+            size_gib,
+            self.max_bsu_count,
+            self.max_total_size_gib,
+        )?;
+        info!(
+            "\"{}\" drive: growing to {}GiB at next reconcile",
+            self.name, size_gib
+        );
+        self.grow_to_gib = Some(size_gib);
+        Ok(())
+    }
+
+    // Creates at once all BSU needed to reach the requested size, returns true if any was created
+    pub fn grow_to_target(&mut self) -> Result<bool, Box<dyn Error>> {
+        let Some(size_gib) = self.grow_to_gib.take() else {
+            return Ok(false);
+        };
+        let sizes_gib: Vec<usize> = self.all_bsu.iter().map(|bsu| bsu.size_gib).collect();
+        let plan = match grow_to_plan(
+            &sizes_gib,
+            size_gib,
+            self.max_bsu_count,
+            self.max_total_size_gib,
+        ) {
+            Ok(plan) => plan,
+            Err(err) => {
+                error!("\"{}\" drive: cannot grow: {}", self.name, err);
+                return Ok(false);
+            }
+        };
+        if !self.lock_volume_budget().fits(plan.len()) {
+            warn!(
+                "\"{}\" drive: account volume budget reached, cannot grow to {}GiB",
+                self.name, size_gib
+            );
+            return Ok(false);
+        }
+        info!(
+            "\"{}\" drive: growing to {}GiB with new BSU of {:?}GiB",
+            self.name, size_gib, plan
+        );
+        for bsu_size_gib in plan.iter() {
             self.early_exit()?;
-            while !self.is_vg_extended()? {
-                self.vg_extend()?;
-                self.early_exit()?;
+            self.create_bsu_gib(*bsu_size_gib)?;
+            self.fetch_all_drive_bsu()?;
+        }
+        Ok(!plan.is_empty())
+    }
+
+    pub fn reconcile(&mut self) -> Result<(), Box<dyn Error>> {
+        self.ready = false;
+        self.trace = ReconcileTrace::default();
+        if let Some(config) = self.pending_config.take() {
+            self.apply_config(config);
+        }
+        let start = time::Instant::now();
+        let ret = self.reconcile_target();
+        metrics::update_drive(
+            &self.name,
+            DriveMetrics {
+                size_bytes: self.fs_size_bytes,
+                used_bytes: self.fs_used_bytes,
+                bsu_count: self.all_bsu.len(),
+                reconcile_duration_s: start.elapsed().as_secs_f64(),
+                reconcile_failed: ret.is_err(),
+                seconds_to_full: self.seconds_to_full,
+                seconds_to_max: self.seconds_to_max,
+                device_stats: self.device_stats.clone(),
+                last_scrub_epoch_s: self
+                    .persisted
+                    .last_scrub
+                    .as_ref()
+                    .map(|scrub| scrub.started_epoch_s)
+                    .unwrap_or(0),
+                scrub_uncorrectable_errors: self
+                    .persisted
+                    .last_scrub
+                    .as_ref()
+                    .map(|scrub| scrub.uncorrectable_errors)
+                    .unwrap_or(0),
+            },
+        );
+        if self.trace_reconcile {
+            info!("\"{}\" drive: reconcile trace: {}", self.name, self.trace);
+        }
+        ret
+    }
+
+    pub fn status(&self) -> DriveStatus {
+        DriveStatus {
+            name: self.name.clone(),
+            target: self.target.clone(),
+            bsu_count: self.all_bsu.len(),
+            total_size_bytes: self.all_bsu.iter().map(|bsu| bsu.size_bytes).sum(),
+            fs_used_bytes: self.fs_used_bytes,
+            fs_size_bytes: self.fs_size_bytes,
+            seconds_to_full: Some(self.seconds_to_full)
+                .filter(|seconds| seconds.is_finite())
+                .map(|seconds| seconds.round() as u64),
+            seconds_to_max: Some(self.seconds_to_max)
+                .filter(|seconds| seconds.is_finite())
+                .map(|seconds| seconds.round() as u64),
+            last_reconcile_epoch_s: self.last_reconcile_epoch_s,
+            last_success_epoch_s: self.persisted.last_success_epoch_s,
+            last_error: self.persisted.last_error.clone(),
+            last_scrub: self.persisted.last_scrub.clone(),
+            orphan_bsu: self.orphan_bsu.clone(),
+            device_stats: self.device_stats.clone(),
+            growth_blocked: self.growth_blocked,
+        }
+    }
+
+    pub fn apply_config(&mut self, config: ConfigFileDrive) {
+        // Reuse Drive::new to resolve defaults the same way as at startup
+        let (_sender, receiver) = channel::<DriveCmd>();
+        let updated = Drive::new(config, receiver);
+        if updated.mount_path != self.mount_path {
+            warn!(
+                "\"{}\" drive: mount-path cannot be changed on a running drive, keeping {}",
+                self.name, self.mount_path
+            );
+        }
+        if updated.filesystem != self.filesystem {
+            warn!(
+                "\"{}\" drive: filesystem cannot be changed on an existing drive, keeping {}",
+                self.name,
+                self.filesystem.to_string()
+            );
+        }
+        if updated.backend != self.backend {
+            warn!(
+                "\"{}\" drive: backend cannot be changed on an existing drive, keeping {}",
+                self.name,
+                self.backend.to_string()
+            );
+        }
+        if updated.encryption.is_some() != self.encryption.is_some() {
+            warn!(
+                "\"{}\" drive: encryption cannot be enabled nor disabled on an existing drive, keeping it {}",
+                self.name,
+                if self.encryption.is_some() {
+                    "enabled"
+                } else {
+                    "disabled"
+                }
+            );
+        } else {
+            self.encryption = updated.encryption;
+        }
+        if updated.target != self.target {
+            info!(
+                "\"{}\" drive: target changed from {} to {}",
+                self.name,
+                self.target.to_string(),
+                updated.target.to_string()
+            );
+        }
+        self.target = updated.target;
+        self.disk_type = updated.disk_type;
+        self.disk_iops_per_gib = updated.disk_iops_per_gib;
+        self.subregion = updated.subregion;
+        self.migrate_disk_type = updated.migrate_disk_type;
+        self.adopt_unmanaged_bsu = updated.adopt_unmanaged_bsu;
+        self.max_total_size_gib = updated.max_total_size_gib;
+        self.time_to_max_warning_s = updated.time_to_max_warning_s;
+        self.pvmove_ionice = updated.pvmove_ionice;
+        self.initial_size_gib = updated.initial_size_gib;
+        self.max_bsu_count = updated.max_bsu_count;
+        self.min_bsu_count = updated.min_bsu_count;
+        self.floor_size_gib = updated.floor_size_gib;
+        self.max_used_space_perc = updated.max_used_space_perc;
+        self.min_used_space_perc = updated.min_used_space_perc;
+        self.min_free_bytes = updated.min_free_bytes;
+        self.max_free_bytes = updated.max_free_bytes;
+        self.removal_margin_bytes = updated.removal_margin_bytes;
+        self.usage_basis = updated.usage_basis;
+        self.disk_scale_factor_perc = updated.disk_scale_factor_perc;
+        self.max_attach_attempts = updated.max_attach_attempts;
+        self.on_attach_failure = updated.on_attach_failure;
+        self.remove_mount_path_on_delete = updated.remove_mount_path_on_delete;
+        self.on_missing_pv = updated.on_missing_pv;
+        self.append_only = updated.append_only;
+        self.max_fs_size_gib = updated.max_fs_size_gib;
+        self.wait_options = updated.wait_options;
+        self.trace_reconcile = updated.trace_reconcile;
+        // Used for next mounts only, current mount is kept as is
+        self.mount_options = updated.mount_options;
+        self.volumes = self.updated_volumes(updated.volumes);
+        self.snapshot_interval_s = updated.snapshot_interval_s;
+        self.snapshot_retention = updated.snapshot_retention;
+        self.snapshot_freeze = updated.snapshot_freeze;
+        self.scrub_interval_s = updated.scrub_interval_s;
+        self.rebalance_interval_s = updated.rebalance_interval_s;
+        self.restore_from_snapshot = updated.restore_from_snapshot;
+        self.manage_fstab = updated.manage_fstab;
+        self.clean_shutdown = updated.clean_shutdown;
+        self.resize_cooldown_s = updated.resize_cooldown_s;
+        self.resize_dead_band_perc = updated.resize_dead_band_perc;
+        self.growth_strategy = updated.growth_strategy;
+        self.min_creation_interval_s = updated.min_creation_interval_s;
+        self.emergency_used_space_perc = updated.emergency_used_space_perc;
+        // Only used when the VG is created
+        self.vg_alloc_policy = updated.vg_alloc_policy;
+        self.vg_extent_size_mib = updated.vg_extent_size_mib;
+        info!("\"{}\" drive: configuration applied", self.name);
+    }
+
+    // New volumes are created on next reconcile, removed ones are left as they are
+    fn updated_volumes(&self, updated: Vec<Volume>) -> Vec<Volume> {
+        for volume in self.volumes.iter() {
+            if !updated.iter().any(|v| v.lv_name == volume.lv_name) {
+                warn!(
+                    "\"{}\" drive: volume \"{}\" removed from configuration, its LV is kept but no longer managed",
+                    self.name, volume.lv_name
+                );
             }
+        }
+        updated
+            .into_iter()
+            .map(|mut volume| {
+                if let Some(current) = self.volumes.iter().find(|v| v.lv_name == volume.lv_name) {
+                    if current.mount_path != volume.mount_path {
+                        warn!(
+                            "\"{}\" drive: mount-path of volume \"{}\" cannot be changed on a running drive, keeping {}",
+                            self.name, volume.lv_name, current.mount_path
+                        );
+                        volume.mount_path = current.mount_path.clone();
+                    }
+                }
+                volume
+            })
+            .collect()
+    }
+
+    // Main volume first, so additional volumes can be mounted below its mount path
+    pub fn all_volumes(&self) -> Vec<Volume> {
+        let mut volumes = vec![Volume {
+            lv_name: lvm::DEFAULT_LV_NAME.to_string(),
+            mount_path: self.mount_path.clone(),
+            mount_options: self.mount_options.clone(),
+            size_perc: None,
+        }];
+        volumes.extend(self.volumes.iter().cloned());
+        volumes
+    }
 
+    pub fn main_volume(&self) -> Volume {
+        self.all_volumes().remove(0)
+    }
+
+    pub fn trace_step<T: ToString>(&mut self, step: &str, outcome: T) {
+        if self.trace_reconcile {
+            self.trace.record(step, outcome);
+        }
+    }
+
+    fn reconcile_target(&mut self) -> Result<(), Box<dyn Error>> {
+        info!(
+            "\"{}\" drive: entering {:?} drive target",
+            self.name, self.target
+        );
+        info!(
+            "\"{}\" drive: start reconcile {}",
+            self.name,
+            self.target.to_string()
+        );
+        match self.target {
+            DriveTarget::Online | DriveTarget::Frozen => self.reconcile_online(),
+            DriveTarget::ReadOnly => self.reconcile_read_only(),
+            DriveTarget::Offline => self.reconcile_offline(),
+            DriveTarget::Delete => self.reconcile_delete(),
+        }
+    }
+
+    // Nested mount paths are unmounted first
+    fn umount_volumes(&mut self) -> Result<(), Box<dyn Error>> {
+        for volume in self.all_volumes().iter().rev() {
             self.early_exit()?;
-            while !self.is_lv_created()? {
-                self.lv_create()?;
+            while self.is_fs_mounted(volume)? {
                 self.early_exit()?;
+                self.fs_umount(volume)?;
+                self.dry_run_stop()?;
+            }
+        }
+        Ok(())
+    }
+
+    // BSU stay attached: next start only has to mount the drive again. A busy filesystem is
+    // retried shortly, then left mounted so stopping never hangs.
+    pub fn shutdown_cleanly(&mut self) {
+        if let Err(err) = fs::sync_with(self.executor.as_ref()) {
+            error!("\"{}\" drive: cannot sync on shutdown: {}", self.name, err);
+        }
+        for attempt in 1..=CLEAN_SHUTDOWN_UMOUNT_ATTEMPTS {
+            let Err(err) = self.umount_volumes() else {
+                info!("\"{}\" drive: unmounted on shutdown", self.name);
+                return;
+            };
+            if attempt == CLEAN_SHUTDOWN_UMOUNT_ATTEMPTS {
+                error!(
+                    "\"{}\" drive: cannot unmount on shutdown, leaving it mounted: {}",
+                    self.name, err
+                );
+                return;
             }
+            warn!(
+                "\"{}\" drive: cannot unmount on shutdown (attempt {}/{}): {}",
+                self.name, attempt, CLEAN_SHUTDOWN_UMOUNT_ATTEMPTS, err
+            );
+            sleep(CLEAN_SHUTDOWN_RETRY_INTERVAL);
+        }
+    }
+
+    pub fn reconcile_offline(&mut self) -> Result<(), Box<dyn Error>> {
+        self.umount_volumes()?;
+        for volume in self.all_volumes().iter() {
+            self.fstab_remove(volume);
+        }
+
+        if self.backend == Backend::Lvm {
+            let result = self.disable_lv();
+            self.log_ignored_error("disable lv", result);
+            let result = self.disable_vg();
+            self.log_ignored_error("disable vg", result);
+        }
+
+        self.early_exit()?;
+        self.fetch_all_drive_bsu()?;
+        if self.bsu_count() == 0 {
+            return Ok(());
+        }
 
+        if self.encryption.is_some() {
+            let result = self.luks_close_all();
+            self.log_ignored_error("close luks devices", result);
+        }
+
+        while self.are_bsu_attached()? {
             self.early_exit()?;
-            self.enable_lv().ok();
+            self.bsu_detach_all_from_this_vm()?;
+            self.dry_run_stop()?;
+            self.early_exit()?;
+            self.fetch_all_drive_bsu()?;
+            self.early_exit()?;
+        }
+        if self.backend == Backend::Lvm {
+            let result = self.vg_scan();
+            self.log_ignored_error("scan vg", result);
+        }
+        Ok(())
+    }
 
+    pub fn reconcile_delete(&mut self) -> Result<(), Box<dyn Error>> {
+        self.reconcile_offline()?;
+        self.delete_all_bsu()?;
+        self.dry_run_stop()?;
+        if self.remove_mount_path_on_delete {
+            for volume in self.all_volumes().iter().rev() {
+                self.remove_mount_path(volume)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn reconcile_online(&mut self) -> Result<(), Box<dyn Error>> {
+        'start_again: loop {
+            debug!("\"{}\" drive: reconcile online loop again", self.name);
             self.early_exit()?;
-            self.lv_extend()?;
+            self.crash_resume()?;
 
             self.early_exit()?;
-            while !self.is_fs_formated()? {
-                self.fs_format()?;
-                self.early_exit()?;
+            self.fetch_all_drive_bsu()?;
+
+            self.early_exit()?;
+            if self.restore_drive_tags()? {
+                self.dry_run_stop()?;
+                self.trace_step("drive tags restored", "start again");
+                continue 'start_again;
             }
 
             self.early_exit()?;
-            while !self.is_mount_path_created() {
-                self.create_mount_path()?;
+            if self.resume_bsu_removal()? {
+                self.dry_run_stop()?;
+                self.trace_step("removal resumed", "start again");
+                continue 'start_again;
             }
 
             self.early_exit()?;
-            while !self.is_fs_mounted()? {
-                self.fs_mount()?;
+            while !self.are_bsu_attached()? {
+                if let Err(err) = self.bsu_attach_missing() {
+                    self.handle_attach_failure()?;
+                    return Err(err);
+                }
+                self.dry_run_stop()?;
+                self.fetch_all_drive_bsu()?;
                 self.early_exit()?;
             }
 
             self.early_exit()?;
-            while !self.is_fs_extended()? {
-                self.fs_extend()?;
-                self.early_exit()?;
+            if self.migrate_disk_type {
+                self.migrate_bsu_disk_type()?;
             }
 
             self.early_exit()?;
-            if self.is_drive_reached_max_attached_bsu()? {
-                self.remove_smallest_bsu()?;
+            if self.bsu_count() == 0 {
+                if self.target == DriveTarget::Frozen {
+                    warn!(
+                        "\"{}\" drive: frozen drive has no BSU, not creating the initial one",
+                        self.name
+                    );
+                    return Ok(());
+                }
+                if let Some(restore_from) = self.restore_from_snapshot.clone() {
+                    self.trace_step("no bsu", "restore from snapshot");
+                    self.restore_bsu_from_snapshot(&restore_from)?;
+                } else {
+                    self.trace_step("no bsu", "create initial bsu");
+                    self.create_initial_bsu()?;
+                }
+                self.dry_run_stop()?;
+                self.trace_step("restart", "start again");
+                continue 'start_again;
+            }
+
+            if self.backend == Backend::Lvm {
+                self.early_exit()?;
+                if self.encryption.is_some() {
+                    while !self.are_luks_opened()? {
+                        self.luks_open_missing()?;
+                        self.dry_run_stop()?;
+                        self.early_exit()?;
+                    }
+                }
+
+                self.early_exit()?;
+                if !self.vg_adopted {
+                    self.adopt_existing_vg()?;
+                }
+
+                self.early_exit()?;
+                while !self.are_pv_initialized()? {
+                    self.pv_initialize_missing()?;
+                    self.dry_run_stop()?;
+                    self.early_exit()?;
+                }
+
+                self.early_exit()?;
+                let result = self.vg_scan();
+                self.log_ignored_error("scan vg", result);
+
+                self.early_exit()?;
+                while !self.is_vg_created()? {
+                    self.vg_create()?;
+                    self.dry_run_stop()?;
+                    self.early_exit()?;
+                }
+
+                self.early_exit()?;
+                if let Some(policy) = self.missing_pv_action()? {
+                    self.trace_step("missing pv", format!("{:?}", policy));
+                    return self.handle_missing_pv(policy);
+                }
+
+                self.early_exit()?;
+                self.enable_vg()
+                    .map_err(|err| self.activation_error("vg", err))?;
+
+                self.early_exit()?;
+                while !self.is_vg_extended()? {
+                    self.vg_extend()?;
+                    self.dry_run_stop()?;
+                    self.early_exit()?;
+                }
+
                 self.early_exit()?;
+                self.detect_orphan_bsu()?;
+
+                // Main volume takes all remaining space, additional ones are created first
+                for volume in self.all_volumes().iter().rev() {
+                    self.early_exit()?;
+                    while !self.is_lv_created(volume)? {
+                        self.lv_create(volume)?;
+                        self.dry_run_stop()?;
+                        self.early_exit()?;
+                    }
+                }
+
+                self.early_exit()?;
+                self.enable_lv()
+                    .map_err(|err| self.activation_error("lv", err))?;
+
+                self.early_exit()?;
+                self.lv_extend()?;
+            } else {
+                self.early_exit()?;
+                self.btrfs_device_scan()?;
+            }
+
+            for volume in self.all_volumes().iter() {
+                loop {
+                    self.early_exit()?;
+                    let restored = self.restore_from_snapshot.is_some();
+                    match format_action(restored, || self.is_fs_formated(volume))? {
+                        FormatAction::None => break,
+                        FormatAction::Format => self.fs_format(volume)?,
+                        FormatAction::Refuse => {
+                            return Err(Box::new(BsudError::Config(format!(
+                                "\"{}\" drive: restored volume {} does not contain a {} filesystem, refusing to format it",
+                                self.name,
+                                volume.lv_name,
+                                self.filesystem.to_string()
+                            ))))
+                        }
+                    };
+                    self.dry_run_stop()?;
+                }
+
+                self.early_exit()?;
+                while !self.is_mount_path_created(volume) {
+                    self.create_mount_path(volume)?;
+                    self.dry_run_stop()?;
+                }
+
+                self.early_exit()?;
+                while !self.is_fs_mounted(volume)? {
+                    self.fs_mount(volume)?;
+                    self.dry_run_stop()?;
+                    self.early_exit()?;
+                }
+                self.fstab_add(volume);
+
+                // btrfs devices are used entirely once added, LVM needs the fs to grow
+                if self.backend == Backend::BtrfsRaw {
+                    self.early_exit()?;
+                    while !self.are_btrfs_devices_added()? {
+                        self.btrfs_devices_add()?;
+                        self.dry_run_stop()?;
+                        self.early_exit()?;
+                    }
+                    continue;
+                }
+
+                self.early_exit()?;
+                while !self.is_fs_extended(volume)? {
+                    self.fs_extend(volume)?;
+                    self.dry_run_stop()?;
+                    self.early_exit()?;
+                }
+            }
+
+            self.early_exit()?;
+            self.ready = true;
+            for volume in self.all_volumes().iter() {
+                let mount_check = self.check_mount(volume)?;
+                if !self.is_ready(&mount_check) {
+                    self.ready = false;
+                    self.trace_step("ready", self.ready);
+                    return Err(Box::new(BsudError::Transient(format!(
+                        "\"{}\" drive: mount validation failed on {}: {:?}",
+                        self.name, volume.mount_path, mount_check
+                    ))));
+                }
+            }
+            self.trace_step("ready", self.ready);
+
+            self.early_exit()?;
+            self.update_device_stats();
+
+            self.early_exit()?;
+            self.snapshot_if_due();
+
+            self.early_exit()?;
+            if self.can_scale() && self.grow_to_target()? {
+                self.dry_run_stop()?;
+                self.trace_step("grow to target", "start again");
                 continue 'start_again;
             }
 
             self.early_exit()?;
-            if self.is_drive_low_space_left()? {
-                if self.is_max_space_reached() {
-                    return Ok(());
+            let state = self.gather_state()?;
+            self.record_usage_sample(&state);
+            if let Some(threshold) = self.emergency_used_space_perc {
+                self.near_full_seen = is_near_full(&state, threshold);
+            }
+
+            self.early_exit()?;
+            if self.can_scale() && self.rebalance_step(&state)? {
+                self.dry_run_stop()?;
+                self.trace_step("rebalance", "start again");
+                continue 'start_again;
+            }
+
+            self.early_exit()?;
+            if self.scale(&state)? == ScaleAction::None {
+                self.scrub_if_due(self.pv_move_progress.is_some());
+                return Ok(());
+            }
+            self.dry_run_stop()?;
+            self.early_exit()?;
+        }
+    }
+
+    // Only attaches, activates and mounts what already exists: a drive which is not complete
+    // is reported, never initialized.
+    pub fn reconcile_read_only(&mut self) -> Result<(), Box<dyn Error>> {
+        self.early_exit()?;
+        self.fetch_all_drive_bsu()?;
+        if self.bsu_count() == 0 {
+            return Err(Box::new(format_err!(
+                "\"{}\" drive: read-only drive has no BSU",
+                self.name
+            )));
+        }
+
+        self.early_exit()?;
+        while !self.are_bsu_attached()? {
+            if let Err(err) = self.bsu_attach_missing() {
+                self.handle_attach_failure()?;
+                return Err(err);
+            }
+            self.dry_run_stop()?;
+            self.fetch_all_drive_bsu()?;
+            self.early_exit()?;
+        }
+
+        self.early_exit()?;
+        if self.backend == Backend::Lvm {
+            self.luks_open_existing()?;
+            let result = self.vg_scan();
+            self.log_ignored_error("scan vg", result);
+            if !self.is_vg_created()? {
+                return Err(self.read_only_error("vg not found"));
+            }
+            self.enable_vg()
+                .map_err(|err| self.activation_error("vg", err))?;
+            for volume in self.all_volumes().iter() {
+                if !self.is_lv_created(volume)? {
+                    return Err(self.read_only_error(&format!("lv {} not found", volume.lv_name)));
                 }
-                if !self.is_drive_reached_max_attached_bsu_minus_one()?
-                    && !self.is_drive_contains_smallest_bsu()
-                {
-                    self.create_smaller_bsu()?;
+            }
+            self.enable_lv()
+                .map_err(|err| self.activation_error("lv", err))?;
+        } else {
+            self.btrfs_device_scan()?;
+        }
+
+        for volume in self.all_volumes().iter() {
+            self.early_exit()?;
+            if !self.is_fs_formated(volume)? {
+                return Err(self.read_only_error(&format!(
+                    "no {} filesystem on {}",
+                    self.filesystem.to_string(),
+                    volume.lv_name
+                )));
+            }
+            while !self.is_mount_path_created(volume) {
+                self.create_mount_path(volume)?;
+                self.dry_run_stop()?;
+            }
+            self.early_exit()?;
+            while !self.is_fs_mounted(volume)? {
+                self.fs_mount(volume)?;
+                self.dry_run_stop()?;
+                self.early_exit()?;
+            }
+        }
+
+        self.early_exit()?;
+        self.ready = true;
+        for volume in self.all_volumes().iter() {
+            let mount_check = self.check_mount(volume)?;
+            if !self.is_ready(&mount_check) {
+                self.ready = false;
+                return Err(Box::new(BsudError::Transient(format!(
+                    "\"{}\" drive: mount validation failed on {}: {:?}",
+                    self.name, volume.mount_path, mount_check
+                ))));
+            }
+        }
+        let state = self.gather_state()?;
+        self.record_usage_sample(&state);
+        Ok(())
+    }
+
+    pub fn can_scale(&self) -> bool {
+        !matches!(self.target, DriveTarget::Frozen | DriveTarget::ReadOnly)
+    }
+
+    fn read_only_error(&self, reason: &str) -> Box<dyn Error> {
+        Box::new(format_err!(
+            "\"{}\" drive: {}, read-only drives are never initialized",
+            self.name,
+            reason
+        ))
+    }
+
+    // Returns the applied action, frozen and read-only drives never add nor remove BSU
+    pub fn scale(&mut self, state: &DriveState) -> Result<ScaleAction, Box<dyn Error>> {
+        let mut trace = std::mem::take(&mut self.trace);
+        let action = self.scale_action_traced(state, &mut trace);
+        self.growth_blocked = self.is_growth_blocked(state);
+        if self.trace_reconcile {
+            self.trace = trace;
+        }
+        if !self.can_scale() && action != ScaleAction::None {
+            let target = self.target.to_string();
+            info!(
+                "\"{}\" drive: {}, skipping scale action {:?}",
+                self.name, target, action
+            );
+            self.trace_step(&target, "skip scaling");
+            return Ok(ScaleAction::None);
+        }
+        if action != ScaleAction::None {
+            self.scrub_if_due(true);
+            self.trace_step("restart", "start again");
+        }
+        match action {
+            ScaleAction::None => return Ok(action),
+            ScaleAction::RemoveSmallest => self.remove_smallest_bsu()?,
+            ScaleAction::CreateSmaller => self.create_smaller_bsu()?,
+            ScaleAction::CreateLarger => self.create_larger_bsu()?,
+            ScaleAction::RemoveLargest => self.remove_largest_bsu()?,
+            ScaleAction::CreateIdeal => self.create_ideal_bsu()?,
+        };
+        self.record_resize(action);
+        Ok(action)
+    }
+
+    pub fn discover(&mut self) -> Result<DiscoveryReport, Box<dyn Error>> {
+        let (stale_bsu, all_bsu) = self.fetch_drive_bsu()?;
+        self.all_bsu = all_bsu;
+        self.resolve_device_paths();
+        let vm_id: String = VM_ID.try_read()?.clone();
+        let lvm = lvm::get_report_with(self.executor.as_ref(), &self.name)?;
+        let vg_found = lvm.is_some();
+        let lv_found = lvm.map(|lvm| !lvm.lv.is_empty()).unwrap_or(false);
+        let main_volume = self.main_volume();
+        let mounted = self.is_fs_mounted(&main_volume).unwrap_or(false);
+        let mut planned = Vec::new();
+        if !stale_bsu.is_empty() {
+            planned.push(format!("delete {} stale BSU", stale_bsu.len()));
+        }
+        let unmanaged = self.all_bsu.iter().filter(|bsu| !bsu.managed).count();
+        if self.adopt_unmanaged_bsu && unmanaged > 0 {
+            planned.push(format!("adopt {} unmanaged BSU", unmanaged));
+        }
+        let not_attached = self
+            .all_bsu
+            .iter()
+            .filter(|bsu| bsu.vm_id.as_ref() != Some(&vm_id))
+            .count();
+        match self.target {
+            DriveTarget::Online | DriveTarget::Frozen | DriveTarget::ReadOnly => {
+                if self.all_bsu.is_empty() && self.target == DriveTarget::Online {
+                    planned.push("create initial BSU".to_string());
+                }
+                if not_attached > 0 {
+                    planned.push(format!("attach {} BSU", not_attached));
+                }
+                let initializes = self.target != DriveTarget::ReadOnly;
+                if initializes && self.backend == Backend::Lvm && (!vg_found || !lv_found) {
+                    planned.push("initialize LVM".to_string());
+                }
+                if !mounted {
+                    planned.push(format!("mount fs on {}", self.mount_path));
                 } else {
-                    self.create_larger_bsu()?;
+                    let state = self.gather_state()?;
+                    match self.scale_action(&state) {
+                        ScaleAction::None => {}
+                        _ if !self.can_scale() => {}
+                        action => planned.push(format!("scale: {:?}", action)),
+                    };
                 }
-                continue 'start_again;
             }
+            DriveTarget::Offline | DriveTarget::Delete => {
+                if mounted {
+                    planned.push(format!("umount fs from {}", self.mount_path));
+                }
+                let attached = self.all_bsu.len() - not_attached;
+                if attached > 0 {
+                    planned.push(format!("detach {} BSU", attached));
+                }
+                if let DriveTarget::Delete = self.target {
+                    planned.push(format!("delete {} BSU", self.all_bsu.len()));
+                }
+            }
+        };
+        Ok(DiscoveryReport {
+            name: self.name.clone(),
+            target: self.target.clone(),
+            all_bsu: self.all_bsu.clone(),
+            vg_found,
+            lv_found,
+            mounted,
+            planned,
+        })
+    }
+
+    pub fn check_mount(&mut self, volume: &Volume) -> Result<MountCheck, Box<dyn Error>> {
+        let mounted = self.is_fs_mounted(volume)?;
+        let fs_type = fs::detect_fs_type(&volume.mount_path)?;
+        let writable = mounted && fs::is_writable(&volume.mount_path);
+        Ok(MountCheck {
+            mounted,
+            fs_type,
+            writable,
+        })
+    }
+
+    // Read-only drives are expected not to be writable
+    pub fn is_ready(&self, check: &MountCheck) -> bool {
+        let expected_fs_type = self.filesystem.to_string();
+        let ret = check.mounted
+            && check.fs_type.as_deref() == Some(expected_fs_type.as_str())
+            && (check.writable || self.target == DriveTarget::ReadOnly);
+        info!("\"{}\" drive: is ready -> {}", self.name, ret);
+        ret
+    }
+
+    pub fn update_device_stats(&mut self) {
+        if self.filesystem != Filesystem::Btrfs {
+            return;
+        }
+        self.device_stats = match fs::device_stats_with(self.executor.as_ref(), &self.mount_path) {
+            Ok(stats) => stats,
+            Err(err) => {
+                error!("\"{}\" drive: cannot get device stats: {}", self.name, err);
+                return;
+            }
+        };
+        for stat in self.device_stats.iter().filter(|stat| stat.has_errors()) {
+            warn!(
+                "\"{}\" drive: device errors detected: {:?}",
+                self.name, stat
+            );
+        }
+    }
+
+    pub fn gather_state(&self) -> Result<DriveState, Box<dyn Error>> {
+        let (used_bytes, size_bytes, available_bytes) = match self.usage_source {
+            // All volumes share the same VG, scaling considers their total usage
+            UsageSource::Fs => {
+                let (mut used_bytes, mut size_bytes, mut available_bytes) = (0, 0, 0);
+                for volume in self.all_volumes() {
+                    if self.usage_basis == UsageBasis::Physical {
+                        let usage =
+                            fs::btrfs_usage_with(self.executor.as_ref(), &volume.mount_path)?;
+                        used_bytes += usage.used_bytes;
+                        size_bytes += usage.device_size_bytes;
+                        available_bytes += usage.available_bytes();
+                        continue;
+                    }
+                    let source = self.volume_source(&volume);
+                    used_bytes += fs::used_bytes(&source)?;
+                    size_bytes += fs::size_bytes(&source)?;
+                    available_bytes += fs::available_bytes(&source)?;
+                }
+                (used_bytes, size_bytes, available_bytes)
+            }
+            UsageSource::Simulated {
+                used_bytes,
+                size_bytes,
+            } => (
+                used_bytes,
+                size_bytes,
+                size_bytes.saturating_sub(used_bytes),
+            ),
+        };
+        Ok(DriveState {
+            all_bsu: self.all_bsu.clone(),
+            used_bytes,
+            size_bytes,
+            available_bytes,
+            now_epoch_s: Instant::now().seconds(),
+        })
+    }
+
+    pub fn scale_action(&self, state: &DriveState) -> ScaleAction {
+        self.scale_action_traced(state, &mut ReconcileTrace::default())
+    }
+
+    // Same as `scale_action` but also records each decision taken in `trace`
+    pub fn scale_action_traced(
+        &self,
+        state: &DriveState,
+        trace: &mut ReconcileTrace,
+    ) -> ScaleAction {
+        let action = self.scale_action_unconstrained(state, trace);
+        let action = self.append_only_filter(action);
+        let action = self.shrink_floor_filter(action, state, trace);
+        let action = self.hysteresis_filter(action, state, state.now_epoch_s, trace);
+        let action = self.creation_pacing_filter(action, state, state.now_epoch_s, trace);
+        trace.record("scale action", format!("{:?}", action));
+        action
+    }
+
+    // Avoids create/remove churn: a resize is not reversed during `resize_cooldown_s` (unless
+    // the drive is about to be full) and a BSU is only removed if usage stays below the
+    // middle of the thresholds plus a dead band, so growth needs a real usage increase.
+    // A zero cooldown or dead band disables its check.
+    pub fn hysteresis_filter(
+        &self,
+        action: ScaleAction,
+        state: &DriveState,
+        now_epoch_s: i64,
+        trace: &mut ReconcileTrace,
+    ) -> ScaleAction {
+        let Some(direction) = action.direction() else {
+            return action;
+        };
+        let used_perc = state.used_perc();
+        if let Some(last) = &self.last_resize {
+            let elapsed_s = now_epoch_s - last.epoch_s;
+            let reversed = last.direction != direction;
+            let in_cooldown = elapsed_s < self.resize_cooldown_s as i64;
+            let critical =
+                used_perc >= (self.max_used_space_perc + self.resize_dead_band_perc).min(1.0);
+            if reversed && in_cooldown && !(direction == ResizeDirection::Grow && critical) {
+                trace.record("resize cooldown", elapsed_s);
+                info!(
+                    "\"{}\" drive: last {:?} was {}s ago (cooldown: {}s), skipping {:?}",
+                    self.name, last.direction, elapsed_s, self.resize_cooldown_s, action
+                );
+                return ScaleAction::None;
+            }
+        }
+        if action == ScaleAction::RemoveLargest && self.resize_dead_band_perc > 0.0 {
+            let largest_bytes = state
+                .all_bsu
+                .iter()
+                .map(|bsu| bsu.size_bytes)
+                .max()
+                .unwrap_or(0);
+            let remaining_bytes = state.size_bytes.saturating_sub(largest_bytes);
+            let middle_perc = (self.min_used_space_perc + self.max_used_space_perc) / 2.0;
+            let limit_perc =
+                (middle_perc + self.resize_dead_band_perc).min(self.max_used_space_perc);
+            let fits = remaining_bytes > 0
+                && (state.used_bytes as f32 / remaining_bytes as f32) <= limit_perc;
+            trace.record("fits dead band", fits);
+            if !fits {
+                info!(
+                    "\"{}\" drive: removing largest BSU would bring usage above {}%, skipping",
+                    self.name,
+                    (limit_perc * 100.0).round()
+                );
+                return ScaleAction::None;
+            }
+        }
+        action
+    }
+
+    // Removing the largest BSU must leave at least `min-bsu-count` BSU and `floor-size-gib`,
+    // the ideal size already accounts for the floor
+    fn shrink_floor_filter(
+        &self,
+        action: ScaleAction,
+        state: &DriveState,
+        trace: &mut ReconcileTrace,
+    ) -> ScaleAction {
+        if action != ScaleAction::RemoveLargest {
+            return action;
+        }
+        let sizes_bytes: Vec<usize> = state.all_bsu.iter().map(|bsu| bsu.size_bytes).collect();
+        let largest_bytes = sizes_bytes.iter().copied().max().unwrap_or(0);
+        let keeps_floor = keeps_shrink_floor(
+            &sizes_bytes,
+            largest_bytes,
+            self.min_bsu_count,
+            self.floor_size_gib,
+        );
+        trace.record("keeps shrink floor", keeps_floor);
+        if !keeps_floor {
+            info!(
+                "\"{}\" drive: shrink floor reached (min BSU count: {}, floor size: {:?}GiB), skipping {:?}",
+                self.name, self.min_bsu_count, self.floor_size_gib, action
+            );
+            return ScaleAction::None;
+        }
+        action
+    }
+
+    // Creations are deferred to a later reconcile, removals are never paced
+    fn creation_pacing_filter(
+        &self,
+        action: ScaleAction,
+        state: &DriveState,
+        now_epoch_s: i64,
+        trace: &mut ReconcileTrace,
+    ) -> ScaleAction {
+        if !action.creates_bsu() {
+            return action;
+        }
+        // A drive about to be full cannot wait
+        if let Some(threshold) = self.emergency_used_space_perc {
+            if is_near_full(state, threshold) {
+                trace.record("near full", "creation not deferred");
+                return action;
+            }
+        }
+        let Some(remaining) = creation_delay(
+            self.last_creation_epoch_s,
+            now_epoch_s,
+            self.min_creation_interval_s,
+        ) else {
+            return action;
+        };
+        trace.record("creation deferred", format!("{}s", remaining.as_secs()));
+        info!(
+            "\"{}\" drive: last BSU created less than {}s ago, deferring {:?} for {}s",
+            self.name,
+            self.min_creation_interval_s,
+            action,
+            remaining.as_secs()
+        );
+        ScaleAction::None
+    }
+
+    fn record_resize(&mut self, action: ScaleAction) {
+        if let Some(direction) = action.direction() {
+            self.last_resize = Some(ResizeEvent {
+                direction,
+                epoch_s: Instant::now().seconds(),
+            });
+        }
+    }
+
+    // Removing a BSU always shrinks the filesystem first
+    fn shrink_blocked_reason(&self) -> Option<&'static str> {
+        if self.append_only {
+            Some("append only")
+        } else if !self.filesystem.can_shrink_online() {
+            Some("filesystem cannot shrink")
+        } else if !self.volumes.is_empty() {
+            Some("drive has several volumes")
+        } else {
+            None
+        }
+    }
+
+    fn append_only_filter(&self, action: ScaleAction) -> ScaleAction {
+        let Some(reason) = self.shrink_blocked_reason() else {
+            return action;
+        };
+        match action {
+            ScaleAction::RemoveSmallest | ScaleAction::RemoveLargest | ScaleAction::CreateIdeal => {
+                info!("\"{}\" drive: {}, skipping {:?}", self.name, reason, action);
+                ScaleAction::None
+            }
+            action => action,
+        }
+    }
+
+    fn scale_action_unconstrained(
+        &self,
+        state: &DriveState,
+        trace: &mut ReconcileTrace,
+    ) -> ScaleAction {
+        let max_bsu_reached = self.is_drive_reached_max_attached_bsu(state);
+        trace.record("max bsu reached", max_bsu_reached);
+        if max_bsu_reached {
+            trace.record("bsu count", state.all_bsu.len());
+            // Removing the only BSU would destroy the filesystem, the drive cannot scale at all
+            if state.all_bsu.len() <= 1 {
+                warn!(
+                    "\"{}\" drive: max-bsu-count ({}) reached with a single BSU, the drive cannot scale",
+                    self.name, self.max_bsu_count
+                );
+                return ScaleAction::None;
+            }
+            // Without removals the drive can only use its remaining BSU slots
+            if let Some(reason) = self.shrink_blocked_reason() {
+                let low_space_left = self.is_drive_low_space_left(state);
+                trace.record("low space left", low_space_left);
+                if low_space_left {
+                    error!(
+                        "\"{}\" drive: low on space with max-bsu-count ({}) BSU and {}, the drive cannot grow anymore",
+                        self.name, self.max_bsu_count, reason
+                    );
+                }
+                return ScaleAction::None;
+            }
+            return ScaleAction::RemoveSmallest;
+        }
+
+        let low_space_left = self.is_drive_low_space_left(state);
+        trace.record("low space left", low_space_left);
+        if low_space_left {
+            let max_space_reached = self.is_max_space_reached(state);
+            trace.record("max space reached", max_space_reached);
+            if max_space_reached {
+                return ScaleAction::None;
+            }
+            let max_fs_size_reached = self.is_max_fs_size_reached(state);
+            trace.record("max fs size reached", max_fs_size_reached);
+            if max_fs_size_reached {
+                return ScaleAction::None;
+            }
+            trace.record("creation blocked", self.creation_blocked);
+            if self.creation_blocked {
+                error!(
+                    "\"{}\" drive: BSU creation blocked due to unattachable BSU",
+                    self.name
+                );
+                return ScaleAction::None;
+            }
+            // A drive which cannot remove BSU never gets its slots back, each one must count
+            let can_create_smaller = !self.is_drive_reached_max_attached_bsu_minus_one(state)
+                && !self.is_drive_contains_smallest_bsu(state)
+                && self.shrink_blocked_reason().is_none();
+            trace.record("can create smaller", can_create_smaller);
+            if can_create_smaller {
+                return ScaleAction::CreateSmaller;
+            }
+            return ScaleAction::CreateLarger;
+        }
 
-            self.early_exit()?;
-            if self.is_drive_high_space_left()? {
-                if self.bsu_count() > 1 {
-                    self.remove_largest_bsu()?;
-                } else {
-                    if self.has_minimal_size() {
-                        return Ok(());
-                    }
-                    self.create_ideal_bsu()?;
-                }
-                self.early_exit()?;
-                continue 'start_again;
+        let high_space_left = self.is_drive_high_space_left(state);
+        trace.record("high space left", high_space_left);
+        if high_space_left {
+            trace.record("bsu count", state.all_bsu.len());
+            if state.all_bsu.len() > 1 {
+                return ScaleAction::RemoveLargest;
             }
-            return Ok(());
+            let minimal_size = self.has_minimal_size(state);
+            trace.record("has minimal size", minimal_size);
+            if minimal_size {
+                return ScaleAction::None;
+            }
+            let ideal_bsu_fits = self.ideal_bsu_fits(state);
+            trace.record("ideal BSU fits", ideal_bsu_fits);
+            if !ideal_bsu_fits {
+                return ScaleAction::None;
+            }
+            return ScaleAction::CreateIdeal;
         }
+        ScaleAction::None
     }
 
     pub fn crash_resume(&mut self) -> Result<(), Box<dyn Error>> {
-        // Run pvmove alone to restart eventual pvmove actions
+        // An interrupted btrfs device delete is simply done again by the next removal
+        if self.backend != Backend::Lvm {
+            return Ok(());
+        }
+        // LVM must see the LUKS mappings to find the PV of an interrupted pvmove
+        if self.encryption.is_some() {
+            if self.all_bsu.is_empty() {
+                self.fetch_all_drive_bsu()?;
+            }
+            self.luks_open_existing()?;
+        }
+        let moves = lvm::get_pv_moves_with(self.executor.as_ref(), &self.name)?;
+        let Some(copy_percent) = moves
+            .iter()
+            .map(|pv_move| pv_move.copy_percent)
+            .reduce(f32::min)
+        else {
+            self.pv_move_progress = None;
+            return Ok(());
+        };
+        for pv_move in moves.iter() {
+            info!(
+                "\"{}\" drive: pvmove of {} in progress: {}%",
+                self.name, pv_move.move_pv, pv_move.copy_percent
+            );
+        }
+        let now_epoch_s = Instant::now().seconds();
+        let progress =
+            lvm::PvMoveProgress::update(self.pv_move_progress, copy_percent, now_epoch_s);
+        self.pv_move_progress = Some(progress);
+        let stalled_s = progress.stalled_s(now_epoch_s);
+        if stalled_s > PV_MOVE_STALL_WARNING_S {
+            error!(
+                "\"{}\" drive: pvmove stalled at {}% for {}s, not waiting for it anymore: check LVM (`lvs -a`, `pvmove --abort`)",
+                self.name, copy_percent, stalled_s
+            );
+            return Ok(());
+        }
+        // Run pvmove alone to restart eventual pvmove actions, this never initializes nor
+        // formats anything so restored BSU are safe.
         // https://www.man7.org/linux/man-pages/man8/pvmove.8.html
-        lvm::pv_move_no_arg()?;
+        lvm::pv_move_no_arg_with(self.executor.as_ref(), &self.pvmove_ionice.command_prefix())?;
         Ok(())
     }
 
+    // Drive BSU split between the ones stuck in creating state and the others, nothing is changed
+    fn fetch_drive_bsu(&self) -> Result<(Vec<Bsu>, Vec<Bsu>), Box<dyn Error>> {
+        let now_epoch_s = Instant::now().seconds();
+        Ok(
+            Bsu::fetch_drive(self.volume_backend.as_ref(), &self.tag_keys, &self.name)?
+                .into_iter()
+                .partition(|bsu| bsu.is_stale_creating(now_epoch_s)),
+        )
+    }
+
     pub fn fetch_all_drive_bsu(&mut self) -> Result<(), Box<dyn Error>> {
         debug!("\"{}\" drive: fetch all bsu", self.name);
-        self.all_bsu = Bsu::fetch_drive(&self.name)?;
+        let (stale_bsu, all_bsu) = self.fetch_drive_bsu()?;
+        for bsu in stale_bsu {
+            if bsu.check_managed("delete stale").is_err() {
+                continue;
+            }
+            warn!(
+                "\"{}\" drive: BSU {} seems stuck in creating state, deleting it",
+                self.name, bsu.id
+            );
+            if let Err(err) = bsu.delete(self.volume_backend.as_ref()) {
+                error!(
+                    "\"{}\" drive: cannot delete stale BSU {}: {}",
+                    self.name, bsu.id, err
+                );
+            }
+        }
+        self.all_bsu = all_bsu;
+        self.forget_attach_failures();
+        self.lock_volume_budget()
+            .set_count(&self.name, self.all_bsu.len());
+        self.resolve_device_paths();
+        if self.adopt_unmanaged_bsu {
+            self.adopt_all_unmanaged_bsu()?;
+        }
         info!(
             "\"{}\" drive: fetched {} BSU",
             self.name,
@@ -371,6 +2495,47 @@ impl Drive {
         Ok(())
     }
 
+    fn adopt_all_unmanaged_bsu(&mut self) -> Result<(), Box<dyn Error>> {
+        let owner = VM_ID.read()?.clone();
+        for bsu in self.all_bsu.iter_mut().filter(|bsu| !bsu.managed) {
+            warn!(
+                "\"{}\" drive: adopting unmanaged BSU {}, bsud may now detach and delete it",
+                self.name, bsu.id
+            );
+            bsu.adopt(self.volume_backend.as_ref(), &self.tag_keys, &owner)?;
+            bsu.managed = true;
+            bsu.owner = Some(owner.clone());
+        }
+        Ok(())
+    }
+
+    // The device name reported by the API is kept as long as it exists, otherwise the kernel
+    // device is found from the volume id so a renamed device does not look detached forever
+    fn resolve_device_paths(&mut self) {
+        let Ok(vm_id) = VM_ID.try_read().map(|vm_id| vm_id.clone()) else {
+            return;
+        };
+        let by_id_dir = Path::new(&self.disk_by_id_dir);
+        for bsu in self.all_bsu.iter_mut() {
+            if bsu.vm_id.as_ref() != Some(&vm_id) {
+                continue;
+            }
+            if let Some(device_path) = &bsu.device_path {
+                if Path::new(device_path).exists() {
+                    continue;
+                }
+            }
+            let Some(resolved) = fs::device_by_id(by_id_dir, &bsu.id) else {
+                continue;
+            };
+            warn!(
+                "\"{}\" drive: BSU {} reported on {:?} is found on {}",
+                self.name, bsu.id, bsu.device_path, resolved
+            );
+            bsu.device_path = Some(resolved);
+        }
+    }
+
     pub fn are_bsu_attached(&mut self) -> Result<bool, Box<dyn Error>> {
         let mut ret = true;
         debug!("\"{}\" drive: are bsu attached ?", self.name);
@@ -418,6 +2583,7 @@ impl Drive {
             );
         }
         info!("\"{}\" drive: are bsu attached ? -> {}", self.name, ret);
+        self.trace_step("bsu attached", ret);
         Ok(ret)
     }
 
@@ -429,7 +2595,187 @@ impl Drive {
             .filter(|bsu| bsu.vm_id.is_none())
             .cloned()
             .collect();
-        Bsu::multiple_attach(&vm_id, &bsus)
+        let vm_subregion = SUBREGION.try_read()?.clone();
+        for bsu in bsus.iter() {
+            bsu.check_subregion(&vm_subregion)?;
+        }
+        let wait = self.wait_options.clone();
+        let backend = self.volume_backend.clone();
+        Bsu::multiple_attach(backend.as_ref(), &vm_id, &bsus, &wait, &mut || {
+            self.early_exit()
+        })
+    }
+
+    // Device LVM sees for the BSU: its LUKS mapping when the drive is encrypted
+    fn pv_device_path(&self, bsu_id: &str, device_path: &str) -> DevicePath {
+        match self.encryption {
+            Some(_) => crypt::mapped_path(bsu_id),
+            None => device_path.to_string(),
+        }
+    }
+
+    // BSU of the drive with the device paths LVM sees
+    pub fn lvm_bsu(&self) -> Vec<Bsu> {
+        self.all_bsu
+            .iter()
+            .map(|bsu| Bsu {
+                device_path: bsu
+                    .device_path
+                    .as_deref()
+                    .map(|device_path| self.pv_device_path(&bsu.id, device_path)),
+                ..bsu.clone()
+            })
+            .collect()
+    }
+
+    pub fn are_luks_opened(&mut self) -> Result<bool, Box<dyn Error>> {
+        self.luks_to_open = self
+            .all_bsu
+            .iter()
+            .filter(|bsu| bsu.device_path.is_some() && !crypt::is_open(&bsu.id))
+            .cloned()
+            .collect();
+        let ret = self.luks_to_open.is_empty();
+        info!("\"{}\" drive: are luks opened -> {}", self.name, ret);
+        self.trace_step("luks opened", ret);
+        Ok(ret)
+    }
+
+    // New BSU are LUKS formatted, anything else than an empty device is left untouched
+    pub fn luks_open_missing(&mut self) -> Result<(), Box<dyn Error>> {
+        let Some(encryption) = self.encryption.clone() else {
+            return Ok(());
+        };
+        for bsu in std::mem::take(&mut self.luks_to_open) {
+            let Some(device_path) = &bsu.device_path else {
+                continue;
+            };
+            if !crypt::is_luks_with(self.executor.as_ref(), device_path)? {
+                if self.restored_bsu.contains(&bsu.id) {
+                    return Err(Box::new(BsudError::Config(format!(
+                        "\"{}\" drive: restored BSU {} is not a LUKS device, refusing to format it",
+                        self.name, bsu.id
+                    ))));
+                }
+                if let Some(signature) = fs::device_signature(device_path)? {
+                    error!(
+                        "\"{}\" drive: REFUSING to encrypt BSU {} ({}): it contains a {} signature",
+                        self.name, bsu.id, device_path, signature
+                    );
+                    return Err(Box::new(BsudError::Config(format!(
+                        "\"{}\" drive: BSU {} contains a {} signature, refusing to LUKS format it",
+                        self.name, bsu.id, signature
+                    ))));
+                }
+                info!(
+                    "\"{}\" drive: LUKS formatting BSU {} ({})",
+                    self.name, bsu.id, device_path
+                );
+                crypt::format_with(self.executor.as_ref(), device_path, &encryption.key_file)?;
+            }
+            crypt::open_with(
+                self.executor.as_ref(),
+                device_path,
+                &bsu.id,
+                &encryption.key_file,
+            )?;
+        }
+        Ok(())
+    }
+
+    // Only reopens existing LUKS devices, nothing is formatted here
+    pub fn luks_open_existing(&mut self) -> Result<(), Box<dyn Error>> {
+        let Some(encryption) = self.encryption.clone() else {
+            return Ok(());
+        };
+        for bsu in self.all_bsu.iter() {
+            let Some(device_path) = &bsu.device_path else {
+                continue;
+            };
+            if crypt::is_open(&bsu.id) || !Path::new(device_path).exists() {
+                continue;
+            }
+            if crypt::is_luks_with(self.executor.as_ref(), device_path)? {
+                info!(
+                    "\"{}\" drive: reopening LUKS device of BSU {}",
+                    self.name, bsu.id
+                );
+                crypt::open_with(
+                    self.executor.as_ref(),
+                    device_path,
+                    &bsu.id,
+                    &encryption.key_file,
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn luks_close_all(&mut self) -> Result<(), Box<dyn Error>> {
+        for bsu in self.all_bsu.iter().filter(|bsu| crypt::is_open(&bsu.id)) {
+            debug!(
+                "\"{}\" drive: closing LUKS device of BSU {}",
+                self.name, bsu.id
+            );
+            crypt::close_with(self.executor.as_ref(), &bsu.id)?;
+        }
+        Ok(())
+    }
+
+    // Attached or deleted BSU are not failing anymore, creation resumes once none is flagged
+    fn forget_attach_failures(&mut self) {
+        let unattached: HashSet<&String> = self
+            .all_bsu
+            .iter()
+            .filter(|bsu| bsu.vm_id.is_none())
+            .map(|bsu| &bsu.id)
+            .collect();
+        self.attach_failures.retain(|id, _| unattached.contains(id));
+        let flagged = self
+            .attach_failures
+            .values()
+            .any(|attempts| *attempts >= self.max_attach_attempts);
+        if self.creation_blocked && !flagged {
+            info!(
+                "\"{}\" drive: no BSU fails to attach anymore, BSU creation resumes",
+                self.name
+            );
+            self.creation_blocked = false;
+        }
+    }
+
+    pub fn handle_attach_failure(&mut self) -> Result<(), Box<dyn Error>> {
+        let unattached: Vec<Bsu> = self
+            .all_bsu
+            .iter()
+            .filter(|bsu| bsu.vm_id.is_none())
+            .cloned()
+            .collect();
+        for bsu in unattached {
+            let attempts = self.attach_failures.entry(bsu.id.clone()).or_insert(0);
+            *attempts += 1;
+            let action = attach_failure_action(
+                *attempts,
+                self.max_attach_attempts,
+                &self.on_attach_failure,
+                self.created_bsu.contains(&bsu.id),
+            );
+            error!(
+                "\"{}\" drive: failed to attach BSU {} ({}/{} attempts) -> {:?}",
+                self.name, bsu.id, attempts, self.max_attach_attempts, action
+            );
+            match action {
+                AttachFailureAction::Retry => {}
+                AttachFailureAction::Delete => {
+                    bsu.delete(self.volume_backend.as_ref())?;
+                    self.lock_volume_budget().release(&self.name, 1);
+                    self.attach_failures.remove(&bsu.id);
+                    self.created_bsu.remove(&bsu.id);
+                }
+                AttachFailureAction::Flag => self.creation_blocked = true,
+            };
+        }
+        Ok(())
     }
 
     pub fn bsu_detach_all_from_this_vm(&mut self) -> Result<(), Box<dyn Error>> {
@@ -438,7 +2784,10 @@ impl Drive {
             self.name,
             self.all_bsu.len()
         );
-        Bsu::multiple_detach(&self.all_bsu)
+        let all_bsu = self.all_bsu.clone();
+        let wait = self.wait_options.clone();
+        let backend = self.volume_backend.clone();
+        Bsu::multiple_detach(backend.as_ref(), &all_bsu, &wait, &mut || self.early_exit())
     }
 
     pub fn delete_all_bsu(&mut self) -> Result<(), Box<dyn Error>> {
@@ -447,43 +2796,339 @@ impl Drive {
             self.name,
             self.all_bsu.len()
         );
+        let mut refused = None;
         for bsu in self.all_bsu.iter() {
-            bsu.delete()?;
+            if let Err(err) = bsu.check_managed("delete") {
+                refused = Some(err);
+                continue;
+            }
+            bsu.delete(self.volume_backend.as_ref())?;
+            self.lock_volume_budget().release(&self.name, 1);
+        }
+        match refused {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    // BSU created before a disk type (or iops) change are modified one by one
+    pub fn migrate_bsu_disk_type(&mut self) -> Result<(), Box<dyn Error>> {
+        let to_modify: Vec<Bsu> = self
+            .all_bsu
+            .iter()
+            .filter(|bsu| bsu.needs_modification(&self.disk_type, self.disk_iops_per_gib))
+            .cloned()
+            .collect();
+        let disk_type = self.disk_type.clone();
+        let wait = self.wait_options.clone();
+        let backend = self.volume_backend.clone();
+        for bsu in to_modify.iter() {
+            info!(
+                "\"{}\" drive: migrating BSU {} from {} to {}",
+                self.name,
+                bsu.id,
+                bsu.disk_type,
+                disk_type.to_string()
+            );
+            bsu.modify(
+                backend.as_ref(),
+                &disk_type,
+                self.disk_iops_per_gib,
+                &wait,
+                &mut || self.early_exit(),
+            )?;
+        }
+        if !to_modify.is_empty() {
+            self.fetch_all_drive_bsu()?;
         }
         Ok(())
     }
 
+    pub fn all_bsu(&self) -> &[Bsu] {
+        &self.all_bsu
+    }
+
+    // BSU created by older versions have no sequence number, new ones are numbered after them
+    pub fn next_bsu_seq(&self) -> usize {
+        self.all_bsu
+            .iter()
+            .filter_map(|bsu| bsu.seq)
+            .max()
+            .map_or(self.all_bsu.len(), |seq| max(seq + 1, self.all_bsu.len()))
+    }
+
     pub fn bsu_count(&mut self) -> usize {
         let count = self.all_bsu.len();
         debug!("\"{}\" drive: bsu count = {}", self.name, count);
         count
     }
 
+    fn lock_volume_budget(&self) -> MutexGuard<'_, VolumeBudget> {
+        self.volume_budget
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    // Refusing locally avoids failed creations against the API until another BSU is deleted
+    fn reserve_bsu(&self, count: usize) -> Result<(), Box<dyn Error>> {
+        let mut budget = self.lock_volume_budget();
+        if budget.try_reserve(&self.name, count) {
+            return Ok(());
+        }
+        Err(Box::new(BsudError::Transient(format!(
+            "\"{}\" drive: account volume budget reached ({}/{} BSU), cannot create {} BSU",
+            self.name,
+            budget.total(),
+            budget.max_bsu.unwrap_or_default(),
+            count
+        ))))
+    }
+
+    pub fn create_bsu_gib(&mut self, size_gib: usize) -> Result<(), Box<dyn Error>> {
+        let name = self.name.clone();
+        let disk_type = self.disk_type.clone();
+        let wait = self.wait_options.clone();
+        let backend = self.volume_backend.clone();
+        let seq = self.next_bsu_seq();
+        let tag_keys = self.tag_keys.clone();
+        let subregion = self.subregion.clone();
+        self.reserve_bsu(1)?;
+        let spec = BsuSpec {
+            drive_name: &name,
+            disk_type: &disk_type,
+            disk_iops_per_gib: self.disk_iops_per_gib,
+            subregion: subregion.as_deref(),
+            seq,
+        };
+        let created = Bsu::create_gib(
+            backend.as_ref(),
+            &tag_keys,
+            &spec,
+            size_gib,
+            &wait,
+            &mut || self.early_exit(),
+        );
+        let bsu_id = match created {
+            Ok(bsu_id) => bsu_id,
+            Err(err) => {
+                self.lock_volume_budget().release(&name, 1);
+                return Err(err);
+            }
+        };
+        self.created_bsu.insert(bsu_id);
+        self.last_creation_epoch_s = Some(Instant::now().seconds());
+        Ok(())
+    }
+
+    // Restored BSU hold the LVM metadata and filesystems of the snapshotted drive, the
+    // normal reconcile flow then adopts them like an existing drive.
+    pub fn restore_bsu_from_snapshot(&mut self, restore_from: &str) -> Result<(), Box<dyn Error>> {
+        let snapshots = Snapshot::fetch_drive(&self.tag_keys, &self.name)?;
+        let batch = snapshot::select_batch(&snapshots, restore_from)?;
+        info!(
+            "\"{}\" drive: restoring {} BSU from snapshots {:?}",
+            self.name,
+            batch.len(),
+            batch
+                .iter()
+                .map(|snapshot| &snapshot.id)
+                .collect::<Vec<_>>()
+        );
+        let name = self.name.clone();
+        let disk_type = self.disk_type.clone();
+        let wait = self.wait_options.clone();
+        let backend = self.volume_backend.clone();
+        let first_seq = self.next_bsu_seq();
+        let tag_keys = self.tag_keys.clone();
+        let subregion = self.subregion.clone();
+        // A partial restore would not hold the whole drive, all BSU are reserved at once
+        self.reserve_bsu(batch.len())?;
+        let mut restored = Vec::new();
+        for (offset, snapshot) in batch.iter().enumerate() {
+            let spec = BsuSpec {
+                drive_name: &name,
+                disk_type: &disk_type,
+                disk_iops_per_gib: self.disk_iops_per_gib,
+                subregion: subregion.as_deref(),
+                seq: first_seq + offset,
+            };
+            let created = Bsu::create_from_snapshot(
+                backend.as_ref(),
+                &tag_keys,
+                &spec,
+                snapshot,
+                self.initial_size_gib,
+                &wait,
+                &mut || self.early_exit(),
+            );
+            match created {
+                Ok(bsu_id) => restored.push(bsu_id),
+                Err(err) => {
+                    let left = self.discard_partial_restore(&restored);
+                    self.lock_volume_budget().release(&name, batch.len() - left);
+                    return Err(err);
+                }
+            }
+        }
+        for bsu_id in restored {
+            self.created_bsu.insert(bsu_id.clone());
+            self.restored_bsu.insert(bsu_id);
+        }
+        Ok(())
+    }
+
+    // The next reconcile restores the whole batch again: the drive must not keep any BSU of
+    // the failed attempt, it would be adopted as an incomplete drive. Returns the number of BSU
+    // which could not be deleted.
+    fn discard_partial_restore(&self, restored: &[String]) -> usize {
+        let mut left = 0;
+        for bsu_id in restored {
+            let bsu = Bsu {
+                id: bsu_id.clone(),
+                drive_name: self.name.clone(),
+                ..Default::default()
+            };
+            if let Err(err) = bsu.delete(self.volume_backend.as_ref()) {
+                error!(
+                    "\"{}\" drive: cannot delete BSU {} of a failed restore, delete it before the next restore: {}",
+                    self.name, bsu_id, err
+                );
+                left += 1;
+            }
+        }
+        left
+    }
+
     pub fn create_initial_bsu(&mut self) -> Result<(), Box<dyn Error>> {
         debug!("\"{}\" drive: create initial BSU", self.name);
-        Bsu::create_gib(
-            &self.name,
-            &self.disk_type,
-            self.disk_iops_per_gib,
-            self.initial_size_gib,
-        )
+        self.create_bsu_gib_within_budget(self.initial_size_gib)
+    }
+
+    // Shrinks the BSU to the space left by `max_total_size_gib`, nothing is created once
+    // the budget is exhausted.
+    fn create_bsu_gib_within_budget(&mut self, size_gib: usize) -> Result<(), Box<dyn Error>> {
+        let total_size_bytes: usize = self.all_bsu.iter().map(|bsu| bsu.size_bytes).sum();
+        let total_gib = bytes_to_gib_rounded(total_size_bytes);
+        let Some(budget_size_gib) =
+            bsu_size_within_budget(size_gib, total_gib, self.max_total_size_gib)
+        else {
+            info!(
+                "\"{}\" drive: max total size reached ({}GiB), skip BSU creation",
+                self.name, total_gib
+            );
+            return Ok(());
+        };
+        // Without any BSU the drive has to wait for a free slot, see `reserve_bsu`
+        if !self.all_bsu.is_empty() && !self.lock_volume_budget().fits(1) {
+            warn!(
+                "\"{}\" drive: account volume budget reached, skip BSU creation",
+                self.name
+            );
+            return Ok(());
+        }
+        if budget_size_gib < size_gib {
+            info!(
+                "\"{}\" drive: BSU size reduced from {}GiB to {}GiB to fit max total size",
+                self.name, size_gib, budget_size_gib
+            );
+        }
+        self.create_bsu_gib(budget_size_gib)
+    }
+
+    pub fn adopt_existing_vg(&mut self) -> Result<(), Box<dyn Error>> {
+        let Some(report) = lvm::get_report_with(self.executor.as_ref(), &self.name)? else {
+            debug!("\"{}\" drive: no existing vg to adopt", self.name);
+            self.vg_adopted = true;
+            return Ok(());
+        };
+        let foreign = foreign_pvs(&report, &self.lvm_bsu());
+        if !foreign.is_empty() {
+            return Err(Box::new(BsudError::Config(format!(
+                "\"{}\" drive: existing vg contains pv {:?} which are not BSU of this drive, refusing to manage it",
+                self.name,
+                foreign
+            ))));
+        }
+        info!(
+            "\"{}\" drive: adopting existing vg with pv {:?}",
+            self.name,
+            report.devices()
+        );
+        self.vg_adopted = true;
+        Ok(())
+    }
+
+    // A BSU which lost its drive tag (manual edit, API glitch) is not fetched anymore while
+    // still being a pv of the vg: its tag is restored instead of provisioning a new BSU.
+    // Returns true when tags were restored.
+    pub fn restore_drive_tags(&mut self) -> Result<bool, Box<dyn Error>> {
+        if self.backend != Backend::Lvm {
+            return Ok(false);
+        }
+        let Some(report) = lvm::get_report_with(self.executor.as_ref(), &self.name)? else {
+            return Ok(false);
+        };
+        let foreign = foreign_pvs(&report, &self.lvm_bsu());
+        if foreign.is_empty() {
+            return Ok(false);
+        }
+        let vm_id = VM_ID.read()?.clone();
+        let untagged: Vec<(String, DevicePath)> =
+            Bsu::fetch_vm_untagged(self.volume_backend.as_ref(), &self.tag_keys, &vm_id)?
+                .into_iter()
+                .map(|(bsu_id, device_path)| {
+                    let pv_path = self.pv_device_path(&bsu_id, &device_path);
+                    (bsu_id, pv_path)
+                })
+                .collect();
+        let members = untagged_vg_members(&foreign, &untagged);
+        for bsu_id in members.iter() {
+            warn!(
+                "\"{}\" drive: BSU {} is a pv of the vg but lost its drive tag, restoring it",
+                self.name, bsu_id
+            );
+            Bsu::restore_drive_tag(
+                self.volume_backend.as_ref(),
+                &self.tag_keys,
+                bsu_id,
+                &self.name,
+            )?;
+        }
+        Ok(!members.is_empty())
+    }
+
+    // BSU tagged for the drive but outside of its vg are only reported, an operator has to
+    // tell whether they hold data
+    pub fn detect_orphan_bsu(&mut self) -> Result<(), Box<dyn Error>> {
+        let Some(report) = lvm::get_report_with(self.executor.as_ref(), &self.name)? else {
+            return Ok(());
+        };
+        let orphans = orphan_bsu(&report, &self.lvm_bsu());
+        if !orphans.is_empty() && orphans != self.orphan_bsu {
+            warn!(
+                "\"{}\" drive: BSU {:?} are tagged for this drive but are not part of its vg, check them",
+                self.name, orphans
+            );
+        }
+        self.orphan_bsu = orphans;
+        Ok(())
     }
 
     pub fn are_pv_initialized(&mut self) -> Result<bool, Box<dyn Error>> {
         let mut ret = true;
         self.pv_to_be_initialized.clear();
         let mut found_devices = HashSet::<String>::new();
-        if let Some(report_with_no_vg) = lvm::get_report_with_no_vg()? {
+        if let Some(report_with_no_vg) = lvm::get_report_with_no_vg_with(self.executor.as_ref())? {
             for device in report_with_no_vg.devices() {
                 found_devices.insert(device);
             }
         }
-        if let Some(report) = lvm::get_report(&self.name)? {
+        if let Some(report) = lvm::get_report_with(self.executor.as_ref(), &self.name)? {
             for device in report.devices() {
                 found_devices.insert(device);
             }
         }
-        for bsu in self.all_bsu.iter() {
+        for bsu in self.lvm_bsu().iter() {
             let Some(device_path) = &bsu.device_path else {
                 error!(
                     "\"{}\" drive: BSU {} should have loca path, please report error",
@@ -496,67 +3141,155 @@ impl Drive {
                     "\"{}\" drive: BSU {} ({}) seems not to be pv initialized",
                     self.name, bsu.id, device_path
                 );
+                // pvcreate would wipe restored data
+                if self.restored_bsu.contains(&bsu.id) {
+                    return Err(Box::new(BsudError::Transient(format!(
+                        "\"{}\" drive: restored BSU {} does not contain LVM metadata yet, refusing to initialize it",
+                        self.name,
+                        bsu.id
+                    ))));
+                }
                 self.pv_to_be_initialized.push(device_path.clone());
                 ret = false;
             }
         }
         info!("\"{}\" drive: are pv initialized -> {}", self.name, ret);
+        self.trace_step("pv initialized", ret);
         Ok(ret)
     }
 
     pub fn pv_initialize_missing(&mut self) -> Result<(), Box<dyn Error>> {
-        for device in self.pv_to_be_initialized.iter() {
-            lvm::init_pv(device)?;
-        }
-        Ok(())
+        let executor = self.executor.as_ref();
+        batch_with_fallback(
+            &self.pv_to_be_initialized,
+            |paths| lvm::init_pvs_with(executor, paths),
+            |path| lvm::init_pv_with(executor, path),
+        )
     }
 
     pub fn is_vg_created(&mut self) -> Result<bool, Box<dyn Error>> {
-        let lvm = lvm::get_report(&self.name)?;
+        let lvm = lvm::get_report_with(self.executor.as_ref(), &self.name)?;
         info!(
             "\"{}\" drive: is vg created -> {}",
             self.name,
             lvm.is_some()
         );
+        self.trace_step("vg created", lvm.is_some());
         Ok(lvm.is_some())
     }
 
     pub fn vg_create(&mut self) -> Result<(), Box<dyn Error>> {
         debug!("\"{}\" drive: create vg", self.name);
+        if self.restore_from_snapshot.is_some() {
+            return Err(Box::new(BsudError::Config(format!(
+                "\"{}\" drive: restored drive should already have a vg, refusing to create one",
+                self.name
+            ))));
+        }
         let mut found_devices = HashSet::<String>::new();
-        if let Some(report_with_no_vg) = lvm::get_report_with_no_vg()? {
+        if let Some(report_with_no_vg) = lvm::get_report_with_no_vg_with(self.executor.as_ref())? {
             for device in report_with_no_vg.devices() {
                 found_devices.insert(device);
             }
         }
-        for bsu in self.all_bsu.iter() {
+        for bsu in self.lvm_bsu().iter() {
             let Some(device_path) = &bsu.device_path else {
                 error!(
-                    "\"{}\" drive: BSU {} should have local path, please report error",
-                    self.name, bsu.id
+                    "\"{}\" drive: BSU {} should have local path, please report error",
+                    self.name, bsu.id
+                );
+                continue;
+            };
+            if found_devices.contains(device_path) {
+                return lvm::vg_create_with(
+                    self.executor.as_ref(),
+                    &self.name,
+                    device_path,
+                    &self.vg_alloc_policy.to_string(),
+                    self.vg_extent_size_mib,
+                );
+            }
+        }
+        Err(Box::new(format_err!(
+            "\"{}\" drive: no PV found to init VG, please report this error",
+            self.name
+        )))
+    }
+
+    pub fn missing_pv_action(&self) -> Result<Option<MissingPvPolicy>, Box<dyn Error>> {
+        let vg = lvm::get_vg_with(self.executor.as_ref(), &self.name)?;
+        Ok(self.missing_pv_policy(&vg))
+    }
+
+    pub fn missing_pv_policy(&self, vg: &lvm::Vg) -> Option<MissingPvPolicy> {
+        if !vg.is_partial() {
+            return None;
+        }
+        warn!(
+            "\"{}\" drive: vg is partial ({} missing pv), applying {} policy",
+            self.name,
+            vg.missing_pv_count(),
+            self.on_missing_pv.to_string()
+        );
+        Some(self.on_missing_pv.clone())
+    }
+
+    pub fn handle_missing_pv(&mut self, policy: MissingPvPolicy) -> Result<(), Box<dyn Error>> {
+        match policy {
+            MissingPvPolicy::Reattach => {
+                self.fetch_all_drive_bsu()?;
+                self.bsu_attach_missing()?;
+                let result = self.vg_scan();
+                self.log_ignored_error("scan vg", result);
+                Err(Box::new(format_err!(
+                    "\"{}\" drive: vg has missing pv, tried to reattach BSU",
+                    self.name
+                )))
+            }
+            MissingPvPolicy::Readonly => {
+                lvm::vg_activate_partial_with(self.executor.as_ref(), &self.name)?;
+                let result = self.enable_lv();
+                self.log_ignored_error("enable lv", result);
+                for volume in self.all_volumes().iter() {
+                    if !self.is_mount_path_created(volume) {
+                        self.create_mount_path(volume)?;
+                    }
+                    if !self.is_fs_mounted(volume)? {
+                        let lv_path = lvm::lv_path(&self.name, &volume.lv_name);
+                        fs::mount_readonly_with(
+                            self.executor.as_ref(),
+                            &lv_path,
+                            &volume.mount_path,
+                            volume.mount_options.as_deref(),
+                        )?;
+                    }
+                }
+                error!(
+                    "\"{}\" drive: vg has missing pv, drive is mounted read-only",
+                    self.name
                 );
-                continue;
-            };
-            if found_devices.contains(device_path) {
-                return lvm::vg_create(&self.name, device_path);
+                Ok(())
+            }
+            MissingPvPolicy::Fail => {
+                self.exit = true;
+                Err(Box::new(format_err!(
+                    "\"{}\" drive: vg has missing pv, stopping drive",
+                    self.name
+                )))
             }
         }
-        Err(Box::new(format_err!(
-            "\"{}\" drive: no PV found to init VG, please report this error",
-            self.name
-        )))
     }
 
     pub fn is_vg_extended(&mut self) -> Result<bool, Box<dyn Error>> {
         let mut ret = true;
         self.pv_to_add_to_vg.clear();
         let mut found_devices = HashSet::<String>::new();
-        if let Some(report_with_no_vg) = lvm::get_report_with_no_vg()? {
+        if let Some(report_with_no_vg) = lvm::get_report_with_no_vg_with(self.executor.as_ref())? {
             for device in report_with_no_vg.devices() {
                 found_devices.insert(device);
             }
         }
-        for bsu in self.all_bsu.iter() {
+        for bsu in self.lvm_bsu().iter() {
             let Some(device_path) = &bsu.device_path else {
                 error!(
                     "\"{}\" drive: BSU {} should have local path, please report error",
@@ -574,186 +3307,660 @@ impl Drive {
             }
         }
         info!("\"{}\" drive: is vg extended -> {}", self.name, ret);
+        self.trace_step("vg extended", ret);
         Ok(ret)
     }
 
     pub fn vg_extend(&mut self) -> Result<(), Box<dyn Error>> {
-        for pv_device_path in self.pv_to_add_to_vg.iter() {
-            lvm::extend_vg(&self.name, pv_device_path)?;
+        if self.pv_to_add_to_vg.is_empty() {
+            return Ok(());
         }
-        Ok(())
+        lvm::extend_vg_with(self.executor.as_ref(), &self.name, &self.pv_to_add_to_vg)
     }
 
-    pub fn is_lv_created(&mut self) -> Result<bool, Box<dyn Error>> {
-        let Some(lvm) = lvm::get_report(&self.name)? else {
+    pub fn is_lv_created(&mut self, volume: &Volume) -> Result<bool, Box<dyn Error>> {
+        let Some(lvm) = lvm::get_report_with(self.executor.as_ref(), &self.name)? else {
             return Err(Box::new(format_err!(
                 "\"{}\" drive: lvm details cannot be found, please report issue",
                 self.name
             )));
         };
-        let Some(_lv) = lvm.lv.into_iter().next() else {
-            debug!("\"{}\" drive: is lv created -> false", self.name);
+        if !lvm.lv.iter().any(|lv| lv.lv_name == volume.lv_name) {
+            debug!(
+                "\"{}\" drive: is lv {} created -> false",
+                self.name, volume.lv_name
+            );
+            self.trace_step("lv created", false);
             return Ok(false);
         };
-        info!("\"{}\" drive: is lv created -> true", self.name);
+        info!(
+            "\"{}\" drive: is lv {} created -> true",
+            self.name, volume.lv_name
+        );
+        self.trace_step("lv created", true);
         Ok(true)
     }
 
-    pub fn lv_create(&mut self) -> Result<(), Box<dyn Error>> {
-        lvm::create_lv(&self.name)
+    pub fn lv_create(&mut self, volume: &Volume) -> Result<(), Box<dyn Error>> {
+        let size_bytes = match volume.size_perc {
+            Some(size_perc) => {
+                let vg_size = lvm::get_vg_size_bytes_with(self.executor.as_ref(), &self.name)?;
+                let extent_size =
+                    lvm::get_vg_extent_size_bytes_with(self.executor.as_ref(), &self.name)?;
+                let allocatable = self.lv_target_size_bytes(vg_size);
+                Some(volume_share_bytes(size_perc, allocatable, extent_size))
+            }
+            None => None,
+        };
+        lvm::create_lv_with(
+            self.executor.as_ref(),
+            &self.name,
+            &volume.lv_name,
+            size_bytes,
+        )
     }
 
+    // Additional volumes are extended first to their share, the main volume takes what
+    // remains.
     pub fn lv_extend(&mut self) -> Result<(), Box<dyn Error>> {
-        let vg_size = lvm::get_vg_size_bytes(&self.name)?;
-        let lv_size = lvm::get_lv_size_bytes(&self.name)?;
-        match vg_size.cmp(&lv_size) {
+        // Steady drives have all their VG allocated, no need to look at each LV
+        let vg_free = lvm::get_vg_free_bytes_with(self.executor.as_ref(), &self.name)?;
+        if vg_free == 0 {
+            debug!("\"{}\" drive: no free space in vg, lv fit vg", self.name);
+            return Ok(());
+        }
+        let vg_size = lvm::get_vg_size_bytes_with(self.executor.as_ref(), &self.name)?;
+        let allocatable = self.lv_target_size_bytes(vg_size);
+        let mut other_lv_size = 0;
+        if !self.volumes.is_empty() {
+            let extent_size =
+                lvm::get_vg_extent_size_bytes_with(self.executor.as_ref(), &self.name)?;
+            for volume in self.volumes.clone() {
+                let lv_size = lvm::get_lv_size_bytes_with(
+                    self.executor.as_ref(),
+                    &self.name,
+                    &volume.lv_name,
+                )?;
+                let size_perc = volume.size_perc.unwrap_or_default();
+                let target_size = volume_share_bytes(size_perc, allocatable, extent_size);
+                if target_size > lv_size {
+                    debug!(
+                        "\"{}\" drive: lv {} can be extended",
+                        self.name, volume.lv_name
+                    );
+                    let lv_path = lvm::lv_path(&self.name, &volume.lv_name);
+                    lvm::lv_extend_size_with(self.executor.as_ref(), &lv_path, target_size)?;
+                }
+                other_lv_size += max(target_size, lv_size);
+            }
+        }
+        let lv_size =
+            lvm::get_lv_size_bytes_with(self.executor.as_ref(), &self.name, lvm::DEFAULT_LV_NAME)?;
+        if vg_size < lv_size + other_lv_size {
+            return Err(Box::new(format_err!(
+                "\"{}\" drive: vg_size ({}) < lv_size ({})",
+                self.name,
+                vg_size,
+                lv_size + other_lv_size
+            )));
+        }
+        let target_size = allocatable.saturating_sub(other_lv_size);
+        match target_size.cmp(&lv_size) {
             Ordering::Greater => {
                 debug!("\"{}\" drive: lv can be extended", self.name);
-                let lv_path = lvm::lv_path(&self.name);
-                lvm::lv_extend_full(&lv_path)?;
+                let lv_path = lvm::lv_path(&self.name, lvm::DEFAULT_LV_NAME);
+                if allocatable == vg_size {
+                    lvm::lv_extend_full_with(self.executor.as_ref(), &lv_path)?;
+                } else {
+                    lvm::lv_extend_size_with(self.executor.as_ref(), &lv_path, target_size)?;
+                }
             }
             Ordering::Equal => debug!("\"{}\" drive: lv fit vg", self.name),
-            Ordering::Less => {
-                return Err(Box::new(format_err!(
-                    "\"{}\" drive: vg_size ({}) < lv_size ({})",
-                    self.name,
-                    vg_size,
-                    lv_size
-                )));
-            }
+            Ordering::Less => debug!("\"{}\" drive: lv reached max fs size", self.name),
         };
         Ok(())
     }
 
+    pub fn lv_target_size_bytes(&self, vg_size_bytes: usize) -> usize {
+        match self.max_fs_size_gib {
+            Some(max_fs_size_gib) => min(vg_size_bytes, gib_to_bytes(max_fs_size_gib)),
+            None => vg_size_bytes,
+        }
+    }
+
+    pub fn is_max_fs_size_reached(&self, state: &DriveState) -> bool {
+        let Some(max_fs_size_gib) = self.max_fs_size_gib else {
+            return false;
+        };
+        let fs_size_gib = bytes_to_gib_rounded(state.size_bytes);
+        let ret = fs_size_gib >= max_fs_size_gib || is_gib_close(fs_size_gib, max_fs_size_gib);
+        info!(
+            "\"{}\" drive: is max fs size reached -> {} ({}/{}Gib)",
+            self.name, ret, fs_size_gib, max_fs_size_gib
+        );
+        ret
+    }
+
     pub fn enable_lv(&mut self) -> Result<(), Box<dyn Error>> {
-        debug!("\"{}\" drive: disabling lv {}", self.name, self.name);
-        lvm::lv_activate(true, &self.name)
+        if let Some(report) = lvm::get_report_with(self.executor.as_ref(), &self.name)? {
+            if report.inactive_lvs().is_empty() {
+                debug!("\"{}\" drive: lv {} already active", self.name, self.name);
+                return Ok(());
+            }
+        }
+        debug!("\"{}\" drive: enabling lv {}", self.name, self.name);
+        lvm::lv_activate_with(self.executor.as_ref(), true, &self.name)
     }
 
     pub fn disable_lv(&mut self) -> Result<(), Box<dyn Error>> {
         debug!("\"{}\" drive: disabling lv {}", self.name, self.name);
-        lvm::lv_activate(false, &self.name)
+        lvm::lv_activate_with(self.executor.as_ref(), false, &self.name)
     }
 
     pub fn enable_vg(&mut self) -> Result<(), Box<dyn Error>> {
         debug!("\"{}\" drive: enabling vg {}", self.name, self.name);
-        lvm::vg_activate(true, &self.name)
+        lvm::vg_activate_with(self.executor.as_ref(), true, &self.name)
     }
 
     pub fn disable_vg(&mut self) -> Result<(), Box<dyn Error>> {
         debug!("\"{}\" drive: disabling vg {}", self.name, self.name);
-        lvm::vg_activate(false, &self.name)
+        lvm::vg_activate_with(self.executor.as_ref(), false, &self.name)
+    }
+
+    // The vg exists but cannot be activated: this reconcile stops here and the next one retries
+    fn activation_error(&self, kind: &str, err: Box<dyn Error>) -> Box<dyn Error> {
+        Box::new(BsudError::Transient(format!(
+            "\"{}\" drive: {} exists but activation failed, retrying next reconcile: {}",
+            self.name, kind, err
+        )))
+    }
+
+    // Some steps are allowed to fail but their reason must stay visible
+    fn log_ignored_error(&self, action: &str, result: Result<(), Box<dyn Error>>) {
+        if let Err(err) = result {
+            warn!("\"{}\" drive: cannot {}: {}", self.name, action, err);
+        }
     }
 
     pub fn vg_scan(&self) -> Result<(), Box<dyn Error>> {
         debug!("\"{}\" drive: vgscan", self.name);
-        lvm::vg_scan()
+        lvm::vg_scan_with(self.executor.as_ref())
+    }
+
+    // Device holding the filesystem of a volume, btrfs-raw filesystems are found through
+    // their mount path as they span several devices
+    pub fn volume_source(&self, volume: &Volume) -> String {
+        match self.backend {
+            Backend::Lvm => lvm::lv_path(&self.name, &volume.lv_name),
+            Backend::BtrfsRaw => volume.mount_path.clone(),
+        }
+    }
+
+    fn fstab_source(&self, volume: &Volume) -> String {
+        match self.backend {
+            Backend::Lvm => lvm::lv_path(&self.name, &volume.lv_name),
+            Backend::BtrfsRaw => btrfs::fstab_source(&self.name),
+        }
+    }
+
+    fn bsu_device_paths(&self) -> Vec<DevicePath> {
+        let mut paths: Vec<DevicePath> = self
+            .all_bsu
+            .iter()
+            .filter_map(|bsu| bsu.device_path.as_deref())
+            .map(btrfs::resolve_device)
+            .collect();
+        paths.sort();
+        paths
+    }
+
+    // Any device of a btrfs-raw filesystem holds its superblock
+    fn formated_bsu_devices(&self) -> Result<Vec<DevicePath>, Box<dyn Error>> {
+        let mut formated = Vec::new();
+        for path in self.bsu_device_paths() {
+            if fs::device_seems_formated(&path, &self.filesystem)? {
+                formated.push(path);
+            }
+        }
+        Ok(formated)
+    }
+
+    pub fn btrfs_device_scan(&mut self) -> Result<(), Box<dyn Error>> {
+        debug!("\"{}\" drive: btrfs device scan", self.name);
+        btrfs::device_scan_with(self.executor.as_ref())
     }
 
-    pub fn is_fs_formated(&mut self) -> Result<bool, Box<dyn Error>> {
-        let lv_path = lvm::lv_path(&self.name);
-        let ret = fs::device_seems_formated(&lv_path)?;
-        info!("\"{}\" drive: is fs formated -> {}", self.name, ret);
+    pub fn are_btrfs_devices_added(&mut self) -> Result<bool, Box<dyn Error>> {
+        let fs_devices = btrfs::devices_with(self.executor.as_ref(), &self.mount_path)?;
+        self.btrfs_devices_to_add = btrfs::missing_devices(&self.bsu_device_paths(), &fs_devices);
+        let ret = self.btrfs_devices_to_add.is_empty();
+        info!(
+            "\"{}\" drive: are btrfs devices added ? -> {} (to add: {:?})",
+            self.name, ret, self.btrfs_devices_to_add
+        );
+        self.trace_step("btrfs devices added", ret);
+        Ok(ret)
+    }
+
+    pub fn btrfs_devices_add(&mut self) -> Result<(), Box<dyn Error>> {
+        debug!(
+            "\"{}\" drive: btrfs device add {:?}",
+            self.name, self.btrfs_devices_to_add
+        );
+        btrfs::device_add_with(
+            self.executor.as_ref(),
+            &self.btrfs_devices_to_add,
+            &self.mount_path,
+        )
+    }
+
+    pub fn is_fs_formated(&mut self, volume: &Volume) -> Result<bool, Box<dyn Error>> {
+        let ret = match self.backend {
+            Backend::Lvm => {
+                let lv_path = lvm::lv_path(&self.name, &volume.lv_name);
+                fs::device_seems_formated(&lv_path, &self.filesystem)?
+            }
+            Backend::BtrfsRaw => !self.formated_bsu_devices()?.is_empty(),
+        };
+        info!(
+            "\"{}\" drive: is fs {} formated -> {}",
+            self.name, volume.lv_name, ret
+        );
+        self.trace_step("fs formated", ret);
         Ok(ret)
     }
 
-    pub fn fs_format(&mut self) -> Result<(), Box<dyn Error>> {
-        debug!("\"{}\" drive: fs format", self.name);
-        let lv_path = lvm::lv_path(&self.name);
-        fs::format(&lv_path)
+    pub fn fs_format(&mut self, volume: &Volume) -> Result<(), Box<dyn Error>> {
+        debug!("\"{}\" drive: fs format {}", self.name, volume.lv_name);
+        if self.backend == Backend::BtrfsRaw {
+            // Other BSU join the filesystem once it is mounted
+            let Some(device_path) = self.bsu_device_paths().into_iter().next() else {
+                return Err(Box::new(format_err!(
+                    "\"{}\" drive: no attached BSU to format",
+                    self.name
+                )));
+            };
+            self.check_format_target(&device_path)?;
+            return btrfs::format_with(
+                self.executor.as_ref(),
+                &btrfs::label(&self.name),
+                &device_path,
+            );
+        }
+        let lv_path = lvm::lv_path(&self.name, &volume.lv_name);
+        self.check_format_target(&lv_path)?;
+        fs::format_with(self.executor.as_ref(), &self.filesystem, &lv_path)
+    }
+
+    fn check_format_target(&mut self, device_path: &str) -> Result<(), Box<dyn Error>> {
+        if let Err(err) = fs::check_format_target(device_path, &self.filesystem) {
+            error!(
+                "\"{}\" drive: REFUSING to format {}, check which device is behind it: {}",
+                self.name, device_path, err
+            );
+            self.trace_step("format target", "foreign signature");
+            return Err(Box::new(format_err!("\"{}\" drive: {}", self.name, err)));
+        }
+        Ok(())
     }
 
-    pub fn is_mount_path_created(&mut self) -> bool {
-        let ret = fs::is_folder(&self.mount_path);
+    pub fn is_mount_path_created(&mut self, volume: &Volume) -> bool {
+        let ret = fs::is_folder(&volume.mount_path);
         debug!(
-            "\"{}\" drive: is mount target created ? -> {}",
-            self.name, ret
+            "\"{}\" drive: is mount target {} created ? -> {}",
+            self.name, volume.mount_path, ret
         );
+        self.trace_step("mount path created", ret);
         ret
     }
 
-    pub fn create_mount_path(&mut self) -> Result<(), Box<dyn Error>> {
+    pub fn create_mount_path(&mut self, volume: &Volume) -> Result<(), Box<dyn Error>> {
         debug!(
             "\"{}\" drive: try creating folder in {}",
-            self.name, self.mount_path
+            self.name, volume.mount_path
+        );
+        fs::create_folder(&volume.mount_path, self.dry_run)
+    }
+
+    pub fn remove_mount_path(&mut self, volume: &Volume) -> Result<(), Box<dyn Error>> {
+        debug!(
+            "\"{}\" drive: try removing folder {}",
+            self.name, volume.mount_path
         );
-        fs::create_folder(&self.mount_path)
+        if !fs::remove_empty_folder(&volume.mount_path, self.dry_run)? {
+            info!(
+                "\"{}\" drive: mount path {} kept as it is not an empty folder",
+                self.name, volume.mount_path
+            );
+        }
+        Ok(())
     }
 
-    pub fn is_fs_mounted(&mut self) -> Result<bool, Box<dyn Error>> {
-        let lv_path = lvm::lv_path(&self.name);
-        let ret = fs::is_mounted(&lv_path, &self.mount_path)?;
-        info!("\"{}\" drive: is fs mounted ? -> {}", self.name, ret);
+    pub fn is_fs_mounted(&mut self, volume: &Volume) -> Result<bool, Box<dyn Error>> {
+        let ret = match self.backend {
+            Backend::Lvm => {
+                let lv_path = lvm::lv_path(&self.name, &volume.lv_name);
+                fs::is_mounted(&lv_path, &volume.mount_path)?
+            }
+            Backend::BtrfsRaw => match fs::detect_mount(&volume.mount_path)? {
+                None => false,
+                Some((source, fs_type)) => {
+                    let device_paths = self.bsu_device_paths();
+                    if !btrfs::is_drive_mount(&self.name, &fs_type, &source, &device_paths) {
+                        return Err(Box::new(format_err!(
+                            "\"{}\" drive: {} ({}) is mounted on {}, not the drive's filesystem",
+                            self.name,
+                            source,
+                            fs_type,
+                            volume.mount_path
+                        )));
+                    }
+                    true
+                }
+            },
+        };
+        info!(
+            "\"{}\" drive: is fs mounted on {} ? -> {}",
+            self.name, volume.mount_path, ret
+        );
+        self.trace_step("fs mounted", ret);
         Ok(ret)
     }
 
-    pub fn fs_mount(&mut self) -> Result<(), Box<dyn Error>> {
-        debug!("\"{}\" drive: fs mount", self.name);
-        let lv_path = lvm::lv_path(&self.name);
-        fs::mount(&lv_path, &self.mount_path)
+    pub fn fs_mount(&mut self, volume: &Volume) -> Result<(), Box<dyn Error>> {
+        debug!("\"{}\" drive: fs mount {}", self.name, volume.mount_path);
+        let device_path = match self.backend {
+            Backend::Lvm => lvm::lv_path(&self.name, &volume.lv_name),
+            Backend::BtrfsRaw => {
+                let Some(device_path) = self.formated_bsu_devices()?.into_iter().next() else {
+                    return Err(Box::new(format_err!(
+                        "\"{}\" drive: no formated BSU to mount",
+                        self.name
+                    )));
+                };
+                device_path
+            }
+        };
+        if self.target == DriveTarget::ReadOnly {
+            return fs::mount_readonly_with(
+                self.executor.as_ref(),
+                &device_path,
+                &volume.mount_path,
+                volume.mount_options.as_deref(),
+            );
+        }
+        fs::mount_with(
+            self.executor.as_ref(),
+            &device_path,
+            &volume.mount_path,
+            volume.mount_options.as_deref(),
+        )
+    }
+
+    // fstab is a convenience for reboots, failing to update it does not stop the drive
+    pub fn fstab_add(&mut self, volume: &Volume) {
+        if !self.manage_fstab {
+            return;
+        }
+        let source = self.fstab_source(volume);
+        if let Err(err) = fs::fstab::add(
+            fs::fstab::FSTAB_PATH,
+            &source,
+            &volume.mount_path,
+            &self.filesystem.to_string(),
+            volume.mount_options.as_deref(),
+            self.dry_run,
+        ) {
+            error!("\"{}\" drive: cannot add fstab entry: {}", self.name, err);
+        }
+    }
+
+    pub fn fstab_remove(&mut self, volume: &Volume) {
+        if !self.manage_fstab {
+            return;
+        }
+        let source = self.fstab_source(volume);
+        if let Err(err) = fs::fstab::remove(fs::fstab::FSTAB_PATH, &source, self.dry_run) {
+            error!(
+                "\"{}\" drive: cannot remove fstab entry: {}",
+                self.name, err
+            );
+        }
+    }
+
+    // Snapshots are a convenience, failing to take them does not stop the drive
+    pub fn snapshot_if_due(&mut self) {
+        let Some(interval_s) = self.snapshot_interval_s else {
+            return;
+        };
+        let now_epoch_s = Instant::now().seconds();
+        if !snapshot::is_due(
+            self.persisted.last_snapshot_epoch_s,
+            interval_s,
+            now_epoch_s,
+        ) || !snapshot::is_due(
+            self.last_snapshot_failure_epoch_s,
+            min(interval_s, SNAPSHOT_RETRY_S),
+            now_epoch_s,
+        ) {
+            return;
+        }
+        if let Err(err) = self.snapshot_all_bsu(now_epoch_s) {
+            error!("\"{}\" drive: cannot snapshot: {}", self.name, err);
+            self.last_snapshot_failure_epoch_s = Some(now_epoch_s);
+            return;
+        }
+        self.last_snapshot_failure_epoch_s = None;
+        info!("\"{}\" drive: snapshot of all BSU done", self.name);
+        self.persisted.last_snapshot_epoch_s = Some(now_epoch_s);
+        self.save_persisted_state();
+        // Old batches are only pruned once a new one fully exists
+        if let Err(err) = self.snapshot_prune() {
+            error!("\"{}\" drive: cannot prune snapshots: {}", self.name, err);
+        }
+    }
+
+    // Scrub runs in background between reconciles: a busy drive (scaling or moving a PV)
+    // cancels it and a new one starts once the drive is steady again. Like snapshots,
+    // failing to scrub does not stop the drive.
+    pub fn scrub_if_due(&mut self, drive_busy: bool) {
+        if self.scrub_interval_s.is_none() {
+            return;
+        }
+        if let Err(err) = self.scrub_step(drive_busy) {
+            error!("\"{}\" drive: cannot scrub: {}", self.name, err);
+        }
+    }
+
+    fn scrub_step(&mut self, drive_busy: bool) -> Result<(), Box<dyn Error>> {
+        let status = fs::scrub_status_with(self.executor.as_ref(), &self.mount_path)?;
+        if !status.running {
+            if let Some(started_epoch_s) = self.persisted.scrub_started_epoch_s.take() {
+                self.record_scrub(started_epoch_s, &status);
+            }
+        }
+        let last_scrub_epoch_s = self
+            .persisted
+            .last_scrub
+            .as_ref()
+            .filter(|scrub| scrub.finished)
+            .map(|scrub| scrub.started_epoch_s);
+        let now_epoch_s = Instant::now().seconds();
+        match scrub_action(
+            self.scrub_interval_s,
+            last_scrub_epoch_s,
+            status.running,
+            drive_busy,
+            now_epoch_s,
+        ) {
+            ScrubAction::None => {}
+            ScrubAction::Start => {
+                info!("\"{}\" drive: starting scrub", self.name);
+                fs::scrub_start_with(self.executor.as_ref(), &self.mount_path)?;
+                self.persisted.scrub_started_epoch_s = Some(now_epoch_s);
+                self.save_persisted_state();
+            }
+            ScrubAction::Cancel => {
+                info!("\"{}\" drive: cancelling scrub, drive is busy", self.name);
+                fs::scrub_cancel_with(self.executor.as_ref(), &self.mount_path)?;
+            }
+        };
+        Ok(())
+    }
+
+    fn record_scrub(&mut self, started_epoch_s: i64, status: &fs::ScrubStatus) {
+        let report = ScrubReport {
+            started_epoch_s,
+            finished: status.finished,
+            corrected_errors: status.corrected_errors,
+            uncorrectable_errors: status.uncorrectable_errors,
+        };
+        if report.uncorrectable_errors > 0 {
+            error!(
+                "\"{}\" drive: scrub found {} uncorrectable errors ({} corrected)",
+                self.name, report.uncorrectable_errors, report.corrected_errors
+            );
+        } else if report.finished {
+            info!(
+                "\"{}\" drive: scrub finished, {} errors corrected",
+                self.name, report.corrected_errors
+            );
+        } else {
+            warn!("\"{}\" drive: scrub did not finish", self.name);
+        }
+        self.persisted.last_scrub = Some(report);
+        self.save_persisted_state();
+    }
+
+    // Each BSU is snapshotted separately, at a slightly different time: the set of snapshots
+    // is NOT crash-consistent as LVM spreads the filesystem over all BSU. `sync` flushes
+    // pending writes but does not prevent new ones; freezing the filesystems blocks writes
+    // during the whole batch (including API rate limiting), at the cost of stalling
+    // applications. Without freeze, restoring a batch may need a filesystem check.
+    fn snapshot_all_bsu(&mut self, batch_epoch_s: i64) -> Result<(), Box<dyn Error>> {
+        fs::sync_with(self.executor.as_ref())?;
+        let mut frozen = Vec::new();
+        let mut result = Ok(());
+        if self.snapshot_freeze {
+            for volume in self.all_volumes() {
+                if let Err(err) = fs::freeze_with(self.executor.as_ref(), &volume.mount_path) {
+                    result = Err(err);
+                    break;
+                }
+                frozen.push(volume);
+            }
+        }
+        if result.is_ok() {
+            let batch_size = self.all_bsu.len();
+            result = snapshot::create_batch(
+                &self.all_bsu,
+                |bsu| {
+                    Snapshot::create(bsu, &self.tag_keys, batch_epoch_s, batch_size, self.dry_run)
+                },
+                |snapshot_id| Snapshot::delete_id(&self.name, snapshot_id, self.dry_run),
+            )
+            .map(|_| ());
+        }
+        // Always unfreeze, a frozen filesystem would hang the applications
+        for volume in frozen.iter().rev() {
+            if let Err(err) = fs::unfreeze_with(self.executor.as_ref(), &volume.mount_path) {
+                error!(
+                    "\"{}\" drive: cannot unfreeze {}: {}",
+                    self.name, volume.mount_path, err
+                );
+            }
+        }
+        result
+    }
+
+    fn snapshot_prune(&mut self) -> Result<(), Box<dyn Error>> {
+        let snapshots = Snapshot::fetch_drive(&self.tag_keys, &self.name)?;
+        for snapshot in snapshot::to_prune(&snapshots, self.snapshot_retention) {
+            snapshot.delete(self.dry_run)?;
+        }
+        Ok(())
     }
 
-    pub fn fs_umount(&mut self) -> Result<(), Box<dyn Error>> {
-        debug!("\"{}\" drive: fs umount", self.name);
-        let lv_path = lvm::lv_path(&self.name);
-        fs::umount(&lv_path)
+    pub fn fs_umount(&mut self, volume: &Volume) -> Result<(), Box<dyn Error>> {
+        debug!("\"{}\" drive: fs umount {}", self.name, volume.mount_path);
+        fs::umount_with(self.executor.as_ref(), &self.volume_source(volume))
     }
 
-    pub fn is_fs_extended(&mut self) -> Result<bool, Box<dyn Error>> {
-        let lv_size = lvm::get_lv_size_bytes(&self.name)?;
-        let lv_path = lvm::lv_path(&self.name);
-        let fs_size = fs::size_bytes(&lv_path)?;
+    pub fn is_fs_extended(&mut self, volume: &Volume) -> Result<bool, Box<dyn Error>> {
+        let lv_size =
+            lvm::get_lv_size_bytes_with(self.executor.as_ref(), &self.name, &volume.lv_name)?;
+        let lv_path = lvm::lv_path(&self.name, &volume.lv_name);
+        let fs_size = fs::capacity_bytes_with(
+            self.executor.as_ref(),
+            &self.filesystem,
+            &lv_path,
+            &volume.mount_path,
+        )?;
         debug!(
-            "\"{}\" drive: lv size: {}B ({}GiB), fs size: {}B ({}GiB)",
+            "\"{}\" drive: lv {} size: {}B ({}GiB), fs size: {}B ({}GiB)",
             self.name,
+            volume.lv_name,
             lv_size,
             bytes_to_gib(lv_size),
             fs_size,
             bytes_to_gib(fs_size)
         );
-        let ret = match fs_size.cmp(&lv_size) {
-            Ordering::Equal => true,
-            Ordering::Less => false,
-            Ordering::Greater => {
-                return Err(Box::new(format_err!(
-                    "\"{}\" drive: fs_size > lv_size",
-                    self.name
-                )))
-            }
-        };
-        info!("\"{}\" drive: is fs extended ? -> {}", self.name, ret);
+        if fs_size > lv_size {
+            return Err(Box::new(format_err!(
+                "\"{}\" drive: fs_size > lv_size",
+                self.name
+            )));
+        }
+        let ret = fs::is_grown(fs_size, lv_size);
+        info!(
+            "\"{}\" drive: is fs {} extended ? -> {}",
+            self.name, volume.lv_name, ret
+        );
+        self.trace_step("fs extended", ret);
         Ok(ret)
     }
 
-    pub fn fs_extend(&mut self) -> Result<(), Box<dyn Error>> {
-        debug!("\"{}\" drive: fs extend", self.name);
-        fs::extend_fs_max(&self.mount_path)
+    pub fn fs_extend(&mut self, volume: &Volume) -> Result<(), Box<dyn Error>> {
+        debug!("\"{}\" drive: fs extend {}", self.name, volume.lv_name);
+        let lv_path = lvm::lv_path(&self.name, &volume.lv_name);
+        fs::extend_fs_max_with(
+            self.executor.as_ref(),
+            &self.filesystem,
+            &lv_path,
+            &volume.mount_path,
+        )
     }
 
-    pub fn is_drive_reached_max_attached_bsu(&mut self) -> Result<bool, Box<dyn Error>> {
-        let count = self.bsu_count();
+    pub fn is_drive_reached_max_attached_bsu(&self, state: &DriveState) -> bool {
+        let count = state.all_bsu.len();
         let ret = count >= self.max_bsu_count;
         info!(
             "\"{}\" drive: is drive reached max attached BSU: (count: {}, max: {}) -> {}",
             self.name, count, self.max_bsu_count, ret
         );
-        Ok(ret)
+        ret
+    }
+
+    pub fn is_growth_blocked(&self, state: &DriveState) -> bool {
+        state.all_bsu.len() >= self.max_bsu_count
+            && self.shrink_blocked_reason().is_some()
+            && self.is_drive_low_space_left(state)
     }
 
-    pub fn is_drive_reached_max_attached_bsu_minus_one(&mut self) -> Result<bool, Box<dyn Error>> {
-        let ret = self.bsu_count() == self.max_bsu_count - 1;
+    pub fn is_drive_reached_max_attached_bsu_minus_one(&self, state: &DriveState) -> bool {
+        let ret = state.all_bsu.len() == self.max_bsu_count - 1;
         info!(
             "\"{}\" drive: is drive reached max attached BSU minus ONE (count: {}, max: {}) -> {}",
             self.name,
-            self.all_bsu.len(),
+            state.all_bsu.len(),
             self.max_bsu_count,
             ret
         );
-        Ok(ret)
+        ret
     }
 
-    pub fn is_drive_contains_smallest_bsu(&mut self) -> bool {
-        let ret = self.smallest_bsu().size_gib <= self.initial_size_gib;
+    pub fn is_drive_contains_smallest_bsu(&self, state: &DriveState) -> bool {
+        let ret = state.smallest_bsu_size_gib() <= self.initial_size_gib;
         debug!(
             "\"{}\" drive: is_drive_contains_smallest_bsu ? -> {}",
             self.name, ret
@@ -761,32 +3968,100 @@ impl Drive {
         ret
     }
 
+    pub fn is_rebalance_due(&self, now_epoch_s: i64) -> bool {
+        self.rebalance_requested
+            || self.rebalance_interval_s.is_some_and(|interval_s| {
+                snapshot::is_due(
+                    self.persisted.last_rebalance_epoch_s,
+                    interval_s,
+                    now_epoch_s,
+                )
+            })
+    }
+
+    // Removes the smallest BSU while the others hold the current usage plus headroom, one
+    // BSU per call, regardless of `min-used-space-perc`. Returns true if a BSU was removed.
+    pub fn rebalance_step(&mut self, state: &DriveState) -> Result<bool, Box<dyn Error>> {
+        if !self.is_rebalance_due(Instant::now().seconds()) {
+            return Ok(false);
+        }
+        if self.append_only_filter(ScaleAction::RemoveSmallest) == ScaleAction::None {
+            self.finish_rebalance();
+            return Ok(false);
+        }
+        let sizes_bytes: Vec<usize> = state.all_bsu.iter().map(|bsu| bsu.size_bytes).collect();
+        let target_size_bytes = self.ideal_size_bytes(state);
+        let smallest_bytes = sizes_bytes.iter().copied().min().unwrap_or(0);
+        if !rebalance_removes(&sizes_bytes, target_size_bytes)
+            || !keeps_shrink_floor(
+                &sizes_bytes,
+                smallest_bytes,
+                self.min_bsu_count,
+                self.floor_size_gib,
+            )
+        {
+            info!(
+                "\"{}\" drive: rebalanced on {} BSU for {}GiB used",
+                self.name,
+                sizes_bytes.len(),
+                bytes_to_gib(state.used_bytes)
+            );
+            self.finish_rebalance();
+            return Ok(false);
+        }
+        info!(
+            "\"{}\" drive: rebalancing, {} BSU are more than needed for {}GiB",
+            self.name,
+            sizes_bytes.len(),
+            bytes_to_gib(target_size_bytes)
+        );
+        // A removal refused by the safety checks ends the rebalance
+        if let Err(err) = self.remove_smallest_bsu() {
+            self.finish_rebalance();
+            return Err(err);
+        }
+        self.record_resize(ScaleAction::RemoveSmallest);
+        Ok(true)
+    }
+
+    fn finish_rebalance(&mut self) {
+        self.rebalance_requested = false;
+        if self.rebalance_interval_s.is_some() {
+            self.persisted.last_rebalance_epoch_s = Some(Instant::now().seconds());
+            self.save_persisted_state();
+        }
+    }
+
     pub fn remove_smallest_bsu(&mut self) -> Result<(), Box<dyn Error>> {
         debug!("\"{}\" drive: remove smallest BSU", self.name);
         let bsu = self.smallest_bsu();
         self.remove_bsu(&bsu)
     }
 
-    pub fn is_drive_low_space_left(&mut self) -> Result<bool, Box<dyn Error>> {
-        let lv_path = lvm::lv_path(&self.name);
-        let usage_per = fs::used_perc(&lv_path)?;
-        let ret = usage_per >= self.max_used_space_perc;
+    // An absolute free space threshold takes precedence over its percentage counterpart
+    pub fn is_drive_low_space_left(&self, state: &DriveState) -> bool {
+        let usage_per = state.used_perc();
+        let ret = match self.min_free_bytes {
+            Some(min_free_bytes) => state.available_bytes < min_free_bytes,
+            None => usage_per >= self.max_used_space_perc,
+        };
         debug!(
-            "\"{}\" drive: used space perc: {}, max_used_space_perc: {}",
-            self.name, usage_per, self.max_used_space_perc
+            "\"{}\" drive: used space perc: {}, max_used_space_perc: {}, available: {}B, min_free_bytes: {:?}",
+            self.name, usage_per, self.max_used_space_perc, state.available_bytes, self.min_free_bytes
         );
         info!(
             "\"{}\" drive: is drive low space left -> {}",
             self.name, ret
         );
-        Ok(ret)
+        ret
     }
 
-    pub fn is_max_space_reached(&mut self) -> bool {
+    pub fn is_max_space_reached(&self, state: &DriveState) -> bool {
         let Some(max_total_size_gib) = self.max_total_size_gib else {
             return false;
         };
-        let total_gib = self.all_bsu_size_gib();
+        // BSU sizes are whole GiB, no rounding tolerance is needed against the cap
+        let total_gib = state.all_bsu_size_gib();
         let ret = total_gib >= max_total_size_gib;
         info!(
             "\"{}\" drive: is max space reached -> {} ({}/{}Gib)",
@@ -795,40 +4070,37 @@ impl Drive {
         ret
     }
 
-    pub fn all_bsu_size_gib(&self) -> usize {
-        let mut total_size: usize = 0;
-        for bsu in self.all_bsu.iter() {
-            total_size += bsu.size_bytes;
-        }
-        bytes_to_gib_rounded(total_size)
-    }
-
     pub fn create_larger_bsu(&mut self) -> Result<(), Box<dyn Error>> {
         debug!("\"{}\" drive: create larger BSU", self.name);
-        let largest_size_gib = self.largest_bsu().size_gib as f32;
-        let new_bsu_size_gib =
-            (largest_size_gib + largest_size_gib * self.disk_scale_factor_perc).ceil() as usize;
-        let final_bsu_size = min(MAX_BSU_SIZE_GIB, new_bsu_size_gib);
-        Bsu::create_gib(
-            &self.name,
-            &self.disk_type,
-            self.disk_iops_per_gib,
-            final_bsu_size,
-        )
+        let size_gib = self.next_bsu_size_gib(true);
+        self.create_bsu_gib_within_budget(size_gib)
     }
 
     pub fn create_smaller_bsu(&mut self) -> Result<(), Box<dyn Error>> {
         debug!("\"{}\" drive: create smaller BSU", self.name);
-        let largest_size_gib = self.smallest_bsu().size_gib as f32;
-        let new_bsu_size_gib =
-            (largest_size_gib - largest_size_gib * self.disk_scale_factor_perc).ceil() as usize;
-        let final_bsu_size = max(self.initial_size_gib, new_bsu_size_gib);
-        Bsu::create_gib(
-            &self.name,
-            &self.disk_type,
-            self.disk_iops_per_gib,
-            final_bsu_size,
-        )
+        let size_gib = self.next_bsu_size_gib(false);
+        self.create_bsu_gib_within_budget(size_gib)
+    }
+
+    pub fn next_bsu_size_gib(&self, larger: bool) -> usize {
+        let sizes_gib: Vec<usize> = self.all_bsu.iter().map(|bsu| bsu.size_gib).collect();
+        let size_gib = match self.growth_strategy {
+            GrowthStrategy::Adaptive => adaptive_bsu_size_gib(
+                &sizes_gib,
+                larger,
+                self.initial_size_gib,
+                self.disk_scale_factor_perc,
+            ),
+            GrowthStrategy::Uniform => uniform_bsu_size_gib(&sizes_gib, self.initial_size_gib),
+            GrowthStrategy::Geometric => geometric_bsu_size_gib(&sizes_gib, self.initial_size_gib),
+        };
+        info!(
+            "\"{}\" drive: next BSU size with {} growth -> {}GiB",
+            self.name,
+            self.growth_strategy.to_string(),
+            size_gib
+        );
+        size_gib
     }
 
     pub fn largest_bsu(&self) -> Bsu {
@@ -859,51 +4131,119 @@ impl Drive {
             .clone()
     }
 
-    pub fn is_drive_high_space_left(&mut self) -> Result<bool, Box<dyn Error>> {
-        let lv_path = lvm::lv_path(&self.name);
-        let usage_per = fs::used_perc(&lv_path)?;
-        let ret = usage_per <= self.min_used_space_perc;
+    pub fn is_drive_high_space_left(&self, state: &DriveState) -> bool {
+        let usage_per = state.used_perc();
+        let ret = match self.max_free_bytes {
+            Some(max_free_bytes) => state.available_bytes > max_free_bytes,
+            None => usage_per <= self.min_used_space_perc,
+        };
         debug!(
-            "\"{}\" drive: used space perc: {}, low space perc: {}",
-            self.name, usage_per, self.min_used_space_perc
+            "\"{}\" drive: used space perc: {}, low space perc: {}, available: {}B, max_free_bytes: {:?}",
+            self.name, usage_per, self.min_used_space_perc, state.available_bytes, self.max_free_bytes
         );
         info!(
             "\"{}\" drive: is drive high space left -> {}",
             self.name, ret
         );
-        Ok(ret)
+        ret
+    }
+
+    pub fn record_usage_sample(&mut self, state: &DriveState) {
+        self.fs_used_bytes = state.used_bytes;
+        self.fs_size_bytes = state.size_bytes;
+        let sample = UsageSample {
+            timestamp_s: Instant::now().seconds(),
+            used_bytes: state.used_bytes,
+        };
+        if self.usage_samples.len() >= MAX_USAGE_SAMPLES {
+            self.usage_samples.pop_front();
+        }
+        self.usage_samples.push_back(sample);
+        let samples = self.usage_samples.make_contiguous();
+        self.seconds_to_full = seconds_to_full(samples, state.size_bytes);
+        self.seconds_to_max = match self.max_total_size_gib {
+            Some(max_total_size_gib) => seconds_to_full(samples, gib_to_bytes(max_total_size_gib)),
+            None => f64::INFINITY,
+        };
+        info!(
+            "\"{}\" drive: estimated seconds to full -> {}",
+            self.name, self.seconds_to_full
+        );
+        let time_to_max = format_eta_s(self.seconds_to_max);
+        if self.seconds_to_max < self.time_to_max_warning_s as f64 {
+            warn!(
+                "\"{}\" drive: projected to reach max-total-size-gib in {}, consider raising it",
+                self.name, time_to_max
+            );
+        } else {
+            info!(
+                "\"{}\" drive: projected time to max total size -> {}",
+                self.name, time_to_max
+            );
+        }
     }
 
-    pub fn has_minimal_size(&self) -> bool {
-        let total_size_gib = self.all_bsu_size_gib();
-        let ret = total_size_gib == self.initial_size_gib;
-        info!("\"{}\" drive: has minimal size -> {}", self.name, ret);
+    pub fn has_minimal_size(&self, state: &DriveState) -> bool {
+        let total_size_gib = state.all_bsu_size_gib();
+        let ideal_size_gib = self.ideal_size_gib(state);
+        let ret = total_size_gib <= self.initial_size_gib
+            || is_gib_close(total_size_gib, self.initial_size_gib)
+            || is_gib_close(total_size_gib, ideal_size_gib);
+        info!(
+            "\"{}\" drive: has minimal size -> {} (total: {}GiB, initial: {}GiB, ideal: {}GiB)",
+            self.name, ret, total_size_gib, self.initial_size_gib, ideal_size_gib
+        );
         ret
     }
 
-    pub fn ideal_size_bytes(&mut self) -> Result<usize, Box<dyn Error>> {
-        let lv_path = lvm::lv_path(&self.name);
-        let used_size_bytes = fs::used_bytes(&lv_path)? as f32;
+    pub fn ideal_size_gib(&self, state: &DriveState) -> usize {
+        bytes_to_gib_rounded(self.ideal_size_bytes(state))
+    }
+
+    pub fn ideal_size_bytes(&self, state: &DriveState) -> usize {
+        let used_size_bytes = state.used_bytes as f32;
         let middle_perc = (self.min_used_space_perc + self.max_used_space_perc) / 2.0;
         let ideal_size_bytes = (used_size_bytes / middle_perc).ceil() as usize;
+        let ideal_free_bytes = ideal_free_bytes(
+            ideal_size_bytes.saturating_sub(state.used_bytes),
+            self.min_free_bytes,
+            self.max_free_bytes,
+        );
+        let ideal_size_bytes = state.used_bytes + ideal_free_bytes;
         let ideal_size_bytes = max(ideal_size_bytes, gib_to_bytes(self.initial_size_gib));
-        let ideal_size_bytes = min(ideal_size_bytes, fs::size_bytes(&lv_path)?);
-        Ok(ideal_size_bytes)
+        let ideal_size_bytes = max(
+            ideal_size_bytes,
+            gib_to_bytes(self.floor_size_gib.unwrap_or(0)),
+        );
+        min(ideal_size_bytes, state.size_bytes)
+    }
+
+    // The ideal BSU lives next to the current ones until they are removed, a reduced one
+    // could not hold the data so the consolidation is skipped instead
+    pub fn ideal_bsu_fits(&self, state: &DriveState) -> bool {
+        let ideal_size_gib = self.ideal_size_gib(state);
+        bsu_size_within_budget(
+            ideal_size_gib,
+            state.all_bsu_size_gib(),
+            self.max_total_size_gib,
+        ) == Some(ideal_size_gib)
     }
 
     pub fn create_ideal_bsu(&mut self) -> Result<(), Box<dyn Error>> {
-        let ideal_size_gib = bytes_to_gib_rounded(self.ideal_size_bytes()?);
+        let state = self.gather_state()?;
+        let ideal_size_gib = self.ideal_size_gib(&state);
+        if !self.ideal_bsu_fits(&state) {
+            info!(
+                "\"{}\" drive: fit BSU of size {}GiB exceeds max total size, skip consolidation",
+                self.name, ideal_size_gib
+            );
+            return Ok(());
+        }
         info!(
             "\"{}\" drive: create fit BSU of size {}GiB",
             self.name, ideal_size_gib
         );
-        Bsu::create_gib(
-            &self.name,
-            &self.disk_type,
-            self.disk_iops_per_gib,
-            ideal_size_gib,
-        )?;
-        Ok(())
+        self.create_bsu_gib(ideal_size_gib)
     }
 
     pub fn remove_largest_bsu(&mut self) -> Result<(), Box<dyn Error>> {
@@ -913,22 +4253,69 @@ impl Drive {
     }
 
     pub fn remove_bsu(&mut self, bsu: &Bsu) -> Result<(), Box<dyn Error>> {
+        if self.append_only {
+            return Err(Box::new(format_err!(
+                "\"{}\" drive: cannot remove BSU {} in append only mode",
+                self.name,
+                bsu.id
+            )));
+        }
+        if self.bsu_count() <= 1 {
+            return Err(Box::new(format_err!(
+                "\"{}\" drive: cannot remove BSU {}, it is the only BSU of the drive",
+                self.name,
+                bsu.id
+            )));
+        }
+        bsu.check_managed("remove")?;
+        if !self.filesystem.can_shrink_online() {
+            return Err(Box::new(format_err!(
+                "\"{}\" drive: cannot remove BSU {}, {} filesystem cannot be shrunk",
+                self.name,
+                bsu.id,
+                self.filesystem.to_string()
+            )));
+        }
+        if !self.volumes.is_empty() {
+            return Err(Box::new(format_err!(
+                "\"{}\" drive: cannot remove BSU {}, drives with several volumes cannot be shrunk",
+                self.name,
+                bsu.id
+            )));
+        }
         info!(
             "removing BSU {} of size {}B ({}GiB)",
             bsu.id,
             bsu.size_bytes,
             bytes_to_gib(bsu.size_bytes)
         );
-        let lv_path = lvm::lv_path(&self.name);
-        let free_space_bytes = fs::available_bytes(&lv_path)?;
-        if free_space_bytes < bsu.size_bytes {
+        let state = self.gather_state()?;
+        let free_space_bytes = state.available_bytes;
+        // Shrinking btrfs relocates chunks: they must fit in space not allocated to any chunk
+        let unallocated_bytes = match self.filesystem {
+            Filesystem::Btrfs => Some(fs::btrfs_unallocated_bytes_with(
+                self.executor.as_ref(),
+                &self.mount_path,
+            )?),
+            _ => None,
+        };
+        if !removal_fits(
+            bsu.size_bytes,
+            free_space_bytes,
+            unallocated_bytes,
+            self.removal_margin_bytes,
+        ) {
             return Err(Box::new(format_err!(
-                "\"{}\" drive: cannot remove BSU. free space left: {}B ({}GiB), bsu size to remove: {} ({}GiB)",
+                "\"{}\" drive: cannot remove BSU. free space left: {}B ({}GiB), unallocated: {}, bsu size to remove: {} ({}GiB), margin: {}B",
                 self.name,
                 free_space_bytes,
                 bytes_to_gib(free_space_bytes),
+                unallocated_bytes
+                    .map(|bytes| format!("{}B", bytes))
+                    .unwrap_or_else(|| "n/a".to_string()),
                 bsu.size_bytes,
-                bytes_to_gib(bsu.size_bytes)
+                bytes_to_gib(bsu.size_bytes),
+                self.removal_margin_bytes
             )));
         }
         let Some(device_path) = &bsu.device_path else {
@@ -939,8 +4326,136 @@ impl Drive {
             )));
         };
 
-        let ideal_size_bytes = self.ideal_size_bytes()?;
-        let fs_size_bytes = fs::size_bytes(&lv_path)?;
+        if self.backend == Backend::BtrfsRaw {
+            btrfs::device_delete_with(
+                self.executor.as_ref(),
+                &btrfs::resolve_device(device_path),
+                &self.mount_path,
+            )?;
+        } else {
+            let pv_path = self.pv_device_path(&bsu.id, device_path);
+            self.release_pv(&state, bsu, &pv_path)?;
+        }
+        self.finish_bsu_removal(bsu)
+    }
+
+    // Completes a removal interrupted by a restart before anything else touches the drive.
+    // While evacuated, the PV is found from its LVM tag; once out of the VG, the BSU is found
+    // from the persisted state. Returns true when a removal was completed.
+    pub fn resume_bsu_removal(&mut self) -> Result<bool, Box<dyn Error>> {
+        if self.backend != Backend::Lvm {
+            return Ok(false);
+        }
+        if let Some(report) = lvm::get_report_with(self.executor.as_ref(), &self.name)? {
+            let moving: Vec<String> = lvm::pv_moves(&report)
+                .into_iter()
+                .map(|pv_move| pv_move.move_pv)
+                .collect();
+            for pv in lvm::removing_pvs(&report) {
+                if moving.contains(&pv.pv_name) {
+                    info!(
+                        "\"{}\" drive: pvmove of {} still in progress, its removal resumes once done",
+                        self.name, pv.pv_name
+                    );
+                    continue;
+                }
+                if !pv.is_evacuated() {
+                    warn!(
+                        "\"{}\" drive: removal of pv {} was interrupted before its data moved, cancelling it",
+                        self.name, pv.pv_name
+                    );
+                    lvm::mark_pv_removing_with(self.executor.as_ref(), &pv.pv_name, false)?;
+                    continue;
+                }
+                let Some(bsu) = self
+                    .lvm_bsu()
+                    .into_iter()
+                    .find(|bsu| bsu.device_path.as_deref() == Some(pv.pv_name.as_str()))
+                else {
+                    warn!(
+                        "\"{}\" drive: evacuated pv {} does not belong to any BSU of the drive",
+                        self.name, pv.pv_name
+                    );
+                    continue;
+                };
+                self.remember_removing_bsu(&bsu.id);
+                break;
+            }
+        }
+        let Some(bsu_id) = self.persisted.removing_bsu_id.clone() else {
+            return Ok(false);
+        };
+        let Some(bsu) = self.all_bsu.iter().find(|bsu| bsu.id == bsu_id).cloned() else {
+            info!(
+                "\"{}\" drive: BSU {} is already deleted, removal is complete",
+                self.name, bsu_id
+            );
+            self.persisted.removing_bsu_id = None;
+            self.save_persisted_state();
+            return Ok(false);
+        };
+        info!(
+            "\"{}\" drive: resuming removal of BSU {}",
+            self.name, bsu.id
+        );
+        if let Some(device_path) = &bsu.device_path {
+            let pv_path = self.pv_device_path(&bsu.id, device_path);
+            self.pv_leave_vg(&bsu.id, &pv_path)?;
+        }
+        self.finish_bsu_removal(&bsu)?;
+        Ok(true)
+    }
+
+    // Takes an evacuated PV out of the VG, each step is skipped when already done
+    fn pv_leave_vg(&mut self, bsu_id: &str, pv_path: &str) -> Result<(), Box<dyn Error>> {
+        self.remember_removing_bsu(bsu_id);
+        if let Some(report) = lvm::get_report_with(self.executor.as_ref(), &self.name)? {
+            if report.has_pv(pv_path) {
+                lvm::vg_reduce_with(self.executor.as_ref(), &self.name, pv_path)?;
+            }
+        }
+        if let Some(report) = lvm::get_report_with_no_vg_with(self.executor.as_ref())? {
+            if report.has_pv(pv_path) {
+                lvm::pv_remove_with(self.executor.as_ref(), pv_path)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn remember_removing_bsu(&mut self, bsu_id: &str) {
+        if self.persisted.removing_bsu_id.as_deref() != Some(bsu_id) {
+            self.persisted.removing_bsu_id = Some(bsu_id.to_string());
+            self.save_persisted_state();
+        }
+    }
+
+    fn finish_bsu_removal(&mut self, bsu: &Bsu) -> Result<(), Box<dyn Error>> {
+        bsu.check_managed("remove")?;
+        if self.encryption.is_some() && crypt::is_open(&bsu.id) {
+            crypt::close_with(self.executor.as_ref(), &bsu.id)?;
+        }
+        let wait = self.wait_options.clone();
+        let backend = self.volume_backend.clone();
+        if bsu.vm_id.is_some() {
+            bsu.detach(backend.as_ref(), &wait, &mut || self.early_exit())?;
+        }
+        bsu.delete(backend.as_ref())?;
+        self.lock_volume_budget().release(&self.name, 1);
+        if self.persisted.removing_bsu_id.take().is_some() {
+            self.save_persisted_state();
+        }
+        Ok(())
+    }
+
+    // Moves data out of the BSU's PV, then takes it out of the VG
+    fn release_pv(
+        &mut self,
+        state: &DriveState,
+        bsu: &Bsu,
+        device_path: &String,
+    ) -> Result<(), Box<dyn Error>> {
+        let ideal_size_bytes = self.ideal_size_bytes(state);
+        let fs_size_bytes = state.size_bytes;
         let largest_possible_new_fs_size = fs_size_bytes - bsu.size_bytes;
         // trying (when possible) to lower more than required to delete the BSU will drastically help pvmove not to move useless fs data.
         let new_fs_size_bytes = min(largest_possible_new_fs_size, ideal_size_bytes);
@@ -964,18 +4479,753 @@ impl Drive {
             bytes_to_gib(largest_possible_new_fs_size)
         );
 
-        fs::resize(&self.mount_path, new_fs_size_bytes)?;
-        let lv_path = lvm::lv_path(&self.name);
-        lvm::lv_reduce(&lv_path, new_fs_size_bytes)?;
-        lvm::pv_move(device_path)?;
-        lvm::vg_reduce(&self.name, device_path)?;
-        lvm::pv_remove(device_path)?;
+        // Marked first so a restart from now on finds the removal back from LVM
+        lvm::mark_pv_removing_with(self.executor.as_ref(), device_path, true)?;
+        fs::resize_with(
+            self.executor.as_ref(),
+            &self.filesystem,
+            &self.mount_path,
+            new_fs_size_bytes,
+        )?;
+        let main_volume = self.main_volume();
+        let lv_path = lvm::lv_path(&self.name, &main_volume.lv_name);
+        lvm::lv_reduce_with(self.executor.as_ref(), &lv_path, new_fs_size_bytes)?;
+        lvm::pv_move_with(
+            self.executor.as_ref(),
+            device_path,
+            &self.pvmove_ionice.command_prefix(),
+        )?;
+        self.pv_leave_vg(&bsu.id, device_path)?;
         // Once pv moved, be sure we can expand back lv and fs.
         self.lv_extend()?;
-        self.fs_extend()?;
+        self.fs_extend(&main_volume)
+    }
+}
 
-        bsu.detach()?;
-        bsu.delete()?;
-        Ok(())
+#[derive(Debug, Clone, PartialEq)]
+pub enum FormatAction {
+    None,
+    Format,
+    Refuse,
+}
+
+// Drives restored from snapshot must find their filesystem: formatting would wipe restored data
+fn format_action<F>(restored: bool, is_formated: F) -> Result<FormatAction, Box<dyn Error>>
+where
+    F: FnOnce() -> Result<bool, Box<dyn Error>>,
+{
+    Ok(match (is_formated()?, restored) {
+        (true, _) => FormatAction::None,
+        (false, false) => FormatAction::Format,
+        (false, true) => FormatAction::Refuse,
+    })
+}
+
+// A filesystem of the drive, backed by its own LV in the drive's VG
+#[derive(Debug, Clone, PartialEq)]
+pub struct Volume {
+    pub lv_name: String,
+    pub mount_path: String,
+    pub mount_options: Option<String>,
+    // Percentage of the drive's allocatable space, None for the main volume
+    pub size_perc: Option<usize>,
+}
+
+impl From<ConfigFileVolume> for Volume {
+    fn from(config: ConfigFileVolume) -> Self {
+        Volume {
+            lv_name: config.name,
+            mount_path: config.mount_path,
+            mount_options: config
+                .mount_options
+                .map(|options| options.trim().to_string())
+                .filter(|options| !options.is_empty()),
+            size_perc: Some(config.size_perc),
+        }
+    }
+}
+
+// Scale the largest BSU up or the smallest BSU down by `scale_factor`
+fn adaptive_bsu_size_gib(
+    sizes_gib: &[usize],
+    larger: bool,
+    initial_size_gib: usize,
+    scale_factor: f32,
+) -> usize {
+    if larger {
+        let largest_gib = sizes_gib.iter().copied().max().unwrap_or(initial_size_gib) as f32;
+        let size_gib = (largest_gib + largest_gib * scale_factor).ceil() as usize;
+        min(MAX_BSU_SIZE_GIB, size_gib)
+    } else {
+        let smallest_gib = sizes_gib.iter().copied().min().unwrap_or(initial_size_gib) as f32;
+        let size_gib = (smallest_gib - smallest_gib * scale_factor).ceil() as usize;
+        max(initial_size_gib, size_gib)
+    }
+}
+
+// All BSU keep the size of the largest one, which is the initial BSU on new drives
+fn uniform_bsu_size_gib(sizes_gib: &[usize], initial_size_gib: usize) -> usize {
+    let size_gib = sizes_gib.iter().copied().max().unwrap_or(initial_size_gib);
+    min(MAX_BSU_SIZE_GIB, max(initial_size_gib, size_gib))
+}
+
+// Each BSU doubles the largest one, so the drive size roughly doubles too
+fn geometric_bsu_size_gib(sizes_gib: &[usize], initial_size_gib: usize) -> usize {
+    let size_gib = sizes_gib
+        .iter()
+        .copied()
+        .max()
+        .map_or(initial_size_gib, |largest_gib| {
+            largest_gib.saturating_mul(2)
+        });
+    min(MAX_BSU_SIZE_GIB, max(initial_size_gib, size_gib))
+}
+
+// Size of the next BSU so the drive never exceeds `max_total_size_gib`, None once the budget
+// is exhausted
+fn bsu_size_within_budget(
+    size_gib: usize,
+    total_gib: usize,
+    max_total_size_gib: Option<usize>,
+) -> Option<usize> {
+    let Some(max_total_size_gib) = max_total_size_gib else {
+        return Some(size_gib);
+    };
+    let remaining_gib = max_total_size_gib.saturating_sub(total_gib);
+    if remaining_gib == 0 {
+        return None;
+    }
+    Some(min(size_gib, remaining_gib))
+}
+
+// Sizes of the BSU to add so the drive reaches `target_gib`, as few as possible and of even
+// sizes. Targets above the drive's limits are rejected.
+pub fn grow_to_plan(
+    sizes_gib: &[usize],
+    target_gib: usize,
+    max_bsu_count: usize,
+    max_total_size_gib: Option<usize>,
+) -> Result<Vec<usize>, String> {
+    if let Some(max_total_size_gib) = max_total_size_gib {
+        if target_gib > max_total_size_gib {
+            return Err(format!(
+                "{}GiB is above max-total-size-gib ({}GiB)",
+                target_gib, max_total_size_gib
+            ));
+        }
+    }
+    let total_gib: usize = sizes_gib.iter().sum();
+    if target_gib <= total_gib {
+        return Ok(Vec::new());
+    }
+    let missing_gib = target_gib - total_gib;
+    let count = missing_gib.div_ceil(MAX_BSU_SIZE_GIB);
+    let free_slots = max_bsu_count.saturating_sub(sizes_gib.len());
+    if count > free_slots {
+        return Err(format!(
+            "{}GiB needs {} more BSU, max-bsu-count ({}) allows {}",
+            target_gib, count, max_bsu_count, free_slots
+        ));
+    }
+    Ok((0..count)
+        .map(|index| missing_gib / count + usize::from(index < missing_gib % count))
+        .collect())
+}
+
+// Time left before another BSU can be created, None if creation is allowed now
+pub fn creation_delay(
+    last_creation_epoch_s: Option<i64>,
+    now_epoch_s: i64,
+    min_interval_s: u64,
+) -> Option<time::Duration> {
+    let elapsed_s = now_epoch_s - last_creation_epoch_s?;
+    let remaining_s = min_interval_s as i64 - elapsed_s;
+    if remaining_s <= 0 {
+        return None;
+    }
+    Some(time::Duration::from_secs(remaining_s as u64))
+}
+
+// Drives started together get evenly spread delays, distinct while they fit in the jitter
+pub fn initial_reconcile_delay_s(index: usize, count: usize, max_jitter_s: u64) -> u64 {
+    let max_jitter_s = min(max_jitter_s, RECONCILE_COOLDOWN_S);
+    if count == 0 {
+        return 0;
+    }
+    min(
+        index as u64 * (max_jitter_s + 1) / count as u64,
+        max_jitter_s,
+    )
+}
+
+// Delay before the next reconcile depending on how the previous ones failed
+pub fn retry_delay_s(policy: Option<RetryPolicy>, consecutive_failures: u32) -> u64 {
+    match policy {
+        None => RECONCILE_COOLDOWN_S,
+        Some(RetryPolicy::Soon) => TRANSIENT_RETRY_S,
+        Some(RetryPolicy::Backoff) => {
+            let exponent = consecutive_failures.saturating_sub(1).min(16);
+            min(RECONCILE_COOLDOWN_S << exponent, MAX_RETRY_DELAY_S)
+        }
+        Some(RetryPolicy::Unhealthy) => MAX_RETRY_DELAY_S,
+    }
+}
+
+pub fn is_near_full(state: &DriveState, threshold: f32) -> bool {
+    state.size_bytes > 0 && state.used_perc() >= threshold
+}
+
+// Cooldown is over once strictly more than `cooldown_s` elapsed since the last reconcile
+pub fn cooldown_remaining(elapsed_s: i64, cooldown_s: u64) -> Option<time::Duration> {
+    let remaining_s = cooldown_s as i64 - elapsed_s + 1;
+    if remaining_s <= 0 {
+        return None;
+    }
+    Some(time::Duration::from_secs(remaining_s as u64))
+}
+
+// Rounded down to LVM extents so the LV never asks more than its share
+pub fn volume_share_bytes(
+    size_perc: usize,
+    allocatable_bytes: usize,
+    extent_size_bytes: usize,
+) -> usize {
+    let share = allocatable_bytes * min(size_perc, 100) / 100;
+    match extent_size_bytes {
+        0 => share,
+        extent_size_bytes => share - share % extent_size_bytes,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Transition {
+    pub step: String,
+    pub outcome: String,
+}
+
+// Ordered list of decisions taken during a reconcile pass
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ReconcileTrace {
+    pub transitions: Vec<Transition>,
+}
+
+impl ReconcileTrace {
+    pub fn record<T: ToString>(&mut self, step: &str, outcome: T) {
+        self.transitions.push(Transition {
+            step: step.to_string(),
+            outcome: outcome.to_string(),
+        });
+    }
+}
+
+impl fmt::Display for ReconcileTrace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let transitions: Vec<String> = self
+            .transitions
+            .iter()
+            .map(|transition| format!("{}: {}", transition.step, transition.outcome))
+            .collect();
+        write!(f, "{}", transitions.join(" -> "))
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DiscoveryReport {
+    pub name: String,
+    pub target: DriveTarget,
+    pub all_bsu: Vec<Bsu>,
+    pub vg_found: bool,
+    pub lv_found: bool,
+    pub mounted: bool,
+    pub planned: Vec<String>,
+}
+
+impl fmt::Display for DiscoveryReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "\"{}\" drive (target: {})",
+            self.name,
+            self.target.to_string()
+        )?;
+        for bsu in self.all_bsu.iter() {
+            writeln!(
+                f,
+                "  BSU {}: {}GiB, {}, vm: {}, device: {}",
+                bsu.id,
+                bsu.size_gib,
+                bsu.state,
+                bsu.vm_id.as_deref().unwrap_or("none"),
+                bsu.device_path.as_deref().unwrap_or("none")
+            )?;
+        }
+        writeln!(
+            f,
+            "  vg found: {}, lv found: {}, mounted: {}",
+            self.vg_found, self.lv_found, self.mounted
+        )?;
+        if self.planned.is_empty() {
+            return write!(f, "  would do: nothing");
+        }
+        write!(f, "  would do: {}", self.planned.join(", "))
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MountCheck {
+    pub mounted: bool,
+    pub fs_type: Option<String>,
+    pub writable: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DriveHealth {
+    NeverReconciled,
+    Healthy,
+    Stale,
+    // Last reconcile failed in a way which needs an operator, see the last error
+    Unhealthy,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UsageSource {
+    Fs,
+    // Used by tests to drive scaling decisions without writing real data
+    Simulated {
+        used_bytes: usize,
+        size_bytes: usize,
+    },
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DriveState {
+    pub all_bsu: Vec<Bsu>,
+    pub used_bytes: usize,
+    pub size_bytes: usize,
+    pub available_bytes: usize,
+    // When the state was gathered, scaling decisions never read the clock themselves
+    pub now_epoch_s: i64,
+}
+
+impl DriveState {
+    pub fn used_perc(&self) -> f32 {
+        if self.size_bytes == 0 {
+            return 0.0;
+        }
+        self.used_bytes as f32 / self.size_bytes as f32
+    }
+
+    pub fn all_bsu_size_gib(&self) -> usize {
+        let total_size: usize = self.all_bsu.iter().map(|bsu| bsu.size_bytes).sum();
+        bytes_to_gib_rounded(total_size)
+    }
+
+    pub fn smallest_bsu_size_gib(&self) -> usize {
+        self.all_bsu
+            .iter()
+            .map(|bsu| bsu.size_gib)
+            .min()
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScaleAction {
+    None,
+    RemoveSmallest,
+    CreateSmaller,
+    CreateLarger,
+    RemoveLargest,
+    CreateIdeal,
+}
+
+impl ScaleAction {
+    pub fn creates_bsu(&self) -> bool {
+        matches!(
+            self,
+            ScaleAction::CreateSmaller | ScaleAction::CreateLarger | ScaleAction::CreateIdeal
+        )
+    }
+
+    // Removing the smallest BSU only makes room for a larger one, it is not a resize
+    pub fn direction(&self) -> Option<ResizeDirection> {
+        match self {
+            ScaleAction::CreateSmaller | ScaleAction::CreateLarger => Some(ResizeDirection::Grow),
+            ScaleAction::RemoveLargest | ScaleAction::CreateIdeal => Some(ResizeDirection::Shrink),
+            ScaleAction::None | ScaleAction::RemoveSmallest => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ResizeDirection {
+    Grow,
+    Shrink,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ResizeEvent {
+    pub direction: ResizeDirection,
+    pub epoch_s: i64,
+}
+
+// PV of the vg matching no device of the drive's BSU, missing PV have their own policy
+pub fn foreign_pvs(report: &lvm::Lvm, all_bsu: &[Bsu]) -> Vec<String> {
+    let bsu_devices: HashSet<&String> = all_bsu
+        .iter()
+        .filter_map(|bsu| bsu.device_path.as_ref())
+        .collect();
+    report
+        .pv
+        .iter()
+        .filter(|pv| pv.pv_missing.is_empty() && !pv.pv_name.starts_with('['))
+        .map(|pv| pv.pv_name.clone())
+        .filter(|pv_name| !bsu_devices.contains(pv_name))
+        .collect()
+}
+
+// Untagged BSU (id, pv path) whose pv belongs to the vg
+pub fn untagged_vg_members(
+    foreign_pvs: &[String],
+    untagged: &[(String, DevicePath)],
+) -> Vec<String> {
+    untagged
+        .iter()
+        .filter(|(_, pv_path)| foreign_pvs.contains(pv_path))
+        .map(|(bsu_id, _)| bsu_id.clone())
+        .collect()
+}
+
+pub fn orphan_bsu(report: &lvm::Lvm, all_bsu: &[Bsu]) -> Vec<String> {
+    all_bsu
+        .iter()
+        .filter(|bsu| match &bsu.device_path {
+            Some(device_path) => !report.pv.iter().any(|pv| pv.pv_name == *device_path),
+            None => true,
+        })
+        .map(|bsu| bsu.id.clone())
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AttachFailureAction {
+    Retry,
+    Delete,
+    Flag,
+}
+
+// BSU which failed to attach `attempts` times, only the ones created by this run are known
+// to be empty and can be deleted
+fn attach_failure_action(
+    attempts: usize,
+    max_attempts: usize,
+    policy: &AttachFailurePolicy,
+    created_by_this_run: bool,
+) -> AttachFailureAction {
+    if attempts < max_attempts {
+        return AttachFailureAction::Retry;
+    }
+    match policy {
+        AttachFailurePolicy::Delete if created_by_this_run => AttachFailureAction::Delete,
+        _ => AttachFailureAction::Flag,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UsageSample {
+    pub timestamp_s: i64,
+    pub used_bytes: usize,
+}
+
+// Free space aimed by the ideal size: the middle of the thresholds, absolute ones replacing
+// percentages (at least twice `min_free_bytes` or at most half `max_free_bytes` alone)
+pub fn ideal_free_bytes(
+    percentage_free_bytes: usize,
+    min_free_bytes: Option<usize>,
+    max_free_bytes: Option<usize>,
+) -> usize {
+    match (min_free_bytes, max_free_bytes) {
+        (Some(min_free), Some(max_free)) => min_free + max_free.saturating_sub(min_free) / 2,
+        (Some(min_free), None) => max(percentage_free_bytes, min_free.saturating_mul(2)),
+        (None, Some(max_free)) => min(percentage_free_bytes, max_free / 2),
+        (None, None) => percentage_free_bytes,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScrubAction {
+    None,
+    Start,
+    Cancel,
+}
+
+// Scrub reads the whole filesystem: it never runs while the drive moves data (pvmove,
+// resize) and a running scrub is cancelled when the drive becomes busy
+pub fn scrub_action(
+    interval_s: Option<u64>,
+    last_scrub_epoch_s: Option<i64>,
+    running: bool,
+    drive_busy: bool,
+    now_epoch_s: i64,
+) -> ScrubAction {
+    let Some(interval_s) = interval_s else {
+        return ScrubAction::None;
+    };
+    match (running, drive_busy) {
+        (true, true) => ScrubAction::Cancel,
+        (true, false) | (false, true) => ScrubAction::None,
+        (false, false) if snapshot::is_due(last_scrub_epoch_s, interval_s, now_epoch_s) => {
+            ScrubAction::Start
+        }
+        (false, false) => ScrubAction::None,
+    }
+}
+
+// The fs shrunk by the BSU size keeps `margin_bytes`, btrfs `unallocated_bytes` too as used
+// space may fit in available space while the chunks to relocate do not
+pub fn removal_fits(
+    bsu_size_bytes: usize,
+    available_bytes: usize,
+    unallocated_bytes: Option<usize>,
+    margin_bytes: usize,
+) -> bool {
+    let needed_bytes = bsu_size_bytes.saturating_add(margin_bytes);
+    available_bytes >= needed_bytes
+        && unallocated_bytes.is_none_or(|unallocated| unallocated >= needed_bytes)
+}
+
+// Removing a BSU of `removed_bytes` keeps the drive at or above its shrink floor
+pub fn keeps_shrink_floor(
+    bsu_sizes_bytes: &[usize],
+    removed_bytes: usize,
+    min_bsu_count: usize,
+    floor_size_gib: Option<usize>,
+) -> bool {
+    let remaining_bytes = bsu_sizes_bytes
+        .iter()
+        .sum::<usize>()
+        .saturating_sub(removed_bytes);
+    bsu_sizes_bytes.len() > min_bsu_count
+        && floor_size_gib.is_none_or(|floor_gib| remaining_bytes >= gib_to_bytes(floor_gib))
+}
+
+// The smallest BSU can go when the remaining ones still reach `target_size_bytes`, the
+// last BSU is never removed
+pub fn rebalance_removes(bsu_sizes_bytes: &[usize], target_size_bytes: usize) -> bool {
+    let Some(smallest_bytes) = bsu_sizes_bytes.iter().min() else {
+        return false;
+    };
+    let total_bytes: usize = bsu_sizes_bytes.iter().sum();
+    bsu_sizes_bytes.len() > 1 && total_bytes - smallest_bytes >= target_size_bytes
+}
+
+// Seconds before `size_bytes` is reached from the least squares trend of `samples`,
+// infinite without enough samples or when usage does not grow
+pub fn seconds_to_full(samples: &[UsageSample], size_bytes: usize) -> f64 {
+    if samples.len() < 2 {
+        return f64::INFINITY;
+    }
+    let count = samples.len() as f64;
+    let mean_t = samples.iter().map(|s| s.timestamp_s as f64).sum::<f64>() / count;
+    let mean_u = samples.iter().map(|s| s.used_bytes as f64).sum::<f64>() / count;
+    let mut covariance = 0.0;
+    let mut variance = 0.0;
+    for sample in samples {
+        let dt = sample.timestamp_s as f64 - mean_t;
+        covariance += dt * (sample.used_bytes as f64 - mean_u);
+        variance += dt * dt;
+    }
+    if variance == 0.0 {
+        return f64::INFINITY;
+    }
+    let slope_bytes_per_s = covariance / variance;
+    if slope_bytes_per_s <= 0.0 {
+        return f64::INFINITY;
+    }
+    let Some(last) = samples.last() else {
+        return f64::INFINITY;
+    };
+    let bytes_left = size_bytes.saturating_sub(last.used_bytes) as f64;
+    bytes_left / slope_bytes_per_s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn samples(points: &[(i64, usize)]) -> Vec<UsageSample> {
+        points
+            .iter()
+            .map(|(timestamp_s, used_bytes)| UsageSample {
+                timestamp_s: *timestamp_s,
+                used_bytes: *used_bytes,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn seconds_to_full_not_enough_samples() {
+        assert_eq!(seconds_to_full(&samples(&[]), 100), f64::INFINITY);
+        assert_eq!(seconds_to_full(&samples(&[(0, 10)]), 100), f64::INFINITY);
+    }
+
+    #[test]
+    fn seconds_to_full_flat_usage() {
+        let s = samples(&[(0, 50), (30, 50), (60, 50)]);
+        assert_eq!(seconds_to_full(&s, 100), f64::INFINITY);
+    }
+
+    #[test]
+    fn seconds_to_full_decreasing_usage() {
+        let s = samples(&[(0, 80), (30, 60), (60, 40)]);
+        assert_eq!(seconds_to_full(&s, 100), f64::INFINITY);
+    }
+
+    #[test]
+    fn seconds_to_full_linear_growth() {
+        // 1 byte per second, 40 bytes left
+        let s = samples(&[(0, 0), (30, 30), (60, 60)]);
+        assert!((seconds_to_full(&s, 100) - 40.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn seconds_to_full_already_full() {
+        let s = samples(&[(0, 50), (30, 100)]);
+        assert_eq!(seconds_to_full(&s, 100), 0.0);
+    }
+
+    #[test]
+    fn attach_failure_retry_below_max_attempts() {
+        let action = attach_failure_action(2, 5, &AttachFailurePolicy::Delete, true);
+        assert_eq!(action, AttachFailureAction::Retry);
+    }
+
+    #[test]
+    fn attach_failure_delete_created_bsu() {
+        let action = attach_failure_action(5, 5, &AttachFailurePolicy::Delete, true);
+        assert_eq!(action, AttachFailureAction::Delete);
+    }
+
+    #[test]
+    fn attach_failure_never_delete_foreign_bsu() {
+        let action = attach_failure_action(5, 5, &AttachFailurePolicy::Delete, false);
+        assert_eq!(action, AttachFailureAction::Flag);
+    }
+
+    #[test]
+    fn attach_failure_flag() {
+        let action = attach_failure_action(6, 5, &AttachFailurePolicy::Flag, true);
+        assert_eq!(action, AttachFailureAction::Flag);
+    }
+
+    #[test]
+    fn discovery_report_content() {
+        let report = DiscoveryReport {
+            name: "example".to_string(),
+            target: DriveTarget::Online,
+            all_bsu: vec![Bsu {
+                id: "vol-12345678".to_string(),
+                size_gib: 10,
+                state: "in-use".to_string(),
+                vm_id: Some("i-12345678".to_string()),
+                device_path: Some("/dev/xvdb".to_string()),
+                ..Default::default()
+            }],
+            vg_found: true,
+            lv_found: false,
+            mounted: false,
+            planned: vec!["initialize LVM".to_string(), "mount fs on /mnt".to_string()],
+        };
+        let output = report.to_string();
+        assert_eq!(
+            output,
+            "\"example\" drive (target: online)\n\
+             \x20 BSU vol-12345678: 10GiB, in-use, vm: i-12345678, device: /dev/xvdb\n\
+             \x20 vg found: true, lv found: false, mounted: false\n\
+             \x20 would do: initialize LVM, mount fs on /mnt"
+        );
+        let report = DiscoveryReport {
+            planned: Vec::new(),
+            ..report
+        };
+        assert!(report.to_string().ends_with("  would do: nothing"));
+    }
+
+    #[test]
+    fn restore_never_formats() {
+        let formated = || -> Result<bool, Box<dyn Error>> { Ok(true) };
+        let not_formated = || -> Result<bool, Box<dyn Error>> { Ok(false) };
+        let failing =
+            || -> Result<bool, Box<dyn Error>> { Err(Box::new(format_err!("xfs found"))) };
+        assert_eq!(
+            format_action(true, formated).expect("format action"),
+            FormatAction::None
+        );
+        assert_eq!(
+            format_action(false, formated).expect("format action"),
+            FormatAction::None
+        );
+        assert_eq!(
+            format_action(false, not_formated).expect("format action"),
+            FormatAction::Format
+        );
+        assert_eq!(
+            format_action(true, not_formated).expect("format action"),
+            FormatAction::Refuse
+        );
+        assert!(format_action(true, failing).is_err());
+    }
+
+    #[test]
+    fn bsu_size_fits_max_total_size() {
+        // no limit: size is kept as is
+        assert_eq!(bsu_size_within_budget(10, 100, None), Some(10));
+        // enough budget left
+        assert_eq!(bsu_size_within_budget(10, 20, Some(100)), Some(10));
+        assert_eq!(bsu_size_within_budget(10, 90, Some(100)), Some(10));
+        // remaining budget smaller than initial size: final BSU fills the budget exactly
+        assert_eq!(bsu_size_within_budget(10, 91, Some(100)), Some(9));
+        assert_eq!(bsu_size_within_budget(10, 99, Some(100)), Some(1));
+        assert_eq!(bsu_size_within_budget(10, 0, Some(4)), Some(4));
+        // budget exhausted or exceeded: nothing is created
+        assert_eq!(bsu_size_within_budget(10, 100, Some(100)), None);
+        assert_eq!(bsu_size_within_budget(10, 120, Some(100)), None);
+    }
+
+    #[test]
+    fn growth_strategies() {
+        // adaptive: scale the largest BSU up or the smallest BSU down, never below initial size
+        assert_eq!(adaptive_bsu_size_gib(&[10], true, 10, 0.2), 12);
+        assert_eq!(adaptive_bsu_size_gib(&[10, 12, 15], true, 10, 0.2), 18);
+        assert_eq!(adaptive_bsu_size_gib(&[20, 30], false, 10, 0.2), 16);
+        assert_eq!(adaptive_bsu_size_gib(&[11, 30], false, 10, 0.2), 10);
+        assert_eq!(
+            adaptive_bsu_size_gib(&[14000], true, 10, 0.2),
+            MAX_BSU_SIZE_GIB
+        );
+
+        // uniform: every BSU has the size of the largest one
+        assert_eq!(uniform_bsu_size_gib(&[], 10), 10);
+        assert_eq!(uniform_bsu_size_gib(&[10, 10, 10], 10), 10);
+        assert_eq!(uniform_bsu_size_gib(&[10, 25], 10), 25);
+        assert_eq!(uniform_bsu_size_gib(&[5], 10), 10);
+
+        // geometric: double the largest BSU
+        assert_eq!(geometric_bsu_size_gib(&[], 10), 10);
+        assert_eq!(geometric_bsu_size_gib(&[10], 10), 20);
+        assert_eq!(geometric_bsu_size_gib(&[10, 20, 40], 10), 80);
+        assert_eq!(geometric_bsu_size_gib(&[10000], 10), MAX_BSU_SIZE_GIB);
+
+        let config: ConfigFileDrive = serde_json::from_str(
+            r#"{"name": "data", "target": "online", "mount-path": "/data",
+                "growth-strategy": "geometric"}"#,
+        )
+        .expect("config with growth strategy");
+        assert_eq!(config.growth_strategy, Some(GrowthStrategy::Geometric));
+        assert_eq!(
+            "Uniform".parse::<GrowthStrategy>(),
+            Ok(GrowthStrategy::Uniform)
+        );
+        assert_eq!(GrowthStrategy::default().to_string(), "adaptive");
     }
 }
@@ -0,0 +1,82 @@
+use crate::bsu::{ApiFailure, WaitTimeoutError};
+use std::error::Error;
+use std::fmt;
+
+// Errors are still passed around as `Box<dyn Error>`: the reconcile loop finds a `BsudError`
+// back with `retry_policy` to decide when to try again. Other errors are retried with backoff.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BsudError {
+    // Outscale API call which failed after its retries
+    Api {
+        operation: String,
+        failure: ApiFailure,
+        message: String,
+    },
+    Lvm(String),
+    Fs(String),
+    // The drive's configuration does not match what is found, an operator must act
+    Config(String),
+    Timeout(WaitTimeoutError),
+    // The drive is still converging, e.g. a device which just appeared
+    Transient(String),
+}
+
+impl BsudError {
+    pub fn lvm(err: Box<dyn Error>) -> Box<dyn Error> {
+        Box::new(BsudError::Lvm(err.to_string()))
+    }
+
+    pub fn fs(err: Box<dyn Error>) -> Box<dyn Error> {
+        Box::new(BsudError::Fs(err.to_string()))
+    }
+
+    pub fn retry_policy(&self) -> RetryPolicy {
+        match self {
+            // Requests the API rejects are rejected again until something changes:
+            // credentials, permissions, quotas or parameters
+            BsudError::Api {
+                failure: ApiFailure::Status(status),
+                ..
+            } if (400..500).contains(status) && ![404, 409, 429].contains(status) => {
+                RetryPolicy::Unhealthy
+            }
+            BsudError::Api { .. } | BsudError::Lvm(_) | BsudError::Fs(_) => RetryPolicy::Backoff,
+            BsudError::Config(_) => RetryPolicy::Unhealthy,
+            BsudError::Timeout(_) | BsudError::Transient(_) => RetryPolicy::Soon,
+        }
+    }
+}
+
+impl fmt::Display for BsudError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BsudError::Api {
+                operation, message, ..
+            } => write!(f, "{} failed: {}", operation, message),
+            BsudError::Lvm(message)
+            | BsudError::Fs(message)
+            | BsudError::Config(message)
+            | BsudError::Transient(message) => write!(f, "{}", message),
+            BsudError::Timeout(timeout) => write!(f, "{}", timeout),
+        }
+    }
+}
+
+impl Error for BsudError {}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RetryPolicy {
+    // Next reconcile comes shortly
+    Soon,
+    // Reconciles are spaced more and more while failing
+    Backoff,
+    // Retried at the longest interval and reported as unhealthy
+    Unhealthy,
+}
+
+pub fn retry_policy(err: &(dyn Error + 'static)) -> RetryPolicy {
+    match err.downcast_ref::<BsudError>() {
+        Some(err) => err.retry_policy(),
+        None => RetryPolicy::Backoff,
+    }
+}
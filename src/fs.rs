@@ -1,45 +1,248 @@
+pub mod fstab;
+
+use crate::config::Filesystem;
+use crate::error::BsudError;
 use crate::utils::bytes_to_gib;
-use crate::utils::exec;
+use crate::utils::{dry_run_log, Executor, SystemExecutor};
 use easy_error::format_err;
 use lfs_core::{self, Stats};
 use log::debug;
 use proc_mounts::MountList;
+use serde::Serialize;
 use std::error::Error;
 use std::fs::create_dir;
+use std::fs::read_dir;
+use std::fs::remove_dir;
+use std::fs::remove_file;
 use std::fs::File;
 use std::io::Read;
 use std::path::Path;
 use std::path::PathBuf;
 
-pub fn device_seems_formated(device_path: &String) -> Result<bool, Box<dyn Error>> {
-    debug!("does device {} seems formated ?", device_path);
-    // Read fs header, consider unformated if reading only zeros
-    let mut buffer = [0; 1_000_000];
-    let mut file = File::open(device_path)?;
-    let n = file.read(&mut buffer[..])?;
-    for byte in &buffer[..n] {
-        if *byte != 0 {
-            debug!("does device {} seems formated ? -> true", device_path);
-            return Ok(true);
+// Superblock magics: (filesystem, absolute offset on device, magic)
+// btrfs superblock starts at 64KiB and its magic is 0x40 bytes inside it.
+const FS_MAGICS: [(Filesystem, usize, &[u8]); 3] = [
+    (Filesystem::Btrfs, 0x10040, b"_BHRfS_M"),
+    (Filesystem::Ext4, 0x438, &[0x53, 0xEF]),
+    (Filesystem::Xfs, 0, b"XFSB"),
+];
+const FS_MAGIC_READ_BYTES: u64 = 0x10048;
+pub const DISK_BY_ID_DIR: &str = "/dev/disk/by-id";
+// Other data signatures no filesystem should be created over: (name, offset, magic)
+// The swap magic ends the first 4KiB page.
+const DATA_SIGNATURES: [(&str, usize, &[u8]); 3] = [
+    ("LUKS", 0, b"LUKS\xba\xbe"),
+    ("LVM2 PV", 0x200, b"LABELONE"),
+    ("swap", 0xff6, b"SWAPSPACE2"),
+];
+
+fn detect_fs_magic(header: &[u8]) -> Option<Filesystem> {
+    FS_MAGICS
+        .iter()
+        .find(|(_, offset, magic)| header.get(*offset..*offset + magic.len()) == Some(*magic))
+        .map(|(filesystem, _, _)| filesystem.clone())
+}
+
+// A device holding another known filesystem is never considered as unformated to avoid
+// formatting over existing data.
+fn header_seems_formated(header: &[u8], filesystem: &Filesystem) -> Result<bool, Box<dyn Error>> {
+    match detect_fs_magic(header) {
+        None => Ok(false),
+        Some(found) if found == *filesystem => Ok(true),
+        Some(found) => Err(Box::new(format_err!(
+            "device contains a {} filesystem while {} is expected",
+            found.to_string(),
+            filesystem.to_string()
+        ))),
+    }
+}
+
+fn data_signature(header: &[u8]) -> Option<String> {
+    DATA_SIGNATURES
+        .iter()
+        .find(|(_, offset, magic)| header.get(*offset..*offset + magic.len()) == Some(*magic))
+        .map(|(name, _, _)| name.to_string())
+}
+
+// Any recognizable signature other than the expected filesystem
+pub fn foreign_signature(header: &[u8], filesystem: &Filesystem) -> Option<String> {
+    if let Some(found) = detect_fs_magic(header) {
+        if found != *filesystem {
+            return Some(found.to_string());
         }
     }
-    debug!("does device {} seems formated ? -> false", device_path);
-    Ok(false)
+    data_signature(header)
+}
+
+pub fn any_signature(header: &[u8]) -> Option<String> {
+    detect_fs_magic(header)
+        .map(|found| found.to_string())
+        .or_else(|| data_signature(header))
+}
+
+fn read_header(device_path: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut header = Vec::new();
+    File::open(device_path)?
+        .take(FS_MAGIC_READ_BYTES)
+        .read_to_end(&mut header)?;
+    Ok(header)
+}
+
+pub fn device_seems_formated(
+    device_path: &String,
+    filesystem: &Filesystem,
+) -> Result<bool, Box<dyn Error>> {
+    debug!("does device {} seems formated ?", device_path);
+    let header = read_header(device_path)?;
+    let ret = header_seems_formated(&header, filesystem)?;
+    debug!("does device {} seems formated ? -> {}", device_path, ret);
+    Ok(ret)
+}
+
+pub fn device_signature(device_path: &str) -> Result<Option<String>, Box<dyn Error>> {
+    Ok(any_signature(&read_header(device_path)?))
+}
+
+// Last check before formatting: whatever said the device was empty, never format over
+// data which is recognizably something else.
+pub fn check_format_target(
+    device_path: &str,
+    filesystem: &Filesystem,
+) -> Result<(), Box<dyn Error>> {
+    let header = read_header(device_path)?;
+    match foreign_signature(&header, filesystem) {
+        None => Ok(()),
+        Some(signature) => Err(Box::new(format_err!(
+            "refusing to format {} as {}: it contains a {} signature",
+            device_path,
+            filesystem.to_string(),
+            signature
+        ))),
+    }
 }
 
-pub fn format(device_path: &String) -> Result<(), Box<dyn Error>> {
-    exec("mkfs.btrfs", &[device_path])?;
+type Command = (&'static str, Vec<String>);
+
+pub fn format_command(filesystem: &Filesystem, device_path: &str) -> Command {
+    let cmd = match filesystem {
+        Filesystem::Btrfs => "mkfs.btrfs",
+        Filesystem::Ext4 => "mkfs.ext4",
+        Filesystem::Xfs => "mkfs.xfs",
+    };
+    (cmd, vec![device_path.to_string()])
+}
+
+// ext4 is grown through its device, btrfs and xfs through their mount point
+pub fn grow_command(filesystem: &Filesystem, device_path: &str, mount_target: &str) -> Command {
+    match filesystem {
+        Filesystem::Btrfs => (
+            "btrfs",
+            vec![
+                "filesystem".to_string(),
+                "resize".to_string(),
+                "max".to_string(),
+                mount_target.to_string(),
+            ],
+        ),
+        Filesystem::Ext4 => ("resize2fs", vec![device_path.to_string()]),
+        Filesystem::Xfs => ("xfs_growfs", vec![mount_target.to_string()]),
+    }
+}
+
+fn shrink_command(
+    filesystem: &Filesystem,
+    mount_target: &str,
+    new_size_bytes: usize,
+) -> Result<Command, Box<dyn Error>> {
+    if !filesystem.can_shrink_online() {
+        return Err(Box::new(format_err!(
+            "{} filesystem cannot be shrunk while mounted",
+            filesystem.to_string()
+        )));
+    }
+    Ok((
+        "btrfs",
+        vec![
+            "filesystem".to_string(),
+            "resize".to_string(),
+            format!("{}", new_size_bytes),
+            mount_target.to_string(),
+        ],
+    ))
+}
+
+fn exec_command(executor: &dyn Executor, (cmd, args): Command) -> Result<(), Box<dyn Error>> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    executor.exec_mut(cmd, &args).map_err(BsudError::fs)?;
     Ok(())
 }
 
+pub fn format(filesystem: &Filesystem, device_path: &str) -> Result<(), Box<dyn Error>> {
+    format_with(&SystemExecutor::default(), filesystem, device_path)
+}
+
+pub fn format_with(
+    executor: &dyn Executor,
+    filesystem: &Filesystem,
+    device_path: &str,
+) -> Result<(), Box<dyn Error>> {
+    exec_command(executor, format_command(filesystem, device_path))
+}
+
 pub fn is_folder(path: &String) -> bool {
     PathBuf::from(path).is_dir()
 }
 
-pub fn create_folder(path: &String) -> Result<(), Box<dyn Error>> {
+pub fn create_folder(path: &String, dry_run: bool) -> Result<(), Box<dyn Error>> {
+    if dry_run {
+        dry_run_log(&format!("create folder {}", path));
+        return Ok(());
+    }
     Ok(create_dir(path)?)
 }
 
+// Kernel device of a volume from the udev links embedding its id in `by_id_dir`, e.g.
+// "nvme-Outscale_BSU_vol-0123abcd" or "virtio-vol0123abcd". Device names reported by the API
+// may not match the kernel's node once a volume is attached again.
+pub fn device_by_id(by_id_dir: &Path, volume_id: &str) -> Option<String> {
+    let compact_id = volume_id.replace('-', "");
+    let mut links: Vec<PathBuf> = read_dir(by_id_dir)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+                return false;
+            };
+            !name.contains("-part") && (name.contains(volume_id) || name.contains(&compact_id))
+        })
+        .collect();
+    // Stable choice when several links point to the same device
+    links.sort();
+    let device = links.first()?.canonicalize().ok()?;
+    debug!("volume {} found on {:?} by id", volume_id, device);
+    Some(device.to_string_lossy().to_string())
+}
+
+// Never remove non-empty folders to avoid any data loss
+pub fn remove_empty_folder(path: &String, dry_run: bool) -> Result<bool, Box<dyn Error>> {
+    if !is_folder(path) {
+        debug!("{} is not a folder, nothing to remove", path);
+        return Ok(false);
+    }
+    if read_dir(path)?.next().is_some() {
+        debug!("{} is not empty, keep it", path);
+        return Ok(false);
+    }
+    if dry_run {
+        dry_run_log(&format!("remove folder {}", path));
+        return Ok(true);
+    }
+    remove_dir(path)?;
+    debug!("{} removed", path);
+    Ok(true)
+}
+
 pub fn is_mounted(device_path: &String, mount_target: &String) -> Result<bool, Box<dyn Error>> {
     let mount_list = MountList::new()?;
     let source = Path::new(device_path.as_str());
@@ -63,21 +266,144 @@ pub fn is_mounted(device_path: &String, mount_target: &String) -> Result<bool, B
     Ok(true)
 }
 
-pub fn mount(device_path: &String, mount_target: &String) -> Result<(), Box<dyn Error>> {
-    exec("mount", &[device_path, mount_target])?;
+pub fn detect_fs_type(mount_target: &String) -> Result<Option<String>, Box<dyn Error>> {
+    let mount_list = MountList::new()?;
+    let dest = PathBuf::from(mount_target);
+    let Some(mount_info) = mount_list.get_mount_by_dest(&dest) else {
+        debug!("nothing mounted on {}", mount_target);
+        return Ok(None);
+    };
+    Ok(Some(mount_info.fstype.clone()))
+}
+
+// Source and filesystem type of what is mounted on mount_target
+pub fn detect_mount(mount_target: &str) -> Result<Option<(String, String)>, Box<dyn Error>> {
+    let mount_list = MountList::new()?;
+    let dest = PathBuf::from(mount_target);
+    let Some(mount_info) = mount_list.get_mount_by_dest(&dest) else {
+        debug!("nothing mounted on {}", mount_target);
+        return Ok(None);
+    };
+    Ok(Some((
+        mount_info.source.to_string_lossy().to_string(),
+        mount_info.fstype.clone(),
+    )))
+}
+
+pub fn is_writable(mount_target: &String) -> bool {
+    let check_path = PathBuf::from(mount_target).join(".bsud-write-check");
+    if let Err(err) = File::create(&check_path) {
+        debug!("{} is not writable: {}", mount_target, err);
+        return false;
+    }
+    remove_file(&check_path).is_ok()
+}
+
+pub fn mount_args(device_path: &str, mount_target: &str, options: Option<&str>) -> Vec<String> {
+    let mut args = Vec::new();
+    if let Some(options) = options {
+        args.push("-o".to_string());
+        args.push(options.to_string());
+    }
+    args.push(device_path.to_string());
+    args.push(mount_target.to_string());
+    args
+}
+
+pub fn mount(
+    device_path: &str,
+    mount_target: &str,
+    options: Option<&str>,
+) -> Result<(), Box<dyn Error>> {
+    mount_with(
+        &SystemExecutor::default(),
+        device_path,
+        mount_target,
+        options,
+    )
+}
+
+pub fn mount_with(
+    executor: &dyn Executor,
+    device_path: &str,
+    mount_target: &str,
+    options: Option<&str>,
+) -> Result<(), Box<dyn Error>> {
+    exec_command(
+        executor,
+        ("mount", mount_args(device_path, mount_target, options)),
+    )
+}
+
+pub fn mount_readonly(
+    device_path: &str,
+    mount_target: &str,
+    options: Option<&str>,
+) -> Result<(), Box<dyn Error>> {
+    mount_readonly_with(
+        &SystemExecutor::default(),
+        device_path,
+        mount_target,
+        options,
+    )
+}
+
+pub fn mount_readonly_with(
+    executor: &dyn Executor,
+    device_path: &str,
+    mount_target: &str,
+    options: Option<&str>,
+) -> Result<(), Box<dyn Error>> {
+    let options = match options {
+        Some(options) => format!("ro,{}", options),
+        None => "ro".to_string(),
+    };
+    mount_with(executor, device_path, mount_target, Some(&options))
+}
+
+pub fn sync() -> Result<(), Box<dyn Error>> {
+    sync_with(&SystemExecutor::default())
+}
+
+pub fn sync_with(executor: &dyn Executor) -> Result<(), Box<dyn Error>> {
+    executor.exec_mut("sync", &[])?;
+    Ok(())
+}
+
+// Blocks all writes on the filesystem until `unfreeze` is called
+pub fn freeze(mount_target: &str) -> Result<(), Box<dyn Error>> {
+    freeze_with(&SystemExecutor::default(), mount_target)
+}
+
+pub fn freeze_with(executor: &dyn Executor, mount_target: &str) -> Result<(), Box<dyn Error>> {
+    executor.exec_mut("fsfreeze", &["--freeze", mount_target])?;
+    Ok(())
+}
+
+pub fn unfreeze(mount_target: &str) -> Result<(), Box<dyn Error>> {
+    unfreeze_with(&SystemExecutor::default(), mount_target)
+}
+
+pub fn unfreeze_with(executor: &dyn Executor, mount_target: &str) -> Result<(), Box<dyn Error>> {
+    executor.exec_mut("fsfreeze", &["--unfreeze", mount_target])?;
     Ok(())
 }
 
 pub fn umount(device_path: &String) -> Result<(), Box<dyn Error>> {
-    exec("umount", &[device_path])?;
+    umount_with(&SystemExecutor::default(), device_path)
+}
+
+pub fn umount_with(executor: &dyn Executor, device_path: &String) -> Result<(), Box<dyn Error>> {
+    executor.exec_mut("umount", &[device_path])?;
     Ok(())
 }
 
+// Filesystems spanning several devices (btrfs-raw drives) are found through their mount point
 fn get_stats(device_path: &String) -> Result<Option<Stats>, Box<dyn Error>> {
     let mut read_options = lfs_core::ReadOptions::default();
     read_options.remote_stats(false);
     for mount in lfs_core::read_mounts(&read_options)? {
-        if mount.info.fs == *device_path {
+        if mount.info.fs == *device_path || mount.info.mount_point == Path::new(device_path) {
             let stats = mount.stats?;
             return Ok(Some(stats));
         }
@@ -152,16 +478,474 @@ pub fn used_perc(device_path: &String) -> Result<f32, Box<dyn Error>> {
     Ok(available_perc)
 }
 
-pub fn extend_fs_max(mount_target: &String) -> Result<(), Box<dyn Error>> {
-    exec("btrfs", &["filesystem", "resize", "max", mount_target])?;
+pub fn extend_fs_max(
+    filesystem: &Filesystem,
+    device_path: &str,
+    mount_target: &str,
+) -> Result<(), Box<dyn Error>> {
+    extend_fs_max_with(
+        &SystemExecutor::default(),
+        filesystem,
+        device_path,
+        mount_target,
+    )
+}
+
+pub fn extend_fs_max_with(
+    executor: &dyn Executor,
+    filesystem: &Filesystem,
+    device_path: &str,
+    mount_target: &str,
+) -> Result<(), Box<dyn Error>> {
+    exec_command(
+        executor,
+        grow_command(filesystem, device_path, mount_target),
+    )
+}
+
+// statvfs leaves out the ext4 inode tables and journal or the xfs log, the size the fs was
+// grown to is read from its superblock instead. btrfs reports the whole device.
+pub fn capacity_bytes(
+    filesystem: &Filesystem,
+    device_path: &str,
+    mount_target: &str,
+) -> Result<usize, Box<dyn Error>> {
+    capacity_bytes_with(
+        &SystemExecutor::default(),
+        filesystem,
+        device_path,
+        mount_target,
+    )
+}
+
+pub fn capacity_bytes_with(
+    executor: &dyn Executor,
+    filesystem: &Filesystem,
+    device_path: &str,
+    mount_target: &str,
+) -> Result<usize, Box<dyn Error>> {
+    let output = match filesystem {
+        Filesystem::Btrfs => return size_bytes(&device_path.to_string()),
+        Filesystem::Ext4 => executor.exec_checked("dumpe2fs", &["-h", device_path])?,
+        Filesystem::Xfs => executor.exec_checked("xfs_info", &[mount_target])?,
+    };
+    let Some(capacity) = parse_capacity(filesystem, &output.stdout) else {
+        return Err(Box::new(format_err!(
+            "cannot parse {} capacity of {}",
+            filesystem.to_string(),
+            device_path
+        )));
+    };
+    Ok(capacity)
+}
+
+// Block count times block size from `dumpe2fs -h` ("Block count:  262144") or from the
+// data section of `xfs_info` ("data     =    bsize=4096   blocks=262144, imaxpct=25")
+pub fn parse_capacity(filesystem: &Filesystem, output: &str) -> Option<usize> {
+    match filesystem {
+        Filesystem::Btrfs => None,
+        Filesystem::Ext4 => {
+            let field = |name: &str| {
+                output.lines().find_map(|line| {
+                    line.strip_prefix(name)
+                        .and_then(|value| value.trim().parse::<usize>().ok())
+                })
+            };
+            Some(field("Block count:")? * field("Block size:")?)
+        }
+        Filesystem::Xfs => {
+            let data = output
+                .lines()
+                .find(|line| line.trim_start().starts_with("data"))?;
+            let field = |name: &str| {
+                data.split(|c: char| c.is_whitespace() || c == ',')
+                    .find_map(|token| token.strip_prefix(name))
+                    .and_then(|value| value.parse::<usize>().ok())
+            };
+            Some(field("blocks=")? * field("bsize=")?)
+        }
+    }
+}
+
+// resize2fs drops a trailing block group too small for its metadata and xfs_growfs a too
+// small trailing allocation group, both far below the 4MiB LVM extent
+pub const GROW_SLACK_BYTES: usize = 4 * 1024 * 1024;
+
+pub fn is_grown(capacity_bytes: usize, device_size_bytes: usize) -> bool {
+    capacity_bytes + GROW_SLACK_BYTES > device_size_bytes
+}
+
+pub fn resize(
+    filesystem: &Filesystem,
+    mount_path: &str,
+    new_size_bytes: usize,
+) -> Result<(), Box<dyn Error>> {
+    resize_with(
+        &SystemExecutor::default(),
+        filesystem,
+        mount_path,
+        new_size_bytes,
+    )
+}
+
+pub fn resize_with(
+    executor: &dyn Executor,
+    filesystem: &Filesystem,
+    mount_path: &str,
+    new_size_bytes: usize,
+) -> Result<(), Box<dyn Error>> {
+    exec_command(
+        executor,
+        shrink_command(filesystem, mount_path, new_size_bytes)?,
+    )
+}
+
+// Overall section of `btrfs filesystem usage -b`. `used_bytes` is what the devices store:
+// compressed data counts for its compressed size and RAID profiles for each copy.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BtrfsUsage {
+    pub device_size_bytes: usize,
+    pub device_allocated_bytes: usize,
+    pub device_unallocated_bytes: usize,
+    pub used_bytes: usize,
+}
+
+impl BtrfsUsage {
+    pub fn available_bytes(&self) -> usize {
+        self.device_size_bytes.saturating_sub(self.used_bytes)
+    }
+}
+
+pub fn btrfs_usage(mount_target: &str) -> Result<BtrfsUsage, Box<dyn Error>> {
+    btrfs_usage_with(&SystemExecutor::default(), mount_target)
+}
+
+pub fn btrfs_usage_with(
+    executor: &dyn Executor,
+    mount_target: &str,
+) -> Result<BtrfsUsage, Box<dyn Error>> {
+    let output = executor.exec_checked("btrfs", &["filesystem", "usage", "-b", mount_target])?;
+    let Some(usage) = parse_btrfs_usage(&output.stdout) else {
+        return Err(Box::new(format_err!(
+            "cannot parse btrfs usage of {}",
+            mount_target
+        )));
+    };
+    Ok(usage)
+}
+
+// Parse lines like "    Device allocated:\t\t  12884901888" until the first per-profile section
+pub fn parse_btrfs_usage(output: &str) -> Option<BtrfsUsage> {
+    let field = |name: &str| {
+        output
+            .lines()
+            .take_while(|line| !line.starts_with("Data,"))
+            .find_map(|line| {
+                line.trim()
+                    .strip_prefix(name)
+                    .and_then(|value| value.trim().parse::<usize>().ok())
+            })
+    };
+    Some(BtrfsUsage {
+        device_size_bytes: field("Device size:")?,
+        device_allocated_bytes: field("Device allocated:")?,
+        device_unallocated_bytes: field("Device unallocated:")?,
+        used_bytes: field("Used:")?,
+    })
+}
+
+// Space of the btrfs devices not allocated to any chunk, statvfs counts the free space of
+// allocated chunks as available while shrinking needs room to relocate whole chunks
+pub fn btrfs_unallocated_bytes(mount_target: &str) -> Result<usize, Box<dyn Error>> {
+    btrfs_unallocated_bytes_with(&SystemExecutor::default(), mount_target)
+}
+
+pub fn btrfs_unallocated_bytes_with(
+    executor: &dyn Executor,
+    mount_target: &str,
+) -> Result<usize, Box<dyn Error>> {
+    Ok(btrfs_usage_with(executor, mount_target)?.device_unallocated_bytes)
+}
+
+// Scrub runs in background, its progress is polled with `btrfs scrub status`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ScrubStatus {
+    pub running: bool,
+    pub finished: bool,
+    pub corrected_errors: u64,
+    pub uncorrectable_errors: u64,
+}
+
+pub fn scrub_start(mount_target: &str) -> Result<(), Box<dyn Error>> {
+    scrub_start_with(&SystemExecutor::default(), mount_target)
+}
+
+pub fn scrub_start_with(executor: &dyn Executor, mount_target: &str) -> Result<(), Box<dyn Error>> {
+    executor.exec_mut("btrfs", &["scrub", "start", mount_target])?;
     Ok(())
 }
 
-pub fn resize(mount_path: &str, new_size_bytes: usize) -> Result<(), Box<dyn Error>> {
-    let new_size = format!("{}", new_size_bytes);
-    exec(
-        "btrfs",
-        &["filesystem", "resize", new_size.as_str(), mount_path],
-    )?;
+pub fn scrub_cancel(mount_target: &str) -> Result<(), Box<dyn Error>> {
+    scrub_cancel_with(&SystemExecutor::default(), mount_target)
+}
+
+pub fn scrub_cancel_with(
+    executor: &dyn Executor,
+    mount_target: &str,
+) -> Result<(), Box<dyn Error>> {
+    executor.exec_mut("btrfs", &["scrub", "cancel", mount_target])?;
     Ok(())
 }
+
+pub fn scrub_status(mount_target: &str) -> Result<ScrubStatus, Box<dyn Error>> {
+    scrub_status_with(&SystemExecutor::default(), mount_target)
+}
+
+pub fn scrub_status_with(
+    executor: &dyn Executor,
+    mount_target: &str,
+) -> Result<ScrubStatus, Box<dyn Error>> {
+    let output = executor.exec_checked("btrfs", &["scrub", "status", "-R", mount_target])?;
+    Ok(parse_scrub_status(&output.stdout))
+}
+
+// Parse `btrfs scrub status -R` lines like "Status:  running" or "uncorrectable_errs: 0"
+pub fn parse_scrub_status(output: &str) -> ScrubStatus {
+    let mut status = ScrubStatus::default();
+    for line in output.lines() {
+        let Some((key, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        match key.trim() {
+            "Status" => {
+                status.running = value == "running";
+                status.finished = value == "finished";
+            }
+            "corrected_errs" => status.corrected_errors = value.parse().unwrap_or(0),
+            "uncorrectable_errs" => status.uncorrectable_errors = value.parse().unwrap_or(0),
+            _ => {}
+        }
+    }
+    status
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct DeviceStat {
+    pub device: String,
+    pub write_io_errs: u64,
+    pub read_io_errs: u64,
+    pub flush_io_errs: u64,
+    pub corruption_errs: u64,
+    pub generation_errs: u64,
+}
+
+impl DeviceStat {
+    pub fn errors(&self) -> [(&'static str, u64); 5] {
+        [
+            ("write_io", self.write_io_errs),
+            ("read_io", self.read_io_errs),
+            ("flush_io", self.flush_io_errs),
+            ("corruption", self.corruption_errs),
+            ("generation", self.generation_errs),
+        ]
+    }
+
+    pub fn has_errors(&self) -> bool {
+        self.write_io_errs
+            + self.read_io_errs
+            + self.flush_io_errs
+            + self.corruption_errs
+            + self.generation_errs
+            > 0
+    }
+}
+
+pub fn device_stats(mount_target: &String) -> Result<Vec<DeviceStat>, Box<dyn Error>> {
+    device_stats_with(&SystemExecutor::default(), mount_target)
+}
+
+pub fn device_stats_with(
+    executor: &dyn Executor,
+    mount_target: &String,
+) -> Result<Vec<DeviceStat>, Box<dyn Error>> {
+    let output = executor.exec_checked("btrfs", &["device", "stats", mount_target])?;
+    Ok(parse_device_stats(&output.stdout))
+}
+
+// Parse `btrfs device stats` lines like "[/dev/mapper/example-bsud].write_io_errs    0"
+fn parse_device_stats(output: &str) -> Vec<DeviceStat> {
+    let mut stats: Vec<DeviceStat> = Vec::new();
+    for line in output.lines() {
+        let mut fields = line.split_whitespace();
+        let (Some(key), Some(value)) = (fields.next(), fields.next()) else {
+            continue;
+        };
+        let Some((device, counter)) = key.rsplit_once('.') else {
+            continue;
+        };
+        let device = device.trim_start_matches('[').trim_end_matches(']');
+        let Ok(value) = value.parse::<u64>() else {
+            continue;
+        };
+        let index = match stats.iter().position(|stat| stat.device == device) {
+            Some(index) => index,
+            None => {
+                stats.push(DeviceStat {
+                    device: device.to_string(),
+                    ..Default::default()
+                });
+                stats.len() - 1
+            }
+        };
+        let stat = &mut stats[index];
+        match counter {
+            "write_io_errs" => stat.write_io_errs = value,
+            "read_io_errs" => stat.read_io_errs = value,
+            "flush_io_errs" => stat.flush_io_errs = value,
+            "corruption_errs" => stat.corruption_errs = value,
+            "generation_errs" => stat.generation_errs = value,
+            _ => debug!("unknown btrfs device stat {}", counter),
+        };
+    }
+    stats
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+    use std::fs::{create_dir_all, remove_dir_all, write};
+
+    fn strings(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    fn header_with(offset: usize, magic: &[u8]) -> Vec<u8> {
+        let mut header = vec![0u8; 0x10048];
+        header[offset..offset + magic.len()].copy_from_slice(magic);
+        header
+    }
+
+    #[test]
+    fn remove_empty_folder_removes_empty() {
+        let path = temp_dir().join("bsud-units-empty-folder");
+        create_dir_all(&path).expect("create folder");
+        let path_str = path.to_string_lossy().to_string();
+        assert!(remove_empty_folder(&path_str, false).expect("remove empty folder"));
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn remove_empty_folder_keeps_non_empty() {
+        let path = temp_dir().join("bsud-units-non-empty-folder");
+        create_dir_all(&path).expect("create folder");
+        write(path.join("data"), "data").expect("write file");
+        let path_str = path.to_string_lossy().to_string();
+        assert!(!remove_empty_folder(&path_str, false).expect("remove non empty folder"));
+        assert!(path.join("data").exists());
+        remove_dir_all(&path).expect("cleanup");
+    }
+
+    #[test]
+    fn remove_empty_folder_missing() {
+        let path = temp_dir().join("bsud-units-missing-folder");
+        let path_str = path.to_string_lossy().to_string();
+        assert!(!remove_empty_folder(&path_str, false).expect("remove missing folder"));
+    }
+
+    #[test]
+    fn btrfs_device_stats_parsing() {
+        let output = "[/dev/mapper/example-bsud].write_io_errs    0
+    [/dev/mapper/example-bsud].read_io_errs     2
+    [/dev/mapper/example-bsud].flush_io_errs    0
+    [/dev/mapper/example-bsud].corruption_errs  1
+    [/dev/mapper/example-bsud].generation_errs  0
+    [/dev/xvdc].write_io_errs    0
+    [/dev/xvdc].read_io_errs     0
+    [/dev/xvdc].flush_io_errs    0
+    [/dev/xvdc].corruption_errs  0
+    [/dev/xvdc].generation_errs  0
+    ";
+        let stats = parse_device_stats(output);
+        assert_eq!(
+            stats,
+            vec![
+                DeviceStat {
+                    device: "/dev/mapper/example-bsud".to_string(),
+                    read_io_errs: 2,
+                    corruption_errs: 1,
+                    ..Default::default()
+                },
+                DeviceStat {
+                    device: "/dev/xvdc".to_string(),
+                    ..Default::default()
+                },
+            ]
+        );
+        assert!(stats[0].has_errors());
+        assert!(!stats[1].has_errors());
+        assert!(parse_device_stats("garbage\n").is_empty());
+    }
+
+    #[test]
+    fn filesystem_commands() {
+        let lv = "/dev/test/bsud";
+        let mnt = "/mnt/test";
+        let matrix = [
+            (
+                Filesystem::Btrfs,
+                ("mkfs.btrfs", strings(&[lv])),
+                ("btrfs", strings(&["filesystem", "resize", "max", mnt])),
+                true,
+            ),
+            (
+                Filesystem::Ext4,
+                ("mkfs.ext4", strings(&[lv])),
+                ("resize2fs", strings(&[lv])),
+                false,
+            ),
+            (
+                Filesystem::Xfs,
+                ("mkfs.xfs", strings(&[lv])),
+                ("xfs_growfs", strings(&[mnt])),
+                false,
+            ),
+        ];
+        for (filesystem, format, grow, can_shrink) in matrix {
+            assert_eq!(format_command(&filesystem, lv), format);
+            assert_eq!(grow_command(&filesystem, lv, mnt), grow);
+            let shrink = shrink_command(&filesystem, mnt, 1024);
+            assert_eq!(shrink.is_ok(), can_shrink, "{:?}", filesystem);
+            assert_eq!(filesystem.can_shrink_online(), can_shrink);
+        }
+        assert_eq!(
+            shrink_command(&Filesystem::Btrfs, mnt, 1024).unwrap(),
+            ("btrfs", strings(&["filesystem", "resize", "1024", mnt]))
+        );
+    }
+
+    #[test]
+    fn fs_magic_detection() {
+        let btrfs = header_with(0x10040, b"_BHRfS_M");
+        let ext4 = header_with(0x438, &[0x53, 0xEF]);
+        let xfs = header_with(0, b"XFSB");
+        let zeros = vec![0u8; 0x10048];
+        // Stale metadata which is not a known superblock
+        let garbage = header_with(512, b"LABELONE LVM2 001");
+
+        assert_eq!(detect_fs_magic(&btrfs), Some(Filesystem::Btrfs));
+        assert_eq!(detect_fs_magic(&ext4), Some(Filesystem::Ext4));
+        assert_eq!(detect_fs_magic(&xfs), Some(Filesystem::Xfs));
+        assert_eq!(detect_fs_magic(&zeros), None);
+        assert_eq!(detect_fs_magic(&garbage), None);
+        assert_eq!(detect_fs_magic(&[]), None);
+
+        assert!(header_seems_formated(&btrfs, &Filesystem::Btrfs).unwrap());
+        assert!(header_seems_formated(&ext4, &Filesystem::Ext4).unwrap());
+        assert!(header_seems_formated(&xfs, &Filesystem::Xfs).unwrap());
+        assert!(!header_seems_formated(&zeros, &Filesystem::Btrfs).unwrap());
+        assert!(!header_seems_formated(&garbage, &Filesystem::Xfs).unwrap());
+        assert!(header_seems_formated(&ext4, &Filesystem::Btrfs).is_err());
+    }
+}
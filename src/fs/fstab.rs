@@ -0,0 +1,199 @@
+use crate::utils::dry_run_log;
+use log::{debug, info};
+use std::error::Error;
+use std::fs::{metadata, read_to_string, rename, set_permissions, File};
+use std::io::Write;
+use std::os::unix::fs::{chown, MetadataExt};
+use std::path::Path;
+
+pub const FSTAB_PATH: &str = "/etc/fstab";
+const MARKER_PREFIX: &str = "# managed by bsud:";
+
+// fstab fields are separated by blanks, spaces in paths must be escaped
+fn escape(field: &str) -> String {
+    field
+        .replace('\\', "\\134")
+        .replace(' ', "\\040")
+        .replace('\t', "\\011")
+}
+
+fn marker(device_path: &str) -> String {
+    format!("{} {}", MARKER_PREFIX, escape(device_path))
+}
+
+fn is_entry_for(line: &str, device_path: &str) -> bool {
+    let line = line.trim_start();
+    if line.starts_with('#') {
+        return false;
+    }
+    line.split_whitespace().next() == Some(escape(device_path).as_str())
+}
+
+fn entry_line(
+    device_path: &str,
+    mount_target: &str,
+    fs_type: &str,
+    options: Option<&str>,
+) -> String {
+    // nofail: boot must not be blocked if BSU are not attached yet
+    let options = match options {
+        Some(options) => format!("{},nofail", options),
+        None => "defaults,nofail".to_string(),
+    };
+    format!(
+        "{} {} {} {} 0 0",
+        escape(device_path),
+        escape(mount_target),
+        fs_type,
+        options
+    )
+}
+
+// Only lines starting with `device_path` and bsud markers are touched, other lines are
+// kept as they are.
+fn with_entry(content: &str, device_path: &str, entry: &str) -> String {
+    let mut lines = without_entry_lines(content, device_path);
+    lines.push(marker(device_path));
+    lines.push(entry.to_string());
+    lines.join("\n") + "\n"
+}
+
+fn without_entry(content: &str, device_path: &str) -> String {
+    let lines = without_entry_lines(content, device_path);
+    if lines.is_empty() {
+        return String::new();
+    }
+    lines.join("\n") + "\n"
+}
+
+fn without_entry_lines(content: &str, device_path: &str) -> Vec<String> {
+    let marker = marker(device_path);
+    content
+        .lines()
+        .filter(|line| line.trim() != marker && !is_entry_for(line, device_path))
+        .map(|line| line.to_string())
+        .collect()
+}
+
+fn update<F>(fstab_path: &str, dry_run: bool, transform: F) -> Result<bool, Box<dyn Error>>
+where
+    F: Fn(&str) -> String,
+{
+    let content = if Path::new(fstab_path).exists() {
+        read_to_string(fstab_path)?
+    } else {
+        String::new()
+    };
+    let new_content = transform(&content);
+    if new_content == content {
+        debug!("{} already up to date", fstab_path);
+        return Ok(false);
+    }
+    if dry_run {
+        dry_run_log(&format!("update {}:\n{}", fstab_path, new_content));
+        return Ok(false);
+    }
+    replace(fstab_path, &new_content)?;
+    Ok(true)
+}
+
+// The new fstab is fully on disk with the mode and owner of the old one before it replaces
+// it, and the rename itself is synced: a crash never leaves a truncated fstab behind.
+fn replace(fstab_path: &str, content: &str) -> Result<(), Box<dyn Error>> {
+    let tmp_path = format!("{}.bsud.tmp", fstab_path);
+    let mut tmp = File::create(&tmp_path)?;
+    tmp.write_all(content.as_bytes())?;
+    if let Ok(existing) = metadata(fstab_path) {
+        set_permissions(&tmp_path, existing.permissions())?;
+        chown(&tmp_path, Some(existing.uid()), Some(existing.gid()))?;
+    }
+    tmp.sync_all()?;
+    rename(&tmp_path, fstab_path)?;
+    let dir = match Path::new(fstab_path).parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+pub fn add(
+    fstab_path: &str,
+    device_path: &str,
+    mount_target: &str,
+    fs_type: &str,
+    options: Option<&str>,
+    dry_run: bool,
+) -> Result<(), Box<dyn Error>> {
+    let entry = entry_line(device_path, mount_target, fs_type, options);
+    if update(fstab_path, dry_run, |content| {
+        with_entry(content, device_path, &entry)
+    })? {
+        info!("{} entry added in {}", device_path, fstab_path);
+    }
+    Ok(())
+}
+
+pub fn remove(fstab_path: &str, device_path: &str, dry_run: bool) -> Result<(), Box<dyn Error>> {
+    if update(fstab_path, dry_run, |content| {
+        without_entry(content, device_path)
+    })? {
+        info!("{} entry removed from {}", device_path, fstab_path);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+    use std::fs::{write, Permissions};
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn fstab_entries() {
+        let existing = "# /etc/fstab\nUUID=1234 / ext4 defaults 0 1\n/dev/other/bsud /mnt/other btrfs defaults 0 0\n";
+        let device = "/dev/data/bsud";
+        let entry = entry_line(device, "/mnt/my data", "btrfs", Some("noatime"));
+        assert_eq!(
+            entry,
+            "/dev/data/bsud /mnt/my\\040data btrfs noatime,nofail 0 0"
+        );
+
+        let added = with_entry(existing, device, &entry);
+        assert!(added.starts_with(existing));
+        assert!(added.ends_with(&format!("{}\n", entry)));
+        // Idempotent
+        assert_eq!(with_entry(&added, device, &entry), added);
+
+        // Updated options replace the previous entry
+        let entry = entry_line(device, "/mnt/my data", "btrfs", None);
+        let updated = with_entry(&added, device, &entry);
+        assert_eq!(updated.matches("/dev/data/bsud ").count(), 1);
+        assert!(updated.contains("btrfs defaults,nofail 0 0"));
+
+        assert_eq!(without_entry(&updated, device), existing);
+        assert_eq!(without_entry(existing, device), existing);
+    }
+
+    #[test]
+    fn fstab_file_update() {
+        let path = temp_dir().join("bsud-units-fstab");
+        let path = path.to_string_lossy().to_string();
+        write(&path, "UUID=1234 / ext4 defaults 0 1\n").expect("write fstab");
+        set_permissions(&path, Permissions::from_mode(0o640)).expect("chmod fstab");
+        add(&path, "/dev/data/bsud", "/mnt/data", "xfs", None, false).expect("add");
+        let mode = std::fs::metadata(&path)
+            .expect("fstab metadata")
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o640);
+        add(&path, "/dev/data/bsud", "/mnt/data", "xfs", None, false).expect("add again");
+        let content = std::fs::read_to_string(&path).expect("read fstab");
+        assert_eq!(content.matches("/dev/data/bsud").count(), 2); // marker + entry
+        remove(&path, "/dev/data/bsud", false).expect("remove");
+        let content = std::fs::read_to_string(&path).expect("read fstab");
+        assert_eq!(content, "UUID=1234 / ext4 defaults 0 1\n");
+        std::fs::remove_file(&path).expect("cleanup");
+    }
+}
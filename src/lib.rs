@@ -1,7 +1,20 @@
 pub mod args;
 pub mod bsu;
+pub mod btrfs;
 pub mod config;
+pub mod control;
+pub mod credentials;
+pub mod crypt;
+pub mod doctor;
 pub mod drive;
+pub mod error;
 pub mod fs;
+pub mod logging;
 pub mod lvm;
+pub mod metrics;
+pub mod notify;
+pub mod scheduler;
+pub mod snapshot;
+pub mod state;
+pub mod sweeper;
 pub mod utils;
@@ -0,0 +1,118 @@
+use clap::ValueEnum;
+use log::Level;
+use serde::Serialize;
+use std::io::Write;
+
+#[derive(ValueEnum, Debug, Clone, Default, PartialEq)]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct JsonRecord<'a> {
+    pub ts: String,
+    pub level: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub drive: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bsu_id: Option<&'a str>,
+    pub msg: &'a str,
+}
+
+// Log lines concerning a drive all start with `"<name>" drive`, this prefix becomes the
+// `drive` field instead of rewriting every log call.
+fn split_drive(msg: &str) -> (Option<&str>, &str) {
+    let Some(rest) = msg.strip_prefix('"') else {
+        return (None, msg);
+    };
+    let Some((name, rest)) = rest.split_once("\" drive") else {
+        return (None, msg);
+    };
+    let rest = rest.strip_prefix(':').unwrap_or(rest);
+    (Some(name), rest.trim_start())
+}
+
+fn find_bsu_id(msg: &str) -> Option<&str> {
+    msg.split(|c: char| !(c.is_ascii_alphanumeric() || c == '-'))
+        .find(|word| word.starts_with("vol-") && word.len() > "vol-".len())
+}
+
+fn json_record<'a>(ts: String, level: Level, msg: &'a str) -> JsonRecord<'a> {
+    let (drive, msg) = split_drive(msg);
+    JsonRecord {
+        ts,
+        level: level.as_str(),
+        drive,
+        bsu_id: find_bsu_id(msg),
+        msg,
+    }
+}
+
+// Default text format is kept as env_logger's one
+pub fn init(format: &LogFormat) {
+    let mut builder = env_logger::Builder::from_default_env();
+    if *format == LogFormat::Json {
+        builder.format(|buf, record| {
+            let msg = record.args().to_string();
+            let record = json_record(buf.timestamp().to_string(), record.level(), &msg);
+            match serde_json::to_string(&record) {
+                Ok(line) => writeln!(buf, "{}", line),
+                Err(_) => writeln!(buf, "{}", msg),
+            }
+        });
+    }
+    builder.init();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_log_records() {
+        assert_eq!(
+            split_drive("\"data\" drive: start reconcile online"),
+            (Some("data"), "start reconcile online")
+        );
+        assert_eq!(
+            split_drive("\"data\" drive received Stop command"),
+            (Some("data"), "received Stop command")
+        );
+        assert_eq!(
+            split_drive("starting bsud v0.1.4"),
+            (None, "starting bsud v0.1.4")
+        );
+        assert_eq!(
+            find_bsu_id("detaching BSU vol-12ab34cd on vm Some(\"i-1\")"),
+            Some("vol-12ab34cd")
+        );
+        assert_eq!(find_bsu_id("no volume here"), None);
+
+        let record = json_record(
+            "2024-05-01T10:00:00Z".to_string(),
+            log::Level::Info,
+            "\"data\" drive: BSU vol-12ab34cd attached",
+        );
+        assert_eq!(
+            record,
+            JsonRecord {
+                ts: "2024-05-01T10:00:00Z".to_string(),
+                level: "INFO",
+                drive: Some("data"),
+                bsu_id: Some("vol-12ab34cd"),
+                msg: "BSU vol-12ab34cd attached",
+            }
+        );
+        assert_eq!(
+            serde_json::to_string(&json_record(
+                "2024-05-01T10:00:00Z".to_string(),
+                log::Level::Warn,
+                "received signal 15",
+            ))
+            .expect("json"),
+            r#"{"ts":"2024-05-01T10:00:00Z","level":"WARN","msg":"received signal 15"}"#
+        );
+    }
+}
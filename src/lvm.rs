@@ -1,37 +1,59 @@
+use crate::error::BsudError;
 use crate::utils::bytes_to_gib;
-use crate::utils::exec;
-use crate::utils::exec_bool;
-use easy_error::format_err;
+use crate::utils::exec_error_detail;
+use crate::utils::prefix_command;
+use crate::utils::ExecOutput;
+use crate::utils::Executor;
+use crate::utils::SystemExecutor;
+use crate::utils::MAX_EXEC_ERROR_OUTPUT_LEN;
 use log::debug;
 use serde_derive::Deserialize;
 use serde_derive::Serialize;
 use std::error::Error;
 
-const LV_NAME: &str = "bsud";
+pub const DEFAULT_LV_NAME: &str = "bsud";
 
-pub fn lv_path(drive_name: &str) -> String {
-    let drive_name = drive_name.replace('-', "--");
-    format!("/dev/mapper/{}-{}", drive_name, LV_NAME)
+// device-mapper escapes '-' in VG and LV names by doubling them
+pub fn lv_path(vg_name: &str, lv_name: &str) -> String {
+    format!(
+        "/dev/mapper/{}-{}",
+        vg_name.replace('-', "--"),
+        lv_name.replace('-', "--")
+    )
 }
 
 pub fn get_reports() -> Result<Vec<Lvm>, Box<dyn Error>> {
-    let output = exec(
-        "lvm",
-        &[
-            "fullreport",
-            "--all",
-            "--units",
-            "B",
-            "--reportformat",
-            "json",
-        ],
-    )?;
-    let desc: JsonDesc = serde_json::from_str(output.stdout.as_str())?;
+    get_reports_with(&SystemExecutor::default())
+}
+
+pub fn get_reports_with(executor: &dyn Executor) -> Result<Vec<Lvm>, Box<dyn Error>> {
+    let output = executor
+        .exec_checked(
+            "lvm",
+            &[
+                "fullreport",
+                "--all",
+                "--units",
+                "B",
+                "--reportformat",
+                "json",
+            ],
+        )
+        .map_err(BsudError::lvm)?;
+    let desc: JsonDesc = serde_json::from_str(output.stdout.as_str())
+        .map_err(|err| BsudError::Lvm(format!("cannot parse lvm report: {}", err)))?;
     Ok(desc.report)
 }
 
 pub fn get_report(name: &String) -> Result<Option<Lvm>, Box<dyn Error>> {
-    let all_lvm = get_reports()?;
+    get_report_with(&SystemExecutor::default(), name)
+}
+
+pub fn get_report_with(
+    executor: &dyn Executor,
+    name: &String,
+) -> Result<Option<Lvm>, Box<dyn Error>> {
+    let all_lvm = get_reports_with(executor)?;
     for lvm in all_lvm {
         let Some(vg) = lvm.vg.first() else {
             continue;
@@ -44,7 +66,11 @@ pub fn get_report(name: &String) -> Result<Option<Lvm>, Box<dyn Error>> {
 }
 
 pub fn get_report_with_no_vg() -> Result<Option<Lvm>, Box<dyn Error>> {
-    let all_lvm = get_reports()?;
+    get_report_with_no_vg_with(&SystemExecutor::default())
+}
+
+pub fn get_report_with_no_vg_with(executor: &dyn Executor) -> Result<Option<Lvm>, Box<dyn Error>> {
+    let all_lvm = get_reports_with(executor)?;
     for lvm in all_lvm {
         if lvm.vg.is_empty() {
             return Ok(Some(lvm));
@@ -54,145 +80,562 @@ pub fn get_report_with_no_vg() -> Result<Option<Lvm>, Box<dyn Error>> {
 }
 
 pub fn get_vg(name: &String) -> Result<Vg, Box<dyn Error>> {
-    let Some(lvm) = get_report(name)? else {
-        return Err(Box::new(format_err!(
+    get_vg_with(&SystemExecutor::default(), name)
+}
+
+pub fn get_vg_with(executor: &dyn Executor, name: &String) -> Result<Vg, Box<dyn Error>> {
+    let Some(lvm) = get_report_with(executor, name)? else {
+        return Err(Box::new(BsudError::Lvm(format!(
             "\"{}\" drive: Cannot get LVM description",
             name
-        )));
+        ))));
     };
     let Some(vg) = lvm.vg.into_iter().next() else {
-        return Err(Box::new(format_err!(
+        return Err(Box::new(BsudError::Lvm(format!(
             "\"{}\" drive: Cannot get VG description",
             name
-        )));
+        ))));
     };
     Ok(vg)
 }
 
-pub fn get_lv(name: &String) -> Result<Lv, Box<dyn Error>> {
-    let Some(lvm) = get_report(name)? else {
-        return Err(Box::new(format_err!(
+pub fn get_lv(vg_name: &String, lv_name: &str) -> Result<Lv, Box<dyn Error>> {
+    get_lv_with(&SystemExecutor::default(), vg_name, lv_name)
+}
+
+pub fn get_lv_with(
+    executor: &dyn Executor,
+    vg_name: &String,
+    lv_name: &str,
+) -> Result<Lv, Box<dyn Error>> {
+    let Some(lvm) = get_report_with(executor, vg_name)? else {
+        return Err(Box::new(BsudError::Lvm(format!(
             "\"{}\" drive: Cannot get LVM description",
-            name
-        )));
+            vg_name
+        ))));
     };
-    let Some(lv) = lvm.lv.into_iter().next() else {
-        return Err(Box::new(format_err!(
-            "\"{}\" drive: Cannot get LV description",
-            name
-        )));
+    let Some(lv) = lvm.lv.into_iter().find(|lv| lv.lv_name == lv_name) else {
+        return Err(Box::new(BsudError::Lvm(format!(
+            "\"{}\" drive: Cannot get LV {} description",
+            vg_name, lv_name
+        ))));
     };
     Ok(lv)
 }
 
 pub fn init_pv(path: &String) -> Result<(), Box<dyn Error>> {
-    exec("lvm", &["pvcreate", path])?;
+    init_pv_with(&SystemExecutor::default(), path)
+}
+
+pub fn init_pv_with(executor: &dyn Executor, path: &String) -> Result<(), Box<dyn Error>> {
+    executor
+        .exec_mut("lvm", &["pvcreate", path])
+        .map_err(BsudError::lvm)?;
     Ok(())
 }
 
-pub fn vg_create(vg_name: &String, initial_pv_path: &String) -> Result<(), Box<dyn Error>> {
-    exec(
-        "lvm",
-        &["vgcreate", "--alloc", "normal", vg_name, initial_pv_path],
-    )?;
+pub fn init_pvs(paths: &[String]) -> Result<(), Box<dyn Error>> {
+    init_pvs_with(&SystemExecutor::default(), paths)
+}
+
+pub fn init_pvs_with(executor: &dyn Executor, paths: &[String]) -> Result<(), Box<dyn Error>> {
+    executor
+        .exec_mut("lvm", &init_pvs_args(paths))
+        .map_err(BsudError::lvm)?;
     Ok(())
 }
 
+fn init_pvs_args(paths: &[String]) -> Vec<&str> {
+    let mut args = vec!["pvcreate"];
+    args.extend(paths.iter().map(String::as_str));
+    args
+}
+
+pub fn vg_create(
+    vg_name: &String,
+    initial_pv_path: &String,
+    alloc_policy: &str,
+    extent_size_mib: Option<usize>,
+) -> Result<(), Box<dyn Error>> {
+    vg_create_with(
+        &SystemExecutor::default(),
+        vg_name,
+        initial_pv_path,
+        alloc_policy,
+        extent_size_mib,
+    )
+}
+
+pub fn vg_create_with(
+    executor: &dyn Executor,
+    vg_name: &String,
+    initial_pv_path: &String,
+    alloc_policy: &str,
+    extent_size_mib: Option<usize>,
+) -> Result<(), Box<dyn Error>> {
+    let extent_size = extent_size_mib.map(|size_mib| format!("{}m", size_mib));
+    executor
+        .exec_mut(
+            "lvm",
+            &create_vg_args(
+                vg_name,
+                initial_pv_path,
+                alloc_policy,
+                extent_size.as_deref(),
+            ),
+        )
+        .map_err(BsudError::lvm)?;
+    Ok(())
+}
+
+// Without extent size, lvm uses its default (4MiB)
+pub fn create_vg_args<'a>(
+    vg_name: &'a str,
+    initial_pv_path: &'a str,
+    alloc_policy: &'a str,
+    extent_size: Option<&'a str>,
+) -> Vec<&'a str> {
+    let mut args = vec!["vgcreate", "--alloc", alloc_policy];
+    if let Some(extent_size) = extent_size {
+        args.extend(["--physicalextentsize", extent_size]);
+    }
+    args.extend([vg_name, initial_pv_path]);
+    args
+}
+
 pub fn vg_activate(activate: bool, vg_name: &String) -> Result<(), Box<dyn Error>> {
-    if activate {
-        exec("vgchange", &["-ay", vg_name])?;
-    } else {
-        exec("vgchange", &["-an", vg_name])?;
+    vg_activate_with(&SystemExecutor::default(), activate, vg_name)
+}
+
+pub fn vg_activate_with(
+    executor: &dyn Executor,
+    activate: bool,
+    vg_name: &String,
+) -> Result<(), Box<dyn Error>> {
+    let output = executor
+        .exec_unchecked_mut("vgchange", &[activation_flag(activate), vg_name])
+        .map_err(BsudError::lvm)?;
+    activation_result("vg", vg_name, activate, &output)
+}
+
+fn activation_flag(activate: bool) -> &'static str {
+    match activate {
+        true => "-ay",
+        false => "-an",
     }
+}
+
+// vgchange/lvchange succeed on already (de)activated VG or LV, a non zero exit means
+// the activation really failed (e.g. missing PV, device-mapper error)
+pub fn activation_result(
+    kind: &str,
+    name: &str,
+    activate: bool,
+    output: &ExecOutput,
+) -> Result<(), Box<dyn Error>> {
+    if output.success {
+        return Ok(());
+    }
+    let action = match activate {
+        true => "activate",
+        false => "deactivate",
+    };
+    let detail = exec_error_detail(&output.stdout, &output.stderr, MAX_EXEC_ERROR_OUTPUT_LEN);
+    Err(Box::new(BsudError::Lvm(format!(
+        "cannot {} {} {}: {}",
+        action, kind, name, detail
+    ))))
+}
+
+pub fn vg_activate_partial(vg_name: &String) -> Result<(), Box<dyn Error>> {
+    vg_activate_partial_with(&SystemExecutor::default(), vg_name)
+}
+
+pub fn vg_activate_partial_with(
+    executor: &dyn Executor,
+    vg_name: &String,
+) -> Result<(), Box<dyn Error>> {
+    executor
+        .exec_mut("vgchange", &["-ay", "--activationmode", "partial", vg_name])
+        .map_err(BsudError::lvm)?;
     Ok(())
 }
 
-pub fn extend_vg(vg_name: &String, pv_device_path: &String) -> Result<(), Box<dyn Error>> {
-    exec("lvm", &["vgextend", vg_name, pv_device_path])?;
+pub fn extend_vg(vg_name: &str, pv_device_paths: &[String]) -> Result<(), Box<dyn Error>> {
+    extend_vg_with(&SystemExecutor::default(), vg_name, pv_device_paths)
+}
+
+pub fn extend_vg_with(
+    executor: &dyn Executor,
+    vg_name: &str,
+    pv_device_paths: &[String],
+) -> Result<(), Box<dyn Error>> {
+    executor
+        .exec_mut("lvm", &extend_vg_args(vg_name, pv_device_paths))
+        .map_err(BsudError::lvm)?;
     Ok(())
 }
 
-pub fn create_lv(vg_name: &String) -> Result<(), Box<dyn Error>> {
-    exec(
-        "lvm",
-        &["lvcreate", "--extents", "100%FREE", "-n", LV_NAME, vg_name],
-    )?;
+fn extend_vg_args<'a>(vg_name: &'a str, pv_device_paths: &'a [String]) -> Vec<&'a str> {
+    let mut args = vec!["vgextend", vg_name];
+    args.extend(pv_device_paths.iter().map(String::as_str));
+    args
+}
+
+// Without size, the LV takes all remaining space of the VG
+pub fn create_lv(
+    vg_name: &str,
+    lv_name: &str,
+    size_bytes: Option<usize>,
+) -> Result<(), Box<dyn Error>> {
+    create_lv_with(&SystemExecutor::default(), vg_name, lv_name, size_bytes)
+}
+
+pub fn create_lv_with(
+    executor: &dyn Executor,
+    vg_name: &str,
+    lv_name: &str,
+    size_bytes: Option<usize>,
+) -> Result<(), Box<dyn Error>> {
+    let size = size_bytes.map(|size_bytes| format!("{}B", size_bytes));
+    executor
+        .exec_mut("lvm", &create_lv_args(vg_name, lv_name, size.as_deref()))
+        .map_err(BsudError::lvm)?;
     Ok(())
 }
 
+pub fn create_lv_args<'a>(
+    vg_name: &'a str,
+    lv_name: &'a str,
+    size: Option<&'a str>,
+) -> Vec<&'a str> {
+    let mut args = vec!["lvcreate"];
+    match size {
+        Some(size) => args.extend(["--size", size]),
+        None => args.extend(["--extents", "100%FREE"]),
+    };
+    args.extend(["-n", lv_name, vg_name]);
+    args
+}
+
+// Reports are requested with `--units B`, sizes look like "10733223936B"
+fn parse_size_bytes(size: &str) -> Result<usize, Box<dyn Error>> {
+    let trimmed = size.trim();
+    let digits = trimmed
+        .strip_suffix('B')
+        .or_else(|| trimmed.strip_suffix('b'))
+        .unwrap_or(trimmed);
+    if digits.is_empty() || !digits.bytes().all(|byte| byte.is_ascii_digit()) {
+        return Err(Box::new(BsudError::Lvm(format!(
+            "invalid LVM size \"{}\"",
+            size
+        ))));
+    }
+    Ok(digits.parse::<usize>()?)
+}
+
 pub fn get_vg_size_bytes(vg_name: &String) -> Result<usize, Box<dyn Error>> {
-    let mut vg = get_vg(vg_name)?;
-    vg.vg_size.pop();
-    let vg_size_bytes = vg.vg_size.parse::<usize>()?;
-    Ok(vg_size_bytes)
+    get_vg_size_bytes_with(&SystemExecutor::default(), vg_name)
 }
 
-pub fn get_lv_size_bytes(vg_name: &String) -> Result<usize, Box<dyn Error>> {
-    let mut lv = get_lv(vg_name)?;
-    lv.lv_size.pop();
-    let lv_size_bytes = lv.lv_size.parse::<usize>()?;
-    Ok(lv_size_bytes)
+pub fn get_vg_size_bytes_with(
+    executor: &dyn Executor,
+    vg_name: &String,
+) -> Result<usize, Box<dyn Error>> {
+    let vg = get_vg_with(executor, vg_name)?;
+    parse_size_bytes(&vg.vg_size)
+}
+
+pub fn get_vg_extent_size_bytes(vg_name: &String) -> Result<usize, Box<dyn Error>> {
+    get_vg_extent_size_bytes_with(&SystemExecutor::default(), vg_name)
+}
+
+pub fn get_vg_extent_size_bytes_with(
+    executor: &dyn Executor,
+    vg_name: &String,
+) -> Result<usize, Box<dyn Error>> {
+    let vg = get_vg_with(executor, vg_name)?;
+    parse_size_bytes(&vg.vg_extent_size)
+}
+
+pub fn get_vg_free_bytes(vg_name: &String) -> Result<usize, Box<dyn Error>> {
+    get_vg_free_bytes_with(&SystemExecutor::default(), vg_name)
+}
+
+pub fn get_vg_free_bytes_with(
+    executor: &dyn Executor,
+    vg_name: &String,
+) -> Result<usize, Box<dyn Error>> {
+    get_vg_with(executor, vg_name)?.free_bytes()
+}
+
+pub fn get_lv_size_bytes(vg_name: &String, lv_name: &str) -> Result<usize, Box<dyn Error>> {
+    get_lv_size_bytes_with(&SystemExecutor::default(), vg_name, lv_name)
+}
+
+pub fn get_lv_size_bytes_with(
+    executor: &dyn Executor,
+    vg_name: &String,
+    lv_name: &str,
+) -> Result<usize, Box<dyn Error>> {
+    let lv = get_lv_with(executor, vg_name, lv_name)?;
+    parse_size_bytes(&lv.lv_size)
 }
 
 pub fn lv_extend_full(lv_path: &String) -> Result<(), Box<dyn Error>> {
-    exec("lvm", &["lvextend", "--extents", "+100%FREE", lv_path])?;
+    lv_extend_full_with(&SystemExecutor::default(), lv_path)
+}
+
+pub fn lv_extend_full_with(
+    executor: &dyn Executor,
+    lv_path: &String,
+) -> Result<(), Box<dyn Error>> {
+    executor
+        .exec_mut("lvm", &["lvextend", "--extents", "+100%FREE", lv_path])
+        .map_err(BsudError::lvm)?;
     Ok(())
 }
 
-pub fn lv_activate(activate: bool, lv_name: &String) -> Result<(), Box<dyn Error>> {
-    if activate {
-        exec("lvchange", &["-ay", lv_name])?;
-    } else {
-        exec("lvchange", &["-an", lv_name])?;
-    }
+pub fn lv_extend_size(lv_path: &String, new_size_bytes: usize) -> Result<(), Box<dyn Error>> {
+    lv_extend_size_with(&SystemExecutor::default(), lv_path, new_size_bytes)
+}
+
+pub fn lv_extend_size_with(
+    executor: &dyn Executor,
+    lv_path: &String,
+    new_size_bytes: usize,
+) -> Result<(), Box<dyn Error>> {
+    executor
+        .exec_mut(
+            "lvm",
+            &[
+                "lvextend",
+                "--size",
+                format!("{}B", new_size_bytes).as_str(),
+                lv_path,
+            ],
+        )
+        .map_err(BsudError::lvm)?;
     Ok(())
 }
 
+pub fn lv_activate(activate: bool, lv_name: &String) -> Result<(), Box<dyn Error>> {
+    lv_activate_with(&SystemExecutor::default(), activate, lv_name)
+}
+
+pub fn lv_activate_with(
+    executor: &dyn Executor,
+    activate: bool,
+    lv_name: &String,
+) -> Result<(), Box<dyn Error>> {
+    let output = executor
+        .exec_unchecked_mut("lvchange", &[activation_flag(activate), lv_name])
+        .map_err(BsudError::lvm)?;
+    activation_result("lv", lv_name, activate, &output)
+}
+
 pub fn vg_scan() -> Result<(), Box<dyn Error>> {
-    exec("vgscan", &[])?;
+    vg_scan_with(&SystemExecutor::default())
+}
+
+pub fn vg_scan_with(executor: &dyn Executor) -> Result<(), Box<dyn Error>> {
+    executor
+        .exec_checked("vgscan", &[])
+        .map_err(BsudError::lvm)?;
     Ok(())
 }
 
-pub fn pv_move(pv_path: &String) -> Result<(), Box<dyn Error>> {
-    exec_bool("lvm", &["pvmove", pv_path])?;
+// `prefix` runs pvmove through another command, e.g. ionice to yield to the workload's IO
+pub fn pv_move_command<'a>(
+    prefix: &[&'a str],
+    pv_path: Option<&'a str>,
+) -> (&'a str, Vec<&'a str>) {
+    let mut args = vec!["pvmove"];
+    args.extend(pv_path);
+    prefix_command(prefix, "lvm", &args)
+}
+
+pub fn pv_move(pv_path: &String, prefix: &[&str]) -> Result<(), Box<dyn Error>> {
+    pv_move_with(&SystemExecutor::default(), pv_path, prefix)
+}
+
+pub fn pv_move_with(
+    executor: &dyn Executor,
+    pv_path: &String,
+    prefix: &[&str],
+) -> Result<(), Box<dyn Error>> {
+    let (cmd, args) = pv_move_command(prefix, Some(pv_path));
+    executor.exec_bool_mut(cmd, &args).map_err(BsudError::lvm)?;
     Ok(())
 }
 
-pub fn pv_move_no_arg() -> Result<(), Box<dyn Error>> {
-    exec_bool("lvm", &["pvmove"])?;
+pub fn pv_move_no_arg(prefix: &[&str]) -> Result<(), Box<dyn Error>> {
+    pv_move_no_arg_with(&SystemExecutor::default(), prefix)
+}
+
+pub fn pv_move_no_arg_with(executor: &dyn Executor, prefix: &[&str]) -> Result<(), Box<dyn Error>> {
+    let (cmd, args) = pv_move_command(prefix, None);
+    executor.exec_bool_mut(cmd, &args).map_err(BsudError::lvm)?;
     Ok(())
 }
 
+// An in-flight (or interrupted) pvmove shows up as a hidden LV having `move_pv` set
+#[derive(Debug, Clone, PartialEq)]
+pub struct PvMove {
+    pub lv_name: String,
+    pub move_pv: String,
+    pub copy_percent: f32,
+}
+
+pub fn pv_moves(report: &Lvm) -> Vec<PvMove> {
+    report
+        .lv
+        .iter()
+        .filter(|lv| !lv.move_pv.is_empty())
+        .map(|lv| PvMove {
+            lv_name: lv.lv_name.clone(),
+            move_pv: lv.move_pv.clone(),
+            copy_percent: lv.copy_percent.parse::<f32>().unwrap_or(0.0),
+        })
+        .collect()
+}
+
+pub fn get_pv_moves(vg_name: &String) -> Result<Vec<PvMove>, Box<dyn Error>> {
+    get_pv_moves_with(&SystemExecutor::default(), vg_name)
+}
+
+pub fn get_pv_moves_with(
+    executor: &dyn Executor,
+    vg_name: &String,
+) -> Result<Vec<PvMove>, Box<dyn Error>> {
+    Ok(get_report_with(executor, vg_name)?
+        .map(|report| pv_moves(&report))
+        .unwrap_or_default())
+}
+
+// Tracks when a move last progressed to tell a slow move from a stuck one
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PvMoveProgress {
+    pub copy_percent: f32,
+    pub since_epoch_s: i64,
+}
+
+impl PvMoveProgress {
+    pub fn update(previous: Option<PvMoveProgress>, copy_percent: f32, now_epoch_s: i64) -> Self {
+        match previous {
+            Some(previous) if copy_percent <= previous.copy_percent => previous,
+            _ => PvMoveProgress {
+                copy_percent,
+                since_epoch_s: now_epoch_s,
+            },
+        }
+    }
+
+    pub fn stalled_s(&self, now_epoch_s: i64) -> i64 {
+        now_epoch_s - self.since_epoch_s
+    }
+}
+
 pub fn lv_reduce(lv_path: &String, new_fs_size_bytes: usize) -> Result<(), Box<dyn Error>> {
+    lv_reduce_with(&SystemExecutor::default(), lv_path, new_fs_size_bytes)
+}
+
+pub fn lv_reduce_with(
+    executor: &dyn Executor,
+    lv_path: &String,
+    new_fs_size_bytes: usize,
+) -> Result<(), Box<dyn Error>> {
     debug!(
         "lv_reduce {} of size {}B ({}GiB)",
         lv_path,
         new_fs_size_bytes,
         bytes_to_gib(new_fs_size_bytes)
     );
-    exec(
-        "lvm",
-        &[
-            "lvreduce",
-            "--yes",
-            "--size",
-            format!("{}B", new_fs_size_bytes).as_str(),
-            lv_path,
-        ],
-    )?;
+    executor
+        .exec_mut(
+            "lvm",
+            &[
+                "lvreduce",
+                "--yes",
+                "--size",
+                format!("{}B", new_fs_size_bytes).as_str(),
+                lv_path,
+            ],
+        )
+        .map_err(BsudError::lvm)?;
     Ok(())
 }
 
 pub fn vg_reduce(name: &str, device_path: &str) -> Result<(), Box<dyn Error>> {
-    exec("lvm", &["vgreduce", name, device_path])?;
+    vg_reduce_with(&SystemExecutor::default(), name, device_path)
+}
+
+pub fn vg_reduce_with(
+    executor: &dyn Executor,
+    name: &str,
+    device_path: &str,
+) -> Result<(), Box<dyn Error>> {
+    executor
+        .exec_mut("lvm", &["vgreduce", name, device_path])
+        .map_err(BsudError::lvm)?;
     Ok(())
 }
 
 pub fn pv_remove(device_path: &str) -> Result<(), Box<dyn Error>> {
-    exec("lvm", &["pvremove", device_path])?;
+    pv_remove_with(&SystemExecutor::default(), device_path)
+}
+
+pub fn pv_remove_with(executor: &dyn Executor, device_path: &str) -> Result<(), Box<dyn Error>> {
+    executor
+        .exec_mut("lvm", &["pvremove", device_path])
+        .map_err(BsudError::lvm)?;
+    Ok(())
+}
+
+// A PV being evacuated is tagged and made non allocatable: LVM keeps track of an interrupted
+// removal and never extends LVs back onto the PV meanwhile.
+pub const REMOVING_PV_TAG: &str = "bsud-removing";
+
+pub fn mark_pv_removing_args(device_path: &str, removing: bool) -> Vec<&str> {
+    let (allocatable, tag_action) = if removing {
+        ("n", "--addtag")
+    } else {
+        ("y", "--deltag")
+    };
+    vec![
+        "pvchange",
+        "--allocatable",
+        allocatable,
+        tag_action,
+        REMOVING_PV_TAG,
+        device_path,
+    ]
+}
+
+pub fn mark_pv_removing(device_path: &str, removing: bool) -> Result<(), Box<dyn Error>> {
+    mark_pv_removing_with(&SystemExecutor::default(), device_path, removing)
+}
+
+pub fn mark_pv_removing_with(
+    executor: &dyn Executor,
+    device_path: &str,
+    removing: bool,
+) -> Result<(), Box<dyn Error>> {
+    executor
+        .exec_mut("lvm", &mark_pv_removing_args(device_path, removing))
+        .map_err(BsudError::lvm)?;
     Ok(())
 }
 
+pub fn removing_pvs(report: &Lvm) -> Vec<Pv> {
+    report
+        .pv
+        .iter()
+        .filter(|pv| pv.pv_tags.split(',').any(|tag| tag == REMOVING_PV_TAG))
+        .cloned()
+        .collect()
+}
+
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(default)]
 pub struct JsonDesc {
@@ -210,6 +653,19 @@ pub struct Lvm {
 }
 
 impl Lvm {
+    // LVs reported inactive, activation is skipped when all are already active
+    pub fn inactive_lvs(&self) -> Vec<String> {
+        self.lv
+            .iter()
+            .filter(|lv| lv.lv_active != "active")
+            .map(|lv| lv.lv_name.clone())
+            .collect()
+    }
+
+    pub fn has_pv(&self, device_path: &str) -> bool {
+        self.pv.iter().any(|pv| pv.pv_name == device_path)
+    }
+
     pub fn devices(&self) -> Vec<String> {
         let mut all_devices = Vec::new();
         for pv in self.pv.iter() {
@@ -259,6 +715,21 @@ pub struct Vg {
     pub vg_mda_copies: String,
 }
 
+impl Vg {
+    pub fn missing_pv_count(&self) -> usize {
+        self.vg_missing_pv_count.parse::<usize>().unwrap_or(0)
+    }
+
+    pub fn free_bytes(&self) -> Result<usize, Box<dyn Error>> {
+        parse_size_bytes(&self.vg_free)
+    }
+
+    // vg_partial is set to "partial" when one or more PV are missing
+    pub fn is_partial(&self) -> bool {
+        self.vg_partial == "partial" || self.missing_pv_count() > 0
+    }
+}
+
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(default)]
 pub struct Pv {
@@ -292,6 +763,13 @@ pub struct Pv {
     pub pv_device_id_type: String,
 }
 
+impl Pv {
+    // No extent allocated anymore: the PV can leave its VG
+    pub fn is_evacuated(&self) -> bool {
+        self.pv_pe_alloc_count.trim() == "0"
+    }
+}
+
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(default)]
 pub struct Lv {
@@ -464,3 +942,68 @@ pub struct Seg {
     pub vdo_header_size: String,
     pub lv_uuid: String,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vg_extend_single_command() {
+        let pvs = vec!["/dev/xvdb".to_string(), "/dev/xvdc".to_string()];
+        assert_eq!(
+            extend_vg_args("example", &pvs),
+            vec!["vgextend", "example", "/dev/xvdb", "/dev/xvdc"]
+        );
+    }
+
+    #[test]
+    fn pv_init_single_command() {
+        let pvs = vec!["/dev/xvdb".to_string(), "/dev/xvdc".to_string()];
+        assert_eq!(
+            init_pvs_args(&pvs),
+            vec!["pvcreate", "/dev/xvdb", "/dev/xvdc"]
+        );
+    }
+
+    #[test]
+    fn lvm_size_parsing() {
+        assert_eq!(parse_size_bytes("12345B").expect("with unit"), 12345);
+        assert_eq!(parse_size_bytes("12345").expect("bare number"), 12345);
+        assert_eq!(parse_size_bytes(" 4194304B ").expect("padded"), 4194304);
+        for malformed in ["", "B", "12.5B", "<10.00g", "12345K", "-1B", "12 345B"] {
+            let err = parse_size_bytes(malformed).expect_err(malformed);
+            assert!(err.to_string().contains("invalid LVM size"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn vg_free_bytes() {
+        let vg = Vg {
+            vg_size: "21466447872B".to_string(),
+            vg_free: "4194304B".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(vg.free_bytes().expect("free bytes"), 4194304);
+        let full = Vg {
+            vg_free: "0B".to_string(),
+            ..vg.clone()
+        };
+        assert_eq!(full.free_bytes().expect("no free bytes"), 0);
+        let unknown = Vg {
+            vg_free: String::new(),
+            ..vg
+        };
+        assert!(unknown.free_bytes().is_err());
+
+        let report: JsonDesc = serde_json::from_str(
+            r#"{"report": [{"vg": [{"vg_name": "data", "vg_size": "10733223936B", "vg_free": "0B"}]}]}"#,
+        )
+        .expect("lvm report");
+        assert_eq!(
+            report.report[0].vg[0]
+                .free_bytes()
+                .expect("report free bytes"),
+            0
+        );
+    }
+}
@@ -1,68 +1,288 @@
 mod args;
 mod bsu;
+mod btrfs;
 mod config;
+mod control;
+mod credentials;
+mod crypt;
+mod doctor;
 mod drive;
+mod error;
 mod fs;
+mod logging;
 mod lvm;
+mod metrics;
+mod notify;
+mod scheduler;
+mod snapshot;
+mod state;
+mod sweeper;
 mod utils;
 
-use drive::Drives;
+use drive::{Drive, DriveCmd, Drives};
 use log::{debug, error, info, warn};
-use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
 use signal_hook::iterator::Signals;
 use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread::{self, sleep};
+use std::time::Duration;
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
+const ONCE_POLL_INTERVAL: Duration = Duration::from_millis(200);
 
 fn main() {
-    env_logger::init();
+    let args = args::parse();
+    logging::init(&args.log_format);
     info!("starting bsud v{}", VERSION);
 
-    let mut signals = Signals::new([SIGINT, SIGTERM]).expect("cannot init signals");
+    // Signals are handled from the very beginning so startup can be interrupted
+    let mut signals = Signals::new([SIGINT, SIGTERM, SIGHUP]).expect("cannot init signals");
+    let interrupted = Arc::new(AtomicBool::new(false));
+    let (signal_sender, signal_receiver) = channel::<i32>();
+    {
+        let interrupted = interrupted.clone();
+        thread::spawn(move || {
+            for sig in signals.forever() {
+                warn!("received signal {:?}", sig);
+                if matches!(sig, SIGINT | SIGTERM) {
+                    interrupted.store(true, Ordering::SeqCst);
+                }
+                if signal_sender.send(sig).is_err() {
+                    break;
+                }
+            }
+        });
+    }
 
-    let args = args::parse();
     debug!("args: {:?}", args);
 
-    let config = config::load(args.config_path).unwrap_or_else(|err| {
+    // Does not need credentials, VM metadata nor lvm/btrfs: can run anywhere
+    if args.check_config {
+        check_config(&args.config_path);
+    }
+
+    if let Some(args::Command::Doctor) = args.command {
+        doctor(&args.config_path);
+    }
+
+    let config = config::load(args.config_path.clone()).unwrap_or_else(|err| {
         error!("cannot init configuration: {}", err);
         exit(1)
     });
     debug!("config: {:?}", config);
+    if let Err(err) = config::discover_vm_config(config.metadata_timeout()) {
+        error!("cannot discover VM metadata: {}", err);
+        exit(1);
+    }
+    if let Err(err) = config::configure_cloud(&config) {
+        error!("cannot configure cloud access: {}", err);
+        exit(1);
+    }
 
-    if !pre_flight_check() {
+    if !pre_flight_check(&config) {
         exit(1);
     }
 
-    let mut drives = Drives::run(config).unwrap_or_else(|err| {
+    if interrupted.load(Ordering::SeqCst) {
+        exit(0);
+    }
+
+    if args.discover_only {
+        discover_only(config);
+    }
+
+    if args.dry_run {
+        dry_run(config, &interrupted);
+    }
+
+    if args.once {
+        once(config, &interrupted, &signal_receiver);
+    }
+
+    if let Some(address) = &config.metrics_address {
+        if let Err(err) = metrics::serve(address) {
+            error!("cannot serve metrics on {}: {}", address, err);
+            exit(1);
+        }
+    }
+
+    let watchdog_staleness_s = config
+        .watchdog_staleness_s
+        .unwrap_or(notify::DEFAULT_WATCHDOG_STALENESS_S);
+    let control_socket = config
+        .control_socket
+        .clone()
+        .unwrap_or_else(|| control::DEFAULT_CONTROL_SOCKET.to_string());
+    let orphan_sweeper = config.orphan_sweeper();
+    let mut drives = Drives::run(config, &interrupted, false).unwrap_or_else(|err| {
+        if interrupted.load(Ordering::SeqCst) {
+            info!("{}", err);
+            exit(0);
+        }
         error!("cannot run drives: {}", err);
         exit(1);
     });
 
-    loop {
-        for sig in signals.forever() {
-            warn!("received signal {:?}", sig);
-            match sig {
-                SIGINT | SIGTERM => {
-                    if let Err(err) = drives.stop() {
-                        error!("error while stopping: {}", err);
-                    }
-                    exit(0);
+    if let Err(err) = control::serve(&control_socket, drives.router()) {
+        error!(
+            "cannot listen on control socket {}: {}",
+            control_socket, err
+        );
+    }
+    notify::ready();
+    notify::spawn_watchdog(watchdog_staleness_s);
+    if let Some(orphan_sweeper) = orphan_sweeper {
+        sweeper::spawn(orphan_sweeper, drives.router());
+    }
+
+    for sig in signal_receiver.iter() {
+        match sig {
+            SIGINT | SIGTERM => {
+                notify::stopping();
+                if let Err(err) = drives.stop() {
+                    error!("error while stopping: {}", err);
                 }
-                _unmanaged_sig => {
-                    error!("unmanaged signal {}", _unmanaged_sig);
+                exit(0);
+            }
+            SIGHUP => reload(&mut drives, &args.config_path),
+            _unmanaged_sig => {
+                error!("unmanaged signal {}", _unmanaged_sig);
+            }
+        }
+    }
+    exit(1)
+}
+
+// An invalid configuration is rejected as a whole, running drives are left untouched
+fn reload(drives: &mut Drives, config_path: &str) {
+    info!("reloading configuration from {}", config_path);
+    let config = match config::load(config_path.to_string()) {
+        Ok(config) => config,
+        Err(err) => {
+            error!("cannot reload configuration, keeping current one: {}", err);
+            return;
+        }
+    };
+    debug!("config: {:?}", config);
+    // VM metadata does not change, only credentials may have
+    if let Err(err) = config::configure_cloud(&config) {
+        error!("cannot reload configuration, keeping current one: {}", err);
+        return;
+    }
+    if let Err(err) = drives.reload(config) {
+        error!("error while reloading configuration: {}", err);
+    }
+}
+
+fn check_config(config_path: &str) -> ! {
+    let config = config::check(config_path.to_string()).unwrap_or_else(|err| {
+        error!("invalid configuration {}: {}", config_path, err);
+        exit(1)
+    });
+    for drive_config in config.drives {
+        let (_sender, receiver) = channel::<DriveCmd>();
+        let drive = Drive::new(drive_config, receiver);
+        println!("{}", drive.settings());
+    }
+    println!("configuration {} is valid", config_path);
+    exit(0)
+}
+
+fn doctor(config_path: &str) -> ! {
+    println!("bsud {}", doctor::LONG_VERSION.replace('\n', ", "));
+    let checks = doctor::run(config_path);
+    for check in checks.iter() {
+        println!("{}", check);
+    }
+    exit(doctor::exit_code(&checks))
+}
+
+fn discover_only(config: config::Config) -> ! {
+    let mut code = 0;
+    for drive_config in config.drives {
+        let (_sender, receiver) = channel::<DriveCmd>();
+        let mut drive = Drive::new(drive_config, receiver).with_dry_run(true);
+        match drive.discover() {
+            Ok(report) => println!("{}", report),
+            Err(err) => {
+                error!("\"{}\" drive: cannot discover: {}", drive.name, err);
+                code = 1;
+            }
+        }
+    }
+    exit(code)
+}
+
+fn dry_run(config: config::Config, interrupted: &AtomicBool) -> ! {
+    let mut drives = Drives::run(config, interrupted, true).unwrap_or_else(|err| {
+        error!("cannot run drives: {}", err);
+        exit(1);
+    });
+    drives.wait();
+    exit(0)
+}
+
+// Drives stop by themselves after their pass, a signal stops the ones still running
+fn once(config: config::Config, interrupted: &AtomicBool, signals: &Receiver<i32>) -> ! {
+    utils::set_run_once(true);
+    let mut drives = Drives::run(config, interrupted, false).unwrap_or_else(|err| {
+        error!("cannot run drives: {}", err);
+        exit(1);
+    });
+    while !drives.is_idle() {
+        match signals.recv_timeout(ONCE_POLL_INTERVAL) {
+            Ok(SIGINT | SIGTERM) => {
+                if let Err(err) = drives.stop() {
+                    error!("error while stopping: {}", err);
                 }
+                break;
+            }
+            Ok(_) | Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => sleep(ONCE_POLL_INTERVAL),
+        }
+    }
+    drives.wait();
+    let mut code = 0;
+    for (name, result) in drives.pass_results() {
+        match result {
+            Ok(()) => println!("\"{}\" drive: reconcile succeeded", name),
+            Err(err) => {
+                println!("\"{}\" drive: reconcile failed: {}", name, err);
+                code = 1;
             }
         }
     }
+    exit(code)
 }
 
-fn pre_flight_check() -> bool {
+// Refuse to start when a binary needed by the configured drives is missing, instead of
+// failing in the middle of a reconcile
+fn pre_flight_check(config: &config::Config) -> bool {
     let mut ret = true;
-    if utils::exec("lvm", &["fullreport"]).is_err() {
+    let binaries = doctor::required_binaries(&config.drives);
+    let path = std::env::var("PATH").unwrap_or_default();
+    let missing = doctor::missing_binaries(&binaries, &path);
+    if !missing.is_empty() {
+        error!(
+            "missing required binaries: {}, check installation and PATH",
+            missing.join(", ")
+        );
+        ret = false;
+    }
+    if binaries.contains(&"lvm")
+        && !missing.contains(&"lvm")
+        && utils::exec("lvm", &["fullreport"]).is_err()
+    {
         error!("cannot get lvm fullreport, check installation and permissions");
         ret = false;
     }
-    if utils::exec("btrfs", &["filesystem", "show"]).is_err() {
+    if binaries.contains(&"btrfs")
+        && !missing.contains(&"btrfs")
+        && utils::exec("btrfs", &["filesystem", "show"]).is_err()
+    {
         error!("cannot get run btrfs, check installation and permissions");
         ret = false;
     }
@@ -0,0 +1,295 @@
+use crate::fs::DeviceStat;
+use lazy_static::lazy_static;
+use log::{debug, error, info};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt::Write as FmtWrite;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::RwLock;
+use std::thread;
+use std::time;
+
+// A stalled client must not block the scrapes of the others
+const REQUEST_TIMEOUT_S: u64 = 5;
+
+lazy_static! {
+    pub static ref METRICS: RwLock<Metrics> = RwLock::new(Metrics::default());
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum BsuOperation {
+    Create,
+    Delete,
+    Attach,
+    Detach,
+    Modify,
+}
+
+impl ToString for BsuOperation {
+    fn to_string(&self) -> String {
+        match self {
+            BsuOperation::Create => "create".to_string(),
+            BsuOperation::Delete => "delete".to_string(),
+            BsuOperation::Attach => "attach".to_string(),
+            BsuOperation::Detach => "detach".to_string(),
+            BsuOperation::Modify => "modify".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DriveMetrics {
+    pub size_bytes: usize,
+    pub used_bytes: usize,
+    pub bsu_count: usize,
+    pub reconcile_duration_s: f64,
+    pub reconcile_failed: bool,
+    pub seconds_to_full: f64,
+    pub seconds_to_max: f64,
+    pub device_stats: Vec<DeviceStat>,
+    // 0 when the drive was never scrubbed
+    pub last_scrub_epoch_s: i64,
+    pub scrub_uncorrectable_errors: u64,
+}
+
+// Name, help and value of a per-drive gauge
+type Gauge = (&'static str, &'static str, fn(&DriveMetrics) -> f64);
+
+#[derive(Debug, Clone, Default)]
+pub struct Metrics {
+    pub drives: BTreeMap<String, DriveMetrics>,
+    pub operations: BTreeMap<(String, BsuOperation), u64>,
+}
+
+impl Metrics {
+    // https://prometheus.io/docs/instrumenting/exposition_formats/#text-based-format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let gauges: [Gauge; 9] = [
+            (
+                "bsud_drive_size_bytes",
+                "Drive filesystem size in bytes",
+                |m| m.size_bytes as f64,
+            ),
+            (
+                "bsud_drive_used_bytes",
+                "Drive filesystem used bytes",
+                |m| m.used_bytes as f64,
+            ),
+            ("bsud_bsu_count", "Number of BSU in the drive", |m| {
+                m.bsu_count as f64
+            }),
+            (
+                "bsud_reconcile_duration_seconds",
+                "Duration of the last reconcile pass",
+                |m| m.reconcile_duration_s,
+            ),
+            (
+                "bsud_reconcile_failed",
+                "1 if the last reconcile pass failed",
+                |m| m.reconcile_failed as u8 as f64,
+            ),
+            (
+                "bsud_drive_seconds_to_full",
+                "Projected seconds before the drive filesystem is full, +Inf without projection",
+                |m| m.seconds_to_full,
+            ),
+            (
+                "bsud_drive_seconds_to_max",
+                "Projected seconds before the drive reaches its max total size, +Inf without projection",
+                |m| m.seconds_to_max,
+            ),
+            (
+                "bsud_drive_last_scrub_timestamp_seconds",
+                "Start time of the last scrub, 0 if never scrubbed",
+                |m| m.last_scrub_epoch_s as f64,
+            ),
+            (
+                "bsud_drive_scrub_uncorrectable_errors",
+                "Uncorrectable errors found by the last scrub",
+                |m| m.scrub_uncorrectable_errors as f64,
+            ),
+        ];
+        for (name, help, value) in gauges {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} gauge", name);
+            for (drive, metrics) in self.drives.iter() {
+                let _ = writeln!(
+                    out,
+                    "{}{{drive=\"{}\"}} {}",
+                    name,
+                    escape_label(drive),
+                    render_value(value(metrics))
+                );
+            }
+        }
+        let name = "bsud_drive_device_errors";
+        let _ = writeln!(out, "# HELP {} btrfs error counters of drive devices", name);
+        let _ = writeln!(out, "# TYPE {} gauge", name);
+        for (drive, metrics) in self.drives.iter() {
+            for stat in metrics.device_stats.iter() {
+                for (kind, count) in stat.errors() {
+                    let _ = writeln!(
+                        out,
+                        "{}{{drive=\"{}\",device=\"{}\",kind=\"{}\"}} {}",
+                        name,
+                        escape_label(drive),
+                        escape_label(&stat.device),
+                        kind,
+                        count
+                    );
+                }
+            }
+        }
+        let name = "bsud_bsu_operations_total";
+        let _ = writeln!(out, "# HELP {} BSU operations requested by bsud", name);
+        let _ = writeln!(out, "# TYPE {} counter", name);
+        for ((drive, operation), count) in self.operations.iter() {
+            let _ = writeln!(
+                out,
+                "{}{{drive=\"{}\",operation=\"{}\"}} {}",
+                name,
+                escape_label(drive),
+                operation.to_string(),
+                count
+            );
+        }
+        out
+    }
+}
+
+// Drive names come from the configuration and may contain any character
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+// Prometheus spells infinity "+Inf" where Rust displays "inf"
+fn render_value(value: f64) -> String {
+    if value.is_infinite() && value > 0.0 {
+        return "+Inf".to_string();
+    }
+    value.to_string()
+}
+
+pub fn update_drive(drive_name: &str, drive_metrics: DriveMetrics) {
+    match METRICS.write() {
+        Ok(mut metrics) => {
+            metrics.drives.insert(drive_name.to_string(), drive_metrics);
+        }
+        Err(err) => error!("cannot update metrics: {}", err),
+    };
+}
+
+pub fn count_operation(drive_name: &str, operation: BsuOperation) {
+    match METRICS.write() {
+        Ok(mut metrics) => {
+            *metrics
+                .operations
+                .entry((drive_name.to_string(), operation))
+                .or_insert(0) += 1;
+        }
+        Err(err) => error!("cannot update metrics: {}", err),
+    };
+}
+
+pub fn serve(address: &str) -> Result<(), Box<dyn Error>> {
+    let listener = TcpListener::bind(address)?;
+    info!("serving metrics on http://{}/metrics", address);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if let Err(err) = handle_request(stream) {
+                        debug!("metrics request: {}", err);
+                    }
+                }
+                Err(err) => error!("metrics connection: {}", err),
+            }
+        }
+    });
+    Ok(())
+}
+
+fn handle_request(mut stream: TcpStream) -> Result<(), Box<dyn Error>> {
+    let timeout = time::Duration::from_secs(REQUEST_TIMEOUT_S);
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    let mut request_line = String::new();
+    BufReader::new(&stream).read_line(&mut request_line)?;
+    let path = request_line.split_whitespace().nth(1).unwrap_or_default();
+    let (status, body) = match path {
+        "/metrics" => ("200 OK", METRICS.read()?.render()),
+        _ => ("404 Not Found", String::new()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metrics_render() {
+        let mut metrics = Metrics::default();
+        metrics.drives.insert(
+            "data".to_string(),
+            DriveMetrics {
+                size_bytes: 100,
+                used_bytes: 40,
+                bsu_count: 2,
+                reconcile_duration_s: 1.5,
+                reconcile_failed: true,
+                seconds_to_full: f64::INFINITY,
+                seconds_to_max: f64::INFINITY,
+                device_stats: vec![DeviceStat {
+                    device: "/dev/xvdb".to_string(),
+                    corruption_errs: 4,
+                    ..Default::default()
+                }],
+                last_scrub_epoch_s: 0,
+                scrub_uncorrectable_errors: 2,
+            },
+        );
+        metrics
+            .operations
+            .insert(("data".to_string(), BsuOperation::Create), 3);
+        let out = metrics.render();
+        assert!(out.contains("# TYPE bsud_drive_size_bytes gauge\n"));
+        assert!(out.contains("bsud_drive_size_bytes{drive=\"data\"} 100\n"));
+        assert!(out.contains("bsud_drive_used_bytes{drive=\"data\"} 40\n"));
+        assert!(out.contains("bsud_bsu_count{drive=\"data\"} 2\n"));
+        assert!(out.contains("bsud_reconcile_duration_seconds{drive=\"data\"} 1.5\n"));
+        assert!(out.contains("bsud_reconcile_failed{drive=\"data\"} 1\n"));
+        assert!(out.contains("bsud_drive_seconds_to_full{drive=\"data\"} +Inf\n"));
+        assert!(out.contains("bsud_drive_seconds_to_max{drive=\"data\"} +Inf\n"));
+        assert!(out.contains("bsud_drive_last_scrub_timestamp_seconds{drive=\"data\"} 0\n"));
+        assert!(out.contains("bsud_drive_scrub_uncorrectable_errors{drive=\"data\"} 2\n"));
+        assert!(out.contains("# TYPE bsud_drive_device_errors gauge\n"));
+        assert!(out.contains(
+            "bsud_drive_device_errors{drive=\"data\",device=\"/dev/xvdb\",kind=\"corruption\"} 4\n"
+        ));
+        assert!(out.contains(
+            "bsud_drive_device_errors{drive=\"data\",device=\"/dev/xvdb\",kind=\"write_io\"} 0\n"
+        ));
+        assert!(out.contains("# TYPE bsud_bsu_operations_total counter\n"));
+        assert!(out.contains("bsud_bsu_operations_total{drive=\"data\",operation=\"create\"} 3\n"));
+
+        let mut metrics = Metrics::default();
+        metrics
+            .drives
+            .insert(r#"da"ta\"#.to_string(), DriveMetrics::default());
+        let out = metrics.render();
+        assert!(out.contains(r#"bsud_bsu_count{drive="da\"ta\\"} 0"#));
+    }
+}
@@ -0,0 +1,164 @@
+use datetime::Instant;
+use lazy_static::lazy_static;
+use log::{debug, error, warn};
+use std::cmp::max;
+use std::collections::HashMap;
+use std::env;
+use std::error::Error;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::sync::RwLock;
+use std::thread;
+use std::time;
+
+// https://www.freedesktop.org/software/systemd/man/latest/sd_notify.html
+const NOTIFY_SOCKET_ENV: &str = "NOTIFY_SOCKET";
+const WATCHDOG_USEC_ENV: &str = "WATCHDOG_USEC";
+pub const DEFAULT_WATCHDOG_STALENESS_S: u64 = 1800;
+
+lazy_static! {
+    // Last successful reconcile (epoch) of each running drive
+    static ref LAST_RECONCILE_SUCCESS: RwLock<HashMap<String, Option<i64>>> =
+        RwLock::new(HashMap::new());
+}
+
+// Running drives which never succeeded must also be watched
+pub fn register_drive(drive_name: &str) {
+    match LAST_RECONCILE_SUCCESS.write() {
+        Ok(mut successes) => {
+            successes.entry(drive_name.to_string()).or_insert(None);
+        }
+        Err(err) => error!("cannot register drive: {}", err),
+    };
+}
+
+pub fn record_reconcile_success(drive_name: &str, epoch_s: i64) {
+    match LAST_RECONCILE_SUCCESS.write() {
+        Ok(mut successes) => {
+            successes.insert(drive_name.to_string(), Some(epoch_s));
+        }
+        Err(err) => error!("cannot record reconcile success: {}", err),
+    };
+}
+
+pub fn forget_drive(drive_name: &str) {
+    match LAST_RECONCILE_SUCCESS.write() {
+        Ok(mut successes) => {
+            successes.remove(drive_name);
+        }
+        Err(err) => error!("cannot forget drive reconcile success: {}", err),
+    };
+}
+
+// Without NOTIFY_SOCKET (not started by systemd), nothing is sent
+pub fn notify(state: &str) -> Result<bool, Box<dyn Error>> {
+    let Ok(socket_path) = env::var(NOTIFY_SOCKET_ENV) else {
+        return Ok(false);
+    };
+    notify_to(&socket_path, state)?;
+    Ok(true)
+}
+
+// Paths starting with '@' are abstract sockets
+fn notify_to(socket_path: &str, state: &str) -> Result<(), Box<dyn Error>> {
+    let socket = UnixDatagram::unbound()?;
+    match socket_path.strip_prefix('@') {
+        Some(name) => {
+            let address = SocketAddr::from_abstract_name(name.as_bytes())?;
+            socket.send_to_addr(state.as_bytes(), &address)?;
+        }
+        None => {
+            socket.send_to(state.as_bytes(), socket_path)?;
+        }
+    };
+    debug!("notified {} to {}", state.trim(), socket_path);
+    Ok(())
+}
+
+pub fn ready() {
+    if let Err(err) = notify("READY=1") {
+        error!("cannot notify readiness: {}", err);
+    }
+}
+
+pub fn stopping() {
+    if let Err(err) = notify("STOPPING=1") {
+        error!("cannot notify stopping: {}", err);
+    }
+}
+
+pub fn watchdog_interval(watchdog_usec: &str) -> Option<time::Duration> {
+    match watchdog_usec.trim().parse::<u64>() {
+        Ok(0) | Err(_) => None,
+        Ok(usec) => Some(time::Duration::from_micros(usec)),
+    }
+}
+
+// Drives which did not succeed yet are given `staleness_s` from supervisor start. Without
+// any drive, bsud has nothing to be stuck on.
+pub fn is_healthy(
+    last_successes: &[Option<i64>],
+    started_epoch_s: i64,
+    now_epoch_s: i64,
+    staleness_s: u64,
+) -> bool {
+    if last_successes.is_empty() {
+        return true;
+    }
+    last_successes
+        .iter()
+        .map(|last| max(last.unwrap_or(started_epoch_s), started_epoch_s))
+        .any(|last| now_epoch_s - last < staleness_s as i64)
+}
+
+// Pings systemd at half the watchdog interval, only while at least one drive reconciled
+// recently: if all drives are stuck, systemd restarts bsud.
+pub fn spawn_watchdog(staleness_s: u64) {
+    let Ok(watchdog_usec) = env::var(WATCHDOG_USEC_ENV) else {
+        return;
+    };
+    let Some(interval) = watchdog_interval(&watchdog_usec) else {
+        warn!("invalid {} value \"{}\"", WATCHDOG_USEC_ENV, watchdog_usec);
+        return;
+    };
+    let started_epoch_s = Instant::now().seconds();
+    thread::spawn(move || loop {
+        let last_successes: Vec<Option<i64>> = match LAST_RECONCILE_SUCCESS.read() {
+            Ok(successes) => successes.values().cloned().collect(),
+            Err(err) => {
+                error!("cannot read reconcile successes: {}", err);
+                Vec::new()
+            }
+        };
+        let now_epoch_s = Instant::now().seconds();
+        if is_healthy(&last_successes, started_epoch_s, now_epoch_s, staleness_s) {
+            if let Err(err) = notify("WATCHDOG=1") {
+                error!("cannot notify watchdog: {}", err);
+            }
+        } else {
+            warn!(
+                "no drive reconciled during the last {}s, stop pinging watchdog",
+                staleness_s
+            );
+        }
+        thread::sleep(interval / 2);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+
+    #[test]
+    fn watchdog_notify_socket() {
+        let path = temp_dir().join("bsud-units-notify.sock");
+        let _ = std::fs::remove_file(&path);
+        let receiver = std::os::unix::net::UnixDatagram::bind(&path).expect("bind notify socket");
+        notify_to(&path.to_string_lossy(), "WATCHDOG=1").expect("notify");
+        let mut buf = [0; 64];
+        let len = receiver.recv(&mut buf).expect("receive notification");
+        assert_eq!(&buf[..len], b"WATCHDOG=1");
+        std::fs::remove_file(&path).expect("cleanup");
+    }
+}
@@ -0,0 +1,132 @@
+use crate::drive::Drive;
+use log::{debug, info};
+use std::collections::HashSet;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::time;
+use threadpool::ThreadPool;
+
+// Idle drives handle their commands (stop, status, ...) with at most this delay
+const SCHEDULER_TICK_MS: u64 = 200;
+
+// With `max-concurrent-drives`, drives waiting for their next reconcile don't hold a thread:
+// the scheduler keeps idle drives and hands due ones to a pool of reconcile threads.
+enum Event {
+    // Reconcile pass over, the drive goes back to the idle ones unless it must stop
+    Passed(Box<Drive>, bool),
+    Finished(String),
+}
+
+pub struct Scheduler {
+    drives: Receiver<Drive>,
+    events: Receiver<Event>,
+    events_sender: Sender<Event>,
+    pool: ThreadPool,
+    running: Arc<Mutex<HashSet<String>>>,
+    idle: Vec<Drive>,
+    busy: usize,
+    accepting: bool,
+}
+
+impl Scheduler {
+    pub fn new(
+        drives: Receiver<Drive>,
+        max_concurrent_drives: usize,
+        running: Arc<Mutex<HashSet<String>>>,
+    ) -> Self {
+        let (events_sender, events) = channel::<Event>();
+        Scheduler {
+            drives,
+            events,
+            events_sender,
+            pool: ThreadPool::new(max_concurrent_drives.max(1)),
+            running,
+            idle: Vec::new(),
+            busy: 0,
+            accepting: true,
+        }
+    }
+
+    // Returns once the drives sender is dropped and all drives stopped
+    pub fn run(&mut self) {
+        while self.accepting || !self.idle.is_empty() || self.busy > 0 {
+            self.receive_drives();
+            match self
+                .events
+                .recv_timeout(time::Duration::from_millis(SCHEDULER_TICK_MS))
+            {
+                Ok(event) => self.handle(event),
+                Err(RecvTimeoutError::Timeout) => {}
+                // The scheduler keeps a sender, events cannot be disconnected
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            while let Ok(event) = self.events.try_recv() {
+                self.handle(event);
+            }
+            self.dispatch();
+        }
+        debug!("scheduler: all drives stopped");
+    }
+
+    fn receive_drives(&mut self) {
+        loop {
+            match self.drives.try_recv() {
+                Ok(mut drive) => {
+                    info!("\"{}\" drive: scheduled", drive.name);
+                    drive.start();
+                    self.idle.push(drive);
+                }
+                Err(TryRecvError::Empty) => return,
+                Err(TryRecvError::Disconnected) => {
+                    self.accepting = false;
+                    return;
+                }
+            }
+        }
+    }
+
+    fn handle(&mut self, event: Event) {
+        self.busy -= 1;
+        match event {
+            Event::Passed(drive, true) => self.idle.push(*drive),
+            Event::Passed(drive, false) => self.finish(*drive),
+            Event::Finished(name) => {
+                self.running
+                    .lock()
+                    .unwrap_or_else(|poisoned| poisoned.into_inner())
+                    .remove(&name);
+            }
+        }
+    }
+
+    // Commands of idle drives are handled here, reconciles run in the pool
+    fn dispatch(&mut self) {
+        let mut waiting = Vec::new();
+        for mut drive in std::mem::take(&mut self.idle) {
+            if drive.early_exit().is_err() {
+                self.finish(drive);
+                continue;
+            }
+            if !drive.is_due() {
+                waiting.push(drive);
+                continue;
+            }
+            self.busy += 1;
+            let events = self.events_sender.clone();
+            self.pool.execute(move || {
+                let keep = drive.run_pass();
+                events.send(Event::Passed(Box::new(drive), keep)).ok();
+            });
+        }
+        self.idle = waiting;
+    }
+
+    fn finish(&mut self, mut drive: Drive) {
+        self.busy += 1;
+        let events = self.events_sender.clone();
+        self.pool.execute(move || {
+            drive.finish();
+            events.send(Event::Finished(drive.name.clone())).ok();
+        });
+    }
+}
@@ -0,0 +1,487 @@
+use crate::bsu::{api_call, api_call_non_idempotent, Bsu, TagKeys};
+use crate::utils::{dry_run_log, iso8601_to_epoch_s};
+use easy_error::format_err;
+use log::{debug, error, warn};
+use outscale_api::apis::snapshot_api::{create_snapshot, delete_snapshot, read_snapshots};
+use outscale_api::apis::tag_api::create_tags;
+use outscale_api::models::{
+    CreateSnapshotRequest, CreateTagsRequest, DeleteSnapshotRequest, FiltersSnapshot,
+    ReadSnapshotsRequest, ResourceTag,
+};
+use std::collections::BTreeSet;
+use std::error::Error;
+
+pub const DEFAULT_SNAPSHOT_RETENTION: usize = 7;
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Snapshot {
+    pub id: String,
+    pub drive_name: String,
+    pub volume_id: Option<String>,
+    pub batch_epoch_s: Option<i64>,
+    // Number of snapshots of the batch, unknown for snapshots taken by older versions
+    pub batch_size: Option<usize>,
+    pub state: String,
+    pub volume_size_gib: Option<usize>,
+}
+
+impl Snapshot {
+    pub fn new(
+        snapshot: &outscale_api::models::Snapshot,
+        tag_keys: &TagKeys,
+    ) -> Result<Self, Box<dyn Error>> {
+        let Some(id) = snapshot.snapshot_id.clone() else {
+            return Err(Box::new(format_err!(
+                "snapshot {:?} does not have an id",
+                snapshot
+            )));
+        };
+        let tags = snapshot.tags.clone().unwrap_or_default();
+        let tag_value = |key: &str| {
+            tags.iter()
+                .find(|tag| tag.key == key)
+                .map(|tag| tag.value.clone())
+        };
+        let Some(drive_name) = tag_value(&tag_keys.drive_name()) else {
+            return Err(Box::new(format_err!(
+                "cannot extract drive name from snapshot {}",
+                id
+            )));
+        };
+        // Snapshots without batch tag fall back on their creation date
+        // All snapshots taken during the same schedule share the batch tag, retention
+        // counts batches
+        let batch_epoch_s = tag_value(&tag_keys.snapshot_batch())
+            .and_then(|batch| batch.parse::<i64>().ok())
+            .or_else(|| {
+                snapshot
+                    .creation_date
+                    .as_ref()
+                    .and_then(|date| iso8601_to_epoch_s(date))
+            });
+        let batch_size =
+            tag_value(&tag_keys.snapshot_batch_size()).and_then(|size| size.parse::<usize>().ok());
+        Ok(Snapshot {
+            id,
+            drive_name,
+            volume_id: snapshot.volume_id.clone(),
+            batch_epoch_s,
+            batch_size,
+            state: snapshot.state.clone().unwrap_or_default(),
+            volume_size_gib: snapshot.volume_size.map(|size| size as usize),
+        })
+    }
+
+    pub fn fetch_drive(
+        tag_keys: &TagKeys,
+        drive_name: &String,
+    ) -> Result<Vec<Snapshot>, Box<dyn Error>> {
+        debug!("\"{}\" drive: fetching all snapshots", drive_name);
+        let mut request = ReadSnapshotsRequest::new();
+        let filter = FiltersSnapshot {
+            tags: Some(vec![format!("{}={}", tag_keys.drive_name(), drive_name)]),
+            ..Default::default()
+        };
+        request.filters = Some(Box::new(filter));
+        let response = api_call("read snapshots", |config| {
+            read_snapshots(config, Some(request.clone()))
+        })?;
+        let snapshots = response.snapshots.unwrap_or_default();
+        snapshots
+            .iter()
+            .map(|snapshot| Snapshot::new(snapshot, tag_keys))
+            .collect()
+    }
+
+    // Snapshot creation only waits for the API to register the snapshot: its content is
+    // the BSU content at request time even if the upload completes later.
+    pub fn create(
+        bsu: &Bsu,
+        tag_keys: &TagKeys,
+        batch_epoch_s: i64,
+        batch_size: usize,
+        dry_run: bool,
+    ) -> Result<String, Box<dyn Error>> {
+        debug!(
+            "\"{}\" drive: creating snapshot of BSU {}",
+            bsu.drive_name, bsu.id
+        );
+        let mut request = CreateSnapshotRequest::new();
+        request.volume_id = Some(bsu.id.clone());
+        request.description = Some(format!("bsud \"{}\" drive", bsu.drive_name));
+        if dry_run {
+            dry_run_log(&format!(
+                "create snapshot of BSU {} for \"{}\" drive",
+                bsu.id, bsu.drive_name
+            ));
+            return Ok(String::new());
+        }
+        let response = api_call_non_idempotent("create snapshot", |config| {
+            create_snapshot(config, Some(request.clone()))
+        })?;
+        let Some(snapshot_id) = response.snapshot.and_then(|snapshot| snapshot.snapshot_id) else {
+            return Err(Box::new(format_err!(
+                "snapshot creation did not provide a snapshot id"
+            )));
+        };
+        debug!(
+            "\"{}\" drive: adding tags to snapshot {}",
+            bsu.drive_name, snapshot_id
+        );
+        let tags = vec![
+            ResourceTag::new(tag_keys.drive_name(), bsu.drive_name.clone()),
+            ResourceTag::new(tag_keys.snapshot_batch(), batch_epoch_s.to_string()),
+            ResourceTag::new(tag_keys.snapshot_batch_size(), batch_size.to_string()),
+        ];
+        let tag_request = CreateTagsRequest::new(vec![snapshot_id.clone()], tags);
+        if let Err(err) = api_call("create snapshot tags", |config| {
+            create_tags(config, Some(tag_request.clone()))
+        }) {
+            Snapshot::delete_untagged(&bsu.drive_name, &snapshot_id);
+            return Err(err);
+        }
+        Ok(snapshot_id)
+    }
+
+    // An untagged snapshot is never fetched again, neither restored nor pruned
+    fn delete_untagged(drive_name: &str, snapshot_id: &str) {
+        warn!(
+            "\"{}\" drive: cannot tag new snapshot {}, deleting it",
+            drive_name, snapshot_id
+        );
+        let request = DeleteSnapshotRequest::new(snapshot_id.to_string());
+        if let Err(err) = api_call("delete snapshot", |config| {
+            delete_snapshot(config, Some(request.clone()))
+        }) {
+            error!(
+                "\"{}\" drive: ORPHAN snapshot {} is not tagged and could not be deleted ({}), bsud will never prune it: delete it manually",
+                drive_name, snapshot_id, err
+            );
+        }
+    }
+
+    pub fn delete_id(
+        drive_name: &str,
+        snapshot_id: &str,
+        dry_run: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        Snapshot {
+            id: snapshot_id.to_string(),
+            drive_name: drive_name.to_string(),
+            ..Default::default()
+        }
+        .delete(dry_run)
+    }
+
+    pub fn delete(&self, dry_run: bool) -> Result<(), Box<dyn Error>> {
+        debug!(
+            "\"{}\" drive: deleting snapshot {}",
+            self.drive_name, self.id
+        );
+        if dry_run {
+            dry_run_log(&format!("delete snapshot {}", self.id));
+            return Ok(());
+        }
+        let request = DeleteSnapshotRequest::new(self.id.clone());
+        api_call("delete snapshot", |config| {
+            delete_snapshot(config, Some(request.clone()))
+        })?;
+        Ok(())
+    }
+}
+
+// All snapshots of a batch or none: a batch failing partway deletes the snapshots it already
+// took, a partial batch could never be restored and would only pile up on each retry
+pub fn create_batch<C, D>(
+    all_bsu: &[Bsu],
+    mut create: C,
+    mut delete: D,
+) -> Result<Vec<String>, Box<dyn Error>>
+where
+    C: FnMut(&Bsu) -> Result<String, Box<dyn Error>>,
+    D: FnMut(&str) -> Result<(), Box<dyn Error>>,
+{
+    let mut created = Vec::new();
+    for bsu in all_bsu {
+        match create(bsu) {
+            Ok(snapshot_id) => created.push(snapshot_id),
+            Err(err) => {
+                for snapshot_id in created.iter() {
+                    if let Err(delete_err) = delete(snapshot_id) {
+                        error!(
+                            "\"{}\" drive: cannot delete snapshot {} of a failed batch, delete it manually: {}",
+                            bsu.drive_name, snapshot_id, delete_err
+                        );
+                    }
+                }
+                return Err(err);
+            }
+        }
+    }
+    Ok(created)
+}
+
+// `restore_from` is either "latest" or the batch tag value (epoch) of the snapshots to restore
+pub fn select_batch(
+    snapshots: &[Snapshot],
+    restore_from: &str,
+) -> Result<Vec<Snapshot>, Box<dyn Error>> {
+    let batch_epoch_s = match restore_from {
+        "latest" => snapshots
+            .iter()
+            .filter_map(|snapshot| snapshot.batch_epoch_s)
+            .max(),
+        batch => Some(batch.parse::<i64>().map_err(|_| {
+            format_err!(
+                "invalid snapshot batch \"{}\", expecting \"latest\" or a batch epoch",
+                batch
+            )
+        })?),
+    };
+    let Some(batch_epoch_s) = batch_epoch_s else {
+        return Err(Box::new(format_err!("no snapshot found to restore")));
+    };
+    let batch: Vec<Snapshot> = snapshots
+        .iter()
+        .filter(|snapshot| snapshot.batch_epoch_s == Some(batch_epoch_s))
+        .cloned()
+        .collect();
+    if batch.is_empty() {
+        return Err(Box::new(format_err!(
+            "no snapshot found in batch {}",
+            batch_epoch_s
+        )));
+    }
+    // A partial batch would restore an incomplete VG
+    if let Some(snapshot) = batch
+        .iter()
+        .find(|snapshot| !matches_batch_size(snapshot, batch.len()))
+    {
+        return Err(Box::new(format_err!(
+            "batch {} has {} snapshots while snapshot {} was taken with {}",
+            batch_epoch_s,
+            batch.len(),
+            snapshot.id,
+            snapshot.batch_size.unwrap_or_default()
+        )));
+    }
+    if let Some(snapshot) = batch.iter().find(|snapshot| snapshot.state != "completed") {
+        return Err(Box::new(format_err!(
+            "snapshot {} of batch {} is not completed ({})",
+            snapshot.id,
+            batch_epoch_s,
+            snapshot.state
+        )));
+    }
+    Ok(batch)
+}
+
+// Schedule is an interval like "30m", "6h" or "1d" (seconds without unit)
+pub fn parse_schedule(schedule: &str) -> Option<u64> {
+    let schedule = schedule.trim();
+    let (value, unit_s) = match schedule.chars().last()? {
+        's' => (&schedule[..schedule.len() - 1], 1),
+        'm' => (&schedule[..schedule.len() - 1], 60),
+        'h' => (&schedule[..schedule.len() - 1], 3600),
+        'd' => (&schedule[..schedule.len() - 1], 86400),
+        _ => (schedule, 1),
+    };
+    match value.trim().parse::<u64>() {
+        Ok(0) | Err(_) => None,
+        Ok(value) => value.checked_mul(unit_s),
+    }
+}
+
+pub fn is_due(last_snapshot_epoch_s: Option<i64>, interval_s: u64, now_epoch_s: i64) -> bool {
+    match last_snapshot_epoch_s {
+        None => true,
+        Some(last) => now_epoch_s - last >= interval_s as i64,
+    }
+}
+
+// Keeps the `retention` most recent complete batches and the batches taken since the oldest
+// kept one (their snapshots may still be uploading), snapshots without known batch are never
+// pruned
+pub fn to_prune(snapshots: &[Snapshot], retention: usize) -> Vec<Snapshot> {
+    let batches: BTreeSet<i64> = snapshots
+        .iter()
+        .filter_map(|snapshot| snapshot.batch_epoch_s)
+        .collect();
+    let complete: Vec<i64> = batches
+        .into_iter()
+        .rev()
+        .filter(|batch| is_complete(snapshots, *batch))
+        .take(retention)
+        .collect();
+    let oldest_kept = if retention == 0 {
+        None
+    } else if complete.len() < retention {
+        Some(i64::MIN)
+    } else {
+        complete.last().copied()
+    };
+    snapshots
+        .iter()
+        .filter(|snapshot| match (snapshot.batch_epoch_s, oldest_kept) {
+            (None, _) => false,
+            (Some(_), None) => true,
+            (Some(batch), Some(oldest_kept)) => batch < oldest_kept,
+        })
+        .cloned()
+        .collect()
+}
+
+// A batch left incomplete by a failure never holds the whole drive
+fn is_complete(snapshots: &[Snapshot], batch_epoch_s: i64) -> bool {
+    let batch: Vec<&Snapshot> = snapshots
+        .iter()
+        .filter(|snapshot| snapshot.batch_epoch_s == Some(batch_epoch_s))
+        .collect();
+    batch
+        .iter()
+        .all(|snapshot| snapshot.state == "completed" && matches_batch_size(snapshot, batch.len()))
+}
+
+// Snapshots without batch size tag cannot tell if their batch is missing snapshots
+fn matches_batch_size(snapshot: &Snapshot, batch_len: usize) -> bool {
+    snapshot.batch_size.is_none() || snapshot.batch_size == Some(batch_len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn snapshot_retention() {
+        let snapshot = |id: &str, batch_epoch_s: Option<i64>| Snapshot {
+            id: id.to_string(),
+            drive_name: "data".to_string(),
+            batch_epoch_s,
+            state: "completed".to_string(),
+            ..Default::default()
+        };
+        let mut snapshots = vec![
+            snapshot("snap-1a", Some(100)),
+            snapshot("snap-1b", Some(100)),
+            snapshot("snap-2a", Some(200)),
+            snapshot("snap-3a", Some(300)),
+            snapshot("snap-3b", Some(300)),
+            snapshot("snap-unknown", None),
+        ];
+        let pruned = |snapshots: &[Snapshot], retention: usize| -> Vec<String> {
+            to_prune(snapshots, retention)
+                .into_iter()
+                .map(|snapshot| snapshot.id)
+                .collect()
+        };
+        assert_eq!(pruned(&snapshots, 2), strings(&["snap-1a", "snap-1b"]));
+        assert!(pruned(&snapshots, 3).is_empty());
+        assert_eq!(pruned(&snapshots, 0).len(), 5);
+
+        // Batches still uploading or left in error do not count
+        snapshots[4].state = "pending".to_string();
+        snapshots[2].state = "error".to_string();
+        assert!(pruned(&snapshots, 2).is_empty());
+        snapshots.push(snapshot("snap-4a", Some(400)));
+        assert!(pruned(&snapshots, 2).is_empty());
+        assert_eq!(
+            pruned(&snapshots, 1),
+            strings(&["snap-1a", "snap-1b", "snap-2a", "snap-3a", "snap-3b"])
+        );
+    }
+
+    #[test]
+    fn snapshot_batch_failing_partway() {
+        let all_bsu: Vec<Bsu> = ["vol-a", "vol-b", "vol-c"]
+            .iter()
+            .map(|id| Bsu {
+                id: id.to_string(),
+                drive_name: "data".to_string(),
+                ..Default::default()
+            })
+            .collect();
+        let mut deleted = Vec::new();
+        let err = create_batch(
+            &all_bsu,
+            |bsu| match bsu.id.as_str() {
+                "vol-c" => Err(Box::new(format_err!("quota exceeded")) as Box<dyn Error>),
+                id => Ok(id.replace("vol", "snap")),
+            },
+            |snapshot_id| {
+                deleted.push(snapshot_id.to_string());
+                Ok(())
+            },
+        )
+        .expect_err("failed batch");
+        assert_eq!(err.to_string(), "quota exceeded");
+        assert_eq!(deleted, strings(&["snap-a", "snap-b"]));
+
+        let created = create_batch(
+            &all_bsu[..2],
+            |bsu| Ok(bsu.id.replace("vol", "snap")),
+            |_| panic!("nothing to delete"),
+        )
+        .expect("batch");
+        assert_eq!(created, strings(&["snap-a", "snap-b"]));
+    }
+
+    #[test]
+    fn restore_snapshot_batch_selection() {
+        let snapshot = |id: &str, batch_epoch_s: i64, state: &str| Snapshot {
+            id: id.to_string(),
+            drive_name: "data".to_string(),
+            batch_epoch_s: Some(batch_epoch_s),
+            state: state.to_string(),
+            ..Default::default()
+        };
+        let snapshots = vec![
+            snapshot("snap-1a", 100, "completed"),
+            snapshot("snap-1b", 100, "completed"),
+            snapshot("snap-2a", 200, "completed"),
+            snapshot("snap-2b", 200, "in-queue"),
+        ];
+        let ids =
+            |batch: Vec<Snapshot>| -> Vec<String> { batch.into_iter().map(|s| s.id).collect() };
+        assert_eq!(
+            ids(select_batch(&snapshots, "100").expect("batch 100")),
+            strings(&["snap-1a", "snap-1b"])
+        );
+        // Latest batch is not completed yet
+        assert!(select_batch(&snapshots, "latest").is_err());
+        assert_eq!(
+            ids(select_batch(&snapshots[..2], "latest").expect("latest")),
+            strings(&["snap-1a", "snap-1b"])
+        );
+        assert!(select_batch(&snapshots, "300").is_err());
+        assert!(select_batch(&snapshots, "yesterday").is_err());
+        assert!(select_batch(&[], "latest").is_err());
+
+        // A snapshot of the batch is missing
+        let sized = |id: &str, batch_size: usize| Snapshot {
+            batch_size: Some(batch_size),
+            ..snapshot(id, 300, "completed")
+        };
+        let err = select_batch(&[sized("snap-3a", 2)], "300").expect_err("partial batch");
+        assert!(err.to_string().contains("was taken with 2"));
+        assert_eq!(
+            ids(
+                select_batch(&[sized("snap-3a", 2), sized("snap-3b", 2)], "300")
+                    .expect("batch 300")
+            ),
+            strings(&["snap-3a", "snap-3b"])
+        );
+        assert_eq!(to_prune(&[sized("snap-3a", 2)], 1).len(), 0);
+        assert_eq!(
+            to_prune(
+                &[sized("snap-3a", 2), snapshot("snap-4a", 400, "completed")],
+                1
+            )
+            .len(),
+            1
+        );
+    }
+}
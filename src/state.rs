@@ -0,0 +1,68 @@
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs::{create_dir_all, read_to_string, rename, write};
+use std::path::PathBuf;
+
+pub const DEFAULT_STATE_DIR: &str = "/var/lib/bsud";
+
+// Drive state which must survive bsud restarts
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct PersistedState {
+    pub last_success_epoch_s: Option<i64>,
+    pub last_snapshot_epoch_s: Option<i64>,
+    pub last_error: Option<ReconcileError>,
+    // BSU whose PV left the VG but which is not deleted yet
+    pub removing_bsu_id: Option<String>,
+    // Set while a scrub started by bsud may still run in background
+    pub scrub_started_epoch_s: Option<i64>,
+    pub last_scrub: Option<ScrubReport>,
+    pub last_rebalance_epoch_s: Option<i64>,
+}
+
+// Cleared by the next successful reconcile
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct ReconcileError {
+    pub message: String,
+    pub epoch_s: i64,
+}
+
+// Outcome of the last scrub, `finished` is false when it was cancelled or aborted
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct ScrubReport {
+    pub started_epoch_s: i64,
+    pub finished: bool,
+    pub corrected_errors: u64,
+    pub uncorrectable_errors: u64,
+}
+
+fn state_path(state_dir: &str, drive_name: &str) -> PathBuf {
+    PathBuf::from(state_dir).join(format!("{}.json", drive_name))
+}
+
+pub fn load(state_dir: &str, drive_name: &str) -> Result<PersistedState, Box<dyn Error>> {
+    let path = state_path(state_dir, drive_name);
+    if !path.exists() {
+        debug!("no state file found in {:?}", path);
+        return Ok(PersistedState::default());
+    }
+    let data = read_to_string(&path)?;
+    Ok(serde_json::from_str(&data)?)
+}
+
+pub fn save(
+    state_dir: &str,
+    drive_name: &str,
+    state: &PersistedState,
+) -> Result<(), Box<dyn Error>> {
+    create_dir_all(state_dir)?;
+    let path = state_path(state_dir, drive_name);
+    let tmp_path = path.with_extension("json.tmp");
+    write(&tmp_path, serde_json::to_string(state)?)?;
+    rename(&tmp_path, &path)?;
+    debug!("state saved in {:?}", path);
+    Ok(())
+}
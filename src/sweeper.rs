@@ -0,0 +1,154 @@
+use crate::bsu::{Bsu, OutscaleVolumes, TagKeys, VolumeBackend};
+use crate::config::VM_ID;
+use crate::drive::DriveRouter;
+use datetime::Instant;
+use log::{debug, error, info, warn};
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::thread;
+use std::time;
+
+pub const DEFAULT_ORPHAN_GRACE_S: u64 = 86400;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OrphanStatus {
+    // Attached, or tagged for a running drive which will attach it
+    InUse,
+    // Unattached without running drive for less than the grace period
+    Grace,
+    Orphan,
+}
+
+// `first_seen_epoch_s` is the first sweep which saw the BSU unattached without running drive
+pub fn classify(
+    bsu: &Bsu,
+    live_drives: &HashSet<String>,
+    first_seen_epoch_s: i64,
+    now_epoch_s: i64,
+    grace_s: u64,
+) -> OrphanStatus {
+    if bsu.state != "available" || bsu.vm_id.is_some() || live_drives.contains(&bsu.drive_name) {
+        return OrphanStatus::InUse;
+    }
+    if now_epoch_s - first_seen_epoch_s < grace_s as i64 {
+        return OrphanStatus::Grace;
+    }
+    OrphanStatus::Orphan
+}
+
+// Orphan BSU found by a sweep: `reported` ones are left as they are, `reaped` and `failed`
+// ones are the deletions done and refused when `auto_reap` is set.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SweepReport {
+    pub reported: Vec<String>,
+    pub reaped: Vec<String>,
+    pub failed: Vec<String>,
+}
+
+// Crashes may leave BSU created by this bsud which no drive will ever attach (e.g. tagged for
+// a drive removed from the configuration). They are only reported unless `auto_reap` is set.
+#[derive(Debug, Clone)]
+pub struct Sweeper {
+    pub interval: time::Duration,
+    pub grace_s: u64,
+    pub auto_reap: bool,
+    pub tag_keys: TagKeys,
+    first_seen_epoch_s: HashMap<String, i64>,
+}
+
+impl Sweeper {
+    pub fn new(interval_s: u64, grace_s: u64, auto_reap: bool, tag_keys: TagKeys) -> Self {
+        Sweeper {
+            interval: time::Duration::from_secs(interval_s),
+            grace_s,
+            auto_reap,
+            tag_keys,
+            first_seen_epoch_s: HashMap::new(),
+        }
+    }
+
+    pub fn sweep(
+        &mut self,
+        backend: &dyn VolumeBackend,
+        owner: &str,
+        live_drives: &HashSet<String>,
+        now_epoch_s: i64,
+    ) -> Result<SweepReport, Box<dyn Error>> {
+        debug!("sweeping orphan BSU of {}", owner);
+        let owned = Bsu::fetch_owned(backend, &self.tag_keys, owner)?;
+        let mut first_seen_epoch_s = HashMap::new();
+        let mut report = SweepReport::default();
+        for bsu in owned.iter() {
+            let seen_epoch_s = self
+                .first_seen_epoch_s
+                .get(&bsu.id)
+                .copied()
+                .unwrap_or(now_epoch_s);
+            match classify(bsu, live_drives, seen_epoch_s, now_epoch_s, self.grace_s) {
+                OrphanStatus::InUse => continue,
+                OrphanStatus::Grace => {
+                    debug!(
+                        "BSU {} of drive \"{}\" is not attached and belongs to no running drive",
+                        bsu.id, bsu.drive_name
+                    );
+                }
+                OrphanStatus::Orphan if self.auto_reap => {
+                    warn!(
+                        "deleting orphan BSU {} of drive \"{}\" ({}GiB)",
+                        bsu.id, bsu.drive_name, bsu.size_gib
+                    );
+                    match bsu.delete(backend) {
+                        Ok(()) => report.reaped.push(bsu.id.clone()),
+                        Err(err) => {
+                            error!("cannot delete orphan BSU {}: {}", bsu.id, err);
+                            report.failed.push(bsu.id.clone());
+                        }
+                    }
+                }
+                OrphanStatus::Orphan => {
+                    warn!(
+                        "orphan BSU {} of drive \"{}\" ({}GiB) is not attached and belongs to no running drive for {}s, delete it or set auto-reap",
+                        bsu.id,
+                        bsu.drive_name,
+                        bsu.size_gib,
+                        now_epoch_s - seen_epoch_s
+                    );
+                    report.reported.push(bsu.id.clone());
+                }
+            }
+            first_seen_epoch_s.insert(bsu.id.clone(), seen_epoch_s);
+        }
+        self.first_seen_epoch_s = first_seen_epoch_s;
+        Ok(report)
+    }
+}
+
+// Running drives are read from the router at each sweep
+pub fn spawn(mut sweeper: Sweeper, router: DriveRouter) {
+    info!(
+        "sweeping orphan BSU every {}s (grace period: {}s, auto reap: {})",
+        sweeper.interval.as_secs(),
+        sweeper.grace_s,
+        sweeper.auto_reap
+    );
+    thread::spawn(move || loop {
+        thread::sleep(sweeper.interval);
+        let owner = match VM_ID.read() {
+            Ok(vm_id) => vm_id.clone(),
+            Err(err) => {
+                error!("cannot read vm id: {}", err);
+                continue;
+            }
+        };
+        let live_drives = router.names();
+        let now_epoch_s = Instant::now().seconds();
+        if let Err(err) = sweeper.sweep(
+            &OutscaleVolumes::default(),
+            &owner,
+            &live_drives,
+            now_epoch_s,
+        ) {
+            error!("cannot sweep orphan BSU: {}", err);
+        }
+    });
+}
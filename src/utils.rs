@@ -1,30 +1,207 @@
 use easy_error::format_err;
-use log::trace;
+use log::{debug, info, trace};
 use std::error::Error;
+use std::fmt::Debug;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::process::Stdio;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 const NB_OF_BYTES_IN_GIB: usize = 1024_usize.pow(3);
+const GIB_ROUNDING_TOLERANCE: usize = 1;
+// Some commands (e.g. lvm fullreport) can be very verbose, errors only keep the beginning
+pub const MAX_EXEC_ERROR_OUTPUT_LEN: usize = 1024;
+
+static RUN_ONCE: AtomicBool = AtomicBool::new(false);
+
+pub fn set_run_once(enabled: bool) {
+    RUN_ONCE.store(enabled, Ordering::SeqCst);
+}
+
+pub fn is_run_once() -> bool {
+    RUN_ONCE.load(Ordering::SeqCst)
+}
+
+pub fn dry_run_log(action: &str) {
+    info!("dry-run: would {}", action);
+}
 
 pub fn bytes_to_gib(bytes: usize) -> f32 {
     bytes as f32 / NB_OF_BYTES_IN_GIB as f32
 }
 
 pub fn bytes_to_gib_rounded(bytes: usize) -> usize {
-    bytes_to_gib(bytes).ceil() as usize
+    bytes.div_ceil(NB_OF_BYTES_IN_GIB)
+}
+
+pub fn bytes_to_gib_floor(bytes: usize) -> usize {
+    bytes / NB_OF_BYTES_IN_GIB
 }
 
 pub fn gib_to_bytes(gib: usize) -> usize {
     gib * NB_OF_BYTES_IN_GIB
 }
 
+// Sizes computed from bytes are rounded up and may be off by one GiB
+pub fn is_gib_close(a_gib: usize, b_gib: usize) -> bool {
+    a_gib.abs_diff(b_gib) <= GIB_ROUNDING_TOLERANCE
+}
+
+// Convert an ISO 8601 UTC date (e.g. "2024-03-01T10:20:30.000Z") to epoch seconds
+pub fn iso8601_to_epoch_s(date: &str) -> Option<i64> {
+    let year = date.get(0..4)?.parse::<i64>().ok()?;
+    let month = date.get(5..7)?.parse::<i64>().ok()?;
+    let day = date.get(8..10)?.parse::<i64>().ok()?;
+    let hour = date.get(11..13)?.parse::<i64>().ok()?;
+    let minute = date.get(14..16)?.parse::<i64>().ok()?;
+    let second = date.get(17..19)?.parse::<i64>().ok()?;
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    // http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = (if y >= 0 { y } else { y - 399 }) / 400;
+    let yoe = y - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+    Some(days * 86400 + hour * 3600 + minute * 60 + second)
+}
+
+// Convert epoch seconds to an ISO 8601 UTC date, the reverse of `iso8601_to_epoch_s`
+pub fn epoch_s_to_iso8601(epoch_s: i64) -> String {
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let days = epoch_s.div_euclid(86400) + 719468;
+    let seconds = epoch_s.rem_euclid(86400);
+    let era = (if days >= 0 { days } else { days - 146096 }) / 146097;
+    let doe = days - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.000Z",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60
+    )
+}
+
+// Runs a command through another one, e.g. `ionice -c 3 lvm pvmove`
+pub fn prefix_command<'a>(
+    prefix: &[&'a str],
+    cmd: &'a str,
+    args: &[&'a str],
+) -> (&'a str, Vec<&'a str>) {
+    let Some((prefix_cmd, prefix_args)) = prefix.split_first() else {
+        return (cmd, args.to_vec());
+    };
+    let mut prefixed_args = prefix_args.to_vec();
+    prefixed_args.push(cmd);
+    prefixed_args.extend_from_slice(args);
+    (prefix_cmd, prefixed_args)
+}
+
+// Short human readable duration of a projection, "n/a" when there is none
+pub fn format_eta_s(seconds: f64) -> String {
+    if !seconds.is_finite() || seconds < 0.0 {
+        return "n/a".to_string();
+    }
+    let seconds = seconds.round() as u64;
+    let (days, hours, minutes) = (seconds / 86400, seconds % 86400 / 3600, seconds % 3600 / 60);
+    match (days, hours, minutes) {
+        (0, 0, 0) => format!("{}s", seconds),
+        (0, 0, _) => format!("{}m", minutes),
+        (0, _, _) => format!("{}h{}m", hours, minutes),
+        _ => format!("{}d{}h", days, hours),
+    }
+}
+
+// Run `batch` on all items at once. On failure, run `single` on each item to isolate faulty ones.
+pub fn batch_with_fallback<B, S>(
+    items: &[String],
+    batch: B,
+    single: S,
+) -> Result<(), Box<dyn Error>>
+where
+    B: Fn(&[String]) -> Result<(), Box<dyn Error>>,
+    S: Fn(&String) -> Result<(), Box<dyn Error>>,
+{
+    if items.is_empty() {
+        return Ok(());
+    }
+    let Err(batch_err) = batch(items) else {
+        return Ok(());
+    };
+    if items.len() == 1 {
+        return Err(batch_err);
+    }
+    debug!("batch failed ({}), falling back on each item", batch_err);
+    let mut first_err = None;
+    for item in items {
+        if let Err(err) = single(item) {
+            debug!("{} failed: {}", item, err);
+            first_err.get_or_insert(err);
+        }
+    }
+    match first_err {
+        Some(err) => Err(err),
+        None => Ok(()),
+    }
+}
+
+// Look for an executable `binary` in the colon separated `path` list (e.g. $PATH)
+pub fn find_binary_in(binary: &str, path: &str) -> Option<PathBuf> {
+    path.split(':')
+        .filter(|dir| !dir.is_empty())
+        .map(|dir| Path::new(dir).join(binary))
+        .find(|candidate| {
+            candidate
+                .metadata()
+                .map(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+                .unwrap_or(false)
+        })
+}
+
+pub fn find_binary(binary: &str) -> Option<PathBuf> {
+    let path = std::env::var("PATH").unwrap_or_default();
+    find_binary_in(binary, &path)
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
 pub struct ExecOutput {
     pub success: bool,
     pub stdout: String,
     pub stderr: String,
 }
 
-fn cmd_str(cmd: &str, args: &[&str]) -> String {
+impl ExecOutput {
+    pub fn ok(stdout: &str) -> Self {
+        ExecOutput {
+            success: true,
+            stdout: stdout.to_string(),
+            stderr: String::new(),
+        }
+    }
+
+    pub fn failed(stderr: &str) -> Self {
+        ExecOutput {
+            success: false,
+            stdout: String::new(),
+            stderr: stderr.to_string(),
+        }
+    }
+}
+
+// Command line as logged, e.g. "lvchange -ay data"
+pub fn cmd_str(cmd: &str, args: &[&str]) -> String {
     let mut concatenated_arg = String::from(cmd);
     for arg in args {
         concatenated_arg += " ";
@@ -33,41 +210,232 @@ fn cmd_str(cmd: &str, args: &[&str]) -> String {
     concatenated_arg
 }
 
-fn exec_raw(cmd: &str, args: &[&str]) -> Result<ExecOutput, Box<dyn Error>> {
-    let cmd_str = cmd_str(cmd, args);
-    trace!("exec {}", cmd_str);
-    let output = Command::new(cmd)
-        .args(args)
-        .stdout(Stdio::piped())
-        .stdout(Stdio::piped())
-        .output()?;
-    let stdout = String::from_utf8(output.stdout)?;
-    let stderr = String::from_utf8(output.stderr)?;
-    let success = output.status.success();
-    if !success {
-        if !stdout.is_empty() {
-            trace!("{} stdout: {}", cmd_str, stdout);
+// Trimmed stderr, or stdout when stderr is empty, capped to `max_len` bytes
+pub fn exec_error_detail(stdout: &str, stderr: &str, max_len: usize) -> String {
+    let output = match stderr.trim() {
+        "" => stdout.trim(),
+        stderr => stderr,
+    };
+    if output.len() <= max_len {
+        return output.to_string();
+    }
+    let mut end = max_len;
+    while !output.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}...", &output[..end])
+}
+
+// All commands run by bsud go through an executor, tests use a mock executor to run drive
+// logic without root, LVM or BSU.
+pub trait Executor: Debug + Send + Sync {
+    // A non zero exit is not an error here and is reported through `ExecOutput::success`
+    fn exec(&self, cmd: &str, args: &[&str]) -> Result<ExecOutput, Box<dyn Error>>;
+
+    // Commands changing the system are only logged in dry-run mode
+    fn dry_run(&self) -> bool {
+        false
+    }
+
+    fn exec_checked(&self, cmd: &str, args: &[&str]) -> Result<ExecOutput, Box<dyn Error>> {
+        let output = self.exec(cmd, args)?;
+        if !output.success {
+            let detail =
+                exec_error_detail(&output.stdout, &output.stderr, MAX_EXEC_ERROR_OUTPUT_LEN);
+            if detail.is_empty() {
+                return Err(Box::new(format_err!("{} {:?} exited non zero", cmd, args)));
+            }
+            return Err(Box::new(format_err!(
+                "{} {:?} exited non zero: {}",
+                cmd,
+                args,
+                detail
+            )));
+        }
+        Ok(output)
+    }
+
+    fn exec_bool(&self, cmd: &str, args: &[&str]) -> Result<bool, Box<dyn Error>> {
+        Ok(self.exec(cmd, args)?.success)
+    }
+
+    fn exec_mut(&self, cmd: &str, args: &[&str]) -> Result<ExecOutput, Box<dyn Error>> {
+        if self.dry_run() {
+            dry_run_log(&format!("run {}", cmd_str(cmd, args)));
+            return Ok(ExecOutput::ok(""));
+        }
+        self.exec_checked(cmd, args)
+    }
+
+    // Like `exec_mut` but a non zero exit is returned to the caller instead of being an error
+    fn exec_unchecked_mut(&self, cmd: &str, args: &[&str]) -> Result<ExecOutput, Box<dyn Error>> {
+        if self.dry_run() {
+            dry_run_log(&format!("run {}", cmd_str(cmd, args)));
+            return Ok(ExecOutput::ok(""));
         }
-        if !stderr.is_empty() {
-            trace!("{} stderr: {}", cmd_str, stderr);
+        self.exec(cmd, args)
+    }
+
+    fn exec_bool_mut(&self, cmd: &str, args: &[&str]) -> Result<bool, Box<dyn Error>> {
+        if self.dry_run() {
+            dry_run_log(&format!("run {}", cmd_str(cmd, args)));
+            return Ok(true);
         }
+        self.exec_bool(cmd, args)
     }
-    Ok(ExecOutput {
-        success,
-        stdout,
-        stderr,
-    })
 }
 
-pub fn exec(cmd: &str, args: &[&str]) -> Result<ExecOutput, Box<dyn Error>> {
-    let output = exec_raw(cmd, args)?;
-    if !output.success {
-        return Err(Box::new(format_err!("{} {:?} exited non zero", cmd, args)));
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemExecutor {
+    pub dry_run: bool,
+}
+
+impl Executor for SystemExecutor {
+    fn dry_run(&self) -> bool {
+        self.dry_run
     }
-    Ok(output)
+
+    fn exec(&self, cmd: &str, args: &[&str]) -> Result<ExecOutput, Box<dyn Error>> {
+        let cmd_str = cmd_str(cmd, args);
+        trace!("exec {}", cmd_str);
+        let output = Command::new(cmd)
+            .args(args)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .output()?;
+        let stdout = String::from_utf8(output.stdout)?;
+        let stderr = String::from_utf8(output.stderr)?;
+        let success = output.status.success();
+        if !success {
+            if !stdout.is_empty() {
+                trace!("{} stdout: {}", cmd_str, stdout);
+            }
+            if !stderr.is_empty() {
+                trace!("{} stderr: {}", cmd_str, stderr);
+            }
+        }
+        Ok(ExecOutput {
+            success,
+            stdout,
+            stderr,
+        })
+    }
+}
+
+pub fn exec(cmd: &str, args: &[&str]) -> Result<ExecOutput, Box<dyn Error>> {
+    SystemExecutor::default().exec_checked(cmd, args)
 }
 
 pub fn exec_bool(cmd: &str, args: &[&str]) -> Result<bool, Box<dyn Error>> {
-    let output = exec_raw(cmd, args)?;
-    Ok(output.success)
+    SystemExecutor::default().exec_bool(cmd, args)
+}
+
+pub fn exec_mut(cmd: &str, args: &[&str]) -> Result<ExecOutput, Box<dyn Error>> {
+    SystemExecutor::default().exec_mut(cmd, args)
+}
+
+pub fn exec_unchecked_mut(cmd: &str, args: &[&str]) -> Result<ExecOutput, Box<dyn Error>> {
+    SystemExecutor::default().exec_unchecked_mut(cmd, args)
+}
+
+pub fn exec_bool_mut(cmd: &str, args: &[&str]) -> Result<bool, Box<dyn Error>> {
+    SystemExecutor::default().exec_bool_mut(cmd, args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    #[test]
+    fn iso8601_dates() {
+        assert_eq!(iso8601_to_epoch_s("1970-01-01T00:00:00.000Z"), Some(0));
+        assert_eq!(
+            iso8601_to_epoch_s("2024-03-01T10:20:30.000Z"),
+            Some(1709288430)
+        );
+        assert_eq!(iso8601_to_epoch_s("not a date"), None);
+        assert_eq!(epoch_s_to_iso8601(0), "1970-01-01T00:00:00.000Z");
+        assert_eq!(epoch_s_to_iso8601(1709288430), "2024-03-01T10:20:30.000Z");
+    }
+
+    #[test]
+    fn batch_with_fallback_isolates_failure() {
+        let devices = vec![
+            "/dev/xvdb".to_string(),
+            "/dev/xvdc".to_string(),
+            "/dev/xvdd".to_string(),
+        ];
+        let batch_calls = RefCell::new(0);
+        let single_calls = RefCell::new(Vec::new());
+        let result = batch_with_fallback(
+            &devices,
+            |_| -> Result<(), Box<dyn Error>> {
+                *batch_calls.borrow_mut() += 1;
+                Err(Box::new(format_err!("batch failed")))
+            },
+            |device| -> Result<(), Box<dyn Error>> {
+                single_calls.borrow_mut().push(device.clone());
+                if device == "/dev/xvdc" {
+                    return Err(Box::new(format_err!("bad device")));
+                }
+                Ok(())
+            },
+        );
+        assert!(result.is_err());
+        assert_eq!(*batch_calls.borrow(), 1);
+        assert_eq!(*single_calls.borrow(), devices);
+    }
+
+    #[test]
+    fn batch_with_fallback_batch_success() {
+        let devices = vec!["/dev/xvdb".to_string(), "/dev/xvdc".to_string()];
+        let single_calls = RefCell::new(0);
+        let result = batch_with_fallback(
+            &devices,
+            |_| Ok(()),
+            |_| -> Result<(), Box<dyn Error>> {
+                *single_calls.borrow_mut() += 1;
+                Ok(())
+            },
+        );
+        assert!(result.is_ok());
+        assert_eq!(*single_calls.borrow(), 0);
+    }
+
+    #[test]
+    fn gib_rounding_boundaries() {
+        assert_eq!(bytes_to_gib_rounded(0), 0);
+        assert_eq!(bytes_to_gib_rounded(gib_to_bytes(10)), 10);
+        assert_eq!(bytes_to_gib_rounded(gib_to_bytes(10) + 1), 11);
+        assert_eq!(bytes_to_gib_rounded(gib_to_bytes(10) - 1), 10);
+        assert_eq!(bytes_to_gib_floor(gib_to_bytes(10) - 1), 9);
+        assert_eq!(bytes_to_gib_floor(gib_to_bytes(10) + 1), 10);
+        assert!(is_gib_close(10, 11));
+        assert!(is_gib_close(11, 10));
+        assert!(!is_gib_close(10, 12));
+    }
+
+    #[test]
+    fn exec_captures_stderr() {
+        let output = exec("sh", &["-c", "echo out; echo err >&2"]).expect("exec sh");
+        assert!(output.success);
+        assert_eq!(output.stdout, "out\n");
+        assert_eq!(output.stderr, "err\n");
+    }
+
+    #[test]
+    fn exec_error_includes_output() {
+        let err = exec("sh", &["-c", "echo out; echo '  no such vg  ' >&2; exit 5"])
+            .expect_err("exec should fail");
+        assert!(err.to_string().contains("exited non zero: no such vg"));
+        let err = exec("sh", &["-c", "echo only stdout; exit 1"]).expect_err("exec should fail");
+        assert!(err.to_string().contains("exited non zero: only stdout"));
+
+        assert_eq!(exec_error_detail("out", "err\n", 10), "err");
+        assert_eq!(exec_error_detail(" out \n", "", 10), "out");
+        assert_eq!(exec_error_detail("", "", 10), "");
+        assert_eq!(exec_error_detail("", "0123456789abc", 10), "0123456789...");
+        assert_eq!(exec_error_detail("", "ééé", 3), "é...");
+    }
 }
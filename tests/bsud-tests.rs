@@ -1,6 +1,7 @@
 use async_process::Command;
 use bsudlib::config::{
     discover_vm_config, region, ConfigFileDrive, DiskType, DriveTarget, CLOUD_CONFIG,
+    DEFAULT_METADATA_TIMEOUT_S,
 };
 use bsudlib::drive::{Drive, DriveCmd};
 use bsudlib::utils::bytes_to_gib;
@@ -35,7 +36,8 @@ fn setup_creds() {
         SecretString::new(env::var("OSC_SECRET_KEY").expect("OSC_SECRET_KEY must be set"));
     // This avoid async to crash with blocking request
     block_in_place(move || {
-        discover_vm_config().expect("discover vm config");
+        discover_vm_config(Duration::from_secs(DEFAULT_METADATA_TIMEOUT_S))
+            .expect("discover vm config");
     });
     global_cloud_config.aws_v4_key = Some(AWSv4Key {
         access_key,
@@ -76,6 +78,7 @@ impl DriveEnv {
             max_used_space_perc: Some(85),
             min_used_space_perc: Some(20),
             disk_scale_factor_perc: Some(20),
+            ..Default::default()
         }
     }
 }
@@ -159,7 +162,7 @@ async fn drive_set_usage(
     drive_env: &mut DriveEnv,
     target_gib: usize,
 ) -> Result<(), Box<dyn Error>> {
-    let lv_path = lvm::lv_path(&drive_env.drive.name);
+    let lv_path = lvm::lv_path(&drive_env.drive.name, lvm::DEFAULT_LV_NAME);
     loop {
         wait_for_stabilized_usage(&drive_env.drive).await;
         let current_drive_usage_bytes = fs::used_bytes(&lv_path).expect("get drive usage");
@@ -248,21 +251,21 @@ async fn drive_has_x_bsu(drive_env: &mut DriveEnv, bsu_count: usize) {
 #[given(expr = "drive is mounted")]
 #[then(expr = "drive is mounted")]
 async fn drive_is_mounted(drive_env: &mut DriveEnv) {
-    let lv_path = lvm::lv_path(&drive_env.drive.name);
+    let lv_path = lvm::lv_path(&drive_env.drive.name, lvm::DEFAULT_LV_NAME);
     assert!(fs::is_mounted(&lv_path, &drive_env.drive.mount_path).expect("fs::is_mounted"))
 }
 
 #[given(expr = "drive size is {int}Gib")]
 #[then(expr = "drive size is {int}Gib")]
 async fn drive_has_x_gib(drive_env: &mut DriveEnv, supposed_capa_gib: usize) {
-    let lv_path = lvm::lv_path(&drive_env.drive.name);
+    let lv_path = lvm::lv_path(&drive_env.drive.name, lvm::DEFAULT_LV_NAME);
     let fs_size_bytes = fs::size_bytes(&lv_path).expect("get fs size");
     let fs_size_gib = bytes_to_gib(fs_size_bytes).round() as usize;
     assert_eq!(fs_size_gib, supposed_capa_gib);
 }
 
 async fn wait_for_stabilized_usage(drive: &Drive) {
-    let lv_path = lvm::lv_path(&drive.name);
+    let lv_path = lvm::lv_path(&drive.name, lvm::DEFAULT_LV_NAME);
     let mut usage = fs::used_bytes(&lv_path).expect("get fs usage");
     loop {
         debug!("wait for file usage to stabilize");
@@ -0,0 +1,3466 @@
+mod common;
+
+use bsudlib::bsu::{
+    find_next_available_device_in, ApiFailure, Bsu, DeviceScheme, TagKeys, VolumeBackend,
+    VolumeBudget, WaitOptions, WaitTimeoutError, BSU_TAG_KEY,
+};
+use bsudlib::btrfs;
+use bsudlib::config::{
+    self, canonical_mount_path, drive_conflicts, is_plausible_subregion, AttachFailurePolicy,
+    Backend, Config, ConfigFileDrive, ConfigFileVolume, DiskType, DriveTarget, Filesystem,
+    IoNiceClass, MissingPvPolicy, UsageBasis, VgAllocPolicy,
+};
+use bsudlib::control::handle_line;
+use bsudlib::crypt;
+use bsudlib::doctor::{self, Check};
+use bsudlib::drive::{
+    cooldown_remaining, creation_delay, diff_configs, foreign_pvs, grow_to_plan, ideal_free_bytes,
+    initial_reconcile_delay_s, is_near_full, keeps_shrink_floor, orphan_bsu, rebalance_removes,
+    removal_fits, retry_delay_s, scrub_action, seconds_to_full, untagged_vg_members,
+    volume_share_bytes, Drive, DriveCmd, DriveHealth, DriveRouter, DriveState, DriveStatus, Drives,
+    MountCheck, ReconcileTrace, ResizeDirection, ResizeEvent, ScaleAction, ScrubAction,
+    UsageSource,
+};
+use bsudlib::error::{self, BsudError, RetryPolicy};
+use bsudlib::fs::{
+    btrfs_unallocated_bytes_with, capacity_bytes_with, device_by_id, foreign_signature, is_grown,
+    mount_args, parse_btrfs_usage, parse_capacity, parse_scrub_status, remove_empty_folder,
+    BtrfsUsage, ScrubStatus, GROW_SLACK_BYTES,
+};
+use bsudlib::lvm::{self, create_lv_args, create_vg_args, Lv, Lvm, Pv, Vg};
+use bsudlib::notify;
+use bsudlib::snapshot;
+use bsudlib::state::{self, PersistedState, ReconcileError, ScrubReport};
+use bsudlib::sweeper::{classify, OrphanStatus, SweepReport};
+use bsudlib::utils::{format_eta_s, gib_to_bytes, prefix_command, ExecOutput, Executor};
+use common::{FakeVolumes, MockExecutor};
+use easy_error::format_err;
+use outscale_api::models::{
+    CreateTagsRequest, CreateVolumeRequest, FiltersVolume, LinkVolumeRequest, LinkedVolume,
+    ResourceTag, UnlinkVolumeRequest, UpdateVolumeRequest, Volume,
+};
+use std::collections::{HashMap, HashSet};
+use std::env::temp_dir;
+use std::error::Error;
+use std::fs::{create_dir_all, remove_dir_all, set_permissions, write, Permissions};
+use std::os::unix::fs::{symlink, PermissionsExt};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::channel;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[test]
+fn time_to_max_projection() {
+    assert_eq!(format_eta_s(f64::INFINITY), "n/a");
+    assert_eq!(format_eta_s(f64::NAN), "n/a");
+    assert_eq!(format_eta_s(42.0), "42s");
+    assert_eq!(format_eta_s(600.0), "10m");
+    assert_eq!(format_eta_s(3.0 * 3600.0 + 1200.0), "3h20m");
+    assert_eq!(format_eta_s(2.0 * 86400.0 + 5.0 * 3600.0), "2d5h");
+
+    // Without max total size, there is nothing to project
+    let mut drive = test_drive();
+    drive.usage_source = UsageSource::Simulated {
+        used_bytes: gib_to_bytes(5),
+        size_bytes: gib_to_bytes(10),
+    };
+    let state = drive.gather_state().expect("drive state");
+    drive.record_usage_sample(&state);
+    assert_eq!(drive.seconds_to_max, f64::INFINITY);
+    assert_eq!(drive.status().seconds_to_max, None);
+    assert_eq!(drive.status().seconds_to_full, None);
+    assert_eq!(drive.time_to_max_warning_s, 86400);
+
+    drive.seconds_to_max = 7200.4;
+    drive.seconds_to_full = 3599.6;
+    assert_eq!(drive.status().seconds_to_max, Some(7200));
+    assert_eq!(drive.status().seconds_to_full, Some(3600));
+}
+
+#[test]
+fn attach_failure_flag_cleared_once_attached() {
+    *config::VM_ID.write().expect("vm id") = "i-fake".to_string();
+    let volumes = Arc::new(FakeVolumes::new(vec![fake_volume(
+        "vol-stuck",
+        "test",
+        10,
+        None,
+    )]));
+    let mut drive = test_drive().with_volume_backend(volumes.clone());
+    drive.max_attach_attempts = 1;
+    drive.on_attach_failure = AttachFailurePolicy::Flag;
+    drive.fetch_all_drive_bsu().expect("fetch drive BSU");
+    drive.handle_attach_failure().expect("attach failure");
+    assert!(drive.creation_blocked);
+    drive.fetch_all_drive_bsu().expect("fetch drive BSU");
+    assert!(drive.creation_blocked);
+
+    let request = LinkVolumeRequest::new(
+        "/dev/xvdb".to_string(),
+        "i-fake".to_string(),
+        "vol-stuck".to_string(),
+    );
+    volumes.link_volume(&request).expect("link");
+    drive.fetch_all_drive_bsu().expect("fetch drive BSU");
+    assert!(!drive.creation_blocked);
+}
+
+fn test_drive() -> Drive {
+    let (_sender, receiver) = channel::<DriveCmd>();
+    let config = ConfigFileDrive {
+        name: "test".to_string(),
+        mount_path: "/mnt".to_string(),
+        initial_size_gib: Some(10),
+        max_bsu_count: Some(4),
+        max_used_space_perc: Some(85),
+        min_used_space_perc: Some(20),
+        disk_scale_factor_perc: Some(20),
+        ..Default::default()
+    };
+    Drive::new(config, receiver)
+}
+
+fn test_state(bsu_sizes_gib: &[usize], used_gib: usize) -> DriveState {
+    let all_bsu: Vec<Bsu> = bsu_sizes_gib
+        .iter()
+        .map(|size_gib| Bsu {
+            size_gib: *size_gib,
+            size_bytes: gib_to_bytes(*size_gib),
+            ..Default::default()
+        })
+        .collect();
+    let size_bytes = gib_to_bytes(bsu_sizes_gib.iter().sum());
+    let mut drive = test_drive();
+    drive.usage_source = UsageSource::Simulated {
+        used_bytes: gib_to_bytes(used_gib),
+        size_bytes,
+    };
+    let state = drive.gather_state().expect("simulated state");
+    DriveState { all_bsu, ..state }
+}
+
+#[test]
+fn scale_action_nominal_usage() {
+    let state = test_state(&[10], 5);
+    assert_eq!(test_drive().scale_action(&state), ScaleAction::None);
+}
+
+#[test]
+fn scale_action_low_space_creates_larger() {
+    let state = test_state(&[10], 9);
+    assert_eq!(test_drive().scale_action(&state), ScaleAction::CreateLarger);
+}
+
+#[test]
+fn scale_action_low_space_creates_smaller() {
+    let state = test_state(&[12, 14], 24);
+    assert_eq!(
+        test_drive().scale_action(&state),
+        ScaleAction::CreateSmaller
+    );
+}
+
+#[test]
+fn scale_action_max_bsu_reached() {
+    let state = test_state(&[10, 12, 14, 17], 20);
+    assert_eq!(
+        test_drive().scale_action(&state),
+        ScaleAction::RemoveSmallest
+    );
+}
+
+#[test]
+fn last_bsu_is_never_removed() {
+    // max-bsu-count of 1 reached: the only BSU cannot make room for another one
+    let mut drive = test_drive();
+    drive.max_bsu_count = 1;
+    assert_eq!(drive.scale_action(&test_state(&[10], 9)), ScaleAction::None);
+    assert_eq!(drive.scale_action(&test_state(&[10], 1)), ScaleAction::None);
+
+    let volumes = Arc::new(FakeVolumes::new(vec![fake_volume(
+        "vol-a",
+        "test",
+        10,
+        Some("i-fake"),
+    )]));
+    let mut drive = test_drive().with_volume_backend(volumes.clone());
+    drive.fetch_all_drive_bsu().expect("fetch drive BSU");
+    let bsu = drive.all_bsu()[0].clone();
+    let err = drive.remove_bsu(&bsu).expect_err("refused removal");
+    assert!(err.to_string().contains("it is the only BSU of the drive"));
+    assert!(drive.remove_smallest_bsu().is_err());
+    assert_eq!(volumes.volumes().len(), 1);
+}
+
+#[test]
+fn rebalance_consolidates_bsu() {
+    assert!(rebalance_removes(&[10, 10, 20], 25));
+    assert!(!rebalance_removes(&[10, 20], 25));
+    assert!(!rebalance_removes(&[20], 0));
+    assert!(!rebalance_removes(&[], 0));
+
+    *config::VM_ID.write().expect("vm id") = "i-fake".to_string();
+    let tag_keys = TagKeys::default();
+    let managed = |id: &str, size_gib: i32| {
+        let mut volume = fake_volume(id, "test", size_gib, Some("i-fake"));
+        volume
+            .tags
+            .get_or_insert_with(Vec::new)
+            .push(ResourceTag::new(tag_keys.managed(), "true".to_string()));
+        volume
+    };
+    let volumes = Arc::new(FakeVolumes::new(vec![
+        managed("vol-a", 10),
+        managed("vol-b", 10),
+        managed("vol-c", 20),
+        managed("vol-d", 20),
+    ]));
+    let executor = Arc::new(MockExecutor::new());
+    let mut drive = test_drive()
+        .with_executor(executor.clone())
+        .with_volume_backend(volumes.clone());
+    drive.backend = Backend::BtrfsRaw;
+    drive.wait_options = WaitOptions {
+        timeout: Duration::from_secs(5),
+        poll_interval: Duration::from_millis(1),
+    };
+    drive.usage_source = UsageSource::Simulated {
+        used_bytes: gib_to_bytes(12),
+        size_bytes: gib_to_bytes(60),
+    };
+    let usage = "Overall:
+    Device size:\t\t\t  64424509440
+    Device allocated:\t\t  16106127360
+    Device unallocated:\t\t  48318382080
+    Used:\t\t\t  12884901888
+";
+    drive.fetch_all_drive_bsu().expect("fetch drive BSU");
+    let state = drive.gather_state().expect("simulated state");
+    assert!(!drive.rebalance_step(&state).expect("not requested"));
+    assert_eq!(volumes.volumes().len(), 4);
+
+    drive.rebalance_requested = true;
+    assert!(drive.is_due());
+    let mut removed = 0;
+    loop {
+        executor.reply("btrfs filesystem usage", ExecOutput::ok(usage));
+        drive.fetch_all_drive_bsu().expect("fetch drive BSU");
+        let state = drive.gather_state().expect("simulated state");
+        if !drive.rebalance_step(&state).expect("rebalance step") {
+            break;
+        }
+        removed += 1;
+    }
+    // 12GiB used needs about 23GiB with headroom: both 10GiB BSU go, the 20GiB ones stay
+    assert_eq!(removed, 2);
+    assert!(!drive.rebalance_requested);
+    let mut remaining: Vec<String> = volumes
+        .volumes()
+        .iter()
+        .filter_map(|volume| volume.volume_id.clone())
+        .collect();
+    remaining.sort();
+    assert_eq!(remaining, vec!["vol-c", "vol-d"]);
+    assert_eq!(
+        executor
+            .calls()
+            .iter()
+            .filter(|call| call.starts_with("btrfs device delete"))
+            .count(),
+        2
+    );
+
+    // Safety checks still apply: a removal which does not fit ends the rebalance
+    drive.rebalance_requested = true;
+    drive.fetch_all_drive_bsu().expect("fetch drive BSU");
+    drive.usage_source = UsageSource::Simulated {
+        used_bytes: gib_to_bytes(1),
+        size_bytes: gib_to_bytes(40),
+    };
+    let state = drive.gather_state().expect("simulated state");
+    // usage grew meanwhile, the filesystem cannot shrink by 20GiB anymore
+    drive.usage_source = UsageSource::Simulated {
+        used_bytes: gib_to_bytes(39),
+        size_bytes: gib_to_bytes(40),
+    };
+    assert!(drive.rebalance_step(&state).is_err());
+    assert!(!drive.rebalance_requested);
+    assert_eq!(volumes.volumes().len(), 2);
+
+    let config = ConfigFileDrive {
+        rebalance_schedule: Some("7d".to_string()),
+        ..drive_config("data", 4)
+    };
+    let (_sender, receiver) = channel::<DriveCmd>();
+    assert_eq!(
+        Drive::new(config.clone(), receiver).rebalance_interval_s,
+        Some(7 * 86400)
+    );
+    let error = config_error(vec![ConfigFileDrive {
+        append_only: Some(true),
+        ..config
+    }]);
+    assert!(error.contains("invalid rebalance-schedule"), "{}", error);
+}
+
+#[test]
+fn scale_action_high_space_removes_largest() {
+    let state = test_state(&[10, 12], 1);
+    assert_eq!(
+        test_drive().scale_action(&state),
+        ScaleAction::RemoveLargest
+    );
+}
+
+#[test]
+fn shrink_floor_stops_removals() {
+    let state = test_state(&[10, 12], 1);
+    let mut drive = test_drive();
+    drive.min_bsu_count = 2;
+    assert_eq!(drive.scale_action(&state), ScaleAction::None);
+    drive.min_bsu_count = 1;
+    drive.floor_size_gib = Some(12);
+    assert_eq!(drive.scale_action(&state), ScaleAction::None);
+    drive.floor_size_gib = Some(10);
+    assert_eq!(drive.scale_action(&state), ScaleAction::RemoveLargest);
+
+    // A single large BSU is not replaced by a smaller one below the floor
+    let state = test_state(&[20], 1);
+    assert_eq!(test_drive().scale_action(&state), ScaleAction::CreateIdeal);
+    drive.floor_size_gib = Some(20);
+    assert_eq!(drive.ideal_size_bytes(&state), gib_to_bytes(20));
+    assert_eq!(drive.scale_action(&state), ScaleAction::None);
+
+    let gib = gib_to_bytes(1);
+    assert!(keeps_shrink_floor(&[10 * gib, 12 * gib], 12 * gib, 1, None));
+    assert!(!keeps_shrink_floor(
+        &[10 * gib, 12 * gib],
+        12 * gib,
+        2,
+        None
+    ));
+    assert!(!keeps_shrink_floor(
+        &[10 * gib, 12 * gib],
+        12 * gib,
+        1,
+        Some(11)
+    ));
+
+    let (_sender, receiver) = channel::<DriveCmd>();
+    let drive = Drive::new(drive_config("data", 4), receiver);
+    assert_eq!(drive.min_bsu_count, 1);
+    assert_eq!(drive.floor_size_gib, None);
+    let error = config_error(vec![ConfigFileDrive {
+        min_bsu_count: Some(5),
+        ..drive_config("data", 4)
+    }]);
+    assert!(error.contains("invalid min-bsu-count"), "{}", error);
+    let error = config_error(vec![ConfigFileDrive {
+        floor_size_gib: Some(200),
+        max_total_size_gib: Some(100),
+        ..drive_config("data", 4)
+    }]);
+    assert!(error.contains("invalid floor-size-gib"), "{}", error);
+}
+
+#[test]
+fn near_full_fast_path() {
+    assert!(!is_near_full(&test_state(&[20], 18), 0.95));
+    assert!(is_near_full(&test_state(&[20], 19), 0.95));
+    assert!(!is_near_full(&test_state(&[], 0), 0.95));
+    let error = config_error(vec![ConfigFileDrive {
+        emergency_used_space_perc: Some(80),
+        ..drive_config("data", 4)
+    }]);
+    assert!(
+        error.contains("invalid emergency-used-space-perc"),
+        "{}",
+        error
+    );
+
+    // Usage crossing the threshold makes the drive due right after a reconcile
+    let mut drive = test_drive();
+    drive.last_reconcile = datetime::Instant::now();
+    drive.usage_source = UsageSource::Simulated {
+        used_bytes: gib_to_bytes(19),
+        size_bytes: gib_to_bytes(20),
+    };
+    assert!(!drive.is_due());
+    drive.emergency_used_space_perc = Some(0.95);
+    assert!(drive.is_due());
+    // Usage is not read again before the check interval
+    assert!(!drive.check_near_full());
+    // A reconcile which could not grow the drive does not make it loop
+    drive.near_full_seen = true;
+    drive.last_near_full_check_epoch_s = 0;
+    assert!(!drive.is_due());
+    drive.usage_source = UsageSource::Simulated {
+        used_bytes: gib_to_bytes(10),
+        size_bytes: gib_to_bytes(20),
+    };
+    drive.last_near_full_check_epoch_s = 0;
+    assert!(!drive.check_near_full());
+    assert!(!drive.near_full_seen);
+
+    // Creations are not paced when the drive is near full
+    let state = test_state(&[10, 10], 19);
+    drive.min_creation_interval_s = 600;
+    drive.last_creation_epoch_s = Some(datetime::Instant::now().seconds());
+    drive.emergency_used_space_perc = None;
+    assert_eq!(drive.scale_action(&state), ScaleAction::None);
+    drive.emergency_used_space_perc = Some(0.95);
+    assert!(drive.scale_action(&state).creates_bsu());
+}
+
+#[test]
+fn subregion_override() {
+    assert!(is_plausible_subregion("eu-west-2a"));
+    assert!(is_plausible_subregion("cloudgouv-eu-west-1c"));
+    assert!(!is_plausible_subregion("eu-west-2"));
+    assert!(!is_plausible_subregion("eu-west-a"));
+    assert!(!is_plausible_subregion("EU-west-2a"));
+    assert!(!is_plausible_subregion(""));
+    let error = config_error(vec![ConfigFileDrive {
+        subregion: Some("west".to_string()),
+        ..drive_config("data", 4)
+    }]);
+    assert!(error.contains("invalid subregion"), "{}", error);
+
+    // BSU are created in the configured subregion, which refuses attaching them elsewhere
+    *config::VM_ID.write().expect("vm id") = "i-fake".to_string();
+    *config::SUBREGION.write().expect("subregion") = "eu-west-2a".to_string();
+    let volumes = Arc::new(FakeVolumes::new(Vec::new()));
+    let mut drive = test_drive().with_volume_backend(volumes.clone());
+    drive.wait_options = WaitOptions {
+        timeout: Duration::from_secs(5),
+        poll_interval: Duration::from_millis(1),
+    };
+    drive.subregion = Some("eu-west-2b".to_string());
+    drive.create_bsu_gib(10).expect("create BSU");
+    drive.fetch_all_drive_bsu().expect("fetch drive BSU");
+    assert_eq!(drive.all_bsu()[0].subregion.as_deref(), Some("eu-west-2b"));
+    let err = drive.bsu_attach_missing().expect_err("other subregion");
+    assert!(matches!(
+        err.downcast_ref::<BsudError>(),
+        Some(BsudError::Config(_))
+    ));
+    assert!(
+        err.to_string().contains("is in subregion eu-west-2b"),
+        "{}",
+        err
+    );
+
+    // Without override, BSU follow the VM
+    let volumes = Arc::new(FakeVolumes::new(Vec::new()));
+    let mut drive = test_drive().with_volume_backend(volumes.clone());
+    drive.wait_options = WaitOptions {
+        timeout: Duration::from_secs(5),
+        poll_interval: Duration::from_millis(1),
+    };
+    drive.create_bsu_gib(10).expect("create BSU");
+    drive.fetch_all_drive_bsu().expect("fetch drive BSU");
+    assert_eq!(drive.all_bsu()[0].subregion.as_deref(), Some("eu-west-2a"));
+    assert!(drive.all_bsu()[0].check_subregion("eu-west-2a").is_ok());
+}
+
+#[test]
+fn scale_action_high_space_single_bsu() {
+    let state = test_state(&[20], 1);
+    assert_eq!(test_drive().scale_action(&state), ScaleAction::CreateIdeal);
+    let state = test_state(&[10], 1);
+    assert_eq!(test_drive().scale_action(&state), ScaleAction::None);
+
+    // The fit BSU would stand next to the current one above max total size
+    let state = test_state(&[20], 1);
+    let mut drive = test_drive();
+    drive.max_total_size_gib = Some(25);
+    assert!(!drive.ideal_bsu_fits(&state));
+    assert_eq!(drive.scale_action(&state), ScaleAction::None);
+    drive.max_total_size_gib = Some(30);
+    assert!(drive.ideal_bsu_fits(&state));
+    assert_eq!(drive.scale_action(&state), ScaleAction::CreateIdeal);
+}
+
+#[test]
+fn scale_action_free_bytes_thresholds() {
+    let mut drive = test_drive();
+    drive.min_free_bytes = Some(gib_to_bytes(10));
+
+    // 90% used but 20GiB free: no growth, even if percentages alone would grow
+    let state = test_state(&[100, 100], 180);
+    assert!(!drive.is_drive_low_space_left(&state));
+    assert_eq!(drive.scale_action(&state), ScaleAction::None);
+    assert_ne!(test_drive().scale_action(&state), ScaleAction::None);
+    let state = test_state(&[100, 100], 195);
+    assert!(drive.is_drive_low_space_left(&state));
+
+    // Below min-used-space-perc, shrinking waits for max-free-bytes
+    let state = test_state(&[100, 100], 30);
+    assert_eq!(
+        test_drive().scale_action(&state),
+        ScaleAction::RemoveLargest
+    );
+    drive.max_free_bytes = Some(gib_to_bytes(200));
+    assert_eq!(drive.scale_action(&state), ScaleAction::None);
+    drive.max_free_bytes = Some(gib_to_bytes(50));
+    let state = test_state(&[100, 100], 50);
+    assert_eq!(test_drive().scale_action(&state), ScaleAction::None);
+    assert_eq!(drive.scale_action(&state), ScaleAction::RemoveLargest);
+    // Ideal size keeps free space between both thresholds
+    let state = test_state(&[100, 100], 120);
+    assert_eq!(drive.ideal_size_bytes(&state), gib_to_bytes(150));
+
+    assert_eq!(ideal_free_bytes(100, None, None), 100);
+    assert_eq!(ideal_free_bytes(100, Some(80), None), 160);
+    assert_eq!(ideal_free_bytes(200, Some(80), None), 200);
+    assert_eq!(ideal_free_bytes(100, None, Some(120)), 60);
+    assert_eq!(ideal_free_bytes(100, Some(20), Some(60)), 40);
+
+    let error = config_error(vec![ConfigFileDrive {
+        min_free_bytes: Some(gib_to_bytes(50)),
+        max_free_bytes: Some(gib_to_bytes(10)),
+        ..drive_config("data", 4)
+    }]);
+    assert!(error.contains("invalid min-free-bytes"), "{}", error);
+}
+
+#[test]
+fn removal_safety_margin() {
+    let usage = "Overall:
+    Device size:\t\t\t  21474836480
+    Device allocated:\t\t  12884901888
+    Device unallocated:\t\t   8589934592
+    Device missing:\t\t\t            0
+    Used:\t\t\t   6442450944
+    Free (estimated):\t\t  14495514624\t(min: 10200547328)
+";
+    assert_eq!(
+        parse_btrfs_usage(usage).map(|usage| usage.device_unallocated_bytes),
+        Some(gib_to_bytes(8))
+    );
+    assert_eq!(parse_btrfs_usage("Overall:\n"), None);
+
+    let executor = MockExecutor::new();
+    executor.reply("btrfs filesystem usage", ExecOutput::ok(usage));
+    assert_eq!(
+        btrfs_unallocated_bytes_with(&executor, "/mnt").expect("unallocated"),
+        gib_to_bytes(8)
+    );
+    assert_eq!(executor.calls(), vec!["btrfs filesystem usage -b /mnt"]);
+    assert!(btrfs_unallocated_bytes_with(&executor, "/mnt").is_err());
+
+    let margin = gib_to_bytes(1);
+    assert!(removal_fits(
+        gib_to_bytes(10),
+        gib_to_bytes(11),
+        None,
+        margin
+    ));
+    // available space alone is not enough once the margin is counted
+    assert!(!removal_fits(
+        gib_to_bytes(10),
+        gib_to_bytes(10),
+        None,
+        margin
+    ));
+    assert!(removal_fits(gib_to_bytes(10), gib_to_bytes(10), None, 0));
+    // btrfs chunks must also fit in unallocated space
+    assert!(!removal_fits(
+        gib_to_bytes(10),
+        gib_to_bytes(14),
+        Some(gib_to_bytes(8)),
+        margin
+    ));
+    assert!(removal_fits(
+        gib_to_bytes(10),
+        gib_to_bytes(14),
+        Some(gib_to_bytes(11)),
+        margin
+    ));
+
+    assert_eq!(test_drive().removal_margin_bytes, gib_to_bytes(1));
+    let config: ConfigFileDrive = serde_json::from_str(
+        r#"{"name": "test", "mount-path": "/mnt", "removal-margin-bytes": 0}"#,
+    )
+    .expect("config with removal-margin-bytes");
+    let (_sender, receiver) = channel::<DriveCmd>();
+    assert_eq!(Drive::new(config, receiver).removal_margin_bytes, 0);
+}
+
+#[test]
+fn physical_usage_basis() {
+    // 30GiB written, compressed down to 12GiB on the devices
+    let usage = "Overall:
+    Device size:\t\t\t  21474836480
+    Device allocated:\t\t  14495514624
+    Device unallocated:\t\t   6979321856
+    Device missing:\t\t\t            0
+    Device slack:\t\t\t            0
+    Used:\t\t\t  12884901888
+    Free (estimated):\t\t   8589934592\t(min: 5100273664)
+    Free (statfs, df):\t\t   8589934592
+    Data ratio:\t\t\t         1.00
+    Metadata ratio:\t\t         2.00
+    Global reserve:\t\t     16777216\t(used: 0)
+    Multiple profiles:\t\t           no
+
+Data,single: Size:13421772800, Used:12348030976 (92.00%)
+   /dev/mapper/data-bsud\t13421772800
+
+Metadata,DUP: Size:536870912, Used:268435456 (50.00%)
+   /dev/mapper/data-bsud\t1073741824
+";
+    let parsed = parse_btrfs_usage(usage).expect("btrfs usage");
+    assert_eq!(
+        parsed,
+        BtrfsUsage {
+            device_size_bytes: gib_to_bytes(20),
+            device_allocated_bytes: 14495514624,
+            device_unallocated_bytes: 6979321856,
+            used_bytes: gib_to_bytes(12),
+        }
+    );
+    assert_eq!(parsed.available_bytes(), gib_to_bytes(8));
+    assert_eq!(
+        parse_btrfs_usage("Overall:\n    Device size:\t\t  21474836480\n"),
+        None
+    );
+
+    let executor = Arc::new(MockExecutor::new());
+    let mut drive = test_drive().with_executor(executor.clone());
+    assert_eq!(drive.usage_basis, UsageBasis::Logical);
+    drive.usage_basis = UsageBasis::Physical;
+    executor.reply("btrfs filesystem usage", ExecOutput::ok(usage));
+    let state = drive.gather_state().expect("physical state");
+    assert_eq!(state.used_bytes, gib_to_bytes(12));
+    assert_eq!(state.size_bytes, gib_to_bytes(20));
+    assert_eq!(state.available_bytes, gib_to_bytes(8));
+    assert_eq!(executor.calls(), vec!["btrfs filesystem usage -b /mnt"]);
+
+    let config: ConfigFileDrive = serde_json::from_str(
+        r#"{"name": "test", "mount-path": "/mnt", "usage-basis": "physical"}"#,
+    )
+    .expect("config with usage-basis");
+    assert_eq!(config.usage_basis, Some(UsageBasis::Physical));
+    let config = ConfigFileDrive {
+        usage_basis: Some(UsageBasis::Physical),
+        filesystem: Some(Filesystem::Xfs),
+        ..drive_config("data", 4)
+    };
+    assert!(config_error(vec![config]).contains("invalid usage-basis"));
+}
+
+#[test]
+fn missing_pv_detection() {
+    let vg = Vg {
+        vg_missing_pv_count: "0".to_string(),
+        ..Default::default()
+    };
+    assert!(!vg.is_partial());
+    assert_eq!(test_drive().missing_pv_policy(&vg), None);
+    let vg = Vg {
+        vg_partial: "partial".to_string(),
+        vg_missing_pv_count: "1".to_string(),
+        ..Default::default()
+    };
+    assert!(vg.is_partial());
+    assert_eq!(vg.missing_pv_count(), 1);
+}
+
+#[test]
+fn missing_pv_policy_selection() {
+    let vg = Vg {
+        vg_partial: "partial".to_string(),
+        vg_missing_pv_count: "2".to_string(),
+        ..Default::default()
+    };
+    let mut drive = test_drive();
+    assert_eq!(
+        drive.missing_pv_policy(&vg),
+        Some(MissingPvPolicy::Reattach)
+    );
+    drive.on_missing_pv = MissingPvPolicy::Readonly;
+    assert_eq!(
+        drive.missing_pv_policy(&vg),
+        Some(MissingPvPolicy::Readonly)
+    );
+    drive.on_missing_pv = MissingPvPolicy::Fail;
+    assert_eq!(drive.missing_pv_policy(&vg), Some(MissingPvPolicy::Fail));
+}
+
+#[test]
+fn persisted_last_success_drives_health() {
+    let state_dir = temp_dir().join("bsud-units-state");
+    let state_dir = state_dir.to_string_lossy().to_string();
+    let mut drive = test_drive();
+    drive.state_dir = state_dir.clone();
+    drive.load_persisted_state();
+    assert_eq!(drive.health(1000), DriveHealth::NeverReconciled);
+
+    let persisted = PersistedState {
+        last_success_epoch_s: Some(1000),
+        ..Default::default()
+    };
+    state::save(&state_dir, &drive.name, &persisted).expect("save state");
+    drive.load_persisted_state();
+    assert_eq!(drive.persisted, persisted);
+    assert_eq!(drive.health(1010), DriveHealth::Healthy);
+    assert_eq!(drive.health(1000 + 3600), DriveHealth::Stale);
+    remove_dir_all(&state_dir).expect("cleanup");
+}
+
+#[test]
+fn scale_action_append_only_never_removes() {
+    let mut drive = test_drive();
+    drive.append_only = true;
+    let mut bsu_sizes_gib = vec![10];
+    for used_gib in [9, 20, 1, 40, 2, 70, 0] {
+        let total_gib: usize = bsu_sizes_gib.iter().sum();
+        let used_gib = used_gib.min(total_gib);
+        let state = test_state(&bsu_sizes_gib, used_gib);
+        match drive.scale_action(&state) {
+            ScaleAction::None => {}
+            ScaleAction::CreateLarger => {
+                let largest = *bsu_sizes_gib.iter().max().expect("largest");
+                bsu_sizes_gib.push(largest + largest / 5);
+            }
+            ScaleAction::CreateSmaller => {
+                let smallest = *bsu_sizes_gib.iter().min().expect("smallest");
+                bsu_sizes_gib.push(smallest - smallest / 5);
+            }
+            action => panic!("append only drive should not {:?}", action),
+        }
+    }
+    assert!(bsu_sizes_gib.len() > 1);
+}
+
+#[test]
+fn full_append_only_drive_at_max_bsu_count() {
+    let mut drive = test_drive();
+    drive.append_only = true;
+    // A smaller BSU would waste a slot which can never be freed
+    let state = test_state(&[12, 14], 24);
+    assert_eq!(drive.scale_action(&state), ScaleAction::CreateLarger);
+
+    let state = test_state(&[10, 12, 14, 17], 50);
+    assert_eq!(drive.scale(&state).expect("scale"), ScaleAction::None);
+    assert!(drive.status().growth_blocked);
+
+    let state = test_state(&[10, 12, 14, 17], 20);
+    assert_eq!(drive.scale(&state).expect("scale"), ScaleAction::None);
+    assert!(!drive.status().growth_blocked);
+}
+
+#[test]
+fn readiness_requires_all_mount_checks() {
+    let drive = test_drive();
+    let valid = MountCheck {
+        mounted: true,
+        fs_type: Some("btrfs".to_string()),
+        writable: true,
+    };
+    assert!(drive.is_ready(&valid));
+    let not_mounted = MountCheck {
+        mounted: false,
+        ..valid.clone()
+    };
+    assert!(!drive.is_ready(&not_mounted));
+    let bad_fs = MountCheck {
+        fs_type: Some("ext4".to_string()),
+        ..valid.clone()
+    };
+    assert!(!drive.is_ready(&bad_fs));
+    let no_fs = MountCheck {
+        fs_type: None,
+        ..valid.clone()
+    };
+    assert!(!drive.is_ready(&no_fs));
+    let read_only = MountCheck {
+        writable: false,
+        ..valid
+    };
+    assert!(!drive.is_ready(&read_only));
+}
+
+#[test]
+fn discover_changes_nothing() {
+    *config::VM_ID.write().expect("vm id") = "i-fake".to_string();
+    let volumes = Arc::new(FakeVolumes::new(vec![fake_volume(
+        "vol-unmanaged",
+        "test",
+        10,
+        Some("i-fake"),
+    )]));
+    let (_sender, receiver) = channel::<DriveCmd>();
+    let config = ConfigFileDrive {
+        name: "test".to_string(),
+        mount_path: "/mnt/bsud-units-discover".to_string(),
+        adopt_unmanaged_bsu: Some(true),
+        ..Default::default()
+    };
+    let executor = Arc::new(MockExecutor::new());
+    executor.reply("lvm fullreport", ExecOutput::ok(r#"{"report": []}"#));
+    let mut drive = Drive::new(config, receiver)
+        .with_volume_backend(volumes.clone())
+        .with_executor(executor);
+    let report = drive.discover().expect("discover");
+    assert_eq!(report.all_bsu.len(), 1);
+    assert!(report
+        .planned
+        .contains(&"adopt 1 unmanaged BSU".to_string()));
+    let after = volumes.volumes();
+    assert_eq!(after.len(), 1);
+    assert!(after
+        .iter()
+        .all(|volume| volume.tags.as_ref().map(Vec::len) == Some(1)));
+}
+
+#[test]
+fn minimal_size_tolerates_rounding() {
+    let drive = test_drive();
+    assert!(drive.has_minimal_size(&test_state(&[10], 1)));
+    assert!(drive.has_minimal_size(&test_state(&[11], 1)));
+    assert!(!drive.has_minimal_size(&test_state(&[20], 1)));
+}
+
+#[test]
+fn max_space_is_exact() {
+    let mut drive = test_drive();
+    drive.max_total_size_gib = Some(22);
+    assert!(!drive.is_max_space_reached(&test_state(&[10, 10], 1)));
+    assert!(!drive.is_max_space_reached(&test_state(&[10, 11], 1)));
+    assert!(drive.is_max_space_reached(&test_state(&[10, 12], 1)));
+}
+
+#[test]
+fn fs_size_capped_by_max_fs_size() {
+    let mut drive = test_drive();
+    assert_eq!(
+        drive.lv_target_size_bytes(gib_to_bytes(30)),
+        gib_to_bytes(30)
+    );
+    drive.max_fs_size_gib = Some(20);
+    assert_eq!(
+        drive.lv_target_size_bytes(gib_to_bytes(30)),
+        gib_to_bytes(20)
+    );
+    assert_eq!(
+        drive.lv_target_size_bytes(gib_to_bytes(12)),
+        gib_to_bytes(12)
+    );
+}
+
+#[test]
+fn scale_action_no_growth_at_max_fs_size() {
+    let mut drive = test_drive();
+    drive.max_fs_size_gib = Some(10);
+    let state = test_state(&[10], 9);
+    assert_eq!(drive.scale_action(&state), ScaleAction::None);
+}
+
+#[test]
+fn discover_unknown_drive_from_tagged_bsu() {
+    let bsu = |id: &str, drive_name: &str| Bsu {
+        id: id.to_string(),
+        drive_name: drive_name.to_string(),
+        size_gib: 10,
+        size_bytes: gib_to_bytes(10),
+        ..Default::default()
+    };
+    let all_bsu = vec![
+        bsu("vol-00000001", "unknown"),
+        bsu("vol-00000002", "known"),
+        bsu("vol-00000003", "unknown"),
+    ];
+    let known_drives: HashSet<String> = ["known".to_string()].into_iter().collect();
+    let discovery = Drives::drives_from_bsu(all_bsu, &known_drives);
+    assert_eq!(discovery.len(), 1);
+    let (_sender, drive) = &discovery[0];
+    assert_eq!(drive.name, "unknown");
+    let mut ids: Vec<&str> = drive.all_bsu().iter().map(|bsu| bsu.id.as_str()).collect();
+    ids.sort();
+    assert_eq!(ids, vec!["vol-00000001", "vol-00000003"]);
+}
+
+fn report_with_pvs(pv_names: &[&str]) -> Lvm {
+    Lvm {
+        vg: vec![Vg {
+            vg_name: "test".to_string(),
+            ..Default::default()
+        }],
+        pv: pv_names
+            .iter()
+            .map(|pv_name| Pv {
+                pv_name: pv_name.to_string(),
+                ..Default::default()
+            })
+            .collect(),
+        ..Default::default()
+    }
+}
+
+fn attached_bsu(device_paths: &[&str]) -> Vec<Bsu> {
+    device_paths
+        .iter()
+        .map(|device_path| Bsu {
+            device_path: Some(device_path.to_string()),
+            ..Default::default()
+        })
+        .collect()
+}
+
+#[test]
+fn adopt_vg_matching_bsu() {
+    let report = report_with_pvs(&["/dev/xvdb", "/dev/xvdc"]);
+    let all_bsu = attached_bsu(&["/dev/xvdb", "/dev/xvdc"]);
+    assert!(foreign_pvs(&report, &all_bsu).is_empty());
+}
+
+#[test]
+fn refuse_foreign_vg() {
+    let report = report_with_pvs(&["/dev/xvdb", "/dev/sdz"]);
+    let all_bsu = attached_bsu(&["/dev/xvdb", "/dev/xvdc"]);
+    assert_eq!(foreign_pvs(&report, &all_bsu), vec!["/dev/sdz".to_string()]);
+}
+
+#[test]
+fn drive_tags_reconciled_with_vg() {
+    let foreign = vec!["/dev/sdz".to_string(), "/dev/xvdc".to_string()];
+    let untagged = vec![
+        ("vol-c".to_string(), "/dev/xvdc".to_string()),
+        ("vol-q".to_string(), "/dev/xvdq".to_string()),
+    ];
+    assert_eq!(untagged_vg_members(&foreign, &untagged), vec!["vol-c"]);
+    let all_bsu = vec![
+        Bsu {
+            id: "vol-b".to_string(),
+            device_path: Some("/dev/xvdb".to_string()),
+            ..Default::default()
+        },
+        Bsu {
+            id: "vol-d".to_string(),
+            device_path: Some("/dev/xvdd".to_string()),
+            ..Default::default()
+        },
+    ];
+    let report = report_with_pvs(&["/dev/xvdb"]);
+    assert_eq!(orphan_bsu(&report, &all_bsu), vec!["vol-d"]);
+
+    // A pv of the vg which lost its drive tag is adopted back, other volumes of the VM
+    // are left alone
+    *config::VM_ID.write().expect("vm id") = "i-fake".to_string();
+    let untagged_volume = |id: &str, device: &str| {
+        let mut volume = fake_volume(id, "test", 10, Some("i-fake"));
+        volume.tags = None;
+        if let Some(linked_volumes) = volume.linked_volumes.as_mut() {
+            linked_volumes[0].device_name = Some(device.to_string());
+        }
+        volume
+    };
+    let volumes = Arc::new(FakeVolumes::new(vec![
+        fake_volume("vol-b", "test", 10, Some("i-fake")),
+        untagged_volume("vol-c", "/dev/xvdc"),
+        untagged_volume("vol-q", "/dev/xvdq"),
+    ]));
+    let executor = Arc::new(MockExecutor::new());
+    let mut drive = test_drive()
+        .with_volume_backend(volumes.clone())
+        .with_executor(executor.clone());
+    let vg = r#"{"report": [{"vg": [{"vg_name": "test"}], "pv": [{"pv_name": "/dev/xvdb"}, {"pv_name": "/dev/xvdc"}]}]}"#;
+    drive.fetch_all_drive_bsu().expect("fetch drive BSU");
+    assert_eq!(drive.bsu_count(), 1);
+    executor.reply("lvm fullreport", ExecOutput::ok(vg));
+    assert!(drive.restore_drive_tags().expect("restored tags"));
+    drive.fetch_all_drive_bsu().expect("fetch drive BSU");
+    let mut ids: Vec<&str> = drive.all_bsu().iter().map(|bsu| bsu.id.as_str()).collect();
+    ids.sort();
+    assert_eq!(ids, vec!["vol-b", "vol-c"]);
+    // Only the drive tag is restored, the volume stays unmanaged
+    assert!(!drive.all_bsu().iter().any(|bsu| bsu.managed));
+    executor.reply("lvm fullreport", ExecOutput::ok(vg));
+    assert!(!drive.restore_drive_tags().expect("nothing to restore"));
+
+    // A BSU tagged for the drive outside of the vg is reported
+    executor.reply(
+        "lvm fullreport",
+        ExecOutput::ok(
+            r#"{"report": [{"vg": [{"vg_name": "test"}], "pv": [{"pv_name": "/dev/xvdb"}]}]}"#,
+        ),
+    );
+    drive.detect_orphan_bsu().expect("orphan detection");
+    assert_eq!(drive.orphan_bsu, vec!["vol-c"]);
+    assert_eq!(drive.status().orphan_bsu, vec!["vol-c"]);
+}
+
+#[test]
+fn discovered_drive_offline_until_promoted() {
+    let all_bsu = vec![Bsu {
+        id: "vol-00000001".to_string(),
+        drive_name: "discovered".to_string(),
+        ..Default::default()
+    }];
+    let mut discovery = Drives::drives_from_bsu(all_bsu, &HashSet::new());
+    let (sender, mut drive) = discovery.pop().expect("discovered drive");
+    assert_eq!(drive.target, DriveTarget::Offline);
+    drive.early_exit().expect("no command");
+    assert_eq!(drive.target, DriveTarget::Offline);
+    sender
+        .send(DriveCmd::SetTarget(DriveTarget::Online))
+        .expect("send promotion");
+    drive.early_exit().expect("promotion is not an exit");
+    assert_eq!(drive.target, DriveTarget::Online);
+}
+
+#[test]
+fn next_available_nvme_device() {
+    let fake_dev = ["/dev/nvme0", "/dev/nvme0n1", "/dev/nvme1n1", "/dev/nvme2n1"];
+    let scheme = DeviceScheme::detect(|device: &str| fake_dev.contains(&device));
+    assert_eq!(scheme, DeviceScheme::Nvme);
+
+    // The API is given the first xvd name not linked to the VM yet
+    let mut other_vm = fake_volume("vol-other", "test", 10, Some("i-other"));
+    if let Some(linked) = other_vm.linked_volumes.as_mut() {
+        linked[0].device_name = Some("/dev/xvdc".to_string());
+    }
+    let volumes = FakeVolumes::new(vec![
+        fake_volume("vol-0123abcd", "test", 10, Some("i-fake")),
+        other_vm,
+    ]);
+    let linked = Bsu::vm_device_names(&volumes, &"i-fake".to_string()).expect("device names");
+    assert_eq!(linked, vec!["/dev/xvdb".to_string()]);
+    let exists = |device: &str| fake_dev.contains(&device) || linked.iter().any(|l| l == device);
+    assert_eq!(
+        find_next_available_device_in(&scheme, exists),
+        Some("/dev/xvdc".to_string())
+    );
+
+    // while the host finds the BSU on the nvme node the kernel chose
+    let dev = temp_dir().join("bsud-units-nvme-by-id");
+    let _ = remove_dir_all(&dev);
+    let by_id = dev.join("disk/by-id");
+    create_dir_all(&by_id).expect("by-id dir");
+    write(dev.join("nvme1n1"), "").expect("fake device");
+    symlink(
+        "../../nvme1n1",
+        by_id.join("nvme-Outscale_BSU_vol-0123abcd"),
+    )
+    .expect("link");
+    let nvme1n1 = dev.join("nvme1n1").canonicalize().expect("canonical path");
+    assert_eq!(
+        device_by_id(&by_id, "vol-0123abcd"),
+        Some(nvme1n1.to_string_lossy().to_string())
+    );
+    remove_dir_all(&dev).expect("cleanup");
+}
+
+#[test]
+fn renamed_device_found_by_id() {
+    let dev = temp_dir().join("bsud-units-by-id");
+    let _ = remove_dir_all(&dev);
+    let by_id = dev.join("disk/by-id");
+    create_dir_all(&by_id).expect("by-id dir");
+    for device in ["nvme3n1", "nvme3n1p1", "vdc"] {
+        write(dev.join(device), "").expect("fake device");
+    }
+    symlink(
+        "../../nvme3n1",
+        by_id.join("nvme-Outscale_BSU_vol-0123abcd"),
+    )
+    .expect("link");
+    symlink(
+        "../../nvme3n1p1",
+        by_id.join("nvme-Outscale_BSU_vol-0123abcd-part1"),
+    )
+    .expect("link");
+    symlink("../../vdc", by_id.join("virtio-vol4567ef01")).expect("link");
+    let canonical = |device: &str| {
+        dev.join(device)
+            .canonicalize()
+            .expect("canonical path")
+            .to_string_lossy()
+            .to_string()
+    };
+    assert_eq!(
+        device_by_id(&by_id, "vol-0123abcd"),
+        Some(canonical("nvme3n1"))
+    );
+    // Serials may drop the dash of the volume id
+    assert_eq!(device_by_id(&by_id, "vol-4567ef01"), Some(canonical("vdc")));
+    assert_eq!(device_by_id(&by_id, "vol-89ab"), None);
+    assert_eq!(device_by_id(&dev.join("missing"), "vol-0123abcd"), None);
+
+    // The API still reports the former device name after a reattachment
+    *config::VM_ID.write().expect("vm id") = "i-fake".to_string();
+    let mut volume = fake_volume("vol-0123abcd", "test", 10, Some("i-fake"));
+    if let Some(linked) = volume.linked_volumes.as_mut() {
+        linked[0].device_name = Some(dev.join("xvdq").to_string_lossy().to_string());
+    }
+    let volumes = Arc::new(FakeVolumes::new(vec![volume]));
+    let mut drive = test_drive().with_volume_backend(volumes);
+    drive.disk_by_id_dir = by_id.to_string_lossy().to_string();
+    drive.fetch_all_drive_bsu().expect("fetch drive BSU");
+    assert_eq!(drive.all_bsu()[0].device_path, Some(canonical("nvme3n1")));
+    assert!(drive.are_bsu_attached().expect("attached"));
+    remove_dir_all(&dev).expect("cleanup");
+}
+
+// Volume created by the bsud of `owner`, `drive_name` None when the drive tag is lost
+fn owned_volume(id: &str, drive_name: Option<&str>, owner: &str, vm_id: Option<&str>) -> Volume {
+    let tag_keys = TagKeys::default();
+    let mut volume = fake_volume(id, drive_name.unwrap_or_default(), 10, vm_id);
+    let mut tags = vec![
+        ResourceTag::new(tag_keys.managed(), "true".to_string()),
+        ResourceTag::new(tag_keys.owner(), owner.to_string()),
+    ];
+    if drive_name.is_some() {
+        tags.extend(volume.tags.take().unwrap_or_default());
+    }
+    volume.tags = Some(tags);
+    volume
+}
+
+#[test]
+fn orphan_bsu_sweep() {
+    let live_drives: HashSet<String> = ["data".to_string()].into_iter().collect();
+    let available = Bsu {
+        drive_name: "old".to_string(),
+        state: "available".to_string(),
+        ..Default::default()
+    };
+    assert_eq!(
+        classify(&available, &live_drives, 1000, 1100, 3600),
+        OrphanStatus::Grace
+    );
+    assert_eq!(
+        classify(&available, &live_drives, 1000, 4600, 3600),
+        OrphanStatus::Orphan
+    );
+    let live = Bsu {
+        drive_name: "data".to_string(),
+        ..available.clone()
+    };
+    assert_eq!(
+        classify(&live, &live_drives, 1000, 4600, 3600),
+        OrphanStatus::InUse
+    );
+    let attached = Bsu {
+        vm_id: Some("i-fake".to_string()),
+        state: "in-use".to_string(),
+        ..available.clone()
+    };
+    assert_eq!(
+        classify(&attached, &live_drives, 1000, 4600, 3600),
+        OrphanStatus::InUse
+    );
+
+    let volumes = Arc::new(FakeVolumes::new(vec![
+        owned_volume("vol-live", Some("data"), "i-fake", None),
+        owned_volume("vol-gone", Some("old"), "i-fake", None),
+        owned_volume("vol-attached", Some("old"), "i-fake", Some("i-fake")),
+        owned_volume("vol-untagged", None, "i-fake", None),
+        owned_volume("vol-other-vm", Some("old"), "i-other", None),
+        fake_volume("vol-unmanaged", "old", 10, None),
+    ]));
+    let config = Config {
+        orphan_sweep_schedule: Some("1h".to_string()),
+        orphan_grace_s: Some(3600),
+        ..Default::default()
+    };
+    let mut sweeper = config.orphan_sweeper().expect("sweeper");
+    assert_eq!(sweeper.interval, Duration::from_secs(3600));
+    assert!(!sweeper.auto_reap);
+    let sweep = |sweeper: &mut bsudlib::sweeper::Sweeper, now_epoch_s: i64| {
+        let mut report = sweeper
+            .sweep(volumes.as_ref(), "i-fake", &live_drives, now_epoch_s)
+            .expect("sweep");
+        report.reported.sort();
+        report
+    };
+    assert_eq!(sweep(&mut sweeper, 1000), SweepReport::default());
+    // Reported only, once the grace period is over
+    let report = sweep(&mut sweeper, 4600);
+    assert_eq!(report.reported, vec!["vol-gone", "vol-untagged"]);
+    assert!(report.reaped.is_empty() && report.failed.is_empty());
+    assert_eq!(volumes.volumes().len(), 6);
+
+    // A refused deletion is not reported as reaped
+    sweeper.auto_reap = true;
+    volumes.refuse_delete("vol-untagged");
+    let report = sweep(&mut sweeper, 4700);
+    assert!(report.reported.is_empty());
+    assert_eq!(report.reaped, vec!["vol-gone"]);
+    assert_eq!(report.failed, vec!["vol-untagged"]);
+
+    let mut remaining: Vec<String> = volumes
+        .volumes()
+        .into_iter()
+        .filter_map(|volume| volume.volume_id)
+        .collect();
+    remaining.sort();
+    assert_eq!(
+        remaining,
+        vec![
+            "vol-attached",
+            "vol-live",
+            "vol-other-vm",
+            "vol-unmanaged",
+            "vol-untagged"
+        ]
+    );
+
+    let config = Config {
+        auto_reap: Some(true),
+        ..Default::default()
+    };
+    let error = config.validate().expect_err("auto reap").to_string();
+    assert!(error.starts_with("invalid auto-reap"), "{}", error);
+}
+
+#[test]
+fn stop_interrupts_long_wait() {
+    let volumes = Arc::new(FakeVolumes::new(vec![fake_volume(
+        "vol-a", "test", 10, None,
+    )]));
+    let (sender, receiver) = channel::<DriveCmd>();
+    let mut drive = Drive::new(drive_config("test", 4), receiver);
+    let long_wait = WaitOptions {
+        timeout: Duration::from_secs(600),
+        poll_interval: Duration::from_secs(60),
+    };
+    let stopper = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(100));
+        sender.send(DriveCmd::Stop).expect("send stop");
+    });
+    let start = Instant::now();
+    // Never attached: the BSU stays available
+    let err = Bsu::wait_state(
+        volumes.as_ref(),
+        &"vol-a".to_string(),
+        "in-use",
+        &long_wait,
+        &mut || drive.early_exit(),
+    )
+    .expect_err("stopped wait");
+    assert!(
+        err.to_string().contains("early exit due to drive stop"),
+        "{}",
+        err
+    );
+    assert!(start.elapsed() < Duration::from_secs(5));
+    stopper.join().expect("stopper");
+}
+
+#[test]
+fn startup_interrupted_stops_started_drives() {
+    let (sender, receiver) = channel::<DriveCmd>();
+    let config = ConfigFileDrive {
+        name: "interrupted".to_string(),
+        mount_path: "/mnt".to_string(),
+        ..Default::default()
+    };
+    let mut drive = Drive::new(config, receiver);
+    drive.state_dir = temp_dir()
+        .join("bsud-interrupted")
+        .to_string_lossy()
+        .to_string();
+    let mut drives = Drives::new(1);
+    let interrupted = AtomicBool::new(false);
+    assert!(drives.interrupt_check(&interrupted).is_ok());
+
+    // Stop is pending before the drive thread starts: it must exit before any reconcile
+    sender.send(DriveCmd::Stop).unwrap();
+    drives.start_drive(sender, drive);
+    interrupted.store(true, Ordering::SeqCst);
+    let err = drives.interrupt_check(&interrupted).unwrap_err();
+    assert!(err.to_string().starts_with("startup interrupted"));
+}
+
+#[test]
+fn reconcile_trace_low_space() {
+    let state = test_state(&[10], 9);
+    let mut trace = ReconcileTrace::default();
+    let action = test_drive().scale_action_traced(&state, &mut trace);
+    assert_eq!(action, ScaleAction::CreateLarger);
+    assert_eq!(
+        trace.to_string(),
+        "max bsu reached: false -> low space left: true -> max space reached: false -> \
+         max fs size reached: false -> creation blocked: false -> can create smaller: false -> \
+         scale action: CreateLarger"
+    );
+}
+
+#[test]
+fn reconcile_trace_disabled() {
+    let mut drive = test_drive();
+    drive.trace_step("bsu attached", true);
+    assert!(drive.trace.transitions.is_empty());
+    drive.trace_reconcile = true;
+    drive.trace_step("bsu attached", true);
+    assert_eq!(drive.trace.to_string(), "bsu attached: true");
+}
+
+fn drive_config(name: &str, max_bsu_count: usize) -> ConfigFileDrive {
+    ConfigFileDrive {
+        name: name.to_string(),
+        mount_path: format!("/mnt/{}", name),
+        max_bsu_count: Some(max_bsu_count),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn config_reload_diff() {
+    let current: HashMap<String, ConfigFileDrive> = [
+        ("kept".to_string(), drive_config("kept", 4)),
+        ("changed".to_string(), drive_config("changed", 4)),
+        ("removed".to_string(), drive_config("removed", 4)),
+    ]
+    .into_iter()
+    .collect();
+    let running: HashSet<String> = ["kept", "changed", "removed", "discovered"]
+        .iter()
+        .map(|name| name.to_string())
+        .collect();
+    let new = vec![
+        drive_config("kept", 4),
+        drive_config("changed", 8),
+        drive_config("discovered", 4),
+        drive_config("added", 4),
+    ];
+    let diff = diff_configs(&current, &new, &running);
+    assert_eq!(diff.removed, vec!["removed".to_string()]);
+    assert_eq!(
+        diff.changed,
+        vec![drive_config("changed", 8), drive_config("discovered", 4)]
+    );
+    assert_eq!(diff.added, vec![drive_config("added", 4)]);
+}
+
+#[test]
+fn config_reload_apply() {
+    let mut drive = test_drive();
+    let config = ConfigFileDrive {
+        target: DriveTarget::Offline,
+        mount_path: "/elsewhere".to_string(),
+        max_bsu_count: Some(8),
+        max_used_space_perc: Some(90),
+        ..drive_config("test", 8)
+    };
+    drive.apply_config(config);
+    assert_eq!(drive.max_bsu_count, 8);
+    assert_eq!(drive.max_used_space_perc, 0.9);
+    assert_eq!(drive.target, DriveTarget::Offline);
+    assert_eq!(drive.mount_path, "/mnt");
+}
+
+#[test]
+fn drive_status_snapshot() {
+    let all_bsu = [10, 20]
+        .iter()
+        .enumerate()
+        .map(|(index, size_gib)| Bsu {
+            id: format!("vol-0000000{}", index),
+            drive_name: "status".to_string(),
+            size_gib: *size_gib,
+            size_bytes: gib_to_bytes(*size_gib),
+            ..Default::default()
+        })
+        .collect();
+    let mut discovery = Drives::drives_from_bsu(all_bsu, &HashSet::new());
+    let (sender, mut drive) = discovery.pop().expect("discovered drive");
+    drive.record_usage_sample(&test_state(&[10, 20], 12));
+    drive.persisted.last_error = Some(ReconcileError {
+        message: "previous failure".to_string(),
+        epoch_s: 1000,
+    });
+
+    let (status_sender, status_receiver) = channel::<DriveStatus>();
+    sender
+        .send(DriveCmd::Status(status_sender))
+        .expect("send status");
+    drive.early_exit().expect("status is not an exit");
+    let status = status_receiver.recv().expect("status answer");
+    assert_eq!(
+        status,
+        DriveStatus {
+            name: "status".to_string(),
+            target: DriveTarget::Offline,
+            bsu_count: 2,
+            total_size_bytes: gib_to_bytes(30),
+            fs_used_bytes: gib_to_bytes(12),
+            fs_size_bytes: gib_to_bytes(30),
+            seconds_to_full: None,
+            seconds_to_max: None,
+            last_reconcile_epoch_s: None,
+            last_success_epoch_s: None,
+            last_error: Some(ReconcileError {
+                message: "previous failure".to_string(),
+                epoch_s: 1000,
+            }),
+            last_scrub: None,
+            orphan_bsu: Vec::new(),
+            device_stats: Vec::new(),
+            growth_blocked: false,
+        }
+    );
+}
+
+#[test]
+fn grow_to_command() {
+    assert_eq!(grow_to_plan(&[10], 10, 4, None), Ok(vec![]));
+    assert_eq!(grow_to_plan(&[10], 100, 4, None), Ok(vec![90]));
+    assert_eq!(grow_to_plan(&[10], 20000, 4, None), Ok(vec![9995, 9995]));
+    assert_eq!(
+        grow_to_plan(&[10], 100, 4, Some(50)),
+        Err("100GiB is above max-total-size-gib (50GiB)".to_string())
+    );
+    assert_eq!(
+        grow_to_plan(&[10, 10, 10], 40000, 4, None),
+        Err("40000GiB needs 3 more BSU, max-bsu-count (4) allows 1".to_string())
+    );
+
+    let volumes = Arc::new(FakeVolumes::new(vec![fake_volume(
+        "vol-old", "test", 10, None,
+    )]));
+    let mut drive = test_drive().with_volume_backend(volumes.clone());
+    drive.wait_options = WaitOptions {
+        timeout: Duration::from_secs(5),
+        poll_interval: Duration::from_millis(1),
+    };
+    drive.max_bsu_count = 3;
+    drive.fetch_all_drive_bsu().expect("fetch drive BSU");
+    assert!(drive.request_grow_to(50000).is_err());
+    assert_eq!(drive.grow_to_gib, None);
+    assert!(!drive.grow_to_target().expect("nothing to grow"));
+
+    drive.request_grow_to(20000).expect("reachable size");
+    assert!(drive.is_due());
+    assert!(drive.grow_to_target().expect("grow"));
+    assert_eq!(drive.grow_to_gib, None);
+    let mut sizes: Vec<i32> = volumes
+        .volumes()
+        .iter()
+        .filter_map(|volume| volume.size)
+        .collect();
+    sizes.sort();
+    assert_eq!(sizes, vec![10, 9995, 9995]);
+    assert_eq!(drive.all_bsu().len(), 3);
+
+    let router = DriveRouter::default();
+    let (sender, receiver) = channel::<DriveCmd>();
+    router.insert("data".to_string(), sender);
+    let response = handle_line(r#"{"drive":"data","action":"grow-to"}"#, &router);
+    assert_eq!(response.error.as_deref(), Some("grow-to needs size_gib"));
+    let answer = thread::spawn(move || match receiver.recv() {
+        Ok(DriveCmd::GrowTo(500, reply)) => reply.send(Ok(())).expect("answer"),
+        other => panic!("unexpected command {:?}", other),
+    });
+    let response = handle_line(
+        r#"{"drive":"data","action":"grow-to","size_gib":500}"#,
+        &router,
+    );
+    answer.join().expect("drive answered");
+    assert!(response.ok);
+    assert_eq!(response.queued, Some(false));
+
+    // A busy drive gets the request later
+    let (sender, receiver) = channel::<DriveCmd>();
+    router.insert("data".to_string(), sender);
+    let queued = router
+        .grow_to("data", 500, Duration::from_millis(1))
+        .expect("queued grow-to");
+    assert!(queued);
+    assert!(matches!(receiver.recv(), Ok(DriveCmd::GrowTo(500, _))));
+    drop(receiver);
+    assert!(router
+        .grow_to("data", 500, Duration::from_millis(1))
+        .is_err());
+}
+
+#[test]
+fn control_socket_status() {
+    let router = DriveRouter::default();
+    let (sender, receiver) = channel::<DriveCmd>();
+    router.insert("data".to_string(), sender);
+    let drive = std::thread::spawn(move || {
+        if let Ok(DriveCmd::Status(status_sender)) = receiver.recv() {
+            status_sender.send(test_drive().status()).unwrap();
+        }
+    });
+    let response = handle_line(r#"{"drive":"data","action":"status"}"#, &router);
+    drive.join().unwrap();
+    assert!(response.ok);
+    assert_eq!(response.status.unwrap().name, "test");
+}
+
+fn strings(args: &[&str]) -> Vec<String> {
+    args.iter().map(|arg| arg.to_string()).collect()
+}
+
+#[test]
+fn fs_capacity_ignores_statvfs_overhead() {
+    let dumpe2fs = "Filesystem volume name:   <none>
+Block count:              2621440
+Reserved block count:     131072
+Block size:               4096
+Journal size:             64M
+";
+    let xfs_info = "meta-data=/dev/data/data    isize=512    agcount=4, agsize=655360 blks
+data     =                       bsize=4096   blocks=2621440, imaxpct=25
+log      =internal log           bsize=4096   blocks=16384, version=2
+";
+    let ten_gib = Some(gib_to_bytes(10));
+    assert_eq!(parse_capacity(&Filesystem::Ext4, dumpe2fs), ten_gib);
+    assert_eq!(parse_capacity(&Filesystem::Xfs, xfs_info), ten_gib);
+    assert_eq!(parse_capacity(&Filesystem::Ext4, "Block count: 12\n"), None);
+    assert_eq!(parse_capacity(&Filesystem::Xfs, dumpe2fs), None);
+
+    let executor = MockExecutor::new();
+    executor.reply("dumpe2fs", ExecOutput::ok(dumpe2fs));
+    executor.reply("xfs_info", ExecOutput::ok(xfs_info));
+    for filesystem in [Filesystem::Ext4, Filesystem::Xfs] {
+        assert_eq!(
+            capacity_bytes_with(&executor, &filesystem, "/dev/data/data", "/mnt")
+                .expect("capacity"),
+            gib_to_bytes(10)
+        );
+    }
+    assert_eq!(
+        executor.calls(),
+        vec!["dumpe2fs -h /dev/data/data", "xfs_info /mnt"]
+    );
+
+    // a grown fs matches the LV up to a dropped trailing group, statvfs would be smaller
+    assert!(is_grown(gib_to_bytes(10), gib_to_bytes(10)));
+    assert!(is_grown(gib_to_bytes(10) - 1024 * 1024, gib_to_bytes(10)));
+    assert!(!is_grown(gib_to_bytes(10), gib_to_bytes(11)));
+    assert!(!is_grown(
+        gib_to_bytes(10) - GROW_SLACK_BYTES,
+        gib_to_bytes(10)
+    ));
+}
+
+#[test]
+fn filesystem_without_shrink_never_removes_bsu() {
+    let mut drive = test_drive();
+    drive.filesystem = Filesystem::Xfs;
+    assert_eq!(
+        drive.scale_action(&test_state(&[10, 12], 1)),
+        ScaleAction::None
+    );
+    assert_eq!(
+        drive.scale_action(&test_state(&[10], 9)),
+        ScaleAction::CreateLarger
+    );
+    drive.filesystem = Filesystem::Ext4;
+    assert_eq!(
+        drive.scale_action(&test_state(&[10, 12, 14, 17], 20)),
+        ScaleAction::None
+    );
+}
+
+fn header_with(offset: usize, magic: &[u8]) -> Vec<u8> {
+    let mut header = vec![0u8; 0x10048];
+    header[offset..offset + magic.len()].copy_from_slice(magic);
+    header
+}
+
+#[test]
+fn format_refuses_foreign_signature() {
+    let ext4 = header_with(0x438, &[0x53, 0xEF]);
+    let luks = header_with(0, b"LUKS\xba\xbe");
+    let pv = header_with(0x200, b"LABELONE LVM2 001");
+    let swap = header_with(0xff6, b"SWAPSPACE2");
+    let zeros = vec![0u8; 0x10048];
+    assert_eq!(
+        foreign_signature(&ext4, &Filesystem::Btrfs),
+        Some("ext4".to_string())
+    );
+    assert_eq!(foreign_signature(&ext4, &Filesystem::Ext4), None);
+    assert_eq!(
+        foreign_signature(&luks, &Filesystem::Btrfs),
+        Some("LUKS".to_string())
+    );
+    assert_eq!(
+        foreign_signature(&pv, &Filesystem::Xfs),
+        Some("LVM2 PV".to_string())
+    );
+    assert_eq!(
+        foreign_signature(&swap, &Filesystem::Ext4),
+        Some("swap".to_string())
+    );
+    assert_eq!(foreign_signature(&zeros, &Filesystem::Btrfs), None);
+
+    // A btrfs-raw drive formats its first BSU device
+    let device = temp_dir().join("bsud-units-foreign-signature");
+    let device_path = device.to_string_lossy().to_string();
+    write(&device, &ext4).expect("write device");
+    *config::VM_ID.write().expect("vm id") = "i-fake".to_string();
+    let mut volume = fake_volume("vol-initial", "test", 10, Some("i-fake"));
+    if let Some(linked_volumes) = volume.linked_volumes.as_mut() {
+        linked_volumes[0].device_name = Some(device_path.clone());
+    }
+    let executor = Arc::new(MockExecutor::new());
+    let (_sender, receiver) = channel::<DriveCmd>();
+    let config = ConfigFileDrive {
+        name: "test".to_string(),
+        mount_path: "/mnt".to_string(),
+        backend: Some(Backend::BtrfsRaw),
+        ..Default::default()
+    };
+    let mut drive = Drive::new(config, receiver)
+        .with_volume_backend(Arc::new(FakeVolumes::new(vec![volume])))
+        .with_executor(executor.clone());
+    drive.fetch_all_drive_bsu().expect("fetch drive BSU");
+    let err = drive
+        .fs_format(&drive.main_volume())
+        .expect_err("ext4 data is never formatted");
+    assert!(err.to_string().contains("ext4 signature"), "{}", err);
+    assert!(executor.calls().is_empty());
+
+    write(&device, &zeros).expect("write device");
+    drive
+        .fs_format(&drive.main_volume())
+        .expect("empty device is formatted");
+    assert_eq!(
+        executor.calls(),
+        vec![format!(
+            "mkfs.btrfs -L bsud-test {}",
+            btrfs::resolve_device(&device_path)
+        )]
+    );
+    std::fs::remove_file(&device).expect("cleanup");
+}
+
+#[test]
+fn vg_create_options() {
+    assert_eq!(
+        create_vg_args("data", "/dev/xvdb", "normal", None),
+        vec!["vgcreate", "--alloc", "normal", "data", "/dev/xvdb"]
+    );
+    let config: ConfigFileDrive = serde_json::from_str(
+        r#"{"name": "test", "mount-path": "/mnt", "vg-alloc-policy": "cling", "vg-extent-size-mib": 64}"#,
+    )
+    .expect("config with vg options");
+    assert!(config.validate().is_ok());
+    let error = config_error(vec![ConfigFileDrive {
+        vg_extent_size_mib: Some(48),
+        ..config.clone()
+    }]);
+    assert!(error.contains("invalid vg-extent-size-mib"), "{}", error);
+    let error = config_error(vec![ConfigFileDrive {
+        vg_extent_size_mib: Some(0),
+        ..config.clone()
+    }]);
+    assert!(error.contains("invalid vg-extent-size-mib"), "{}", error);
+    let error = config_error(vec![ConfigFileDrive {
+        backend: Some(Backend::BtrfsRaw),
+        ..config.clone()
+    }]);
+    assert!(error.contains("invalid backend"), "{}", error);
+    assert_eq!(test_drive().vg_alloc_policy, VgAllocPolicy::Normal);
+    assert_eq!(test_drive().vg_extent_size_mib, None);
+
+    *config::VM_ID.write().expect("vm id") = "i-fake".to_string();
+    let executor = Arc::new(MockExecutor::new());
+    let (_sender, receiver) = channel::<DriveCmd>();
+    let mut drive = Drive::new(config, receiver)
+        .with_volume_backend(Arc::new(FakeVolumes::new(vec![fake_volume(
+            "vol-initial",
+            "test",
+            10,
+            Some("i-fake"),
+        )])))
+        .with_executor(executor.clone());
+    assert_eq!(drive.vg_alloc_policy, VgAllocPolicy::Cling);
+    drive.fetch_all_drive_bsu().expect("fetch drive BSU");
+    executor.reply(
+        "lvm fullreport",
+        ExecOutput::ok(r#"{"report": [{"pv": [{"pv_name": "/dev/xvdb"}]}]}"#),
+    );
+    drive.vg_create().expect("vg create");
+    assert_eq!(
+        executor.calls().last(),
+        Some(&"lvm vgcreate --alloc cling --physicalextentsize 64m test /dev/xvdb".to_string())
+    );
+}
+
+#[test]
+fn luks_encryption() {
+    let config: ConfigFileDrive = serde_json::from_str(
+        r#"{"name": "test", "mount-path": "/mnt", "encryption": {"enabled": true, "key-file": "/etc/bsud/test.key"}}"#,
+    )
+    .expect("config with encryption");
+    assert!(config.is_encrypted());
+    assert!(config.validate().is_ok());
+    assert!(doctor::required_binaries(std::slice::from_ref(&config)).contains(&"cryptsetup"));
+    let error = config_error(vec![ConfigFileDrive {
+        backend: Some(Backend::BtrfsRaw),
+        ..config.clone()
+    }]);
+    assert!(error.contains("invalid backend"), "{}", error);
+    let mut no_key = config.clone();
+    if let Some(encryption) = no_key.encryption.as_mut() {
+        encryption.key_file = String::new();
+    }
+    let error = config_error(vec![no_key]);
+    assert!(error.contains("invalid encryption"), "{}", error);
+
+    assert_eq!(crypt::mapped_path("vol-1"), "/dev/mapper/bsud-vol-1");
+    assert_eq!(
+        crypt::open_args("/dev/xvdb", "bsud-vol-1", "/etc/bsud/test.key"),
+        vec![
+            "luksOpen",
+            "--key-file",
+            "/etc/bsud/test.key",
+            "/dev/xvdb",
+            "bsud-vol-1"
+        ]
+    );
+
+    // A new empty BSU is LUKS formatted, then opened
+    let device = temp_dir().join("bsud-units-luks");
+    let device_path = device.to_string_lossy().to_string();
+    write(&device, vec![0u8; 0x10048]).expect("write device");
+    *config::VM_ID.write().expect("vm id") = "i-fake".to_string();
+    let mut volume = fake_volume("vol-initial", "test", 10, Some("i-fake"));
+    if let Some(linked_volumes) = volume.linked_volumes.as_mut() {
+        linked_volumes[0].device_name = Some(device_path.clone());
+    }
+    let executor = Arc::new(MockExecutor::new());
+    let (_sender, receiver) = channel::<DriveCmd>();
+    let mut drive = Drive::new(config, receiver)
+        .with_volume_backend(Arc::new(FakeVolumes::new(vec![volume])))
+        .with_executor(executor.clone());
+    assert!(drive
+        .settings()
+        .contains("encryption key file: /etc/bsud/test.key"));
+    drive.fetch_all_drive_bsu().expect("fetch drive BSU");
+    assert_eq!(
+        drive.lvm_bsu()[0].device_path.as_deref(),
+        Some("/dev/mapper/bsud-vol-initial")
+    );
+
+    executor.reply("cryptsetup isLuks", ExecOutput::failed(""));
+    assert!(!drive.are_luks_opened().expect("luks opened"));
+    drive.luks_open_missing().expect("luks format and open");
+    assert_eq!(
+        executor.calls(),
+        vec![
+            format!("cryptsetup isLuks {}", device_path),
+            format!(
+                "cryptsetup luksFormat --batch-mode --type luks2 --key-file /etc/bsud/test.key {}",
+                device_path
+            ),
+            format!(
+                "cryptsetup luksOpen --key-file /etc/bsud/test.key {} bsud-vol-initial",
+                device_path
+            ),
+        ]
+    );
+
+    // Existing LVM data is never encrypted over
+    write(&device, header_with(0x200, b"LABELONE LVM2 001")).expect("write device");
+    executor.reply("cryptsetup isLuks", ExecOutput::failed(""));
+    assert!(!drive.are_luks_opened().expect("luks opened"));
+    let err = drive.luks_open_missing().expect_err("pv is not formatted");
+    assert!(err.to_string().contains("LVM2 PV signature"), "{}", err);
+    assert_eq!(executor.calls().len(), 4);
+    std::fs::remove_file(&device).expect("cleanup");
+}
+
+#[test]
+fn mount_options() {
+    let mut config = drive_config("data", 4);
+    config.mount_options = Some("noatime".to_string());
+    let (_sender, receiver) = channel::<DriveCmd>();
+    let drive = Drive::new(config.clone(), receiver);
+    assert_eq!(
+        mount_args(
+            "/dev/data/bsud",
+            "/mnt/data",
+            drive.mount_options.as_deref()
+        ),
+        strings(&["-o", "noatime", "/dev/data/bsud", "/mnt/data"])
+    );
+
+    config.mount_options = Some("  ".to_string());
+    let (_sender, receiver) = channel::<DriveCmd>();
+    let drive = Drive::new(config, receiver);
+    assert_eq!(drive.mount_options, None);
+    assert_eq!(
+        mount_args(
+            "/dev/data/bsud",
+            "/mnt/data",
+            drive.mount_options.as_deref()
+        ),
+        strings(&["/dev/data/bsud", "/mnt/data"])
+    );
+}
+
+#[test]
+fn dry_run_stops_at_first_action() {
+    let mut drive = test_drive();
+    assert!(!drive.dry_run);
+    assert!(drive.dry_run_stop().is_ok());
+    drive.dry_run = true;
+    assert!(drive.dry_run_stop().is_err());
+}
+
+#[test]
+fn dry_run_runs_no_mutation() {
+    let executor = MockExecutor::new_dry_run();
+    let output = executor
+        .exec_mut("lvm", &["pvcreate", "/dev/xvdb"])
+        .expect("dry-run pvcreate");
+    assert_eq!(output, ExecOutput::ok(""));
+    assert!(executor
+        .exec_bool_mut("lvm", &["vgremove", "data"])
+        .expect("dry-run vgremove"));
+    executor
+        .exec_unchecked_mut("vgchange", &["-an", "data"])
+        .expect("dry-run vgchange");
+    assert!(executor.calls().is_empty());
+    // Reads still run to show what would be done
+    executor
+        .exec_checked("lvm", &["fullreport"])
+        .expect("fullreport");
+    assert_eq!(executor.calls(), vec!["lvm fullreport".to_string()]);
+
+    let drive = test_drive().with_dry_run(true);
+    assert!(drive.dry_run && drive.executor.dry_run());
+
+    let path = temp_dir().join("bsud-units-dry-run-folder");
+    create_dir_all(&path).expect("create folder");
+    let path_str = path.to_string_lossy().to_string();
+    assert!(remove_empty_folder(&path_str, true).expect("dry-run remove folder"));
+    assert!(path.exists());
+    remove_dir_all(&path).expect("cleanup");
+}
+
+#[test]
+fn multiple_volumes() {
+    assert_eq!(
+        lvm::lv_path("my-drive", lvm::DEFAULT_LV_NAME),
+        "/dev/mapper/my--drive-bsud"
+    );
+    assert_eq!(lvm::lv_path("data", "db-logs"), "/dev/mapper/data-db--logs");
+    assert_eq!(
+        create_lv_args("data", "bsud", None),
+        vec!["lvcreate", "--extents", "100%FREE", "-n", "bsud", "data"]
+    );
+    assert_eq!(
+        create_lv_args("data", "logs", Some("1073741824B")),
+        vec!["lvcreate", "--size", "1073741824B", "-n", "logs", "data"]
+    );
+
+    let extent = 4 * 1024 * 1024;
+    assert_eq!(
+        volume_share_bytes(25, gib_to_bytes(40), extent),
+        gib_to_bytes(10)
+    );
+    let share = volume_share_bytes(33, gib_to_bytes(10), extent);
+    assert_eq!(share % extent, 0);
+    assert!(share <= gib_to_bytes(10) * 33 / 100);
+
+    let config: ConfigFileDrive = serde_json::from_str(
+        r#"{"name": "data", "target": "online", "mount-path": "/data",
+            "volumes": [{"name": "logs", "mount-path": "/data/logs", "size-perc": 20}]}"#,
+    )
+    .expect("config with volumes");
+    let (_sender, receiver) = channel::<DriveCmd>();
+    let volumes = Drive::new(config, receiver).all_volumes();
+    assert_eq!(volumes.len(), 2);
+    assert_eq!(volumes[0].lv_name, lvm::DEFAULT_LV_NAME);
+    assert_eq!(volumes[0].mount_path, "/data");
+    assert_eq!(volumes[1].lv_name, "logs");
+    assert_eq!(volumes[1].size_perc, Some(20));
+
+    // Drives with several volumes only grow
+    let mut drive = test_drive();
+    let state = test_state(&[10, 12], 1);
+    assert_eq!(drive.scale_action(&state), ScaleAction::RemoveLargest);
+    drive.volumes = volumes[1..].to_vec();
+    assert_eq!(drive.scale_action(&state), ScaleAction::None);
+}
+
+#[test]
+fn snapshot_schedule() {
+    assert_eq!(snapshot::parse_schedule("3600"), Some(3600));
+    assert_eq!(snapshot::parse_schedule("30m"), Some(1800));
+    assert_eq!(snapshot::parse_schedule(" 6h "), Some(6 * 3600));
+    assert_eq!(snapshot::parse_schedule("1d"), Some(86400));
+    assert_eq!(snapshot::parse_schedule("0h"), None);
+    assert_eq!(snapshot::parse_schedule("daily"), None);
+    assert_eq!(snapshot::parse_schedule(""), None);
+
+    assert!(snapshot::is_due(None, 3600, 1000));
+    assert!(!snapshot::is_due(Some(1000), 3600, 1000 + 3599));
+    assert!(snapshot::is_due(Some(1000), 3600, 1000 + 3600));
+
+    let config = ConfigFileDrive {
+        snapshot_schedule: Some("6h".to_string()),
+        snapshot_retention: Some(0),
+        ..drive_config("data", 4)
+    };
+    assert!(config_error(vec![config]).contains("invalid snapshot-retention"));
+}
+
+#[test]
+fn scrub_scheduling() {
+    let week_s = 7 * 86400;
+    let now_s = 10 * week_s as i64;
+    assert_eq!(
+        scrub_action(None, None, false, false, now_s),
+        ScrubAction::None
+    );
+    assert_eq!(
+        scrub_action(Some(week_s), None, false, false, now_s),
+        ScrubAction::Start
+    );
+    assert_eq!(
+        scrub_action(Some(week_s), Some(now_s - 3600), false, false, now_s),
+        ScrubAction::None
+    );
+    assert_eq!(
+        scrub_action(
+            Some(week_s),
+            Some(now_s - week_s as i64),
+            false,
+            false,
+            now_s
+        ),
+        ScrubAction::Start
+    );
+    // never started nor kept running while the drive moves data
+    assert_eq!(
+        scrub_action(Some(week_s), None, false, true, now_s),
+        ScrubAction::None
+    );
+    assert_eq!(
+        scrub_action(Some(week_s), None, true, false, now_s),
+        ScrubAction::None
+    );
+    assert_eq!(
+        scrub_action(Some(week_s), None, true, true, now_s),
+        ScrubAction::Cancel
+    );
+
+    let status = parse_scrub_status(
+        "UUID:             5e3e0f8a-2f4c-4d2a-9c9e-2b0c1b7d9a11
+Scrub started:    Tue Oct 13 10:00:00 2026
+Status:           finished
+Duration:         0:12:42
+\tdata_extents_scrubbed: 1203
+\tcorrected_errs: 3
+\tuncorrectable_errs: 1
+\tunverified_errs: 0
+",
+    );
+    assert_eq!(
+        status,
+        ScrubStatus {
+            running: false,
+            finished: true,
+            corrected_errors: 3,
+            uncorrectable_errors: 1,
+        }
+    );
+    assert!(parse_scrub_status("Status:           running\n").running);
+    assert_eq!(
+        parse_scrub_status("no stats available\n"),
+        ScrubStatus::default()
+    );
+
+    // A finished scrub is recorded, the next one waits for the interval
+    let executor = Arc::new(MockExecutor::new());
+    let mut drive = test_drive().with_executor(executor.clone());
+    drive.dry_run = true;
+    drive.scrub_interval_s = Some(week_s);
+    drive.scrub_if_due(false);
+    assert_eq!(
+        executor.calls(),
+        vec!["btrfs scrub status -R /mnt", "btrfs scrub start /mnt"]
+    );
+    let started_epoch_s = drive.persisted.scrub_started_epoch_s.expect("started");
+    executor.reply("btrfs scrub status", ExecOutput::ok("Status: running\n"));
+    drive.scrub_if_due(true);
+    assert_eq!(
+        executor.calls().last().map(String::as_str),
+        Some("btrfs scrub cancel /mnt")
+    );
+    executor.reply(
+        "btrfs scrub status",
+        ExecOutput::ok("Status: finished\nuncorrectable_errs: 0\n"),
+    );
+    drive.scrub_if_due(false);
+    assert_eq!(
+        drive.persisted.last_scrub,
+        Some(ScrubReport {
+            started_epoch_s,
+            finished: true,
+            corrected_errors: 0,
+            uncorrectable_errors: 0,
+        })
+    );
+    assert_eq!(drive.persisted.scrub_started_epoch_s, None);
+    assert_eq!(
+        executor.calls().last().map(String::as_str),
+        Some("btrfs scrub status -R /mnt")
+    );
+
+    let config = ConfigFileDrive {
+        scrub_schedule: Some("7d".to_string()),
+        filesystem: Some(Filesystem::Ext4),
+        ..drive_config("data", 4)
+    };
+    assert!(config_error(vec![config]).contains("invalid scrub-schedule"));
+    let config = ConfigFileDrive {
+        scrub_schedule: Some("weekly".to_string()),
+        ..drive_config("data", 4)
+    };
+    assert!(config_error(vec![config]).contains("invalid scrub-schedule"));
+}
+
+#[test]
+fn watchdog_health() {
+    assert_eq!(
+        notify::watchdog_interval("30000000"),
+        Some(Duration::from_secs(30))
+    );
+    assert_eq!(notify::watchdog_interval("0"), None);
+    assert_eq!(notify::watchdog_interval("soon"), None);
+
+    let started = 1000;
+    // Nothing to watch
+    assert!(notify::is_healthy(&[], started, started + 10_000, 600));
+    // Drives get the staleness window from start to succeed a first time
+    assert!(notify::is_healthy(&[None], started, started + 599, 600));
+    assert!(!notify::is_healthy(&[None], started, started + 600, 600));
+    // One drive reconciling is enough
+    assert!(notify::is_healthy(&[None, Some(5000)], started, 5500, 600));
+    assert!(!notify::is_healthy(
+        &[Some(2000), Some(3000)],
+        started,
+        5500,
+        600
+    ));
+}
+
+#[test]
+fn cooldown_wakes_on_stop() {
+    assert_eq!(cooldown_remaining(0, 30), Some(Duration::from_secs(31)));
+    assert_eq!(cooldown_remaining(30, 30), Some(Duration::from_secs(1)));
+    assert_eq!(cooldown_remaining(31, 30), None);
+
+    let (sender, receiver) = channel::<DriveCmd>();
+    let config = ConfigFileDrive {
+        name: "cooldown".to_string(),
+        mount_path: "/mnt".to_string(),
+        ..Default::default()
+    };
+    let mut drive = Drive::new(config, receiver);
+    sender.send(DriveCmd::Stop).expect("send stop");
+    let start = Instant::now();
+    assert!(drive.wait_cooldown().is_err());
+    assert!(start.elapsed() < Duration::from_millis(500));
+}
+
+#[test]
+fn initial_reconcile_jitter() {
+    assert_eq!(initial_reconcile_delay_s(0, 1, 10), 0);
+    assert_eq!(initial_reconcile_delay_s(0, 0, 10), 0);
+    // Drives started together never share a delay while they fit in the jitter
+    for count in 1..=11 {
+        let delays: HashSet<u64> = (0..count)
+            .map(|index| initial_reconcile_delay_s(index, count, 10))
+            .collect();
+        assert_eq!(delays.len(), count);
+        assert!(delays.iter().all(|delay| *delay <= 10));
+    }
+    for index in 0..100 {
+        assert!(initial_reconcile_delay_s(index, 100, 10) <= 10);
+        assert!(initial_reconcile_delay_s(index, 100, 3600) <= 30);
+        assert_eq!(initial_reconcile_delay_s(index, 100, 0), 0);
+    }
+
+    let (_sender, receiver) = channel::<DriveCmd>();
+    let mut drive = Drive::new(drive_config("data", 4), receiver);
+    assert_eq!(drive.initial_reconcile_jitter_s, 0);
+    assert_eq!(drive.initial_reconcile_delay_s, 0);
+    let first_reconcile = drive.last_reconcile.seconds();
+    drive.delay_first_reconcile(7);
+    assert_eq!(drive.initial_reconcile_delay_s, 7);
+    assert!(drive.last_reconcile.seconds() - first_reconcile >= 7);
+}
+
+#[test]
+fn config_validation() {
+    let valid = Config {
+        drives: vec![drive_config("a", 4), drive_config("b", 4)],
+        ..Default::default()
+    };
+    valid.validate().expect("valid config");
+
+    let mut drive = drive_config("a", 4);
+    drive.max_used_space_perc = Some(101);
+    assert!(config_error(vec![drive]).contains("max-used-space-perc"));
+
+    let mut drive = drive_config("a", 4);
+    drive.min_used_space_perc = Some(90);
+    drive.max_used_space_perc = Some(80);
+    assert!(config_error(vec![drive]).contains("min-used-space-perc"));
+
+    // Defaults are taken into account: min-used-space-perc defaults to 40%
+    let mut drive = drive_config("a", 4);
+    drive.max_used_space_perc = Some(30);
+    assert!(config_error(vec![drive]).contains("min-used-space-perc"));
+
+    let err = config_error(vec![drive_config("a", 0)]);
+    assert!(err.contains("\"a\" drive"));
+    assert!(err.contains("max-bsu-count"));
+
+    let mut drive = drive_config("a", 4);
+    drive.initial_size_gib = Some(14902);
+    assert!(config_error(vec![drive]).contains("initial-size-gib"));
+
+    let mut drive = drive_config("a", 4);
+    drive.initial_size_gib = Some(0);
+    assert!(config_error(vec![drive]).contains("initial-size-gib"));
+
+    let mut drive = drive_config("a", 4);
+    drive.initial_size_gib = Some(20);
+    drive.max_total_size_gib = Some(10);
+    assert!(config_error(vec![drive]).contains("max-total-size-gib"));
+
+    let mut drive = drive_config("a", 4);
+    drive.volumes = Some(vec![
+        ConfigFileVolume {
+            name: "logs".to_string(),
+            mount_path: "/mnt/logs".to_string(),
+            size_perc: 60,
+            mount_options: None,
+        },
+        ConfigFileVolume {
+            name: "db".to_string(),
+            mount_path: "/mnt/db".to_string(),
+            size_perc: 40,
+            mount_options: None,
+        },
+    ]);
+    assert!(config_error(vec![drive]).contains("volumes"));
+
+    assert!(config_error(vec![drive_config("", 4)]).contains("name"));
+    for name in ["../etc", "a b", "a/b", "-data", "data;rm", "dàta"] {
+        assert!(
+            config_error(vec![drive_config(name, 4)]).contains("invalid name"),
+            "{}",
+            name
+        );
+    }
+    assert!(Config {
+        drives: vec![drive_config("data-1.b_2+c", 4)],
+        ..Default::default()
+    }
+    .validate()
+    .is_ok());
+}
+
+#[test]
+fn config_drive_conflicts() {
+    let err = config_error(vec![drive_config("a", 4), drive_config("a", 2)]);
+    assert!(err.contains("\"a\" and \"a\" share the name \"a\""));
+
+    // VG names are case-insensitive
+    let mut upper = drive_config("Data", 4);
+    upper.mount_path = "/mnt/upper".to_string();
+    let conflicts = drive_conflicts(&[drive_config("data", 4), upper]);
+    assert_eq!(
+        conflicts,
+        strings(&["\"data\" and \"Data\" share the name \"data\""])
+    );
+
+    let mut drive = drive_config("b", 4);
+    drive.mount_path = "/mnt/./a/".to_string();
+    let conflicts = drive_conflicts(&[drive_config("a", 4), drive]);
+    assert_eq!(
+        conflicts,
+        strings(&["\"a\" and \"b\" share mount path /mnt/a"])
+    );
+
+    assert!(drive_conflicts(&[drive_config("a", 4), drive_config("b", 4)]).is_empty());
+    assert_eq!(canonical_mount_path("/mnt/x/../y//"), "/mnt/y");
+}
+
+#[test]
+fn check_config_offline() {
+    let dir = temp_dir().join("bsud-units-check-config");
+    create_dir_all(&dir).expect("create folder");
+    let path = dir.join("bsud.json");
+    write(
+        &path,
+        r#"{"drives": [{"name": "data", "mount-path": "/mnt/data", "max-used-space-perc": 90}]}"#,
+    )
+    .expect("write config");
+    let config = config::check(path.to_string_lossy().to_string()).expect("valid config");
+    let (_sender, receiver) = channel::<DriveCmd>();
+    let settings = Drive::new(config.drives[0].clone(), receiver).settings();
+    assert!(settings.starts_with("\"data\" drive (target: online)"));
+    assert!(settings.contains("disk type: gp2"));
+    assert!(settings.contains("used space: 40% to 90%"));
+    assert!(settings.contains("clean shutdown: false"));
+
+    write(
+        &path,
+        r#"{"drives": [{"name": "data", "mount-path": "/mnt/data", "snapshot-schedule": "often"}]}"#,
+    )
+    .expect("write config");
+    let err = config::check(path.to_string_lossy().to_string()).expect_err("invalid config");
+    assert!(err.to_string().contains("snapshot-schedule"));
+    remove_dir_all(&dir).expect("cleanup");
+}
+
+#[test]
+fn clean_shutdown_reconfigure() {
+    let mut drive = test_drive();
+    assert!(!drive.clean_shutdown);
+    let config = ConfigFileDrive {
+        name: "test".to_string(),
+        mount_path: "/mnt".to_string(),
+        clean_shutdown: Some(true),
+        ..Default::default()
+    };
+    drive.apply_config(config);
+    assert!(drive.clean_shutdown);
+}
+
+#[test]
+fn stop_does_not_wait_stuck_drives() {
+    let mut drives = Drives::new(2);
+    drives.shutdown_timeout = Duration::from_millis(200);
+    drives.execute("stuck".to_string(), || {
+        std::thread::sleep(Duration::from_secs(5))
+    });
+    drives.execute("quick".to_string(), || {});
+    let start = Instant::now();
+    let err = drives.stop().expect_err("stuck drive reported");
+    assert!(start.elapsed() < Duration::from_secs(2));
+    assert!(err.to_string().contains("[\"stuck\"]"));
+
+    let mut drives = Drives::new(1);
+    drives.execute("quick".to_string(), || {});
+    drives.stop().expect("all drives stopped");
+}
+
+#[test]
+fn scheduled_drives_share_threads() {
+    let mut drives = Drives::new(1);
+    drives.max_concurrent_drives = Some(1);
+    drives.shutdown_timeout = Duration::from_secs(10);
+    let volumes = Arc::new(FakeVolumes::new(Vec::new()));
+    for name in ["a", "b", "c"] {
+        let (sender, receiver) = channel::<DriveCmd>();
+        let config = ConfigFileDrive {
+            name: name.to_string(),
+            mount_path: format!("/mnt/{}", name),
+            target: DriveTarget::Offline,
+            ..Default::default()
+        };
+        let mut drive = Drive::new(config, receiver)
+            .with_executor(Arc::new(MockExecutor::new()))
+            .with_volume_backend(volumes.clone());
+        drive.state_dir = temp_dir()
+            .join("bsud-scheduled")
+            .to_string_lossy()
+            .to_string();
+        drive.dry_run = true;
+        drives.start_drive(sender, drive);
+    }
+    // Dry run drives stop after one reconcile, one at a time
+    drives.wait();
+    drives.stop().expect("all drives stopped");
+
+    // Stop is handled while the drive waits for its next reconcile
+    let (sender, receiver) = channel::<DriveCmd>();
+    let config = ConfigFileDrive {
+        name: "idle".to_string(),
+        mount_path: "/mnt/idle".to_string(),
+        target: DriveTarget::Offline,
+        ..Default::default()
+    };
+    let mut drive = Drive::new(config, receiver)
+        .with_executor(Arc::new(MockExecutor::new()))
+        .with_volume_backend(volumes.clone());
+    drive.state_dir = temp_dir()
+        .join("bsud-scheduled")
+        .to_string_lossy()
+        .to_string();
+    drives.start_drive(sender, drive);
+    let start = Instant::now();
+    drives.stop().expect("idle drive stopped");
+    assert!(start.elapsed() < Duration::from_secs(5));
+}
+
+#[test]
+fn scale_action_hysteresis_oscillating_usage() {
+    // Decisions only depend on the time the state was gathered at
+    let now_s = 1_000_000;
+    let test_state = |bsu_sizes_gib: &[usize], used_gib: usize| DriveState {
+        now_epoch_s: now_s,
+        ..test_state(bsu_sizes_gib, used_gib)
+    };
+    let mut drive = test_drive();
+    assert_eq!(drive.resize_cooldown_s, 0);
+    assert_eq!(drive.resize_dead_band_perc, 0.0);
+    // Without hysteresis, a removal may leave usage anywhere below the thresholds
+    let state = test_state(&[10, 40], 8);
+    assert_eq!(drive.scale_action(&state), ScaleAction::RemoveLargest);
+    drive.resize_cooldown_s = 600;
+    drive.resize_dead_band_perc = 0.1;
+
+    // Low usage shrinks the drive, usage left after removal stays within the dead band
+    let state = test_state(&[10, 12], 4);
+    assert_eq!(drive.scale_action(&state), ScaleAction::RemoveLargest);
+    drive.last_resize = Some(ResizeEvent {
+        direction: ResizeDirection::Shrink,
+        epoch_s: now_s,
+    });
+
+    // Usage bouncing back right after the shrink does not grow again during cooldown
+    for used_gib in [9, 3, 9, 2, 9] {
+        let state = test_state(&[10], used_gib);
+        assert_eq!(drive.scale_action(&state), ScaleAction::None);
+    }
+    // ... unless the drive is about to be full
+    let state = test_state(&[10], 10);
+    assert_eq!(drive.scale_action(&state), ScaleAction::CreateLarger);
+
+    // Once cooldown is over, growth is allowed again
+    drive.last_resize = Some(ResizeEvent {
+        direction: ResizeDirection::Shrink,
+        epoch_s: now_s - drive.resize_cooldown_s as i64,
+    });
+    let state = test_state(&[10], 9);
+    assert_eq!(drive.scale_action(&state), ScaleAction::CreateLarger);
+
+    // A drive which just grew does not shrink during cooldown
+    drive.last_resize = Some(ResizeEvent {
+        direction: ResizeDirection::Grow,
+        epoch_s: now_s,
+    });
+    let state = test_state(&[10, 12], 1);
+    assert_eq!(drive.scale_action(&state), ScaleAction::None);
+
+    // Removing a BSU which would push usage past the dead band is skipped
+    drive.last_resize = None;
+    let state = test_state(&[10, 40], 8);
+    assert_eq!(drive.scale_action(&state), ScaleAction::None);
+    drive.resize_dead_band_perc = 0.4;
+    assert_eq!(drive.scale_action(&state), ScaleAction::RemoveLargest);
+}
+
+#[test]
+fn bsu_creation_pacing() {
+    assert_eq!(creation_delay(None, 1000, 300), None);
+    assert_eq!(creation_delay(Some(1000), 1000, 0), None);
+    assert_eq!(
+        creation_delay(Some(1000), 1100, 300),
+        Some(Duration::from_secs(200))
+    );
+    assert_eq!(creation_delay(Some(1000), 1300, 300), None);
+
+    let now_s = datetime::Instant::now().seconds();
+    let mut drive = test_drive();
+    drive.resize_cooldown_s = 0;
+    drive.min_creation_interval_s = 300;
+    drive.last_creation_epoch_s = Some(now_s - 10);
+    // still low on space but a BSU was just created
+    let state = test_state(&[10], 9);
+    assert_eq!(drive.scale_action(&state), ScaleAction::None);
+    // removals are not paced
+    let state = test_state(&[10, 12], 1);
+    assert_eq!(drive.scale_action(&state), ScaleAction::RemoveLargest);
+    let state = test_state(&[10, 12, 14, 17], 20);
+    assert_eq!(drive.scale_action(&state), ScaleAction::RemoveSmallest);
+
+    drive.last_creation_epoch_s = Some(now_s - 300);
+    let state = test_state(&[10], 9);
+    assert_eq!(drive.scale_action(&state), ScaleAction::CreateLarger);
+}
+
+#[test]
+fn failed_reconcile_records_error() {
+    let state_dir = temp_dir().join("bsud-units-reconcile-error");
+    let state_dir = state_dir.to_string_lossy().to_string();
+    let mut drive = test_drive();
+    drive.state_dir = state_dir.clone();
+
+    let failure: Result<(), Box<dyn Error>> = Err(Box::new(BsudError::Transient(
+        "cannot attach BSU".to_string(),
+    )));
+    drive.record_reconcile(&failure, 1000);
+    let status = drive.status();
+    assert_eq!(
+        status.last_error,
+        Some(ReconcileError {
+            message: "cannot attach BSU".to_string(),
+            epoch_s: 1000,
+        })
+    );
+    assert_eq!(status.last_success_epoch_s, None);
+    // error survives a restart
+    let persisted = state::load(&state_dir, &drive.name).expect("load state");
+    assert_eq!(persisted.last_error, status.last_error);
+
+    drive.record_reconcile(&Ok(()), 1030);
+    let status = drive.status();
+    assert_eq!(status.last_error, None);
+    assert_eq!(status.last_success_epoch_s, Some(1030));
+    remove_dir_all(&state_dir).expect("cleanup");
+}
+
+#[test]
+fn run_once_records_pass_result() {
+    let mut drive = test_drive();
+    assert!(!drive.run_once);
+    drive.run_once = true;
+    let results = drive.pass_results.clone();
+
+    let failure: Result<(), Box<dyn Error>> = Err(Box::new(BsudError::Transient(
+        "cannot attach BSU".to_string(),
+    )));
+    drive.record_pass(&failure);
+    assert_eq!(
+        results.lock().unwrap().get("test"),
+        Some(&Err("cannot attach BSU".to_string()))
+    );
+    drive.record_pass(&Ok(()));
+    assert_eq!(results.lock().unwrap().get("test"), Some(&Ok(())));
+
+    let drives = Drives::new(1);
+    assert!(drives.is_idle());
+    assert!(drives.pass_results().is_empty());
+}
+
+#[test]
+fn structured_errors() {
+    let api = |failure: ApiFailure| BsudError::Api {
+        operation: "CreateVolume".to_string(),
+        failure,
+        message: "quota exceeded".to_string(),
+    };
+    assert_eq!(
+        api(ApiFailure::Status(403)).retry_policy(),
+        RetryPolicy::Unhealthy
+    );
+    assert_eq!(
+        api(ApiFailure::Status(429)).retry_policy(),
+        RetryPolicy::Backoff
+    );
+    assert_eq!(
+        api(ApiFailure::Status(503)).retry_policy(),
+        RetryPolicy::Backoff
+    );
+    assert_eq!(
+        api(ApiFailure::Status(400)).to_string(),
+        "CreateVolume failed: quota exceeded"
+    );
+    let timeout = BsudError::Timeout(WaitTimeoutError {
+        desired_state: "available".to_string(),
+        last_state: "creating".to_string(),
+        waited: Duration::from_secs(300),
+    });
+    assert_eq!(timeout.retry_policy(), RetryPolicy::Soon);
+    assert_eq!(
+        BsudError::Transient("device not there yet".to_string()).retry_policy(),
+        RetryPolicy::Soon
+    );
+    assert_eq!(
+        BsudError::Config("refusing".to_string()).retry_policy(),
+        RetryPolicy::Unhealthy
+    );
+    let other: Box<dyn Error> = Box::new(format_err!("cannot attach BSU"));
+    assert_eq!(error::retry_policy(other.as_ref()), RetryPolicy::Backoff);
+
+    let executor = MockExecutor::new();
+    executor.reply("lvchange", ExecOutput::failed("partial LV"));
+    let err =
+        lvm::lv_activate_with(&executor, true, &"data".to_string()).expect_err("activation failed");
+    assert!(matches!(
+        err.downcast_ref::<BsudError>(),
+        Some(BsudError::Lvm(_))
+    ));
+    assert_eq!(error::retry_policy(err.as_ref()), RetryPolicy::Backoff);
+
+    assert_eq!(retry_delay_s(None, 0), 30);
+    assert_eq!(retry_delay_s(Some(RetryPolicy::Soon), 3), 5);
+    assert_eq!(retry_delay_s(Some(RetryPolicy::Backoff), 1), 30);
+    assert_eq!(retry_delay_s(Some(RetryPolicy::Backoff), 3), 120);
+    assert_eq!(retry_delay_s(Some(RetryPolicy::Backoff), 40), 600);
+    assert_eq!(retry_delay_s(Some(RetryPolicy::Unhealthy), 1), 600);
+
+    let state_dir = temp_dir().join("bsud-units-structured-errors");
+    let state_dir = state_dir.to_string_lossy().to_string();
+    let mut drive = test_drive();
+    drive.state_dir = state_dir.clone();
+    let failure: Result<(), Box<dyn Error>> = Err(Box::new(BsudError::Config(
+        "\"test\" drive: restored drive should already have a vg, refusing to create one"
+            .to_string(),
+    )));
+    drive.record_reconcile(&failure, 1000);
+    assert_eq!(drive.health(1010), DriveHealth::Unhealthy);
+    assert_eq!(drive.reconcile_delay_s(), 600);
+    drive.record_reconcile(&Ok(()), 1030);
+    assert_eq!(drive.health(1040), DriveHealth::Healthy);
+    assert_eq!(drive.reconcile_delay_s(), 30);
+    remove_dir_all(&state_dir).expect("cleanup");
+}
+
+#[test]
+fn btrfs_raw_backend() {
+    let output = "Label: 'bsud-data'  uuid: 3a1b2c3d-0000-4000-8000-000000000000
+\tTotal devices 3 FS bytes used 1048576
+\tdevid    1 size 10737418240 used 2172649472 path /dev/xvdb
+\tdevid    2 size 12884901888 used 0 path /dev/xvdc
+\tdevid    3 size 0 used 0 path /dev/xvdd MISSING
+
+";
+    let devices = btrfs::parse_filesystem_show(output);
+    assert_eq!(devices.len(), 3);
+    assert_eq!(
+        devices[0],
+        btrfs::Device {
+            devid: 1,
+            size_bytes: 10737418240,
+            used_bytes: 2172649472,
+            path: "/dev/xvdb".to_string(),
+            missing: false,
+        }
+    );
+    assert!(!devices[1].missing);
+    assert!(devices[2].missing);
+    assert!(btrfs::parse_filesystem_show("").is_empty());
+
+    let bsu_devices = strings(&["/dev/xvdb", "/dev/xvdc", "/dev/xvde"]);
+    assert_eq!(
+        btrfs::missing_devices(&bsu_devices, &devices),
+        strings(&["/dev/xvde"])
+    );
+    assert!(btrfs::missing_devices(&bsu_devices[..2], &devices).is_empty());
+
+    let to_add = strings(&["/dev/xvde", "/dev/xvdf"]);
+    assert_eq!(
+        btrfs::device_add_args(&to_add, "/mnt/data"),
+        vec!["device", "add", "/dev/xvde", "/dev/xvdf", "/mnt/data"]
+    );
+    assert_eq!(
+        btrfs::device_delete_args("/dev/xvdc", "/mnt/data"),
+        vec!["device", "delete", "/dev/xvdc", "/mnt/data"]
+    );
+    assert_eq!(
+        btrfs::format_args(&btrfs::label("data"), "/dev/xvdb"),
+        vec!["-L", "bsud-data", "/dev/xvdb"]
+    );
+    assert_eq!(btrfs::fstab_source("data"), "LABEL=bsud-data");
+
+    // Only the drive's own btrfs counts as mounted on its path
+    let devices = strings(&["/dev/xvdb", "/dev/xvdc"]);
+    let is_drive_mount = |fs_type, source| btrfs::is_drive_mount("data", fs_type, source, &devices);
+    assert!(is_drive_mount("btrfs", "/dev/xvdc"));
+    assert!(is_drive_mount("btrfs", "/dev/disk/by-label/bsud-data"));
+    assert!(!is_drive_mount("ext4", "/dev/xvdb"));
+    assert!(!is_drive_mount("btrfs", "/dev/xvdz"));
+    assert!(!is_drive_mount("btrfs", "/dev/disk/by-label/bsud-other"));
+
+    let config: ConfigFileDrive = serde_json::from_str(
+        r#"{"name": "data", "target": "online", "mount-path": "/data", "backend": "btrfs-raw"}"#,
+    )
+    .expect("config with backend");
+    assert_eq!(config.backend, Some(Backend::BtrfsRaw));
+    assert!(config.validate().is_ok());
+    let (_sender, receiver) = channel::<DriveCmd>();
+    let drive = Drive::new(config.clone(), receiver);
+    assert_eq!(drive.backend, Backend::BtrfsRaw);
+    assert_eq!(test_drive().backend, Backend::Lvm);
+    let main_volume = drive.main_volume();
+    assert_eq!(drive.volume_source(&main_volume), "/data");
+
+    let error = config_error(vec![ConfigFileDrive {
+        filesystem: Some(Filesystem::Xfs),
+        ..config.clone()
+    }]);
+    assert!(error.contains("invalid backend"), "{}", error);
+    let error = config_error(vec![ConfigFileDrive {
+        max_fs_size_gib: Some(100),
+        ..config
+    }]);
+    assert!(error.contains("invalid backend"), "{}", error);
+}
+
+#[test]
+fn pv_move_ionice() {
+    assert_eq!(
+        prefix_command(&[], "lvm", &["pvmove"]),
+        ("lvm", vec!["pvmove"])
+    );
+    assert_eq!(
+        lvm::pv_move_command(&[], Some("/dev/xvdc")),
+        ("lvm", vec!["pvmove", "/dev/xvdc"])
+    );
+    assert_eq!(
+        lvm::pv_move_command(&IoNiceClass::BestEffort.command_prefix(), None),
+        ("ionice", vec!["-c", "2", "-n", "7", "lvm", "pvmove"])
+    );
+
+    let config: ConfigFileDrive =
+        serde_json::from_str(r#"{"name": "test", "mount-path": "/mnt", "pvmove-ionice": "idle"}"#)
+            .expect("config with pvmove-ionice");
+    assert!(doctor::required_binaries(std::slice::from_ref(&config)).contains(&"ionice"));
+    assert!(!doctor::required_binaries(&[drive_config("data", 4)]).contains(&"ionice"));
+    let (_sender, receiver) = channel::<DriveCmd>();
+    let drive = Drive::new(config, receiver);
+    assert_eq!(drive.pvmove_ionice, IoNiceClass::Idle);
+    assert_eq!(
+        IoNiceClass::from_str("best-effort"),
+        Ok(IoNiceClass::BestEffort)
+    );
+    assert_eq!(test_drive().pvmove_ionice, IoNiceClass::None);
+
+    let executor = MockExecutor::new();
+    lvm::pv_move_with(
+        &executor,
+        &"/dev/xvdc".to_string(),
+        &drive.pvmove_ionice.command_prefix(),
+    )
+    .expect("pvmove");
+    lvm::pv_move_no_arg_with(&executor, &test_drive().pvmove_ionice.command_prefix())
+        .expect("pvmove");
+    assert_eq!(
+        executor.calls(),
+        vec!["ionice -c 3 lvm pvmove /dev/xvdc", "lvm pvmove"]
+    );
+}
+
+#[test]
+fn interrupted_bsu_removal() {
+    *config::VM_ID.write().expect("vm id") = "i-fake".to_string();
+    let tag_keys = TagKeys::default();
+    let mut volume = fake_volume("vol-a", "test", 10, Some("i-fake"));
+    volume
+        .tags
+        .get_or_insert_with(Vec::new)
+        .push(ResourceTag::new(tag_keys.managed(), "true".to_string()));
+    let volumes = Arc::new(FakeVolumes::new(vec![volume]));
+    let executor = Arc::new(MockExecutor::new());
+    let mut drive = test_drive()
+        .with_executor(executor.clone())
+        .with_volume_backend(volumes.clone());
+    drive.wait_options = WaitOptions {
+        timeout: Duration::from_secs(5),
+        poll_interval: Duration::from_millis(1),
+    };
+    let state_dir = temp_dir().join("bsud-units-interrupted-removal");
+    let state_dir = state_dir.to_string_lossy().to_string();
+    drive.state_dir = state_dir.clone();
+    let report = |pv: &str| ExecOutput::ok(&format!(r#"{{"report": [{}]}}"#, pv));
+
+    assert_eq!(
+        lvm::mark_pv_removing_args("/dev/xvdb", true),
+        vec![
+            "pvchange",
+            "--allocatable",
+            "n",
+            "--addtag",
+            "bsud-removing",
+            "/dev/xvdb"
+        ]
+    );
+    let pv = Pv {
+        pv_name: "/dev/xvdb".to_string(),
+        pv_tags: "backup,bsud-removing".to_string(),
+        pv_pe_alloc_count: "12".to_string(),
+        ..Default::default()
+    };
+    let mut vg_report = report_with_pvs(&["/dev/xvdc"]);
+    vg_report.pv.push(pv.clone());
+    assert_eq!(lvm::removing_pvs(&vg_report), vec![pv.clone()]);
+    assert!(!pv.is_evacuated());
+
+    // Interrupted before its data moved: the removal is cancelled
+    drive.fetch_all_drive_bsu().expect("fetch drive BSU");
+    executor.reply(
+        "lvm fullreport",
+        report(
+            r#"{"vg": [{"vg_name": "test"}], "pv": [{"pv_name": "/dev/xvdb", "pv_tags": "bsud-removing", "pv_pe_alloc_count": "12"}]}"#,
+        ),
+    );
+    assert!(!drive.resume_bsu_removal().expect("cancelled removal"));
+    assert_eq!(
+        executor.calls().last().map(String::as_str),
+        Some("lvm pvchange --allocatable y --deltag bsud-removing /dev/xvdb")
+    );
+
+    // Interrupted once evacuated: the PV leaves the VG, then the BSU is detached and deleted
+    let evacuated = r#"{"vg": [{"vg_name": "test"}], "pv": [{"pv_name": "/dev/xvdb", "pv_tags": "bsud-removing", "pv_pe_alloc_count": "0"}]}"#;
+    executor.reply("lvm fullreport", report(evacuated));
+    executor.reply("lvm fullreport", report(evacuated));
+    executor.reply(
+        "lvm fullreport",
+        report(r#"{"pv": [{"pv_name": "/dev/xvdb"}]}"#),
+    );
+    assert!(drive.resume_bsu_removal().expect("resumed removal"));
+    let calls = executor.calls();
+    assert!(calls.contains(&"lvm vgreduce test /dev/xvdb".to_string()));
+    assert!(calls.contains(&"lvm pvremove /dev/xvdb".to_string()));
+    assert!(volumes.volumes().is_empty());
+    assert_eq!(drive.persisted.removing_bsu_id, None);
+
+    // Interrupted after the PV left the VG: the persisted BSU is deleted
+    let volumes = Arc::new(FakeVolumes::new(vec![fake_volume(
+        "vol-b", "test", 10, None,
+    )]));
+    drive = drive.with_volume_backend(volumes.clone());
+    drive.adopt_unmanaged_bsu = true;
+    drive.fetch_all_drive_bsu().expect("fetch drive BSU");
+    drive.persisted.removing_bsu_id = Some("vol-b".to_string());
+    executor.reply("lvm fullreport", report(r#"{"vg": [{"vg_name": "test"}]}"#));
+    assert!(drive.resume_bsu_removal().expect("resumed removal"));
+    assert!(volumes.volumes().is_empty());
+    assert_eq!(
+        state::load(&state_dir, &drive.name)
+            .expect("load state")
+            .removing_bsu_id,
+        None
+    );
+    // Nothing left to resume
+    executor.reply("lvm fullreport", report(r#"{"vg": [{"vg_name": "test"}]}"#));
+    assert!(!drive.resume_bsu_removal().expect("no removal"));
+    remove_dir_all(&state_dir).expect("cleanup");
+}
+
+#[test]
+fn pv_move_progress() {
+    let mut report = report_with_pvs(&["/dev/xvdb", "/dev/xvdc"]);
+    assert!(lvm::pv_moves(&report).is_empty());
+    report.lv = vec![
+        Lv {
+            lv_name: "bsud".to_string(),
+            ..Default::default()
+        },
+        Lv {
+            lv_name: "[pvmove0]".to_string(),
+            move_pv: "/dev/xvdb".to_string(),
+            copy_percent: "42.50".to_string(),
+            ..Default::default()
+        },
+    ];
+    assert_eq!(
+        lvm::pv_moves(&report),
+        vec![lvm::PvMove {
+            lv_name: "[pvmove0]".to_string(),
+            move_pv: "/dev/xvdb".to_string(),
+            copy_percent: 42.5,
+        }]
+    );
+
+    let progress = lvm::PvMoveProgress::update(None, 10.0, 1000);
+    assert_eq!(progress.stalled_s(1000), 0);
+    // progressing move resets the stall timer
+    let progress = lvm::PvMoveProgress::update(Some(progress), 20.0, 1300);
+    assert_eq!(progress.since_epoch_s, 1300);
+    // no progress: stall time grows
+    let progress = lvm::PvMoveProgress::update(Some(progress), 20.0, 2000);
+    assert_eq!(progress.since_epoch_s, 1300);
+    assert_eq!(progress.stalled_s(5000), 3700);
+}
+
+#[test]
+fn lvm_activation_failure() {
+    let executor = MockExecutor::new();
+    assert!(lvm::vg_activate_with(&executor, true, &"data".to_string()).is_ok());
+    assert!(lvm::lv_activate_with(&executor, false, &"data".to_string()).is_ok());
+    assert_eq!(
+        executor.calls(),
+        vec!["vgchange -ay data", "lvchange -an data"]
+    );
+
+    executor.reply(
+        "lvchange",
+        ExecOutput::failed("  Refusing activation of partial LV data/data.\n"),
+    );
+    let err =
+        lvm::lv_activate_with(&executor, true, &"data".to_string()).expect_err("activation failed");
+    assert_eq!(
+        err.to_string(),
+        "cannot activate lv data: Refusing activation of partial LV data/data."
+    );
+    executor.reply(
+        "vgchange",
+        ExecOutput::failed("device-mapper: reload ioctl failed"),
+    );
+    let err =
+        lvm::vg_activate_with(&executor, true, &"data".to_string()).expect_err("activation failed");
+    assert!(err.to_string().starts_with("cannot activate vg data"));
+
+    let mut report = Lvm {
+        lv: vec![
+            Lv {
+                lv_name: "data".to_string(),
+                lv_active: "active".to_string(),
+                ..Default::default()
+            },
+            Lv {
+                lv_name: "logs".to_string(),
+                ..Default::default()
+            },
+        ],
+        ..Default::default()
+    };
+    assert_eq!(report.inactive_lvs(), vec!["logs".to_string()]);
+    report.lv[1].lv_active = "active".to_string();
+    assert!(report.inactive_lvs().is_empty());
+}
+
+#[test]
+fn drive_with_mock_executor() {
+    let lvm_report = |lv_active: &str, vg_free: &str| {
+        ExecOutput::ok(&format!(
+            r#"{{"report": [{{"vg": [{{"vg_name": "test", "vg_size": "10733223936B", "vg_free": "{}"}}],
+                "lv": [{{"lv_name": "bsud", "lv_active": "{}"}}]}}]}}"#,
+            vg_free, lv_active
+        ))
+    };
+    let executor = Arc::new(MockExecutor::new());
+    let mut drive = test_drive().with_executor(executor.clone());
+
+    // Already active LV is not activated again, a full VG has nothing to extend
+    executor.reply("lvm fullreport", lvm_report("active", "0B"));
+    executor.reply("lvm fullreport", lvm_report("active", "0B"));
+    assert!(drive.enable_lv().is_ok());
+    assert!(drive.lv_extend().is_ok());
+    assert!(executor
+        .calls()
+        .iter()
+        .all(|call| call.starts_with("lvm fullreport")));
+
+    executor.reply("lvm fullreport", lvm_report("", "0B"));
+    executor.reply("lvchange -ay test", ExecOutput::failed("activation failed"));
+    let err = drive
+        .enable_lv()
+        .expect_err("inactive lv cannot be activated");
+    assert_eq!(
+        err.to_string(),
+        "cannot activate lv test: activation failed"
+    );
+    assert_eq!(
+        executor.calls().last().map(String::as_str),
+        Some("lvchange -ay test")
+    );
+}
+
+fn fake_volume(id: &str, drive_name: &str, size_gib: i32, vm_id: Option<&str>) -> Volume {
+    let linked_volumes = vm_id.map(|vm_id| {
+        vec![LinkedVolume {
+            vm_id: Some(vm_id.to_string()),
+            device_name: Some("/dev/xvdb".to_string()),
+            state: Some("attached".to_string()),
+            ..Default::default()
+        }]
+    });
+    Volume {
+        volume_id: Some(id.to_string()),
+        size: Some(size_gib),
+        state: Some(
+            if vm_id.is_some() {
+                "in-use"
+            } else {
+                "available"
+            }
+            .to_string(),
+        ),
+        tags: Some(vec![ResourceTag::new(
+            BSU_TAG_KEY.to_string(),
+            drive_name.to_string(),
+        )]),
+        linked_volumes,
+        ..Default::default()
+    }
+}
+
+// Fake volumes whose tagging always fails, deletions are recorded
+#[derive(Debug)]
+struct UntaggableVolumes {
+    volumes: FakeVolumes,
+    fail_delete: bool,
+    deleted: Mutex<Vec<String>>,
+}
+
+impl UntaggableVolumes {
+    fn new(fail_delete: bool) -> Self {
+        UntaggableVolumes {
+            volumes: FakeVolumes::new(Vec::new()),
+            fail_delete,
+            deleted: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+impl VolumeBackend for UntaggableVolumes {
+    fn create_volume(
+        &self,
+        request: &CreateVolumeRequest,
+    ) -> Result<Option<Volume>, Box<dyn Error>> {
+        self.volumes.create_volume(request)
+    }
+
+    fn read_volumes(&self, filters: &FiltersVolume) -> Result<Vec<Volume>, Box<dyn Error>> {
+        self.volumes.read_volumes(filters)
+    }
+
+    fn link_volume(&self, request: &LinkVolumeRequest) -> Result<(), Box<dyn Error>> {
+        self.volumes.link_volume(request)
+    }
+
+    fn unlink_volume(&self, request: &UnlinkVolumeRequest) -> Result<(), Box<dyn Error>> {
+        self.volumes.unlink_volume(request)
+    }
+
+    fn delete_volume(&self, volume_id: &str) -> Result<(), Box<dyn Error>> {
+        self.deleted
+            .lock()
+            .expect("deleted volumes")
+            .push(volume_id.to_string());
+        if self.fail_delete {
+            return Err(Box::new(format_err!("delete volume rejected")));
+        }
+        self.volumes.delete_volume(volume_id)
+    }
+
+    fn update_volume(&self, request: &UpdateVolumeRequest) -> Result<(), Box<dyn Error>> {
+        self.volumes.update_volume(request)
+    }
+
+    fn create_tags(&self, _request: &CreateTagsRequest) -> Result<(), Box<dyn Error>> {
+        Err(Box::new(BsudError::Transient(
+            "create tags rejected".to_string(),
+        )))
+    }
+}
+
+#[test]
+fn untagged_bsu_deleted_after_creation() {
+    let volumes = Arc::new(UntaggableVolumes::new(false));
+    let mut drive = test_drive().with_volume_backend(volumes.clone());
+    let err = drive.create_bsu_gib(10).expect_err("tagging failure");
+    assert_eq!(err.to_string(), "create tags rejected");
+    assert_eq!(
+        *volumes.deleted.lock().expect("deleted volumes"),
+        vec!["vol-fake0"]
+    );
+    assert!(volumes.volumes.volumes().is_empty());
+
+    // The tagging error is still returned when the volume cannot be deleted either
+    let volumes = Arc::new(UntaggableVolumes::new(true));
+    let mut drive = test_drive().with_volume_backend(volumes.clone());
+    let err = drive.create_bsu_gib(10).expect_err("tagging failure");
+    assert_eq!(err.to_string(), "create tags rejected");
+    assert_eq!(
+        *volumes.deleted.lock().expect("deleted volumes"),
+        vec!["vol-fake0"]
+    );
+    assert_eq!(volumes.volumes.volumes().len(), 1);
+}
+
+#[test]
+fn account_volume_budget() {
+    let mut budget = VolumeBudget::new(Some(3));
+    budget.set_count("other", 2);
+    assert!(budget.fits(1));
+    assert!(!budget.try_reserve("test", 2));
+    assert_eq!(budget.total(), 2);
+    assert!(budget.try_reserve("test", 1));
+    assert!(!budget.fits(1));
+    budget.release("test", 1);
+    assert_eq!(budget.total(), 2);
+    budget.release("other", 3);
+    assert_eq!(budget.total(), 0);
+    assert!(VolumeBudget::default().fits(usize::MAX));
+    let config = Config {
+        max_account_bsu: Some(0),
+        ..Default::default()
+    };
+    let error = config.validate().expect_err("empty budget").to_string();
+    assert!(error.contains("invalid max-account-bsu"), "{}", error);
+
+    // Creation is refused without calling the API once the budget is reached
+    *config::VM_ID.write().expect("vm id") = "i-fake".to_string();
+    let volumes = Arc::new(FakeVolumes::new(Vec::new()));
+    let mut drive = test_drive().with_volume_backend(volumes.clone());
+    drive.wait_options = WaitOptions {
+        timeout: Duration::from_secs(5),
+        poll_interval: Duration::from_millis(1),
+    };
+    let budget = Arc::new(Mutex::new(VolumeBudget::new(Some(2))));
+    budget.lock().expect("budget").set_count("other", 1);
+    drive.volume_budget = budget.clone();
+    drive.create_bsu_gib(10).expect("create BSU");
+    let err = drive.create_bsu_gib(10).expect_err("budget reached");
+    assert!(matches!(
+        err.downcast_ref::<BsudError>(),
+        Some(BsudError::Transient(_))
+    ));
+    assert!(
+        err.to_string().contains("account volume budget reached"),
+        "{}",
+        err
+    );
+    assert_eq!(volumes.volumes().len(), 1);
+
+    // Fetching BSU keeps the count exact, deleted BSU free their slot
+    drive.fetch_all_drive_bsu().expect("fetch drive BSU");
+    assert_eq!(budget.lock().expect("budget").total(), 2);
+    drive.delete_all_bsu().expect("delete BSU");
+    assert_eq!(budget.lock().expect("budget").total(), 1);
+    drive.create_bsu_gib(10).expect("create BSU");
+}
+
+#[test]
+fn grow_path_with_fake_volumes() {
+    *config::VM_ID.write().expect("vm id") = "i-fake".to_string();
+    let volumes = Arc::new(FakeVolumes::new(vec![
+        fake_volume("vol-initial", "test", 10, Some("i-fake")),
+        fake_volume("vol-other", "other", 4, None),
+    ]));
+    let mut drive = test_drive().with_volume_backend(volumes.clone());
+    drive.wait_options = WaitOptions {
+        timeout: Duration::from_secs(5),
+        poll_interval: Duration::from_millis(1),
+    };
+
+    drive.fetch_all_drive_bsu().expect("fetch drive BSU");
+    assert_eq!(drive.all_bsu().len(), 1);
+    drive.usage_source = UsageSource::Simulated {
+        used_bytes: gib_to_bytes(9),
+        size_bytes: gib_to_bytes(10),
+    };
+    let state = drive.gather_state().expect("drive state");
+    assert_eq!(drive.scale_action(&state), ScaleAction::CreateLarger);
+
+    // The new BSU goes through creating -> available before being attached
+    drive.create_larger_bsu().expect("create larger BSU");
+    drive.fetch_all_drive_bsu().expect("fetch drive BSU");
+    let created: Vec<&Bsu> = drive
+        .all_bsu()
+        .iter()
+        .filter(|bsu| bsu.id != "vol-initial")
+        .collect();
+    assert_eq!(created.len(), 1);
+    assert_eq!(created[0].state, "available");
+    assert!(created[0].size_gib > 10);
+    assert_eq!(created[0].vm_id, None);
+
+    drive.bsu_attach_missing().expect("attach BSU");
+    drive.fetch_all_drive_bsu().expect("fetch drive BSU");
+    assert_eq!(drive.all_bsu().len(), 2);
+    for bsu in drive.all_bsu() {
+        assert_eq!(bsu.state, "in-use");
+        assert_eq!(bsu.vm_id.as_deref(), Some("i-fake"));
+    }
+    let other = volumes
+        .volumes()
+        .into_iter()
+        .find(|volume| volume.volume_id.as_deref() == Some("vol-other"))
+        .expect("other drive volume");
+    assert_eq!(other.state.as_deref(), Some("available"));
+}
+
+#[test]
+fn bsu_metadata_tags() {
+    let volumes = Arc::new(FakeVolumes::new(vec![fake_volume(
+        "vol-old", "test", 10, None,
+    )]));
+    let mut drive = test_drive().with_volume_backend(volumes.clone());
+    drive.wait_options = WaitOptions {
+        timeout: Duration::from_secs(5),
+        poll_interval: Duration::from_millis(1),
+    };
+
+    // Volumes created by older versions are still discovered, without metadata
+    drive.fetch_all_drive_bsu().expect("fetch drive BSU");
+    assert_eq!(drive.all_bsu().len(), 1);
+    assert_eq!(drive.all_bsu()[0].seq, None);
+    assert_eq!(drive.all_bsu()[0].bsud_version, None);
+    assert_eq!(drive.next_bsu_seq(), 1);
+
+    drive.create_bsu_gib(10).expect("create BSU");
+    drive.fetch_all_drive_bsu().expect("fetch drive BSU");
+    drive.create_bsu_gib(12).expect("create BSU");
+    drive.fetch_all_drive_bsu().expect("fetch drive BSU");
+    let mut created: Vec<&Bsu> = drive
+        .all_bsu()
+        .iter()
+        .filter(|bsu| bsu.id != "vol-old")
+        .collect();
+    created.sort_by_key(|bsu| bsu.seq);
+    assert_eq!(created.len(), 2);
+    assert_eq!(created[0].seq, Some(1));
+    assert_eq!(created[1].seq, Some(2));
+    for bsu in created {
+        assert_eq!(bsu.drive_name, "test");
+        assert_eq!(bsu.bsud_version.as_deref(), Some(env!("CARGO_PKG_VERSION")));
+        assert!(bsu.created_at_epoch_s.is_some());
+    }
+
+    let tags = Bsu::creation_tags(&TagKeys::default(), "test", "i-fake", 3, 1709288430);
+    let volume = Volume {
+        tags: Some(tags),
+        ..fake_volume("vol-new", "test", 10, None)
+    };
+    let bsu = Bsu::new(&volume, &TagKeys::default()).expect("bsu");
+    assert_eq!(bsu.drive_name, "test");
+    assert_eq!(bsu.seq, Some(3));
+    assert_eq!(bsu.created_at_epoch_s, Some(1709288430));
+}
+
+#[test]
+fn frozen_drive_does_not_scale() {
+    for target in ["online", "offline", "delete", "frozen"] {
+        let parsed = DriveTarget::from_str(target).expect("target");
+        assert_eq!(parsed.to_string(), target);
+    }
+    let config: ConfigFileDrive =
+        serde_json::from_str(r#"{"name": "test", "mount-path": "/mnt", "target": "frozen"}"#)
+            .expect("config");
+    assert_eq!(config.target, DriveTarget::Frozen);
+
+    *config::VM_ID.write().expect("vm id") = "i-fake".to_string();
+    let volumes = Arc::new(FakeVolumes::new(vec![fake_volume(
+        "vol-initial",
+        "test",
+        10,
+        Some("i-fake"),
+    )]));
+    let mut drive = test_drive().with_volume_backend(volumes.clone());
+    drive.wait_options = WaitOptions {
+        timeout: Duration::from_secs(5),
+        poll_interval: Duration::from_millis(1),
+    };
+    drive.target = DriveTarget::Frozen;
+    drive.trace_reconcile = true;
+    drive.fetch_all_drive_bsu().expect("fetch drive BSU");
+    drive.usage_source = UsageSource::Simulated {
+        used_bytes: gib_to_bytes(9),
+        size_bytes: gib_to_bytes(10),
+    };
+    let state = drive.gather_state().expect("drive state");
+    assert_eq!(drive.scale_action(&state), ScaleAction::CreateLarger);
+    assert_eq!(drive.scale(&state).expect("scale"), ScaleAction::None);
+    assert_eq!(volumes.volumes().len(), 1);
+    assert!(drive.trace.to_string().contains("frozen: skip scaling"));
+
+    // Back online, the same state grows the drive
+    drive.target = DriveTarget::Online;
+    assert_eq!(
+        drive.scale(&state).expect("scale"),
+        ScaleAction::CreateLarger
+    );
+    assert_eq!(volumes.volumes().len(), 2);
+}
+
+#[test]
+fn read_only_drive() {
+    let parsed = DriveTarget::from_str("read-only").expect("target");
+    assert_eq!(parsed, DriveTarget::ReadOnly);
+    assert_eq!(parsed.to_string(), "read-only");
+
+    *config::VM_ID.write().expect("vm id") = "i-fake".to_string();
+    let volumes = Arc::new(FakeVolumes::new(vec![fake_volume(
+        "vol-initial",
+        "test",
+        10,
+        Some("i-fake"),
+    )]));
+    let executor = Arc::new(MockExecutor::new());
+    let mut drive = test_drive()
+        .with_volume_backend(volumes.clone())
+        .with_executor(executor.clone());
+    drive.wait_options = WaitOptions {
+        timeout: Duration::from_secs(5),
+        poll_interval: Duration::from_millis(1),
+    };
+    drive.target = DriveTarget::ReadOnly;
+    drive.trace_reconcile = true;
+    drive.fetch_all_drive_bsu().expect("fetch drive BSU");
+    drive.usage_source = UsageSource::Simulated {
+        used_bytes: gib_to_bytes(9),
+        size_bytes: gib_to_bytes(10),
+    };
+    let state = drive.gather_state().expect("drive state");
+    assert!(!drive.can_scale());
+    assert_eq!(drive.scale(&state).expect("scale"), ScaleAction::None);
+    assert_eq!(volumes.volumes().len(), 1);
+    assert!(drive.trace.to_string().contains("read-only: skip scaling"));
+
+    // A read-only mount is ready only for read-only drives
+    let check = MountCheck {
+        mounted: true,
+        fs_type: Some("btrfs".to_string()),
+        writable: false,
+    };
+    assert!(drive.is_ready(&check));
+
+    drive
+        .fs_mount(&drive.main_volume())
+        .expect("read-only mount");
+    assert!(executor
+        .calls()
+        .contains(&"mount -o ro /dev/mapper/test-bsud /mnt".to_string()));
+
+    drive.target = DriveTarget::Online;
+    assert!(!drive.is_ready(&check));
+}
+
+#[test]
+fn tag_prefix_isolation() {
+    let tagged = |id: &str, tag_keys: &TagKeys| Volume {
+        tags: Some(vec![ResourceTag::new(
+            tag_keys.drive_name(),
+            "test".to_string(),
+        )]),
+        ..fake_volume(id, "test", 10, None)
+    };
+    let prefix_a = TagKeys::new("a.bsud");
+    let prefix_b = TagKeys::new("b.bsud");
+    let volumes = Arc::new(FakeVolumes::new(vec![
+        tagged("vol-a", &prefix_a),
+        tagged("vol-b", &prefix_b),
+    ]));
+    let ids = |all_bsu: Vec<Bsu>| all_bsu.into_iter().map(|bsu| bsu.id).collect::<Vec<_>>();
+
+    let name = "test".to_string();
+    let fetched = Bsu::fetch_drive(volumes.as_ref(), &prefix_a, &name).expect("fetch");
+    assert_eq!(ids(fetched), vec!["vol-a"]);
+    let fetched = Bsu::fetch_all_drives(volumes.as_ref(), &prefix_b).expect("fetch");
+    assert_eq!(ids(fetched), vec!["vol-b"]);
+    let fetched = Bsu::fetch_all_drives(volumes.as_ref(), &TagKeys::default()).expect("fetch");
+    assert!(fetched.is_empty());
+
+    // Discovery only sees drives linked to this VM
+    let linked = FakeVolumes::new(vec![
+        fake_volume("vol-here", "here", 10, Some("i-fake")),
+        fake_volume("vol-there", "there", 10, Some("i-other")),
+        fake_volume("vol-free", "free", 10, None),
+    ]);
+    let fetched =
+        Bsu::fetch_vm_drives(&linked, &TagKeys::default(), &"i-fake".to_string()).expect("fetch");
+    assert_eq!(ids(fetched), vec!["vol-here"]);
+    let fetched = Bsu::fetch_all_drives(&linked, &TagKeys::default()).expect("fetch");
+    assert_eq!(ids(fetched).len(), 3);
+
+    // BSU created by a drive only carry its own prefix
+    let mut drive = test_drive().with_volume_backend(volumes.clone());
+    drive.tag_keys = prefix_a.clone();
+    drive.wait_options = WaitOptions {
+        timeout: Duration::from_secs(5),
+        poll_interval: Duration::from_millis(1),
+    };
+    drive.create_bsu_gib(10).expect("create BSU");
+    let fetched = Bsu::fetch_drive(volumes.as_ref(), &prefix_a, &name).expect("fetch");
+    assert_eq!(fetched.len(), 2);
+    let fetched = Bsu::fetch_drive(volumes.as_ref(), &prefix_b, &name).expect("fetch");
+    assert_eq!(ids(fetched), vec!["vol-b"]);
+
+    let config = Config {
+        tag_prefix: Some(String::new()),
+        ..Default::default()
+    };
+    assert!(config.validate().is_err());
+    let config = Config {
+        tag_prefix: Some("a.bsud".to_string()),
+        ..Default::default()
+    };
+    assert_eq!(config.tag_keys().drive_name(), "a.bsud.drive-name");
+}
+
+#[test]
+fn unmanaged_bsu_guard() {
+    *config::VM_ID.write().expect("vm id") = "i-fake".to_string();
+    let tag_keys = TagKeys::default();
+    let managed = |id: &str| {
+        let mut volume = fake_volume(id, "test", 10, Some("i-fake"));
+        volume
+            .tags
+            .get_or_insert_with(Vec::new)
+            .push(ResourceTag::new(tag_keys.managed(), "true".to_string()));
+        volume
+    };
+    let volumes = Arc::new(FakeVolumes::new(vec![
+        managed("vol-managed"),
+        fake_volume("vol-foreign", "test", 10, Some("i-fake")),
+    ]));
+    let mut drive = test_drive().with_volume_backend(volumes.clone());
+    drive.wait_options = WaitOptions {
+        timeout: Duration::from_secs(5),
+        poll_interval: Duration::from_millis(1),
+    };
+    let state = |id: &str| {
+        volumes
+            .volumes()
+            .into_iter()
+            .find(|volume| volume.volume_id.as_deref() == Some(id))
+            .and_then(|volume| volume.state)
+    };
+
+    drive.fetch_all_drive_bsu().expect("fetch drive BSU");
+    let foreign = drive
+        .all_bsu()
+        .iter()
+        .find(|bsu| bsu.id == "vol-foreign")
+        .cloned()
+        .expect("foreign BSU");
+    assert!(!foreign.managed);
+    let err = drive.remove_bsu(&foreign).expect_err("refused removal");
+    assert!(err.to_string().contains("unmanaged BSU vol-foreign"));
+
+    // Managed BSU are still detached and deleted, the foreign one is left untouched
+    let err = drive
+        .bsu_detach_all_from_this_vm()
+        .expect_err("refused detach");
+    assert!(err.to_string().contains("detach unmanaged BSU vol-foreign"));
+    assert_eq!(state("vol-managed").as_deref(), Some("available"));
+    assert_eq!(state("vol-foreign").as_deref(), Some("in-use"));
+
+    drive.fetch_all_drive_bsu().expect("fetch drive BSU");
+    assert!(drive.delete_all_bsu().is_err());
+    assert_eq!(state("vol-managed"), None);
+    assert_eq!(state("vol-foreign").as_deref(), Some("in-use"));
+
+    drive.adopt_unmanaged_bsu = true;
+    drive.fetch_all_drive_bsu().expect("fetch drive BSU");
+    assert!(drive.all_bsu()[0].managed);
+    let bsu = Bsu::new(&volumes.volumes()[0], &tag_keys).expect("bsu");
+    assert!(bsu.managed);
+    assert_eq!(bsu.owner.as_deref(), Some("i-fake"));
+}
+
+#[test]
+fn bsu_modification() {
+    let request = Bsu::modify_request("vol-a", &DiskType::Io1, 10, Some(50));
+    assert_eq!(request.volume_id, "vol-a");
+    assert_eq!(request.volume_type.as_deref(), Some("io1"));
+    assert_eq!(request.iops, Some(500));
+    let request = Bsu::modify_request("vol-a", &DiskType::Gp2, 10, Some(50));
+    assert_eq!(request.volume_type.as_deref(), Some("gp2"));
+    assert_eq!(request.iops, None);
+
+    let volumes = FakeVolumes::new(vec![Volume {
+        volume_type: Some("gp2".to_string()),
+        ..fake_volume("vol-a", "test", 10, None)
+    }]);
+    let bsu = Bsu::new(&volumes.volumes()[0], &TagKeys::default()).expect("bsu");
+    assert!(!bsu.needs_modification(&DiskType::Gp2, None));
+    assert!(bsu.needs_modification(&DiskType::Io1, Some(50)));
+
+    let wait = WaitOptions {
+        timeout: Duration::from_secs(5),
+        poll_interval: Duration::from_millis(1),
+    };
+    bsu.modify(&volumes, &DiskType::Io1, Some(50), &wait, &mut || Ok(()))
+        .expect("modify BSU");
+    let bsu = Bsu::new(&volumes.volumes()[0], &TagKeys::default()).expect("bsu");
+    assert_eq!(bsu.disk_type, "io1");
+    assert_eq!(bsu.iops, Some(500));
+    assert!(!bsu.needs_modification(&DiskType::Io1, Some(50)));
+    assert!(bsu.needs_modification(&DiskType::Io1, Some(30)));
+}
+
+#[test]
+fn doctor_checks() {
+    let executor = MockExecutor::new();
+    executor.reply(
+        "lvm --version",
+        ExecOutput::ok("  LVM version:     2.03.16(2)\n"),
+    );
+    executor.reply(
+        "btrfs --version",
+        ExecOutput::failed("btrfs: permission denied"),
+    );
+    let lvm = doctor::binary_check(&executor, "lvm");
+    assert!(lvm.passed);
+    assert_eq!(lvm.to_string(), "[PASS] lvm: LVM version:     2.03.16(2)");
+    let btrfs = doctor::binary_check(&executor, "btrfs");
+    assert!(!btrfs.passed);
+    assert!(btrfs.to_string().starts_with("[FAIL] btrfs: "));
+    assert!(btrfs.detail.contains("permission denied"));
+
+    let warning = Check {
+        name: "optional".to_string(),
+        passed: false,
+        critical: false,
+        detail: String::new(),
+    };
+    assert_eq!(warning.to_string(), "[WARN] optional");
+    assert_eq!(doctor::exit_code(&[lvm.clone(), warning]), 0);
+    assert_eq!(doctor::exit_code(&[lvm, btrfs]), 1);
+}
+
+#[test]
+fn pre_flight_required_binaries() {
+    let lvm_ext4 = ConfigFileDrive {
+        filesystem: Some(Filesystem::Ext4),
+        ..Default::default()
+    };
+    assert_eq!(
+        doctor::required_binaries(&[lvm_ext4]),
+        vec![
+            "mount",
+            "umount",
+            "lvm",
+            "vgchange",
+            "lvchange",
+            "vgscan",
+            "mkfs.ext4",
+            "resize2fs"
+        ]
+    );
+    let btrfs_raw = ConfigFileDrive {
+        backend: Some(Backend::BtrfsRaw),
+        ..Default::default()
+    };
+    let xfs_frozen = ConfigFileDrive {
+        filesystem: Some(Filesystem::Xfs),
+        snapshot_freeze: Some(true),
+        ..Default::default()
+    };
+    let binaries = doctor::required_binaries(&[btrfs_raw, xfs_frozen]);
+    assert_eq!(
+        binaries,
+        vec![
+            "mount",
+            "umount",
+            "btrfs",
+            "mkfs.btrfs",
+            "lvm",
+            "vgchange",
+            "lvchange",
+            "vgscan",
+            "mkfs.xfs",
+            "xfs_growfs",
+            "fsfreeze"
+        ]
+    );
+
+    let bin = temp_dir().join("bsud-units-pre-flight-bin");
+    create_dir_all(&bin).expect("create folder");
+    write(bin.join("mount"), "#!/bin/sh").expect("write binary");
+    set_permissions(bin.join("mount"), Permissions::from_mode(0o755)).expect("chmod binary");
+    write(bin.join("umount"), "not executable").expect("write file");
+    let path = format!("/nonexistent:{}", bin.to_string_lossy());
+    assert_eq!(
+        doctor::missing_binaries(&["mount", "umount", "lvm"], &path),
+        vec!["umount", "lvm"]
+    );
+    remove_dir_all(&bin).expect("cleanup");
+}
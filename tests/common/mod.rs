@@ -0,0 +1,323 @@
+// Test doubles shared by the integration tests, kept out of the library
+use bsudlib::bsu::VolumeBackend;
+use bsudlib::utils::{cmd_str, ExecOutput, Executor};
+use easy_error::format_err;
+use outscale_api::models::{
+    CreateTagsRequest, CreateVolumeRequest, FiltersVolume, LinkVolumeRequest, LinkedVolume,
+    UnlinkVolumeRequest, UpdateVolumeRequest, Volume,
+};
+use std::error::Error;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+// Replies with scripted outputs: the first expected reply whose command line starts with
+// the given prefix is consumed, commands without a scripted reply succeed with no output.
+#[derive(Debug, Default)]
+pub struct MockExecutor {
+    replies: Mutex<Vec<(String, ExecOutput)>>,
+    calls: Mutex<Vec<String>>,
+    dry_run: bool,
+}
+
+impl MockExecutor {
+    pub fn new() -> Self {
+        MockExecutor::default()
+    }
+
+    // Commands changing the system are only logged, like with `bsud --dry-run`
+    pub fn new_dry_run() -> Self {
+        MockExecutor {
+            dry_run: true,
+            ..Default::default()
+        }
+    }
+
+    pub fn reply(&self, cmd_prefix: &str, output: ExecOutput) -> &Self {
+        if let Ok(mut replies) = self.replies.lock() {
+            replies.push((cmd_prefix.to_string(), output));
+        }
+        self
+    }
+
+    // Command lines run so far, e.g. "lvchange -ay data"
+    pub fn calls(&self) -> Vec<String> {
+        match self.calls.lock() {
+            Ok(calls) => calls.clone(),
+            Err(_) => Vec::new(),
+        }
+    }
+}
+
+impl Executor for MockExecutor {
+    fn dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    fn exec(&self, cmd: &str, args: &[&str]) -> Result<ExecOutput, Box<dyn Error>> {
+        let cmd_str = cmd_str(cmd, args);
+        if let Ok(mut calls) = self.calls.lock() {
+            calls.push(cmd_str.clone());
+        }
+        let Ok(mut replies) = self.replies.lock() else {
+            return Err(Box::new(format_err!("mock executor poisoned")));
+        };
+        match replies
+            .iter()
+            .position(|(prefix, _)| cmd_str.starts_with(prefix.as_str()))
+        {
+            Some(index) => Ok(replies.remove(index).1),
+            None => Ok(ExecOutput::ok("")),
+        }
+    }
+}
+
+// In-memory volumes: each read moves pending volumes one step further, like the API does
+// over time (creating -> available, attaching -> in-use, detaching -> available).
+#[derive(Debug, Default)]
+pub struct FakeVolumes {
+    volumes: Mutex<Vec<Volume>>,
+    created: AtomicUsize,
+    refused_deletes: Mutex<Vec<String>>,
+}
+
+impl FakeVolumes {
+    pub fn new(volumes: Vec<Volume>) -> Self {
+        FakeVolumes {
+            volumes: Mutex::new(volumes),
+            created: AtomicUsize::new(0),
+            refused_deletes: Mutex::new(Vec::new()),
+        }
+    }
+
+    // Deleting this volume fails like an API error would
+    pub fn refuse_delete(&self, volume_id: &str) {
+        if let Ok(mut refused_deletes) = self.refused_deletes.lock() {
+            refused_deletes.push(volume_id.to_string());
+        }
+    }
+
+    pub fn volumes(&self) -> Vec<Volume> {
+        match self.volumes.lock() {
+            Ok(volumes) => volumes.clone(),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    fn update<T, F>(&self, volume_id: &str, update: F) -> Result<T, Box<dyn Error>>
+    where
+        F: FnOnce(&mut Volume) -> Result<T, Box<dyn Error>>,
+    {
+        let mut volumes = self
+            .volumes
+            .lock()
+            .map_err(|_| format_err!("fake volumes poisoned"))?;
+        let Some(volume) = volumes
+            .iter_mut()
+            .find(|volume| volume.volume_id.as_deref() == Some(volume_id))
+        else {
+            return Err(Box::new(format_err!("volume {} not found", volume_id)));
+        };
+        update(volume)
+    }
+
+    fn transition(volume: &mut Volume) {
+        match volume.state.as_deref() {
+            Some("creating") => volume.state = Some("available".to_string()),
+            Some("available") | Some("in-use") => {
+                let links = volume.linked_volumes.take().unwrap_or_default();
+                let links: Vec<LinkedVolume> = links
+                    .into_iter()
+                    .filter(|link| link.state.as_deref() != Some("detaching"))
+                    .map(|mut link| {
+                        if link.state.as_deref() == Some("attaching") {
+                            link.state = Some("attached".to_string());
+                        }
+                        link
+                    })
+                    .collect();
+                volume.state = Some(
+                    if links.is_empty() {
+                        "available"
+                    } else {
+                        "in-use"
+                    }
+                    .to_string(),
+                );
+                volume.linked_volumes = Some(links).filter(|links| !links.is_empty());
+            }
+            _ => {}
+        }
+    }
+
+    fn matches(volume: &Volume, filters: &FiltersVolume) -> bool {
+        let tags = volume.tags.clone().unwrap_or_default();
+        let has_tag = |key: &str, value: Option<&str>| {
+            tags.iter().any(|tag| {
+                tag.key == key
+                    && match value {
+                        Some(value) => tag.value == value,
+                        None => true,
+                    }
+            })
+        };
+        if let Some(ids) = &filters.volume_ids {
+            if !ids.iter().any(|id| volume.volume_id.as_ref() == Some(id)) {
+                return false;
+            }
+        }
+        if let Some(states) = &filters.volume_states {
+            if !states
+                .iter()
+                .any(|state| volume.state.as_ref() == Some(state))
+            {
+                return false;
+            }
+        }
+        if let Some(vm_ids) = &filters.link_volume_vm_ids {
+            let links = volume.linked_volumes.clone().unwrap_or_default();
+            if !links.iter().any(|link| {
+                vm_ids
+                    .iter()
+                    .any(|vm_id| link.vm_id.as_ref() == Some(vm_id))
+            }) {
+                return false;
+            }
+        }
+        if let Some(keys) = &filters.tag_keys {
+            if !keys.iter().all(|key| has_tag(key, None)) {
+                return false;
+            }
+        }
+        if let Some(filter_tags) = &filters.tags {
+            for filter_tag in filter_tags {
+                let (key, value) = filter_tag.split_once('=').unwrap_or((filter_tag, ""));
+                if !has_tag(key, Some(value)) {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+}
+
+impl VolumeBackend for FakeVolumes {
+    fn create_volume(
+        &self,
+        request: &CreateVolumeRequest,
+    ) -> Result<Option<Volume>, Box<dyn Error>> {
+        let mut volumes = self
+            .volumes
+            .lock()
+            .map_err(|_| format_err!("fake volumes poisoned"))?;
+        let volume = Volume {
+            volume_id: Some(format!(
+                "vol-fake{}",
+                self.created.fetch_add(1, Ordering::SeqCst)
+            )),
+            size: request.size,
+            iops: request.iops,
+            volume_type: request.volume_type.clone(),
+            snapshot_id: request.snapshot_id.clone(),
+            subregion_name: Some(request.subregion_name.clone()),
+            state: Some("creating".to_string()),
+            ..Default::default()
+        };
+        volumes.push(volume.clone());
+        Ok(Some(volume))
+    }
+
+    fn read_volumes(&self, filters: &FiltersVolume) -> Result<Vec<Volume>, Box<dyn Error>> {
+        let mut volumes = self
+            .volumes
+            .lock()
+            .map_err(|_| format_err!("fake volumes poisoned"))?;
+        volumes.iter_mut().for_each(FakeVolumes::transition);
+        Ok(volumes
+            .iter()
+            .filter(|volume| FakeVolumes::matches(volume, filters))
+            .cloned()
+            .collect())
+    }
+
+    fn link_volume(&self, request: &LinkVolumeRequest) -> Result<(), Box<dyn Error>> {
+        self.update(&request.volume_id, |volume| {
+            if volume.state.as_deref() != Some("available") {
+                return Err(Box::new(format_err!(
+                    "volume {} is not available",
+                    request.volume_id
+                )));
+            }
+            volume.linked_volumes = Some(vec![LinkedVolume {
+                device_name: Some(request.device_name.clone()),
+                vm_id: Some(request.vm_id.clone()),
+                volume_id: Some(request.volume_id.clone()),
+                state: Some("attaching".to_string()),
+                ..Default::default()
+            }]);
+            Ok(())
+        })
+    }
+
+    fn unlink_volume(&self, request: &UnlinkVolumeRequest) -> Result<(), Box<dyn Error>> {
+        self.update(&request.volume_id, |volume| {
+            let Some(links) = volume.linked_volumes.as_mut() else {
+                return Err(Box::new(format_err!(
+                    "volume {} is not linked",
+                    request.volume_id
+                )));
+            };
+            links
+                .iter_mut()
+                .for_each(|link| link.state = Some("detaching".to_string()));
+            Ok(())
+        })
+    }
+
+    fn delete_volume(&self, volume_id: &str) -> Result<(), Box<dyn Error>> {
+        let refused = match self.refused_deletes.lock() {
+            Ok(refused_deletes) => refused_deletes.iter().any(|id| id == volume_id),
+            Err(_) => false,
+        };
+        if refused {
+            return Err(Box::new(format_err!(
+                "volume {} deletion refused",
+                volume_id
+            )));
+        }
+        let in_use = self.update(volume_id, |volume| Ok(volume.linked_volumes.is_some()))?;
+        if in_use {
+            return Err(Box::new(format_err!("volume {} is in use", volume_id)));
+        }
+        let mut volumes = self
+            .volumes
+            .lock()
+            .map_err(|_| format_err!("fake volumes poisoned"))?;
+        volumes.retain(|volume| volume.volume_id.as_deref() != Some(volume_id));
+        Ok(())
+    }
+
+    fn update_volume(&self, request: &UpdateVolumeRequest) -> Result<(), Box<dyn Error>> {
+        self.update(&request.volume_id, |volume| {
+            if let Some(volume_type) = &request.volume_type {
+                volume.volume_type = Some(volume_type.clone());
+            }
+            if request.iops.is_some() {
+                volume.iops = request.iops;
+            }
+            Ok(())
+        })
+    }
+
+    fn create_tags(&self, request: &CreateTagsRequest) -> Result<(), Box<dyn Error>> {
+        for volume_id in request.resource_ids.iter() {
+            self.update(volume_id, |volume| {
+                volume
+                    .tags
+                    .get_or_insert_with(Vec::new)
+                    .extend(request.tags.iter().cloned());
+                Ok(())
+            })?;
+        }
+        Ok(())
+    }
+}